@@ -1 +1 @@
-export default {"openapi":"3.0.3","info":{"title":"keygate-server","description":"","license":{"name":""},"version":"0.1.0"},"paths":{"/auth/validate":{"post":{"tags":["auth"],"summary":"Validate","description":"Validate\n\nValidate a token.","operationId":"validate","requestBody":{"content":{"application/json":{"schema":{"$ref":"#/components/schemas/ValidateRequest"}}},"required":true},"responses":{"200":{"description":"Token is valid.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/ValidateResponse"}}}},"400":{"description":"Invalid request.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}}}},"components":{"schemas":{"AppError":{"type":"object","title":"AppError","description":"An error that can be returned by the API","properties":{"error":{"type":"string"}}},"ValidateRequest":{"type":"object","required":["token"],"properties":{"token":{"type":"string"}}},"ValidateResponse":{"type":"object","required":["valid"],"properties":{"valid":{"type":"boolean"}}}}}} as const;
\ No newline at end of file
+export default {"openapi":"3.0.3","info":{"title":"keygate-server","description":"","license":{"name":""},"version":"0.1.0"},"paths":{"/auth/validate":{"post":{"tags":["auth"],"summary":"Validate","description":"Validate\n\nValidate a token.","operationId":"validate","requestBody":{"content":{"application/json":{"schema":{"$ref":"#/components/schemas/ValidateRequest"}}},"required":true},"responses":{"200":{"description":"Token is valid.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/ValidateResponse"}}}},"400":{"description":"Invalid request.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}}},"/domains":{"get":{"tags":["domains"],"summary":"List Domains","description":"List Domains\n\nEmail domains whose users log in with an upstream provider.","operationId":"list_domains","responses":{"200":{"description":"Registered domains.","content":{"application/json":{"schema":{"type":"array","items":{"$ref":"#/components/schemas/SsoDomain"}}}}}}},"post":{"tags":["domains"],"summary":"Add Domain","description":"Add Domain\n\nRegister an email domain for an upstream provider. Logins are routed to the provider once the domain is verified.","operationId":"add_domain","requestBody":{"content":{"application/json":{"schema":{"$ref":"#/components/schemas/AddDomainRequest"}}},"required":true},"responses":{"200":{"description":"Domain registered, publish the verification token to verify it.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/SsoDomain"}}}},"400":{"description":"Invalid domain.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}},"404":{"description":"Upstream provider not found.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}},"409":{"description":"The domain is already registered.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}}},"/domains/:domain":{"put":{"tags":["domains"],"summary":"Update Domain","description":"Update Domain\n\nBlock or allow password logins for users of a domain.","operationId":"update_domain","requestBody":{"content":{"application/json":{"schema":{"$ref":"#/components/schemas/UpdateDomainRequest"}}},"required":true},"responses":{"200":{"description":"Domain updated.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/SsoDomain"}}}},"404":{"description":"Domain not found.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}},"delete":{"tags":["domains"],"summary":"Remove Domain","description":"Remove Domain\n\nStop routing logins of a domain to its upstream provider.","operationId":"remove_domain","responses":{"200":{"description":"Domain removed."},"404":{"description":"Domain not found.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}}},"/domains/:domain/verify":{"post":{"tags":["domains"],"summary":"Verify Domain","description":"Verify Domain\n\nCheck that `_keygate-challenge.<domain>` has a TXT record `keygate-domain-verification=<token>`.","operationId":"verify_domain","responses":{"200":{"description":"Domain verified.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/SsoDomain"}}}},"400":{"description":"Verification record not found.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}},"404":{"description":"Domain not found.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}}},"/identity/:identity_id":{"get":{"tags":["identity"],"summary":"Profile","description":"Profile\n\nGet an identity.","operationId":"profile","responses":{"200":{"description":"The identity.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/IdentityProfile"}}}},"404":{"description":"Identity not found.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}}},"/identity/:identity_id/emails/:email/verify":{"post":{"tags":["identity"],"summary":"Mark Email Verified","description":"Mark Email Verified\n\nMark an email address of an identity as verified without sending a code.","operationId":"set_email_verified","responses":{"200":{"description":"Email verified."},"404":{"description":"Email not found.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}}},"/identity/:identity_id/password":{"put":{"tags":["identity"],"summary":"Set Password","description":"Set Password\n\nSet the password of an identity and/or require it to choose a new one on the next login.","operationId":"set_password","requestBody":{"content":{"application/json":{"schema":{"$ref":"#/components/schemas/SetPasswordRequest"}}},"required":true},"responses":{"200":{"description":"Password updated."},"400":{"description":"Invalid password.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}},"404":{"description":"Identity not found.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}}},"/identity/:identity_id/roles":{"get":{"tags":["identity"],"summary":"Roles","description":"Roles\n\nRoles of an identity, synced from its LDAP groups when it logs in.","operationId":"roles","responses":{"200":{"description":"Roles.","content":{"application/json":{"schema":{"type":"array","items":{"type":"string"}}}}},"400":{"description":"Invalid request.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}}},"/identity/:identity_id/trusted-devices":{"get":{"tags":["identity"],"summary":"Trusted Devices","description":"Trusted Devices\n\nList devices of an identity that can skip the second factor when logging in.","operationId":"trusted_devices","responses":{"200":{"description":"Trusted devices.","content":{"application/json":{"schema":{"type":"array","items":{"$ref":"#/components/schemas/TrustedDevice"}}}}},"400":{"description":"Invalid request.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}}},"/identity/:identity_id/trusted-devices/:device_id":{"delete":{"tags":["identity"],"summary":"Revoke Trusted Device","description":"Revoke Trusted Device\n\nRequire the second factor again for logins from a trusted device of an identity.","operationId":"revoke_trusted_device","responses":{"200":{"description":"Device revoked."},"404":{"description":"Device not found.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}}},"/identity/:identity_id/unlock":{"post":{"tags":["identity"],"summary":"Unlock","description":"Unlock\n\nClear all failed login attempts of an identity and lift any lockout.","operationId":"unlock","responses":{"200":{"description":"Identity unlocked."},"400":{"description":"Invalid request.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}}}},"components":{"schemas":{"AddDomainRequest":{"type":"object","required":["domain","provider_id"],"properties":{"block_password":{"type":"boolean","description":"users of the domain can't log in with a password"},"domain":{"type":"string"},"provider_id":{"type":"string","description":"id of a configured upstream provider or SAML connection"}}},"AppError":{"type":"object","title":"AppError","description":"An error that can be returned by the API","properties":{"error":{"type":"string"},"reasons":{"type":"array","items":{"type":"object","required":["code"],"properties":{"code":{"type":"string","enum":["too_short","too_long","too_weak","contains_user_info","common","breached"]}}},"description":"Why a password was rejected"}}},"IdentityProfile":{"type":"object","description":"An identity as shown by the profile APIs","required":["id","email_verified","created_at","last_active"],"properties":{"age_attested_at":{"type":"string","format":"date-time","description":"when the identity confirmed it is at least the minimum age","nullable":true},"birthdate":{"type":"string","format":"date","description":"`YYYY-MM-DD`, only stored if the settings ask for it","nullable":true},"created_at":{"type":"string","format":"date-time"},"email_verified":{"type":"boolean","description":"whether the primary email address is verified"},"full_name":{"type":"string","nullable":true},"id":{"type":"string"},"last_active":{"type":"string","format":"date-time"},"primary_email":{"type":"string","nullable":true},"username":{"type":"string","nullable":true}}},"SetPasswordRequest":{"type":"object","properties":{"must_change":{"type":"boolean","description":"the next login has to choose a new password before it completes"},"password":{"type":"string","description":"leave out to keep the current password","nullable":true}}},"SsoDomain":{"type":"object","description":"An email domain whose users log in with an upstream provider (home-realm discovery)","required":["domain","provider_id","verification_token","block_password","created_at"],"properties":{"block_password":{"type":"boolean","description":"users of the domain can't log in with a password"},"created_at":{"type":"string","format":"date-time"},"domain":{"type":"string"},"provider_id":{"type":"string","description":"the upstream provider or SAML connection users of the domain are sent to"},"verification_token":{"type":"string","description":"published as `keygate-domain-verification=<token>` in a TXT record of\n`_keygate-challenge.<domain>`"},"verified_at":{"type":"string","format":"date-time","description":"logins are only routed once the domain is verified","nullable":true}}},"TrustedDevice":{"type":"object","description":"A device that can skip second factors when logging in","required":["id","label","created_at","expires_at"],"properties":{"created_at":{"type":"string","format":"date-time"},"expires_at":{"type":"string","format":"date-time"},"id":{"type":"string"},"label":{"type":"string"},"last_used_at":{"type":"string","format":"date-time","nullable":true},"revoked_at":{"type":"string","format":"date-time","nullable":true}}},"UpdateDomainRequest":{"type":"object","required":["block_password"],"properties":{"block_password":{"type":"boolean","description":"users of the domain can't log in with a password"}}},"ValidateRequest":{"type":"object","required":["token"],"properties":{"token":{"type":"string"}}},"ValidateResponse":{"type":"object","required":["valid"],"properties":{"valid":{"type":"boolean"}}}}}} as const;
\ No newline at end of file
@@ -1 +1 @@
-export default {"openapi":"3.0.3","info":{"title":"keygate-server","description":"","license":{"name":""},"version":"0.1.0"},"paths":{"/auth/login":{"post":{"tags":["auth"],"summary":"Login","description":"Login\n\nCreate a new login process.","operationId":"login","requestBody":{"content":{"application/json":{"schema":{"$ref":"#/components/schemas/LoginRequest"}}},"required":true},"responses":{"200":{"description":"Login process created.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/LoginResponse"}}}},"400":{"description":"Invalid request.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}}},"/auth/login/:process_id":{"get":{"tags":["auth"],"summary":"Login Status","description":"Login Status\n\nGet the status of a login process.","operationId":"login_status","responses":{"200":{"description":"Login process status.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/LoginStatusResponse"}}}},"400":{"description":"Invalid request.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}}},"/auth/login/step":{"post":{"tags":["auth"],"summary":"Login Step","description":"Login Step\n\nComplete a login step.","operationId":"login_step","requestBody":{"content":{"application/json":{"schema":{"$ref":"#/components/schemas/LoginStepRequest"}}},"required":true},"responses":{"200":{"description":"Login step completed.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/LoginResponse"}}}},"400":{"description":"Invalid request.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}}}},"components":{"schemas":{"AppError":{"type":"object","title":"AppError","description":"An error that can be returned by the API","properties":{"error":{"type":"string"}}},"LoginRequest":{"type":"object","required":["username_or_email"],"properties":{"username_or_email":{"type":"string"}}},"LoginResponse":{"oneOf":[{"type":"object","required":["NextStep"],"properties":{"NextStep":{"type":"object","required":["step_type","process_id"],"properties":{"process_id":{"type":"string"},"step_type":{"type":"array","items":{"$ref":"#/components/schemas/LoginStep"}}}}}},{"type":"object","required":["Success"],"properties":{"Success":{"type":"object","required":["refresh_token"],"properties":{"refresh_token":{"type":"string"}}}}}]},"LoginStatusResponse":{"type":"object","required":["current_step"],"properties":{"current_step":{"type":"string"},"expires_at":{"type":"string","format":"date-time","nullable":true}}},"LoginStep":{"type":"string","enum":["Email","Username","Password"]},"LoginStepRequest":{"type":"object","required":["process_id","step_type","data"],"properties":{"data":{"type":"string"},"process_id":{"type":"string"},"step_type":{"$ref":"#/components/schemas/LoginStep"}}}}}} as const;
\ No newline at end of file
+export default {"openapi":"3.0.3","info":{"title":"keygate-server","description":"","license":{"name":""},"version":"0.1.0"},"paths":{"/auth/login":{"post":{"tags":["auth"],"summary":"Login","description":"Login\n\nCreate a new login process. The application is taken from the (anonymous) token and decides which login steps are allowed.","operationId":"login","requestBody":{"content":{"application/json":{"schema":{"$ref":"#/components/schemas/LoginRequest"}}},"required":true},"responses":{"200":{"description":"Login process created.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/LoginResponse"}}}},"400":{"description":"Invalid request.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}},"403":{"description":"No login method is allowed for this application.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}}},"/auth/login/:process_id":{"get":{"tags":["auth"],"summary":"Login Status","description":"Login Status\n\nGet the status of a login process.","operationId":"login_status","responses":{"200":{"description":"Login process status.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/LoginStatusResponse"}}}},"400":{"description":"Invalid request.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}},"404":{"description":"Login process not found.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}}},"/auth/login/device-approval":{"get":{"tags":["auth"],"summary":"Device Approvals","description":"Device Approvals\n\nList logins from unrecognized devices that wait for approval.","operationId":"device_approvals","responses":{"200":{"description":"Pending logins.","content":{"application/json":{"schema":{"type":"array","items":{"$ref":"#/components/schemas/DeviceApprovalRequest"}}}}},"401":{"description":"Not authenticated.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}},"post":{"tags":["auth"],"summary":"Device Approval","description":"Device Approval\n\nApprove or deny a login from an unrecognized device.\nApproving completes the login, the waiting device receives its tokens from the next `device_approval` step.","operationId":"device_approval","requestBody":{"content":{"application/json":{"schema":{"$ref":"#/components/schemas/DeviceApprovalBody"}}},"required":true},"responses":{"200":{"description":"Login approved or denied."},"400":{"description":"Invalid request.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}},"401":{"description":"Not authenticated.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}}},"/auth/login/email-code":{"post":{"tags":["auth"],"summary":"Login Email Code","description":"Login Email Code\n\nSend a one-time login code to the verified email address of the identity.","operationId":"login_email_code","requestBody":{"content":{"application/json":{"schema":{"$ref":"#/components/schemas/LoginEmailCodeRequest"}}},"required":true},"responses":{"200":{"description":"Code sent."},"400":{"description":"Invalid request.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}},"403":{"description":"A code was sent too recently.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}}},"/auth/login/reauth":{"post":{"tags":["auth"],"summary":"Re-authenticate","description":"Re-authenticate\n\nStart a login process that refreshes the authentication of the current session instead of creating a new one. Complete it with the login step endpoint.","operationId":"reauth","requestBody":{"content":{"application/json":{"schema":{"$ref":"#/components/schemas/ReauthRequest"}}},"required":true},"responses":{"200":{"description":"Login process created.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/LoginResponse"}}}},"401":{"description":"Not authenticated.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}},"403":{"description":"No second factor available.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}}},"/auth/login/saml/:connection_id/metadata":{"get":{"tags":["auth"],"summary":"SAML Metadata","description":"SAML Metadata\n\nService provider metadata of a SAML connection, to import into its IdP.","operationId":"saml_metadata","responses":{"200":{"description":"Service provider metadata.","content":{"application/samlmetadata+xml":{"schema":{"type":"string"}}}},"404":{"description":"SAML connection not found.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}}},"/auth/login/saml/acs":{"post":{"tags":["auth"],"summary":"Login SAML ACS","description":"Login SAML ACS\n\nAssertion consumer service the IdP posts its response to. The application is the one that started the login process of the relay state.","operationId":"login_saml_acs","requestBody":{"content":{"application/x-www-form-urlencoded":{"schema":{"$ref":"#/components/schemas/LoginSamlAcsForm"}}},"required":true},"responses":{"200":{"description":"Upstream login step completed.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/LoginResponse"}}}},"400":{"description":"The IdP did not accept the login or its response is invalid.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}},"404":{"description":"Login process not found.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}}},"/auth/login/saml/callback":{"post":{"tags":["auth"],"summary":"Login SAML Callback","description":"Login SAML Callback\n\nContinue a login with a SAML connection with the response the IdP posted to the assertion consumer service.","operationId":"login_saml_callback","requestBody":{"content":{"application/json":{"schema":{"$ref":"#/components/schemas/LoginSamlCallbackRequest"}}},"required":true},"responses":{"200":{"description":"Upstream login step completed.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/LoginResponse"}}}},"400":{"description":"The IdP did not accept the login or its response is invalid.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}},"404":{"description":"Login process not found.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}}},"/auth/login/start":{"post":{"tags":["auth"],"summary":"Login Start Step","description":"Login Start Step\n\nPrepare a login step before completing it, e.g. send a one-time code.","operationId":"login_start_step","requestBody":{"content":{"application/json":{"schema":{"$ref":"#/components/schemas/LoginStartStepRequest"}}},"required":true},"responses":{"200":{"description":"Step started."},"400":{"description":"Invalid request.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}}},"/auth/login/step":{"post":{"tags":["auth"],"summary":"Login Step","description":"Login Step\n\nComplete a login step. Only the application that created the process can continue it.","operationId":"login_step","requestBody":{"content":{"application/json":{"schema":{"$ref":"#/components/schemas/LoginStepRequest"}}},"required":true},"responses":{"200":{"description":"Login step completed.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/LoginResponse"}}}},"400":{"description":"Invalid request.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}}},"/auth/login/trust-device":{"post":{"tags":["auth"],"summary":"Login Trust Device","description":"Login Trust Device\n\nTrust the device of a login that was completed with a second factor, so future logins can skip the second factor.\nHas to be called with the session the login created, the token only works with the same `device_id`.","operationId":"login_trust_device","requestBody":{"content":{"application/json":{"schema":{"$ref":"#/components/schemas/LoginTrustDeviceRequest"}}},"required":true},"responses":{"200":{"description":"Device trusted.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/LoginTrustDeviceResponse"}}}},"400":{"description":"Invalid request.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}},"401":{"description":"Not authenticated.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}},"403":{"description":"The login was not completed with a second factor by this session.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}}},"/auth/login/upstream":{"post":{"tags":["auth"],"summary":"Login Upstream","description":"Login Upstream\n\nCreate a login process with an upstream OpenID Connect provider or SAML connection. The user has to be sent to the returned url.","operationId":"login_upstream","requestBody":{"content":{"application/json":{"schema":{"$ref":"#/components/schemas/LoginUpstreamRequest"}}},"required":true},"responses":{"200":{"description":"Login process created.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/LoginResponse"}}}},"403":{"description":"Upstream login is not allowed for this application.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}},"404":{"description":"Upstream provider not found.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}}},"/auth/login/upstream/callback":{"post":{"tags":["auth"],"summary":"Login Upstream Callback","description":"Login Upstream Callback\n\nContinue a login with an upstream provider once the user was redirected back.","operationId":"login_upstream_callback","requestBody":{"content":{"application/json":{"schema":{"$ref":"#/components/schemas/LoginUpstreamCallbackRequest"}}},"required":true},"responses":{"200":{"description":"Upstream login step completed.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/LoginResponse"}}}},"400":{"description":"The provider did not accept the login.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}},"404":{"description":"Login process not found.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}}},"/auth/login/upstream/redirect":{"post":{"tags":["auth"],"summary":"Login Upstream Redirect","description":"Login Upstream Redirect\n\nSend a user whose email domain is routed to an upstream provider to the provider. Possible while `upstream` is one of the next steps. The user has to be sent to the returned url.","operationId":"login_upstream_redirect","requestBody":{"content":{"application/json":{"schema":{"$ref":"#/components/schemas/LoginUpstreamRedirectRequest"}}},"required":true},"responses":{"200":{"description":"Upstream request created.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/LoginResponse"}}}},"400":{"description":"The login process is not routed to an upstream provider.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}},"404":{"description":"Login process not found.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}}},"/auth/password-reset":{"post":{"tags":["auth"],"summary":"Password Reset","description":"Password Reset\n\nSend a password reset token to the email address if it is verified. The response is the same whether the address belongs to an identity or not.","operationId":"password_reset","requestBody":{"content":{"application/json":{"schema":{"$ref":"#/components/schemas/PasswordResetRequest"}}},"required":true},"responses":{"200":{"description":"Reset requested."}}}},"/auth/password-reset/confirm":{"post":{"tags":["auth"],"summary":"Password Reset Confirm","description":"Password Reset Confirm\n\nSet a new password with a reset token. All sessions of the identity are signed out.","operationId":"password_reset_confirm","requestBody":{"content":{"application/json":{"schema":{"$ref":"#/components/schemas/PasswordResetConfirmRequest"}}},"required":true},"responses":{"200":{"description":"Password changed."},"400":{"description":"Invalid password or invalid or expired token.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}}},"/auth/signup":{"post":{"tags":["auth"],"summary":"Signup","description":"Signup\n\nCreate a new identity. Depending on the settings, the email address has to be confirmed before the identity is signed in.","operationId":"signup","requestBody":{"content":{"application/json":{"schema":{"$ref":"#/components/schemas/SignupRequest"}}},"required":true},"responses":{"200":{"description":"Identity created.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/SignupResponse"}}}},"400":{"description":"Missing or invalid fields.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}},"403":{"description":"The user is younger than the minimum age.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}},"409":{"description":"The username or email is already taken.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}}},"/auth/signup/confirm":{"post":{"tags":["auth"],"summary":"Signup Confirm","description":"Signup Confirm\n\nConfirm the email address of a new identity and sign it in.","operationId":"signup_confirm","requestBody":{"content":{"application/json":{"schema":{"$ref":"#/components/schemas/SignupConfirmRequest"}}},"required":true},"responses":{"200":{"description":"Email confirmed.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/SignupResponse"}}}},"400":{"description":"Invalid or expired code.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}}},"/auth/verify-email":{"get":{"tags":["auth"],"summary":"Verify Email Link","description":"Verify Email Link\n\nVerify an email address with the link from the verification email.","operationId":"verify_email_link","parameters":[{"name":"code","in":"query","description":"code from the verification email, it can only be used once","required":true,"schema":{"type":"string"}}],"responses":{"200":{"description":"Email verified."},"400":{"description":"Invalid or expired code.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}},"post":{"tags":["auth"],"summary":"Verify Email","description":"Verify Email\n\nVerify an email address with the code from the verification email.","operationId":"verify_email","requestBody":{"content":{"application/json":{"schema":{"$ref":"#/components/schemas/VerifyEmailRequest"}}},"required":true},"responses":{"200":{"description":"Email verified."},"400":{"description":"Invalid or expired code.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}}},"/auth/verify-email/resend":{"post":{"tags":["auth"],"summary":"Resend Verification","description":"Resend Verification\n\nSend a new verification code to an unverified email address. The response is the same whether the address belongs to an identity or not.","operationId":"resend_verification","requestBody":{"content":{"application/json":{"schema":{"$ref":"#/components/schemas/ResendVerificationRequest"}}},"required":true},"responses":{"200":{"description":"Verification requested."}}}},"/identity":{"get":{"tags":["identity"],"summary":"Profile","description":"Profile\n\nGet the current identity.","operationId":"profile","responses":{"200":{"description":"The current identity.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/IdentityProfile"}}}},"401":{"description":"Not authenticated.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}}},"/identity/linked-accounts":{"get":{"tags":["identity"],"summary":"Linked Accounts","description":"Linked Accounts\n\nList the accounts of upstream providers the current identity can log in with.","operationId":"linked_accounts","responses":{"200":{"description":"Linked accounts.","content":{"application/json":{"schema":{"type":"array","items":{"$ref":"#/components/schemas/LinkedAccount"}}}}},"401":{"description":"Not authenticated.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}},"post":{"tags":["identity"],"summary":"Link Account","description":"Link Account\n\nStart linking an account of an upstream provider. Requires a recent authentication.","operationId":"link_account_start","requestBody":{"content":{"application/json":{"schema":{"$ref":"#/components/schemas/LinkAccountRequest"}}},"required":true},"responses":{"200":{"description":"Link started, the user has to be sent to the url.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/LinkAccountResponse"}}}},"401":{"description":"Not authenticated or the session has to re-authenticate.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}},"404":{"description":"Upstream provider not found.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}}},"/identity/linked-accounts/:linked_account_id":{"delete":{"tags":["identity"],"summary":"Unlink Account","description":"Unlink Account\n\nRemove a linked account. Requires a recent authentication, the last way to log in can't be removed.","operationId":"unlink_account","responses":{"200":{"description":"Account unlinked."},"401":{"description":"Not authenticated or the session has to re-authenticate.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}},"403":{"description":"The identity has no other way to log in.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}},"404":{"description":"Linked account not found.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}}},"/identity/linked-accounts/callback":{"post":{"tags":["identity"],"summary":"Link Account Callback","description":"Link Account Callback\n\nLink the account once the provider redirected the user back. Accounts that belong to a different identity are refused.","operationId":"link_account_complete","requestBody":{"content":{"application/json":{"schema":{"$ref":"#/components/schemas/LinkAccountCallbackRequest"}}},"required":true},"responses":{"200":{"description":"Account linked.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/LinkedAccount"}}}},"400":{"description":"The provider did not accept the login.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}},"401":{"description":"Not authenticated.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}},"404":{"description":"Link request not found.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}},"409":{"description":"The account or its email belongs to a different identity.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}}},"/identity/password":{"post":{"tags":["identity"],"summary":"Change Password","description":"Change Password\n\nChange the password of the current identity. Requires the current password or a recent authentication.","operationId":"change_password","requestBody":{"content":{"application/json":{"schema":{"$ref":"#/components/schemas/ChangePasswordRequest"}}},"required":true},"responses":{"200":{"description":"Password changed."},"400":{"description":"Invalid current or new password.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}},"401":{"description":"Not authenticated or the session has to re-authenticate.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}}},"/identity/recovery-codes":{"get":{"tags":["identity"],"summary":"Recovery Codes","description":"Recovery Codes\n\nGet the number of unused recovery codes.","operationId":"recovery_codes","responses":{"200":{"description":"Number of unused recovery codes.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/RecoveryCodesResponse"}}}},"401":{"description":"Not authenticated.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}},"post":{"tags":["identity"],"summary":"Regenerate Recovery Codes","description":"Regenerate Recovery Codes\n\nReplace all recovery codes with a new set. The codes are only shown once. Requires a recent authentication.","operationId":"regenerate_recovery_codes","responses":{"200":{"description":"New recovery codes.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/RegenerateRecoveryCodesResponse"}}}},"401":{"description":"Not authenticated or the session has to re-authenticate.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}}},"/identity/trusted-devices":{"get":{"tags":["identity"],"summary":"Trusted Devices","description":"Trusted Devices\n\nList devices that can skip the second factor when logging in.","operationId":"trusted_devices","responses":{"200":{"description":"Trusted devices.","content":{"application/json":{"schema":{"type":"array","items":{"$ref":"#/components/schemas/TrustedDevice"}}}}},"401":{"description":"Not authenticated.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}}},"/identity/trusted-devices/:device_id":{"delete":{"tags":["identity"],"summary":"Revoke Trusted Device","description":"Revoke Trusted Device\n\nRequire the second factor again for logins from a trusted device.","operationId":"revoke_trusted_device","responses":{"200":{"description":"Device revoked."},"401":{"description":"Not authenticated.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}},"404":{"description":"Device not found.","content":{"application/json":{"schema":{"$ref":"#/components/schemas/AppError"}}}}}}}},"components":{"schemas":{"AppError":{"type":"object","title":"AppError","description":"An error that can be returned by the API","properties":{"error":{"type":"string"},"reasons":{"type":"array","items":{"type":"object","required":["code"],"properties":{"code":{"type":"string","enum":["too_short","too_long","too_weak","contains_user_info","common","breached"]}}},"description":"Why a password was rejected"}}},"ChangePasswordRequest":{"type":"object","required":["new_password"],"properties":{"current_password":{"type":"string","description":"can be left out if the session authenticated recently","nullable":true},"new_password":{"type":"string"},"revoke_other_sessions":{"type":"boolean","description":"sign out all other sessions of the identity"}}},"DeviceApprovalBody":{"type":"object","required":["process_id","approved"],"properties":{"approved":{"type":"boolean"},"process_id":{"type":"string"}}},"DeviceApprovalRequest":{"type":"object","description":"A login from an unrecognized device that waits for approval","required":["process_id","created_at"],"properties":{"created_at":{"type":"string","format":"date-time"},"ip_address":{"type":"string","nullable":true},"process_id":{"type":"string"}}},"IdentityProfile":{"type":"object","description":"An identity as shown by the profile APIs","required":["id","email_verified","created_at","last_active"],"properties":{"age_attested_at":{"type":"string","format":"date-time","description":"when the identity confirmed it is at least the minimum age","nullable":true},"birthdate":{"type":"string","format":"date","description":"`YYYY-MM-DD`, only stored if the settings ask for it","nullable":true},"created_at":{"type":"string","format":"date-time"},"email_verified":{"type":"boolean","description":"whether the primary email address is verified"},"full_name":{"type":"string","nullable":true},"id":{"type":"string"},"last_active":{"type":"string","format":"date-time"},"primary_email":{"type":"string","nullable":true},"username":{"type":"string","nullable":true}}},"LinkAccountCallbackRequest":{"type":"object","required":["state","code"],"properties":{"code":{"type":"string","description":"`code` query parameter the provider redirected back with"},"state":{"type":"string","description":"`state` query parameter the provider redirected back with"}}},"LinkAccountRequest":{"type":"object","required":["provider"],"properties":{"provider":{"type":"string","description":"id of a configured upstream provider"}}},"LinkAccountResponse":{"type":"object","required":["url"],"properties":{"url":{"type":"string","description":"where to send the user to log in with the provider"}}},"LinkedAccount":{"type":"object","description":"An account of an upstream provider the identity can log in with","required":["id","provider_id","subject","created_at"],"properties":{"created_at":{"type":"string","format":"date-time"},"email":{"type":"string","nullable":true},"id":{"type":"string"},"last_login_at":{"type":"string","format":"date-time","nullable":true},"provider_id":{"type":"string"},"subject":{"type":"string","description":"`sub` of the provider's id_token"}}},"LoginEmailCodeRequest":{"type":"object","required":["process_id"],"properties":{"process_id":{"type":"string"}}},"LoginRequest":{"type":"object","required":["username_or_email"],"properties":{"device_id":{"type":"string","description":"random id the client stores for this device, used to recognize known devices","nullable":true},"username_or_email":{"type":"string"}}},"LoginResponse":{"oneOf":[{"type":"object","required":["NextStep"],"properties":{"NextStep":{"type":"object","required":["step_type","process_id"],"properties":{"process_id":{"type":"string"},"step_type":{"type":"array","items":{"$ref":"#/components/schemas/LoginStep"}}}}}},{"type":"object","required":["Success"],"properties":{"Success":{"type":"object","description":"Tokens of the new session, or of the re-authenticated one with its raised claims","required":["access_token","refresh_token"],"properties":{"access_token":{"type":"string"},"refresh_token":{"type":"string"}}}}},{"type":"object","required":["EmailVerificationPending"],"properties":{"EmailVerificationPending":{"type":"object","description":"The login is complete except for the verification of the primary email address.\nOnce it is verified, continue with [`LoginStep::EMAIL_VERIFICATION`].","required":["process_id"],"properties":{"process_id":{"type":"string"}}}}},{"type":"object","required":["UpstreamRedirect"],"properties":{"UpstreamRedirect":{"type":"object","description":"Send the user to `url` to log in with an upstream provider. The provider redirects\nback with `code` and `state` (or posts `SAMLResponse` and `RelayState`), which\ncontinue the login with [`Auth::login_upstream_callback`].","required":["process_id","url"],"properties":{"process_id":{"type":"string"},"url":{"type":"string"}}}}}]},"LoginSamlAcsForm":{"type":"object","description":"The form the IdP posts to the assertion consumer service with the HTTP-POST binding","required":["SAMLResponse","RelayState"],"properties":{"RelayState":{"type":"string"},"SAMLResponse":{"type":"string"}}},"LoginSamlCallbackRequest":{"type":"object","required":["saml_response","relay_state"],"properties":{"relay_state":{"type":"string","description":"`RelayState` form field the IdP posted"},"saml_response":{"type":"string","description":"`SAMLResponse` form field the IdP posted"}}},"LoginStartStepRequest":{"type":"object","required":["process_id","step_type"],"properties":{"process_id":{"type":"string"},"step_type":{"$ref":"#/components/schemas/LoginStep"}}},"LoginStatusResponse":{"type":"object","required":["current_step"],"properties":{"current_step":{"type":"string"},"device_approval":{"type":"string","description":"`pending`, `approved` or `denied` if the login is from an unrecognized device,\n`collected` once the device received the tokens of the approved login","nullable":true},"expires_at":{"type":"string","format":"date-time","nullable":true}}},"LoginStep":{"type":"string","description":"Name of a step in a login process. Apart from identifying the user and waiting for\ndevice approval, every step is completed by the login method of the same name."},"LoginStepRequest":{"type":"object","required":["process_id","step_type","data"],"properties":{"data":{"type":"string"},"process_id":{"type":"string"},"step_type":{"$ref":"#/components/schemas/LoginStep"}}},"LoginTrustDeviceRequest":{"type":"object","required":["process_id","label"],"properties":{"label":{"type":"string","description":"name shown in the list of trusted devices, e.g. the browser and os"},"process_id":{"type":"string"}}},"LoginTrustDeviceResponse":{"type":"object","required":["token"],"properties":{"token":{"type":"string","description":"secret to complete the `trusted_device` step of future logins, only shown once"}}},"LoginUpstreamCallbackRequest":{"type":"object","required":["state","code"],"properties":{"code":{"type":"string","description":"`code` query parameter the provider redirected back with"},"state":{"type":"string","description":"`state` query parameter the provider redirected back with"}}},"LoginUpstreamRedirectRequest":{"type":"object","required":["process_id"],"properties":{"process_id":{"type":"string"}}},"LoginUpstreamRequest":{"type":"object","required":["provider"],"properties":{"device_id":{"type":"string","description":"random id the client stores for this device, used to recognize known devices","nullable":true},"provider":{"type":"string","description":"id of a configured upstream provider or SAML connection"}}},"PasswordResetConfirmRequest":{"type":"object","required":["token","new_password"],"properties":{"new_password":{"type":"string"},"token":{"type":"string","description":"token from the reset email, it can only be used once"}}},"PasswordResetRequest":{"type":"object","required":["email"],"properties":{"email":{"type":"string"}}},"ReauthRequest":{"type":"object","properties":{"second_factor":{"type":"boolean","description":"whether the session has to be raised to multi-factor authentication"}}},"RecoveryCodesResponse":{"type":"object","required":["remaining"],"properties":{"remaining":{"type":"integer","format":"int64"}}},"RegenerateRecoveryCodesResponse":{"type":"object","required":["codes"],"properties":{"codes":{"type":"array","items":{"type":"string"}}}},"ResendVerificationRequest":{"type":"object","required":["email"],"properties":{"email":{"type":"string"}}},"SignupConfirmRequest":{"type":"object","required":["code"],"properties":{"code":{"type":"string","description":"code from the confirmation email"}}},"SignupRequest":{"type":"object","description":"Which fields are required depends on the configured signup flow,\nfields that aren't part of it are rejected","properties":{"age_attestation":{"type":"boolean","description":"confirms the user is at least the minimum age, needed if no birthdate is required"},"birthdate":{"type":"string","format":"date","description":"`YYYY-MM-DD`, required if the settings ask for it","nullable":true},"email":{"type":"string","nullable":true},"full_name":{"type":"string","description":"required if the settings ask for it","nullable":true},"password":{"type":"string","nullable":true},"username":{"type":"string","nullable":true}}},"SignupResponse":{"oneOf":[{"type":"object","required":["Success"],"properties":{"Success":{"type":"object","required":["access_token","refresh_token"],"properties":{"access_token":{"type":"string"},"refresh_token":{"type":"string"}}}}},{"type":"string","description":"A code was sent to the email address, continue with `/auth/signup/confirm`","enum":["RequiresEmailVerification"]}]},"TrustedDevice":{"type":"object","description":"A device that can skip second factors when logging in","required":["id","label","created_at","expires_at"],"properties":{"created_at":{"type":"string","format":"date-time"},"expires_at":{"type":"string","format":"date-time"},"id":{"type":"string"},"label":{"type":"string"},"last_used_at":{"type":"string","format":"date-time","nullable":true},"revoked_at":{"type":"string","format":"date-time","nullable":true}}},"VerifyEmailRequest":{"type":"object","required":["code"],"properties":{"code":{"type":"string","description":"code from the verification email, it can only be used once"}}}}}} as const;
\ No newline at end of file
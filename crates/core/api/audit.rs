@@ -0,0 +1,154 @@
+use std::sync::Arc;
+
+use keygate_utils::random::secure_random_id;
+
+use super::APIError;
+use crate::{
+    database::{models, DatabasePool},
+    KeygateInternal,
+};
+
+#[derive(Debug, Clone)]
+pub struct Audit {
+    keygate: Arc<KeygateInternal>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuditAction {
+    LoginFailed,
+    IdentityUnlocked,
+    DeviceApproved,
+    DeviceDenied,
+    TrustedDeviceAdded,
+    TrustedDeviceRevoked,
+    RecoveryCodeUsed,
+    RecoveryCodesGenerated,
+    PasswordResetRequested,
+    PasswordReset,
+    PasswordChanged,
+    PasswordSet,
+    IdentityMigrated,
+    AccountLinked,
+    AccountUnlinked,
+    EmailVerified,
+}
+
+impl AuditAction {
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            Self::LoginFailed => "login_failed",
+            Self::IdentityUnlocked => "identity_unlocked",
+            Self::DeviceApproved => "device_approved",
+            Self::DeviceDenied => "device_denied",
+            Self::TrustedDeviceAdded => "trusted_device_added",
+            Self::TrustedDeviceRevoked => "trusted_device_revoked",
+            Self::RecoveryCodeUsed => "recovery_code_used",
+            Self::RecoveryCodesGenerated => "recovery_codes_generated",
+            Self::PasswordResetRequested => "password_reset_requested",
+            Self::PasswordReset => "password_reset",
+            Self::PasswordChanged => "password_changed",
+            Self::PasswordSet => "password_set",
+            Self::IdentityMigrated => "identity_migrated",
+            Self::AccountLinked => "account_linked",
+            Self::AccountUnlinked => "account_unlinked",
+            Self::EmailVerified => "email_verified",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct AuditEntry<'a> {
+    pub identity_id: &'a str,
+    pub session_id: Option<&'a str>,
+    pub action: AuditAction,
+    pub target_id: Option<&'a str>,
+    pub target_type: Option<&'a str>,
+    pub data: Option<String>,
+}
+
+impl<'a> AuditEntry<'a> {
+    pub fn new(identity_id: &'a str, action: AuditAction) -> Self {
+        Self {
+            identity_id,
+            session_id: None,
+            action,
+            target_id: None,
+            target_type: None,
+            data: None,
+        }
+    }
+
+    pub fn target(mut self, target_type: &'a str, target_id: &'a str) -> Self {
+        self.target_type = Some(target_type);
+        self.target_id = Some(target_id);
+        self
+    }
+
+    pub fn session(mut self, session_id: &'a str) -> Self {
+        self.session_id = Some(session_id);
+        self
+    }
+
+    pub fn data(mut self, data: String) -> Self {
+        self.data = Some(data);
+        self
+    }
+}
+
+impl Audit {
+    pub(crate) fn new(keygate: Arc<KeygateInternal>) -> Self {
+        Self { keygate }
+    }
+
+    fn db(&self) -> &DatabasePool {
+        &self.keygate.db
+    }
+
+    pub(crate) async fn write(&self, entry: AuditEntry<'_>) -> Result<(), APIError> {
+        let id = secure_random_id();
+        let now = time::OffsetDateTime::now_utc();
+        let action = entry.action.as_str_name();
+
+        sqlx::query!(
+            "INSERT INTO AuditLog (id, created_at, identity_id, session_id, node_id, action, target_id, target_type, data) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+            id,
+            now,
+            entry.identity_id,
+            entry.session_id,
+            self.keygate.config.node_id,
+            action,
+            entry.target_id,
+            entry.target_type,
+            entry.data
+        )
+        .execute(self.db())
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn list(
+        &self,
+        identity_id: &str,
+        offset: u32,
+        count: u32,
+    ) -> Result<Vec<models::AuditLog>, APIError> {
+        if count > 100 {
+            return Err(APIError::invalid_argument(
+                "Count cannot be greater than 100",
+            ));
+        }
+
+        let entries = sqlx::query_as!(
+            models::AuditLog,
+            "SELECT * FROM AuditLog WHERE identity_id = $1 ORDER BY created_at DESC LIMIT $2 OFFSET $3",
+            identity_id,
+            count,
+            offset
+        )
+        .fetch_all(self.db())
+        .await?;
+
+        Ok(entries)
+    }
+}
@@ -1,49 +1,79 @@
-use std::{net::IpAddr, sync::Arc};
+use std::{borrow::Cow, net::IpAddr, sync::Arc};
 
 use keygate_utils::{
-    random::secure_random_id,
+    join_keys,
+    password_policy::PasswordIssue,
+    random::{randomid, secure_random_id},
     tokens::{AccessToken, RefreshToken},
-    validate::{is_valid_email, is_valid_password, is_valid_username},
+    validate::{is_valid_email, is_valid_username},
 };
 
-use super::APIError;
+use super::{
+    audit::{AuditAction, AuditEntry},
+    session::AuthContext,
+    throttle::{identity_key, LoginThrottle},
+    APIError, Audit,
+};
 use crate::{
     database::{
-        models::{Identity, LoginProcess},
+        models::{
+            AccountNaming, ApplicationSettings, Email, EmailVerification, GlobalSettings, Identity,
+            LinkedAccount, LoginProcess, SecondFactorPolicy, SignupFlow,
+        },
         DatabasePool,
     },
+    login::{verified_email, Factor, LoginContext, LoginMethod},
+    mail::EmailMessage,
+    upstream::UpstreamUser,
     KeygateInternal,
 };
+use tracing::warn;
 
 #[derive(Debug, Clone)]
 pub struct Auth {
     keygate: Arc<KeygateInternal>,
 }
 
-#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+/// Name of a step in a login process. Apart from identifying the user and waiting for
+/// device approval, every step is completed by the login method of the same name.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Deserialize, serde::Serialize)]
 #[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
-pub enum LoginStep {
-    Email,
-    Username,
-    Password,
-}
+#[serde(transparent)]
+pub struct LoginStep(
+    #[cfg_attr(feature = "utoipa", schema(value_type = String))] Cow<'static, str>,
+);
 
 impl LoginStep {
-    pub fn as_str_name(&self) -> &'static str {
-        match self {
-            Self::Email => "email",
-            Self::Username => "username",
-            Self::Password => "password",
-        }
+    pub const EMAIL: Self = Self(Cow::Borrowed("email"));
+    pub const USERNAME: Self = Self(Cow::Borrowed("username"));
+    pub const PASSWORD: Self = Self(Cow::Borrowed("password"));
+    pub const EMAIL_CODE: Self = Self(Cow::Borrowed("email_code"));
+    pub const RECOVERY_CODE: Self = Self(Cow::Borrowed("recovery_code"));
+    pub const DEVICE_APPROVAL: Self = Self(Cow::Borrowed("device_approval"));
+    pub const EMAIL_VERIFICATION: Self = Self(Cow::Borrowed("email_verification"));
+    pub const CHANGE_PASSWORD: Self = Self(Cow::Borrowed("change_password"));
+    pub const TRUSTED_DEVICE: Self = Self(Cow::Borrowed("trusted_device"));
+    pub const UPSTREAM: Self = Self(Cow::Borrowed("upstream"));
+
+    pub fn new(name: impl Into<Cow<'static, str>>) -> Self {
+        Self(name.into())
     }
 
-    pub fn from_str_name(name: &str) -> Option<Self> {
-        match name {
-            "email" => Some(Self::Email),
-            "username" => Some(Self::Username),
-            "password" => Some(Self::Password),
-            _ => None,
-        }
+    pub fn as_str_name(&self) -> &str {
+        &self.0
+    }
+
+    // the steps stored as `factors` on a login process
+    pub(crate) fn parse_list(steps: &str) -> Vec<Self> {
+        steps
+            .split(',')
+            .filter(|step| !step.is_empty())
+            .map(|step| Self::new(step.to_string()))
+            .collect()
+    }
+
+    fn identifies(&self) -> bool {
+        *self == Self::EMAIL || *self == Self::USERNAME
     }
 }
 
@@ -54,9 +84,42 @@ pub enum LoginResponse {
         step_type: Vec<LoginStep>,
         process_id: String,
     },
+    /// Tokens of the new session, or of the re-authenticated one with its raised claims
     Success {
+        access_token: String,
         refresh_token: String,
     },
+    /// The login is complete except for the verification of the primary email address.
+    /// Once it is verified, continue with [`LoginStep::EMAIL_VERIFICATION`].
+    EmailVerificationPending { process_id: String },
+    /// Send the user to `url` to log in with an upstream provider. The provider redirects
+    /// back with `code` and `state` (or posts `SAMLResponse` and `RelayState`), which
+    /// continue the login with [`Auth::login_upstream_callback`].
+    UpstreamRedirect { process_id: String, url: String },
+}
+
+/// Fields of a new identity, [`GlobalSettings::signup_flow`] decides which are required
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SignupData<'a> {
+    pub username: Option<&'a str>,
+    pub email: Option<&'a str>,
+    pub password: Option<&'a str>,
+    /// required if [`GlobalSettings::require_full_name`] is set
+    pub full_name: Option<&'a str>,
+    /// required if [`GlobalSettings::require_birthdate`] is set, only stored if
+    /// [`GlobalSettings::store_birthdate`] is
+    pub birthdate: Option<time::Date>,
+    /// the user confirmed being at least [`GlobalSettings::minimum_age`], needed instead of a birthdate
+    pub age_attestation: bool,
+}
+
+#[derive(Debug)]
+pub enum SignupResponse {
+    Success {
+        identity: Identity,
+    },
+    /// A code was sent to the email address, continue with [`Auth::signup_confirm`]
+    RequiresEmailVerification,
 }
 
 #[derive(serde::Deserialize, serde::Serialize)]
@@ -64,6 +127,48 @@ pub enum LoginResponse {
 pub struct LoginStatusResponse {
     pub current_step: String,
     pub expires_at: Option<time::OffsetDateTime>,
+    /// `pending`, `approved` or `denied` if the login is from an unrecognized device,
+    /// `collected` once the device received the tokens of the approved login
+    pub device_approval: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceApproval {
+    Pending,
+    Approved,
+    Denied,
+    /// the tokens of the approved login were handed to the waiting device
+    Collected,
+}
+
+impl DeviceApproval {
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            Self::Pending => "pending",
+            Self::Approved => "approved",
+            Self::Denied => "denied",
+            Self::Collected => "collected",
+        }
+    }
+
+    pub fn from_str_name(name: &str) -> Option<Self> {
+        match name {
+            "pending" => Some(Self::Pending),
+            "approved" => Some(Self::Approved),
+            "denied" => Some(Self::Denied),
+            "collected" => Some(Self::Collected),
+            _ => None,
+        }
+    }
+}
+
+/// A login from an unrecognized device that waits for approval
+#[derive(serde::Deserialize, serde::Serialize)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+pub struct DeviceApprovalRequest {
+    pub process_id: String,
+    pub ip_address: Option<String>,
+    pub created_at: time::OffsetDateTime,
 }
 
 impl Auth {
@@ -75,198 +180,4521 @@ impl Auth {
         &self.keygate.db
     }
 
+    fn identity(&self) -> super::Identity {
+        super::Identity::new(self.keygate.clone())
+    }
+
+    fn session(&self) -> super::Session {
+        super::Session::new(self.keygate.clone())
+    }
+
+    fn context<'a>(
+        &'a self,
+        process: &'a LoginProcess,
+        identity: Option<&'a Identity>,
+        settings: &'a GlobalSettings,
+        completed: &'a [LoginStep],
+    ) -> LoginContext<'a> {
+        LoginContext {
+            keygate: &self.keygate,
+            process,
+            identity,
+            settings,
+            completed,
+            anti_enumeration: self.keygate.config.anti_enumeration(),
+        }
+    }
+
+    async fn load_identity(&self, identity_id: &str) -> Result<Option<Identity>, APIError> {
+        let identity = sqlx::query_as!(
+            Identity,
+            "SELECT * FROM Identity WHERE id = $1",
+            identity_id
+        )
+        .fetch_optional(self.db())
+        .await?;
+
+        Ok(identity)
+    }
+
+    async fn application(&self, application_id: &str) -> Result<ApplicationSettings, APIError> {
+        self.keygate
+            .settings
+            .app(application_id)
+            .await?
+            .ok_or(APIError::not_found("Application not found"))
+    }
+
+    fn login_method(&self, step: &LoginStep) -> Result<Arc<dyn LoginMethod>, APIError> {
+        self.keygate
+            .login_methods
+            .get(step)
+            .ok_or(APIError::invalid_argument("Invalid step type"))
+    }
+
     pub async fn verify_access_token(&self, token: &str) -> Result<AccessToken, APIError> {
-        Ok(AccessToken {
-            audience: "TODO".to_string(),
-            issuer: "TODO".to_string(),
-            key_id: "TODO".to_string(),
-            session_id: "TODO".to_string(),
-            subject: "TODO".to_string(),
-        })
+        self.session().verify_access_token(token).await
     }
 
     pub async fn verify_refresh_token(&self, token: &str) -> Result<RefreshToken, APIError> {
-        Ok(RefreshToken {
-            audience: "TODO".to_string(),
-            issuer: "TODO".to_string(),
-            key_id: "TODO".to_string(),
-            session_id: "TODO".to_string(),
-            subject: "TODO".to_string(),
-        })
+        self.session().verify_refresh_token(token).await
     }
 
     // create a new login process for the given user
     pub async fn login_create(
         &self,
-        // everything with an @ is considered an email
+        // the application's settings decide which steps are allowed
+        application_id: &str,
+        // everything with an @ is considered an email, `login_identifier` decides which are accepted
         username_or_email: &str,
         // ip_address has to be validated by the caller, can be empty (0.0.0.0) if not available
         ip_address: Option<IpAddr>,
+        // random id the client keeps for the device, used to recognize known devices
+        device_id: Option<&str>,
     ) -> Result<LoginResponse, APIError> {
-        let login_process_id = secure_random_id();
+        let settings = self.keygate.settings.global().await?;
+        self.application(application_id).await?;
         let now = time::OffsetDateTime::now_utc();
         let is_email = username_or_email.contains('@');
-        let ip_address = ip_address.map(|ip| ip.to_string());
-        let current_step = match is_email {
-            true => LoginStep::Email,
-            false => LoginStep::Username,
+        match (&settings.login_identifier, is_email) {
+            (AccountNaming::Username, true) => {
+                return Err(APIError::invalid_argument(
+                    "Login with email is not allowed",
+                ))
+            }
+            (AccountNaming::Email, false) => {
+                return Err(APIError::invalid_argument(
+                    "Login with username is not allowed",
+                ))
+            }
+            _ => {}
         }
-        .as_str_name();
 
-        let next_steps = {
-            let mut tx = self.db().begin().await?;
-            let field = match is_email {
-                true => "primary_email",
-                false => "username",
-            };
+        let current_step = match is_email {
+            true => LoginStep::EMAIL,
+            false => LoginStep::USERNAME,
+        };
 
-            let current_identity_id = sqlx::query!(
-                "SELECT id FROM Identity WHERE $1 = $2",
-                field,
+        // case-insensitive, an exact match wins if two identities only differ in case
+        let identity = match is_email {
+            true => sqlx::query_as!(
+                Identity,
+                "SELECT * FROM Identity WHERE primary_email = $1 COLLATE NOCASE ORDER BY primary_email = $1 DESC LIMIT 1",
                 username_or_email
             )
-            .fetch_optional(&mut *tx)
+            .fetch_optional(self.db())
+            .await?,
+            false => sqlx::query_as!(
+                Identity,
+                "SELECT * FROM Identity WHERE username = $1 COLLATE NOCASE ORDER BY username = $1 DESC LIMIT 1",
+                username_or_email
+            )
+            .fetch_optional(self.db())
+            .await?,
+        };
+
+        // checked with the legacy user store once the password is known
+        let legacy = self.keygate.legacy_user_store.load().is_some()
+            && identity.as_ref().is_none_or(|identity| identity.legacy);
+
+        // users of a verified domain are sent to its upstream provider
+        let email = match is_email {
+            true => Some(username_or_email),
+            false => identity
+                .as_ref()
+                .and_then(|identity| identity.primary_email.as_deref()),
+        };
+        let sso_domain = match email {
+            Some(email) => {
+                super::Domains::new(self.keygate.clone())
+                    .route(email)
+                    .await?
+            }
+            None => None,
+        };
+
+        // checked with a bind to the LDAP directory, unknown users may be in the directory
+        let ldap =
+            self.keygate.ldap.is_some() && identity.as_ref().is_none_or(|identity| identity.ldap);
+
+        let identity_id = match &identity {
+            Some(identity) => identity.id.clone(),
+            // the provider identifies unknown users of a domain, or signs them up
+            None if sso_domain.is_some() => secure_random_id(),
+            // unknown identities get a decoy process that fails like a wrong password
+            None if legacy || ldap || self.keygate.config.anti_enumeration() => {
+                decoy_identity_id(username_or_email)
+            }
+            None => return Err(APIError::not_found("User not found")),
+        };
+
+        let process = LoginProcess {
+            id: secure_random_id(),
+            created_at: now,
+            updated_at: now,
+            identity_id,
+            ip_address: ip_address.map(|ip| ip.to_string()),
+            expires_at: Some(now + settings.login_process_expires_in),
+            completed: false,
+            current_step: current_step.as_str_name().to_string(),
+            magic_link: None,
+            email_code: None,
+            email_code_expires_at: None,
+            email_code_attempts: 0,
+            device_hash: device_id.map(keygate_utils::hash::token),
+            device_approval: None,
+            factors: String::new(),
+            session_id: None,
+            reauthenticate: false,
+            require_second_factor: false,
+            application_id: Some(application_id.to_string()),
+            legacy_username: legacy.then(|| username_or_email.to_string()),
+            upstream_provider: sso_domain
+                .as_ref()
+                .map(|sso_domain| sso_domain.provider_id.clone()),
+            upstream_state: None,
+            upstream_nonce: None,
+            upstream_code_verifier: None,
+            upstream_required: sso_domain
+                .as_ref()
+                .is_some_and(|sso_domain| sso_domain.block_password),
+            ldap_username: ldap.then(|| username_or_email.to_string()),
+        };
+
+        sqlx::query!(
+            "INSERT INTO LoginProcess (id, created_at, updated_at, expires_at, completed, current_step, identity_id, ip_address, device_hash, application_id, legacy_username, upstream_provider, upstream_required, ldap_username) VALUES ($1, $2, $3, $4, false, $5, $6, $7, $8, $9, $10, $11, $12, $13)",
+            process.id,
+            process.created_at,
+            process.updated_at,
+            process.expires_at,
+            process.current_step,
+            process.identity_id,
+            process.ip_address,
+            process.device_hash,
+            process.application_id,
+            process.legacy_username,
+            process.upstream_provider,
+            process.upstream_required,
+            process.ldap_username
+        )
+        .execute(self.db())
+        .await?;
+
+        let ctx = self.context(&process, identity.as_ref(), &settings, &[]);
+        let next_steps = self.next_steps(&current_step, &ctx).await?;
+
+        Ok(LoginResponse::NextStep {
+            step_type: next_steps,
+            process_id: process.id,
+        })
+    }
+
+    /// Start a login with an upstream OpenID Connect provider or SAML connection instead of
+    /// a username. The user is sent to the provider, which identifies them (or creates an
+    /// identity for them) once they are back.
+    pub async fn login_create_upstream(
+        &self,
+        application_id: &str,
+        provider_id: &str,
+        ip_address: Option<IpAddr>,
+        device_id: Option<&str>,
+    ) -> Result<LoginResponse, APIError> {
+        let settings = self.keygate.settings.global().await?;
+        self.application(application_id).await?;
+        let now = time::OffsetDateTime::now_utc();
+
+        let (url, state, nonce, code_verifier) = self.upstream_request(provider_id).await?;
+
+        // nothing is completed yet, like for a username that was just entered
+        let current_step = LoginStep::USERNAME;
+        let process = LoginProcess {
+            id: secure_random_id(),
+            created_at: now,
+            updated_at: now,
+            identity_id: secure_random_id(),
+            ip_address: ip_address.map(|ip| ip.to_string()),
+            expires_at: Some(now + settings.login_process_expires_in),
+            completed: false,
+            current_step: current_step.as_str_name().to_string(),
+            magic_link: None,
+            email_code: None,
+            email_code_expires_at: None,
+            email_code_attempts: 0,
+            device_hash: device_id.map(keygate_utils::hash::token),
+            device_approval: None,
+            factors: String::new(),
+            session_id: None,
+            reauthenticate: false,
+            require_second_factor: false,
+            application_id: Some(application_id.to_string()),
+            legacy_username: None,
+            upstream_provider: Some(provider_id.to_string()),
+            upstream_state: Some(keygate_utils::hash::token(&state)),
+            upstream_nonce: Some(nonce),
+            upstream_code_verifier: code_verifier,
+            upstream_required: false,
+            ldap_username: None,
+        };
+
+        let ctx = self.context(&process, None, &settings, &[]);
+        if !self
+            .next_steps(&current_step, &ctx)
             .await?
-            .ok_or(APIError::not_found("User not found"))?
-            .id;
+            .contains(&LoginStep::UPSTREAM)
+        {
+            return Err(APIError::permission_denied(
+                "Upstream login is not allowed for this application",
+            ));
+        }
 
-            sqlx::query!(
-                "INSERT INTO LoginProcess (id, expires_at, current_step, identity_id, ip_address) VALUES ($1, $2, $3, $4, $5)",
-                login_process_id,
-                now,
-                current_step,
-                current_identity_id,
-                ip_address
-            )
-            .execute(&mut *tx)
-            .await?;
+        sqlx::query!(
+            "INSERT INTO LoginProcess (id, created_at, updated_at, expires_at, completed, current_step, identity_id, ip_address, device_hash, application_id, upstream_provider, upstream_state, upstream_nonce, upstream_code_verifier) VALUES ($1, $2, $2, $3, false, $4, $5, $6, $7, $8, $9, $10, $11, $12)",
+            process.id,
+            process.created_at,
+            process.expires_at,
+            process.current_step,
+            process.identity_id,
+            process.ip_address,
+            process.device_hash,
+            process.application_id,
+            process.upstream_provider,
+            process.upstream_state,
+            process.upstream_nonce,
+            process.upstream_code_verifier
+        )
+        .execute(self.db())
+        .await?;
+
+        Ok(LoginResponse::UpstreamRedirect {
+            process_id: process.id,
+            url,
+        })
+    }
+
+    /// Send a user whose email domain is routed to an upstream provider (see
+    /// [`super::Domains`]) to the provider. Only possible while [`LoginStep::UPSTREAM`] is
+    /// one of the next steps of the process.
+    pub async fn login_upstream_redirect(
+        &self,
+        application_id: &str,
+        process_id: &str,
+    ) -> Result<LoginResponse, APIError> {
+        let settings = self.keygate.settings.global().await?;
+        let process = sqlx::query_as!(
+            LoginProcess,
+            "SELECT * FROM LoginProcess WHERE id = $1 AND application_id = $2",
+            process_id,
+            application_id
+        )
+        .fetch_optional(self.db())
+        .await?
+        .ok_or(APIError::not_found("Login process not found"))?;
+        ensure_active(&process)?;
+
+        let current_step = LoginStep::new(process.current_step.clone());
+        let provider_id = match &process.upstream_provider {
+            Some(provider_id) if current_step.identifies() => provider_id.clone(),
+            _ => return Err(APIError::invalid_argument("Invalid step type")),
+        };
+        let identity = self.load_identity(&process.identity_id).await?;
+        let ctx = self.context(&process, identity.as_ref(), &settings, &[]);
+        if !self
+            .next_steps(&current_step, &ctx)
+            .await?
+            .contains(&LoginStep::UPSTREAM)
+        {
+            return Err(APIError::invalid_argument("Invalid step type"));
+        }
+
+        // a new request replaces the previous one
+        let (url, state, nonce, code_verifier) = self.upstream_request(&provider_id).await?;
+        let state_hash = keygate_utils::hash::token(&state);
+        let now = time::OffsetDateTime::now_utc();
+        sqlx::query!(
+            "UPDATE LoginProcess SET upstream_state = $1, upstream_nonce = $2, upstream_code_verifier = $3, updated_at = $4 WHERE id = $5",
+            state_hash,
+            nonce,
+            code_verifier,
+            now,
+            process.id
+        )
+        .execute(self.db())
+        .await?;
+
+        Ok(LoginResponse::UpstreamRedirect {
+            process_id: process.id,
+            url,
+        })
+    }
+
+    // url, state, nonce and code verifier of a request to an upstream provider. SAML
+    // responses answer the request id instead of a nonce, and there is no verifier.
+    async fn upstream_request(
+        &self,
+        provider_id: &str,
+    ) -> Result<(String, String, String, Option<String>), APIError> {
+        match self.keygate.saml.get(provider_id) {
+            Some(_) => {
+                let request = self.keygate.saml.authn_request(provider_id)?;
+                Ok((request.url, request.relay_state, request.request_id, None))
+            }
+            None => {
+                let request = self
+                    .keygate
+                    .upstream
+                    .authorization_request(provider_id)
+                    .await?;
+                let code_verifier = Some(request.code_verifier);
+                Ok((request.url, request.state, request.nonce, code_verifier))
+            }
+        }
+    }
+
+    /// Continue a login with an upstream provider with the `state` and `code` the provider
+    /// redirected the user back with. For SAML connections, these are the `RelayState` and
+    /// `SAMLResponse`. Continues like [`Auth::login_step`] after that.
+    pub async fn login_upstream_callback(
+        &self,
+        application_id: &str,
+        state: &str,
+        code: &str,
+    ) -> Result<LoginResponse, APIError> {
+        // the state can only be used once
+        let state_hash = keygate_utils::hash::token(state);
+        let process_id = sqlx::query!(
+            "UPDATE LoginProcess SET upstream_state = NULL WHERE upstream_state = $1 AND application_id = $2 RETURNING id",
+            state_hash,
+            application_id
+        )
+        .fetch_optional(self.db())
+        .await?
+        .ok_or(APIError::not_found("Login process not found"))?
+        .id;
+
+        self.login_step(application_id, &process_id, LoginStep::UPSTREAM, code)
+            .await
+    }
+
+    /// Continue a SAML login with the `RelayState` and `SAMLResponse` the IdP posted to the
+    /// assertion consumer service. The browser has no token, so the application is the one
+    /// that started the login process the relay state belongs to.
+    pub async fn login_saml_acs(
+        &self,
+        relay_state: &str,
+        saml_response: &str,
+    ) -> Result<LoginResponse, APIError> {
+        let state_hash = keygate_utils::hash::token(relay_state);
+        let process = sqlx::query!(
+            "SELECT application_id, upstream_provider FROM LoginProcess WHERE upstream_state = $1",
+            state_hash
+        )
+        .fetch_optional(self.db())
+        .await?
+        .ok_or(APIError::not_found("Login process not found"))?;
+
+        // OpenID Connect callbacks carry the application's token
+        let (Some(application_id), Some(_)) = (
+            process.application_id,
+            process
+                .upstream_provider
+                .and_then(|provider| self.keygate.saml.get(&provider)),
+        ) else {
+            return Err(APIError::not_found("Login process not found"));
+        };
+
+        self.login_upstream_callback(&application_id, relay_state, saml_response)
+            .await
+    }
+
+    /// Our service provider metadata for a SAML connection, to import into its IdP
+    pub fn saml_metadata(&self, connection_id: &str) -> Result<String, APIError> {
+        self.keygate.saml.metadata(connection_id)
+    }
+
+    /// Start a login process that raises the authentication level of an existing session
+    /// instead of creating a new one, e.g. before a sensitive action. The process is completed
+    /// with [`Auth::login_step`] like a regular login, starting with the first factor.
+    pub async fn reauth_create(
+        &self,
+        application_id: &str,
+        session_id: &str,
+        second_factor: bool,
+    ) -> Result<LoginResponse, APIError> {
+        let settings = self.keygate.settings.global().await?;
+        self.application(application_id).await?;
+        let now = time::OffsetDateTime::now_utc();
 
-            tx.commit().await?;
+        let session = sqlx::query!(
+            "SELECT identity_id, initial_ip_address FROM Session WHERE id = $1 AND revoked_at IS NULL",
+            session_id
+        )
+        .fetch_optional(self.db())
+        .await?
+        .ok_or(APIError::not_found("Session not found"))?;
+        let identity = self.load_identity(&session.identity_id).await?;
 
-            // next step is always password for now
-            // TODO: device login
-            vec![LoginStep::Password]
+        let current_step = LoginStep::USERNAME;
+        let process = LoginProcess {
+            id: secure_random_id(),
+            created_at: now,
+            updated_at: now,
+            identity_id: session.identity_id,
+            ip_address: session.initial_ip_address,
+            expires_at: Some(now + settings.login_process_expires_in),
+            completed: false,
+            current_step: current_step.as_str_name().to_string(),
+            magic_link: None,
+            email_code: None,
+            email_code_expires_at: None,
+            email_code_attempts: 0,
+            device_hash: None,
+            device_approval: None,
+            factors: String::new(),
+            session_id: Some(session_id.to_string()),
+            reauthenticate: true,
+            require_second_factor: second_factor,
+            application_id: Some(application_id.to_string()),
+            legacy_username: None,
+            upstream_provider: None,
+            upstream_state: None,
+            upstream_nonce: None,
+            upstream_code_verifier: None,
+            upstream_required: false,
+            ldap_username: None,
         };
+        let ctx = self.context(&process, identity.as_ref(), &settings, &[]);
+
+        // fail before the first factor if no second factor could complete the process
+        if second_factor {
+            self.next_steps(&LoginStep::PASSWORD, &ctx).await?;
+        }
+
+        sqlx::query!(
+            "INSERT INTO LoginProcess (id, created_at, updated_at, expires_at, completed, current_step, identity_id, ip_address, session_id, reauthenticate, require_second_factor, application_id) VALUES ($1, $2, $2, $3, false, $4, $5, $6, $7, true, $8, $9)",
+            process.id,
+            process.created_at,
+            process.expires_at,
+            process.current_step,
+            process.identity_id,
+            process.ip_address,
+            process.session_id,
+            process.require_second_factor,
+            process.application_id
+        )
+        .execute(self.db())
+        .await?;
+
+        let next_steps = self.next_steps(&current_step, &ctx).await?;
 
         Ok(LoginResponse::NextStep {
             step_type: next_steps,
-            process_id: login_process_id,
+            process_id: process.id,
         })
     }
 
     pub async fn login_step(
         &self,
+        application_id: &str,
         process_id: &str,
         step_type: LoginStep,
         data: &str,
     ) -> Result<LoginResponse, APIError> {
-        let next_steps: Option<Vec<LoginStep>> = {
-            let mut tx = self.db().begin().await?;
+        let settings = self.keygate.settings.global().await?;
+        let now = time::OffsetDateTime::now_utc();
 
-            let current_process = sqlx::query!(
-                "SELECT current_step, identity_id FROM LoginProcess WHERE id = $1",
-                process_id
+        // processes can only be continued by the application that started them
+        let mut current_process = sqlx::query_as!(
+            LoginProcess,
+            "SELECT * FROM LoginProcess WHERE id = $1 AND application_id = $2",
+            process_id,
+            application_id
+        )
+        .fetch_optional(self.db())
+        .await?
+        .ok_or(APIError::not_found("Login process not found"))?;
+
+        // approving completes the login, the waiting device only collects its tokens
+        if step_type == LoginStep::DEVICE_APPROVAL && current_process.completed {
+            return self
+                .login_collect_approved(application_id, &current_process, &settings)
+                .await;
+        }
+        ensure_active(&current_process)?;
+
+        let current_step = LoginStep::new(current_process.current_step.clone());
+        // missing for decoy processes
+        let mut identity = self.load_identity(&current_process.identity_id).await?;
+        let mut completed = LoginStep::parse_list(&current_process.factors);
+
+        let ctx = self.context(&current_process, identity.as_ref(), &settings, &completed);
+        let available_steps = self.next_steps(&current_step, &ctx).await?;
+        if !available_steps.contains(&step_type) {
+            return Err(APIError::invalid_argument("Invalid step type"));
+        }
+
+        let throttle = LoginThrottle::new(self.keygate.clone());
+        throttle
+            .check(
+                &current_process.identity_id,
+                current_process.ip_address.as_deref(),
+                &settings.login_throttle,
             )
-            .fetch_one(&mut *tx)
             .await?;
 
-            let current_step = LoginStep::from_str_name(&current_process.current_step)
-                .ok_or(APIError::invalid_argument("Invalid step type"))?;
+        // the directory checks the passwords of LDAP users instead of the password method
+        let mut ldap_valid = None;
+        if step_type == LoginStep::PASSWORD {
+            self.migrate_legacy_user(&mut current_process, &mut identity, data)
+                .await?;
+            ldap_valid = self
+                .ldap_login(&mut current_process, &mut identity, data)
+                .await?;
+        } else if step_type == LoginStep::UPSTREAM {
+            self.upstream_login(&mut current_process, &mut identity, data)
+                .await?;
+        }
+        let ip_address = current_process.ip_address.as_deref();
+        let ctx = self.context(&current_process, identity.as_ref(), &settings, &completed);
 
-            match (current_step, step_type) {
-                (LoginStep::Email, LoginStep::Password)
-                | (LoginStep::Username, LoginStep::Password) => {
-                    let identity = sqlx::query_as!(
-                        Identity,
-                        "SELECT * FROM Identity WHERE id = $1",
-                        current_process.identity_id
+        if step_type == LoginStep::DEVICE_APPROVAL {
+            return match current_process
+                .device_approval
+                .as_deref()
+                .and_then(DeviceApproval::from_str_name)
+            {
+                Some(DeviceApproval::Denied) => {
+                    Err(APIError::permission_denied("Login was denied"))
+                }
+                _ => Ok(LoginResponse::NextStep {
+                    step_type: vec![LoginStep::DEVICE_APPROVAL],
+                    process_id: process_id.into(),
+                }),
+            };
+        } else if step_type == LoginStep::CHANGE_PASSWORD {
+            let identity = identity
+                .as_ref()
+                .ok_or(APIError::invalid_argument("Invalid step type"))?;
+            self.identity()
+                .login_change_password(identity, data)
+                .await?;
+        } else if step_type == LoginStep::EMAIL_VERIFICATION {
+            if self
+                .requires_email_verification(&current_process, identity.as_ref(), &settings)
+                .await?
+            {
+                return Ok(LoginResponse::EmailVerificationPending {
+                    process_id: process_id.into(),
+                });
+            }
+        } else {
+            let method = self.login_method(&step_type)?;
+            let valid = match ldap_valid {
+                Some(valid) => valid,
+                None => method.verify(&ctx, data).await?,
+            };
+            if !valid {
+                throttle
+                    .record_failure(
+                        &current_process.identity_id,
+                        ip_address,
+                        &settings.login_throttle,
                     )
-                    .fetch_one(&mut *tx)
                     .await?;
 
-                    let password_hash = identity
-                        .password_hash
-                        .ok_or(APIError::invalid_argument("Password not set"))?;
-                    if !keygate_utils::hash::verify(data, &password_hash).map_err(|e| {
-                        APIError::internal(&format!("Failed to verify password: {}", e))
-                    })? {
-                        return Err(APIError::invalid_argument("Invalid password"));
-                    }
-
-                    // TODO: Check if more steps are required (e.g. 2FA)
-                    None
+                let step_name = step_type.as_str_name();
+                let data = match ip_address {
+                    Some(ip_address) => join_keys!("step", step_name, "ip", ip_address),
+                    None => join_keys!("step", step_name),
+                };
+                if identity.is_some() {
+                    let entry =
+                        AuditEntry::new(&current_process.identity_id, AuditAction::LoginFailed)
+                            .target("login_process", process_id)
+                            .data(data);
+                    Audit::new(self.keygate.clone()).write(entry).await?;
                 }
-                _ => return Err(APIError::invalid_argument("Invalid step type")),
+
+                return Err(APIError::invalid_argument(method.invalid_message()));
             }
+            completed.push(step_type.clone());
+        }
+
+        // second factors are only required after the first factor
+        let ctx = self.context(&current_process, identity.as_ref(), &settings, &completed);
+        let next_steps = match current_step.identifies() {
+            true => {
+                Some(self.next_steps(&step_type, &ctx).await?).filter(|steps| !steps.is_empty())
+            }
+            false => None,
+        };
+
+        let factors = completed
+            .iter()
+            .map(LoginStep::as_str_name)
+            .collect::<Vec<_>>()
+            .join(",");
+
+        // a new password has to be chosen before anything else
+        let awaiting_password_change = next_steps.is_none()
+            && step_type != LoginStep::CHANGE_PASSWORD
+            && !current_process.reauthenticate
+            && identity
+                .as_ref()
+                .is_some_and(|identity| identity.must_change_password);
+
+        // unverified email addresses can't finish the login if verification is required
+        let awaiting_verification = next_steps.is_none()
+            && !awaiting_password_change
+            && self
+                .requires_email_verification(&current_process, identity.as_ref(), &settings)
+                .await?;
+
+        // logins from unrecognized devices wait for approval from an existing session
+        let awaiting_approval = next_steps.is_none()
+            && !awaiting_password_change
+            && !awaiting_verification
+            && self
+                .requires_device_approval(&current_process, &settings)
+                .await?;
+
+        let (step_name, device_approval, next_steps) = if awaiting_password_change {
+            (
+                LoginStep::CHANGE_PASSWORD,
+                None,
+                Some(vec![LoginStep::CHANGE_PASSWORD]),
+            )
+        } else if awaiting_verification {
+            (
+                LoginStep::EMAIL_VERIFICATION,
+                None,
+                Some(vec![LoginStep::EMAIL_VERIFICATION]),
+            )
+        } else if awaiting_approval {
+            (
+                LoginStep::DEVICE_APPROVAL,
+                Some(DeviceApproval::Pending.as_str_name()),
+                Some(vec![LoginStep::DEVICE_APPROVAL]),
+            )
+        } else {
+            (step_type, None, next_steps)
         };
+        let step_name = step_name.as_str_name();
+
+        let is_completed = next_steps.is_none();
+        let mut tx = self.db().begin().await?;
+
+        // the step could have been completed concurrently
+        let res = sqlx::query!(
+            "UPDATE LoginProcess SET current_step = $1, completed = $2, updated_at = $3, email_code = NULL, email_code_expires_at = NULL, device_approval = COALESCE($4, device_approval), factors = $5 WHERE id = $6 AND current_step = $7 AND completed = false",
+            step_name,
+            is_completed,
+            now,
+            device_approval,
+            factors,
+            process_id,
+            current_process.current_step
+        )
+        .execute(&mut *tx)
+        .await?;
+        if res.rows_affected() == 0 {
+            return Err(APIError::invalid_argument("Login process changed"));
+        }
+
+        if let (true, Some(device_hash)) = (is_completed, &current_process.device_hash) {
+            let device_id = secure_random_id();
+            sqlx::query!(
+                r#"
+                    INSERT INTO Device (id, identity_id, device_hash, created_at, last_login_at) VALUES ($1, $2, $3, $4, $4)
+                    ON CONFLICT (identity_id, device_hash) DO UPDATE SET last_login_at = $4
+                "#,
+                device_id,
+                current_process.identity_id,
+                device_hash,
+                now
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
 
-        match next_steps {
-            None => Ok(LoginResponse::Success {
-                refresh_token: "TODO".to_string(),
+        let mut tokens = None;
+        if is_completed {
+            let session_id = self
+                .login_session(&current_process, &completed, now)
+                .await?;
+            tokens = Some(
+                self.session()
+                    .issue_tokens(&session_id, application_id)
+                    .await?,
+            );
+        }
+
+        match (next_steps, tokens) {
+            (None, Some((refresh_token, access_token))) => Ok(LoginResponse::Success {
+                access_token: access_token.0,
+                refresh_token: refresh_token.0,
+            }),
+            (None, None) => Err(APIError::internal("Login completed without a session")),
+            (Some(_), _) if awaiting_verification => Ok(LoginResponse::EmailVerificationPending {
+                process_id: process_id.into(),
             }),
-            Some(next_steps) => Ok(LoginResponse::NextStep {
+            (Some(next_steps), _) => Ok(LoginResponse::NextStep {
                 step_type: next_steps,
                 process_id: process_id.into(),
             }),
         }
     }
 
-    pub async fn login_status(&self, process_id: &str) -> Result<LoginStatusResponse, APIError> {
+    /// Prepare a step of the login, e.g. send the code for [`LoginStep::EMAIL_CODE`].
+    /// Like [`Auth::login_step`], only the application that created the process can do this.
+    pub async fn login_start_step(
+        &self,
+        application_id: &str,
+        process_id: &str,
+        step_type: &LoginStep,
+    ) -> Result<(), APIError> {
+        let settings = self.keygate.settings.global().await?;
+
         let process = sqlx::query_as!(
             LoginProcess,
-            "SELECT * FROM LoginProcess WHERE id = $1",
-            process_id
+            "SELECT * FROM LoginProcess WHERE id = $1 AND application_id = $2",
+            process_id,
+            application_id
         )
-        .fetch_one(self.db())
-        .await?;
+        .fetch_optional(self.db())
+        .await?
+        .ok_or(APIError::not_found("Login process not found"))?;
+        ensure_active(&process)?;
 
-        let _ = LoginStep::from_str_name(&process.current_step)
-            .ok_or(APIError::invalid_argument("Invalid step type"))?;
+        let current_step = LoginStep::new(process.current_step.clone());
+        let identity = self.load_identity(&process.identity_id).await?;
+        let completed = LoginStep::parse_list(&process.factors);
+        let ctx = self.context(&process, identity.as_ref(), &settings, &completed);
 
-        Ok(LoginStatusResponse {
-            current_step: process.current_step.to_string(),
-            expires_at: process.expires_at,
-        })
+        if !self
+            .next_steps(&current_step, &ctx)
+            .await?
+            .contains(step_type)
+        {
+            return Err(APIError::invalid_argument("Invalid step type"));
+        }
+
+        self.login_method(step_type)?.start(&ctx).await
     }
 
-    pub async fn signup(
+    /// Send a one-time code to the verified email address of the identity
+    /// that is logging in. The code can then be used with [`LoginStep::EMAIL_CODE`].
+    pub async fn login_send_email_code(
         &self,
-        username: &str,
-        password: &str,
-        email: &str,
-        ip_address: IpAddr,
-    ) -> Result<Identity, APIError> {
-        if !is_valid_username(username) {
-            return Err(APIError::invalid_argument("Invalid username"));
-        }
+        application_id: &str,
+        process_id: &str,
+    ) -> Result<(), APIError> {
+        self.login_start_step(application_id, process_id, &LoginStep::EMAIL_CODE)
+            .await
+    }
 
-        if !is_valid_password(password) {
-            return Err(APIError::invalid_argument("Invalid password"));
-        }
+    /// Approve or deny a login from an unrecognized device.
+    /// `identity_id` is the identity of the already authenticated session.
+    ///
+    /// Approving completes the login and starts its session, the waiting device receives the
+    /// tokens from its next [`LoginStep::DEVICE_APPROVAL`] step.
+    pub async fn login_approve_device(
+        &self,
+        process_id: &str,
+        identity_id: &str,
+        approved: bool,
+    ) -> Result<(), APIError> {
+        let now = time::OffsetDateTime::now_utc();
+        let process = sqlx::query_as!(
+            LoginProcess,
+            "SELECT * FROM LoginProcess WHERE id = $1",
+            process_id
+        )
+        .fetch_optional(self.db())
+        .await?
+        .filter(|process| process.identity_id == identity_id)
+        .ok_or(APIError::not_found("Login process not found"))?;
+        ensure_active(&process)?;
 
-        if !is_valid_email(email) {
-            return Err(APIError::invalid_argument("Invalid email"));
+        if process.device_approval.as_deref() != Some(DeviceApproval::Pending.as_str_name()) {
+            return Err(APIError::invalid_argument(
+                "Login process is not waiting for approval",
+            ));
         }
 
-        let user_id = secure_random_id();
-        let password_hash = keygate_utils::hash::password(password)
-            .map_err(|e| APIError::internal(&format!("Failed to hash password: {}", e)))?;
+        let (device_approval, action) = match approved {
+            true => (DeviceApproval::Approved, AuditAction::DeviceApproved),
+            false => (DeviceApproval::Denied, AuditAction::DeviceDenied),
+        };
+        let device_approval = device_approval.as_str_name();
+        let pending = DeviceApproval::Pending.as_str_name();
 
-        let new_user = sqlx::query_as!(
-            Identity,
-            r#"
-                INSERT INTO Identity (id, username, primary_email, password_hash)
-                VALUES ($1, $2, $3, $4)
-                RETURNING *
-            "#,
-            user_id,
-            username,
-            email,
-            password_hash
+        let mut tx = self.db().begin().await?;
+
+        // the login could have been approved or denied concurrently
+        let res = sqlx::query!(
+            "UPDATE LoginProcess SET device_approval = $1, completed = $2, updated_at = $3 WHERE id = $4 AND device_approval = $5 AND completed = false",
+            device_approval,
+            approved,
+            now,
+            process_id,
+            pending
         )
-        .fetch_one(self.db())
+        .execute(&mut *tx)
         .await?;
+        if res.rows_affected() == 0 {
+            return Err(APIError::invalid_argument(
+                "Login process is not waiting for approval",
+            ));
+        }
+
+        // the approved device is recognized from now on
+        if let (true, Some(device_hash)) = (approved, &process.device_hash) {
+            let device_id = secure_random_id();
+            sqlx::query!(
+                r#"
+                    INSERT INTO Device (id, identity_id, device_hash, created_at, last_login_at) VALUES ($1, $2, $3, $4, $4)
+                    ON CONFLICT (identity_id, device_hash) DO UPDATE SET last_login_at = $4
+                "#,
+                device_id,
+                process.identity_id,
+                device_hash,
+                now
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
 
-        Ok(new_user)
+        if approved {
+            let completed = LoginStep::parse_list(&process.factors);
+            self.login_session(&process, &completed, now).await?;
+        }
+
+        Audit::new(self.keygate.clone())
+            .write(AuditEntry::new(identity_id, action).target("login_process", process_id))
+            .await?;
+
+        Ok(())
+    }
+
+    // hand the tokens of an approved login to the device that waits for them, only once
+    async fn login_collect_approved(
+        &self,
+        application_id: &str,
+        process: &LoginProcess,
+        settings: &GlobalSettings,
+    ) -> Result<LoginResponse, APIError> {
+        let now = time::OffsetDateTime::now_utc();
+        if process.updated_at + settings.login_process_expires_in < now {
+            return Err(APIError::invalid_argument("Login process expired"));
+        }
+
+        // the approving session is still starting the session
+        let Some(session_id) = &process.session_id else {
+            return Ok(LoginResponse::NextStep {
+                step_type: vec![LoginStep::DEVICE_APPROVAL],
+                process_id: process.id.clone(),
+            });
+        };
+
+        let approved = DeviceApproval::Approved.as_str_name();
+        let collected = DeviceApproval::Collected.as_str_name();
+        let res = sqlx::query!(
+            "UPDATE LoginProcess SET device_approval = $1 WHERE id = $2 AND device_approval = $3 AND completed = true",
+            collected,
+            process.id,
+            approved
+        )
+        .execute(self.db())
+        .await?;
+        if res.rows_affected() == 0 {
+            return Err(APIError::invalid_argument(
+                "Login process already completed",
+            ));
+        }
+
+        let (refresh_token, access_token) = self
+            .session()
+            .issue_tokens(session_id, application_id)
+            .await?;
+        Ok(LoginResponse::Success {
+            access_token: access_token.0,
+            refresh_token: refresh_token.0,
+        })
+    }
+
+    /// Logins of the identity that wait for approval
+    pub async fn login_pending_approvals(
+        &self,
+        identity_id: &str,
+    ) -> Result<Vec<DeviceApprovalRequest>, APIError> {
+        let now = time::OffsetDateTime::now_utc();
+        let pending = DeviceApproval::Pending.as_str_name();
+        let processes = sqlx::query_as!(
+            LoginProcess,
+            "SELECT * FROM LoginProcess WHERE identity_id = $1 AND device_approval = $2 AND completed = false AND expires_at > $3",
+            identity_id,
+            pending,
+            now
+        )
+        .fetch_all(self.db())
+        .await?;
+
+        Ok(processes
+            .into_iter()
+            .map(|process| DeviceApprovalRequest {
+                process_id: process.id,
+                ip_address: process.ip_address,
+                created_at: process.created_at,
+            })
+            .collect())
+    }
+
+    /// Trust the device of a login that was just completed with a second factor.
+    /// Only the session created by that login can do this, and the returned token is bound to the
+    /// device id the login was started with. It can be used with [`LoginStep::TRUSTED_DEVICE`] to skip
+    /// second factors until it expires or is revoked. It is only stored hashed, so this is the only time it can be shown.
+    pub async fn login_trust_device(
+        &self,
+        application_id: &str,
+        // the session of the caller, has to be the one the login process created
+        session_id: &str,
+        process_id: &str,
+        label: &str,
+    ) -> Result<String, APIError> {
+        let settings = self.keygate.settings.global().await?;
+        let now = time::OffsetDateTime::now_utc();
+
+        if label.is_empty() || label.len() > 255 {
+            return Err(APIError::invalid_argument("Invalid label"));
+        }
+
+        let process = sqlx::query_as!(
+            LoginProcess,
+            "SELECT * FROM LoginProcess WHERE id = $1 AND application_id = $2",
+            process_id,
+            application_id
+        )
+        .fetch_optional(self.db())
+        .await?
+        .ok_or(APIError::not_found("Login process not found"))?;
+
+        if !process.completed {
+            return Err(APIError::invalid_argument("Login process not completed"));
+        }
+
+        if process.updated_at + settings.login_process_expires_in < now {
+            return Err(APIError::invalid_argument("Login process expired"));
+        }
+
+        if process.session_id.as_deref() != Some(session_id) {
+            return Err(APIError::permission_denied(
+                "Login process belongs to another session",
+            ));
+        }
+
+        // the token is bound to the device, so the login has to identify one
+        let Some(device_hash) = process.device_hash else {
+            return Err(APIError::invalid_argument("Login process has no device id"));
+        };
+
+        // trusting a device from a login that skipped the second factor would never expire
+        let factors: Vec<&str> = process.factors.split(',').collect();
+        if factors.len() < 2 || factors.contains(&LoginStep::TRUSTED_DEVICE.as_str_name()) {
+            return Err(APIError::permission_denied("Second factor required"));
+        }
+
+        let device_id = secure_random_id();
+        let token = randomid(32);
+        let token_hash = keygate_utils::hash::token(&token);
+        let expires_at = now + settings.trusted_device_expires_in;
+
+        let res = sqlx::query!(
+            "INSERT OR IGNORE INTO TrustedDevice (id, identity_id, login_process_id, label, token_hash, created_at, expires_at, device_hash) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+            device_id,
+            process.identity_id,
+            process_id,
+            label,
+            token_hash,
+            now,
+            expires_at,
+            device_hash
+        )
+        .execute(self.db())
+        .await?;
+
+        if res.rows_affected() == 0 {
+            return Err(APIError::AlreadyExists(
+                "Device is already trusted".to_string(),
+            ));
+        }
+
+        Audit::new(self.keygate.clone())
+            .write(
+                AuditEntry::new(&process.identity_id, AuditAction::TrustedDeviceAdded)
+                    .target("trusted_device", &device_id),
+            )
+            .await?;
+
+        Ok(token)
+    }
+
+    /// Clear all failed login attempts of the identity, lifting any lockout
+    pub async fn unlock(&self, identity_id: &str) -> Result<(), APIError> {
+        let unlocked = LoginThrottle::new(self.keygate.clone())
+            .reset(&identity_key(identity_id))
+            .await?;
+
+        if unlocked {
+            Audit::new(self.keygate.clone())
+                .write(AuditEntry::new(identity_id, AuditAction::IdentityUnlocked))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn login_status(
+        &self,
+        application_id: &str,
+        process_id: &str,
+    ) -> Result<LoginStatusResponse, APIError> {
+        let process = sqlx::query_as!(
+            LoginProcess,
+            "SELECT * FROM LoginProcess WHERE id = $1 AND application_id = $2",
+            process_id,
+            application_id
+        )
+        .fetch_optional(self.db())
+        .await?
+        .ok_or(APIError::not_found("Login process not found"))?;
+
+        Ok(LoginStatusResponse {
+            current_step: process.current_step.to_string(),
+            expires_at: process.expires_at,
+            device_approval: process.device_approval,
+        })
+    }
+
+    // accepted users of the legacy user store get an identity with a fresh password hash,
+    // the password step then verifies against it like for any other identity
+    async fn migrate_legacy_user(
+        &self,
+        process: &mut LoginProcess,
+        identity: &mut Option<Identity>,
+        password: &str,
+    ) -> Result<(), APIError> {
+        let (Some(username_or_email), Some(store)) = (
+            process.legacy_username.clone(),
+            self.keygate.legacy_user_store.load_full(),
+        ) else {
+            return Ok(());
+        };
+        let Some(user) = store.verify(&username_or_email, password).await? else {
+            return Ok(());
+        };
+
+        let now = time::OffsetDateTime::now_utc();
+        let password_hash = self
+            .keygate
+            .password_hasher
+            .hash(password)
+            .map_err(|e| APIError::internal(&format!("Failed to hash password: {}", e)))?;
+
+        let migrated = match identity {
+            Some(identity) => {
+                sqlx::query_as!(
+                    Identity,
+                    "UPDATE Identity SET password_hash = $1, legacy = false, updated_at = $2 WHERE id = $3 RETURNING *",
+                    password_hash,
+                    now,
+                    identity.id
+                )
+                .fetch_one(self.db())
+                .await?
+            }
+            None => {
+                let is_email = username_or_email.contains('@');
+                let username = user
+                    .username
+                    .or((!is_email).then(|| username_or_email.clone()));
+                let email = user.email.or(is_email.then(|| username_or_email.clone()));
+
+                let created = self
+                    .identity()
+                    .create(super::identity::CreateIdentity {
+                        username: username.as_deref(),
+                        primary_email: email.as_deref(),
+                        password_hash: Some(&password_hash),
+                    })
+                    .await?;
+
+                if let (true, Some(email)) = (user.email_verified, &created.primary_email) {
+                    sqlx::query!(
+                        "UPDATE Email SET verified = true, verification_code = NULL, verification_code_expires_at = NULL, updated_at = $1 WHERE email = $2 AND identity_id = $3",
+                        now,
+                        email,
+                        created.id
+                    )
+                    .execute(self.db())
+                    .await?;
+                }
+                created
+            }
+        };
+
+        sqlx::query!(
+            "UPDATE LoginProcess SET identity_id = $1, legacy_username = NULL, updated_at = $2 WHERE id = $3",
+            migrated.id,
+            now,
+            process.id
+        )
+        .execute(self.db())
+        .await?;
+
+        let entry = AuditEntry::new(&migrated.id, AuditAction::IdentityMigrated)
+            .target("login_process", &process.id);
+        Audit::new(self.keygate.clone()).write(entry).await?;
+
+        process.identity_id = migrated.id.clone();
+        process.legacy_username = None;
+        *identity = Some(migrated);
+        Ok(())
+    }
+
+    // check the password with a bind to the LDAP directory, `None` if the directory isn't
+    // responsible for the process. Directory users log in to an identity that is created
+    // from their entry on their first login, their roles are synced on every login.
+    async fn ldap_login(
+        &self,
+        process: &mut LoginProcess,
+        identity: &mut Option<Identity>,
+        password: &str,
+    ) -> Result<Option<bool>, APIError> {
+        let (Some(username_or_email), Some(directory)) =
+            (process.ldap_username.clone(), &self.keygate.ldap)
+        else {
+            return Ok(None);
+        };
+        // migrated from the legacy user store in the same step
+        if identity.as_ref().is_some_and(|identity| !identity.ldap) {
+            return Ok(None);
+        }
+        let Some(user) = directory.authenticate(&username_or_email, password).await? else {
+            return Ok(Some(false));
+        };
+
+        let now = time::OffsetDateTime::now_utc();
+        let ldap_identity = match identity {
+            Some(identity) => identity.clone(),
+            None => {
+                let is_email = username_or_email.contains('@');
+                let username = user
+                    .username
+                    .clone()
+                    .or((!is_email).then(|| username_or_email.clone()))
+                    .filter(|username| is_valid_username(username));
+                let email = user
+                    .email
+                    .clone()
+                    .or(is_email.then(|| username_or_email.clone()))
+                    .filter(|email| is_valid_email(email));
+                self.ldap_signup(username.as_deref(), email.as_deref(), &user.dn)
+                    .await?
+            }
+        };
+
+        let mut tx = self.db().begin().await?;
+        sqlx::query!(
+            "DELETE FROM IdentityRole WHERE identity_id = $1",
+            ldap_identity.id
+        )
+        .execute(&mut *tx)
+        .await?;
+        for role in &user.roles {
+            sqlx::query!(
+                "INSERT INTO IdentityRole (identity_id, role, created_at) VALUES ($1, $2, $3)",
+                ldap_identity.id,
+                role,
+                now
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        sqlx::query!(
+            "UPDATE LoginProcess SET identity_id = $1, updated_at = $2 WHERE id = $3",
+            ldap_identity.id,
+            now,
+            process.id
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        process.identity_id = ldap_identity.id.clone();
+        *identity = Some(ldap_identity);
+        Ok(Some(true))
+    }
+
+    // a new identity for a directory user, it has no password hash
+    async fn ldap_signup(
+        &self,
+        username: Option<&str>,
+        email: Option<&str>,
+        dn: &str,
+    ) -> Result<Identity, APIError> {
+        if let Some(username) = username {
+            if self.identity().exists(username).await? {
+                return Err(APIError::AlreadyExists(
+                    "An account with this username already exists".into(),
+                ));
+            }
+        }
+        if let Some(email) = email {
+            let taken = sqlx::query!(
+                "SELECT email FROM Email WHERE email = $1 COLLATE NOCASE",
+                email
+            )
+            .fetch_optional(self.db())
+            .await?;
+            if taken.is_some() {
+                return Err(APIError::AlreadyExists(
+                    "An account with this email already exists".into(),
+                ));
+            }
+        }
+
+        let now = time::OffsetDateTime::now_utc();
+        let identity_id = secure_random_id();
+        let email_verified = self
+            .keygate
+            .ldap
+            .as_ref()
+            .is_some_and(|directory| directory.trust_email());
+
+        let mut tx = self.db().begin().await?;
+        sqlx::query!(
+            "INSERT INTO Identity (id, username, created_at, updated_at, last_active, ldap) VALUES ($1, $2, $3, $3, $3, true)",
+            identity_id,
+            username,
+            now
+        )
+        .execute(&mut *tx)
+        .await?;
+        if let Some(email) = email {
+            sqlx::query!(
+                "INSERT INTO Email (email, identity_id, verified, created_at, updated_at) VALUES ($1, $2, $3, $4, $4)",
+                email,
+                identity_id,
+                email_verified,
+                now
+            )
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query!(
+                "UPDATE Identity SET primary_email = $1 WHERE id = $2",
+                email,
+                identity_id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+
+        let entry = AuditEntry::new(&identity_id, AuditAction::IdentityMigrated).target("ldap", dn);
+        Audit::new(self.keygate.clone()).write(entry).await?;
+
+        self.load_identity(&identity_id)
+            .await?
+            .ok_or(APIError::internal("Identity not found"))
+    }
+
+    // identify the user with the provider of an upstream login, linked accounts log in
+    // to their identity and new ones get an identity of their own
+    async fn upstream_login(
+        &self,
+        process: &mut LoginProcess,
+        identity: &mut Option<Identity>,
+        data: &str,
+    ) -> Result<(), APIError> {
+        // the state is consumed by `login_upstream_callback`, which has to come first
+        let (Some(provider_id), None) =
+            (process.upstream_provider.clone(), &process.upstream_state)
+        else {
+            return Err(APIError::invalid_argument("Invalid step type"));
+        };
+
+        let user = match self.keygate.saml.get(&provider_id) {
+            Some(_) => self.saml_user(process, &provider_id, data).await?,
+            None => self.oidc_user(process, &provider_id, data).await?,
+        };
+        let Some(user) = user else {
+            return Ok(());
+        };
+
+        let now = time::OffsetDateTime::now_utc();
+        let linked = sqlx::query_as!(
+            LinkedAccount,
+            "UPDATE LinkedAccount SET last_login_at = $1 WHERE provider_id = $2 AND subject = $3 RETURNING *",
+            now,
+            provider_id,
+            user.subject
+        )
+        .fetch_optional(self.db())
+        .await?;
+
+        let upstream_identity = match linked {
+            Some(linked) => self
+                .load_identity(&linked.identity_id)
+                .await?
+                .ok_or(APIError::internal("Linked identity not found"))?,
+            None => match self.domain_identity(&provider_id, &user).await? {
+                Some(identity) => identity,
+                None => self.upstream_signup(&provider_id, &user).await?,
+            },
+        };
+
+        sqlx::query!(
+            "UPDATE LoginProcess SET identity_id = $1, updated_at = $2 WHERE id = $3",
+            upstream_identity.id,
+            now,
+            process.id
+        )
+        .execute(self.db())
+        .await?;
+
+        process.identity_id = upstream_identity.id.clone();
+        *identity = Some(upstream_identity);
+        Ok(())
+    }
+
+    // the user of an OpenID Connect provider, `None` if the provider rejects the code
+    async fn oidc_user(
+        &self,
+        process: &LoginProcess,
+        provider_id: &str,
+        code: &str,
+    ) -> Result<Option<UpstreamUser>, APIError> {
+        let (Some(nonce), Some(code_verifier)) = (
+            process.upstream_nonce.as_deref(),
+            process.upstream_code_verifier.as_deref(),
+        ) else {
+            return Err(APIError::invalid_argument("Invalid step type"));
+        };
+
+        // codes are single-use, so is the verifier
+        let res = sqlx::query!(
+            "UPDATE LoginProcess SET upstream_code_verifier = NULL WHERE id = $1 AND upstream_code_verifier = $2",
+            process.id,
+            code_verifier
+        )
+        .execute(self.db())
+        .await?;
+        if res.rows_affected() == 0 {
+            return Err(APIError::invalid_argument("Login process changed"));
+        }
+
+        let claims = self
+            .keygate
+            .upstream
+            .exchange(provider_id, code, code_verifier, nonce)
+            .await?;
+        Ok(claims.map(UpstreamUser::from))
+    }
+
+    // the user of a SAML connection, `None` if the response is invalid or was used before
+    async fn saml_user(
+        &self,
+        process: &LoginProcess,
+        connection_id: &str,
+        saml_response: &str,
+    ) -> Result<Option<UpstreamUser>, APIError> {
+        let Some(request_id) = process.upstream_nonce.as_deref() else {
+            return Err(APIError::invalid_argument("Invalid step type"));
+        };
+
+        // a request is answered once
+        let res = sqlx::query!(
+            "UPDATE LoginProcess SET upstream_nonce = NULL WHERE id = $1 AND upstream_nonce = $2",
+            process.id,
+            request_id
+        )
+        .execute(self.db())
+        .await?;
+        if res.rows_affected() == 0 {
+            return Err(APIError::invalid_argument("Login process changed"));
+        }
+
+        let Some(assertion) = self
+            .keygate
+            .saml
+            .verify(connection_id, saml_response, request_id)?
+        else {
+            return Ok(None);
+        };
+
+        // assertions are accepted with a minute of clock skew, so they are kept a minute longer
+        let now = time::OffsetDateTime::now_utc();
+        let expires_at = time::OffsetDateTime::from_unix_timestamp(assertion.expires_at)
+            .map_err(|_| APIError::internal("Invalid assertion expiry"))?
+            + time::Duration::minutes(1);
+        sqlx::query!("DELETE FROM SamlAssertion WHERE expires_at < $1", now)
+            .execute(self.db())
+            .await?;
+        let res = sqlx::query!(
+            "INSERT INTO SamlAssertion (connection_id, id, expires_at) VALUES ($1, $2, $3) ON CONFLICT DO NOTHING",
+            connection_id,
+            assertion.id,
+            expires_at
+        )
+        .execute(self.db())
+        .await?;
+        if res.rows_affected() == 0 {
+            warn!(
+                "Replayed assertion {} from SAML connection {}",
+                assertion.id, connection_id
+            );
+            return Ok(None);
+        }
+
+        self.keygate.saml.user(connection_id, &assertion).map(Some)
+    }
+
+    // the existing identity with the email of the user if the provider is trusted with its
+    // domain, the account of the provider is linked to it
+    async fn domain_identity(
+        &self,
+        provider_id: &str,
+        user: &UpstreamUser,
+    ) -> Result<Option<Identity>, APIError> {
+        let Some(email) = user.email.as_deref().filter(|_| user.email_verified) else {
+            return Ok(None);
+        };
+        let routed = super::Domains::new(self.keygate.clone())
+            .route(email)
+            .await?
+            .is_some_and(|sso_domain| sso_domain.provider_id == provider_id);
+        if !routed {
+            return Ok(None);
+        }
+
+        let Some(owner) = sqlx::query!(
+            "SELECT identity_id FROM Email WHERE email = $1 COLLATE NOCASE",
+            email
+        )
+        .fetch_optional(self.db())
+        .await?
+        else {
+            return Ok(None);
+        };
+
+        let now = time::OffsetDateTime::now_utc();
+        let linked_account_id = secure_random_id();
+        sqlx::query!(
+            "INSERT INTO LinkedAccount (id, identity_id, provider_id, subject, email, created_at, last_login_at) VALUES ($1, $2, $3, $4, $5, $6, $6)",
+            linked_account_id,
+            owner.identity_id,
+            provider_id,
+            user.subject,
+            user.email,
+            now
+        )
+        .execute(self.db())
+        .await?;
+
+        let entry = AuditEntry::new(&owner.identity_id, AuditAction::AccountLinked)
+            .target("linked_account", &linked_account_id)
+            .data(join_keys!("provider", provider_id, "domain", "verified"));
+        Audit::new(self.keygate.clone()).write(entry).await?;
+
+        self.load_identity(&owner.identity_id).await
+    }
+
+    // a new identity linked to the account of the provider, it has no password
+    async fn upstream_signup(
+        &self,
+        provider_id: &str,
+        user: &UpstreamUser,
+    ) -> Result<Identity, APIError> {
+        let now = time::OffsetDateTime::now_utc();
+        let identity_id = secure_random_id();
+
+        let username = match user
+            .username
+            .as_deref()
+            .filter(|username| is_valid_username(username))
+        {
+            Some(username) if !self.identity().exists(username).await? => username.to_string(),
+            _ => format!("user_{}", randomid(12)),
+        };
+        let email = user.email.as_deref().filter(|email| is_valid_email(email));
+        if let Some(email) = email {
+            let taken = sqlx::query!("SELECT email FROM Email WHERE email = $1", email)
+                .fetch_optional(self.db())
+                .await?;
+            if taken.is_some() {
+                return Err(APIError::AlreadyExists(
+                    "An account with this email already exists, log in to link the provider".into(),
+                ));
+            }
+        }
+
+        let mut tx = self.db().begin().await?;
+        sqlx::query!(
+            "INSERT INTO Identity (id, username, created_at, updated_at, last_active) VALUES ($1, $2, $3, $3, $3)",
+            identity_id,
+            username,
+            now
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        if let Some(email) = email {
+            sqlx::query!(
+                "INSERT INTO Email (email, identity_id, verified, created_at, updated_at) VALUES ($1, $2, $3, $4, $4)",
+                email,
+                identity_id,
+                user.email_verified,
+                now
+            )
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query!(
+                "UPDATE Identity SET primary_email = $1 WHERE id = $2",
+                email,
+                identity_id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        let linked_account_id = secure_random_id();
+        sqlx::query!(
+            "INSERT INTO LinkedAccount (id, identity_id, provider_id, subject, email, created_at, last_login_at) VALUES ($1, $2, $3, $4, $5, $6, $6)",
+            linked_account_id,
+            identity_id,
+            provider_id,
+            user.subject,
+            user.email,
+            now
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        let entry = AuditEntry::new(&identity_id, AuditAction::AccountLinked)
+            .target("linked_account", &linked_account_id)
+            .data(join_keys!("provider", provider_id));
+        Audit::new(self.keygate.clone()).write(entry).await?;
+
+        self.load_identity(&identity_id)
+            .await?
+            .ok_or(APIError::internal("Identity not found"))
+    }
+
+    // steps that can follow `current_step`, empty if the login is complete
+    async fn next_steps(
+        &self,
+        current_step: &LoginStep,
+        ctx: &LoginContext<'_>,
+    ) -> Result<Vec<LoginStep>, APIError> {
+        // steps that wait for something after the factors are complete
+        if *current_step == LoginStep::DEVICE_APPROVAL
+            || *current_step == LoginStep::EMAIL_VERIFICATION
+            || *current_step == LoginStep::CHANGE_PASSWORD
+        {
+            return Ok(vec![current_step.clone()]);
+        }
+
+        let application = match &ctx.process.application_id {
+            Some(application_id) => Some(self.application(application_id).await?),
+            None => None,
+        };
+        let methods = self.keygate.login_methods.all();
+
+        if current_step.identifies() {
+            let mut steps = self.available_steps(&methods, ctx, Factor::First).await?;
+            if let Some(allowed) = application
+                .as_ref()
+                .and_then(|application| application.allowed_first_factors.as_ref())
+            {
+                steps.retain(|step| allowed.iter().any(|name| name == step.as_str_name()));
+            }
+            // domains that block passwords only log in with their provider
+            if ctx.process.upstream_required {
+                steps.retain(|step| *step == LoginStep::UPSTREAM);
+            }
+
+            if steps.is_empty() {
+                return Err(APIError::permission_denied(
+                    "No login method is allowed for this application",
+                ));
+            }
+            return Ok(steps);
+        }
+
+        // re-authentication can still ask for a second factor explicitly
+        let policy = application
+            .map(|application| application.second_factor)
+            .unwrap_or_default();
+        let required = ctx.process.require_second_factor || policy == SecondFactorPolicy::Required;
+        if policy == SecondFactorPolicy::Forbidden && !ctx.process.require_second_factor {
+            return Ok(vec![]);
+        }
+
+        let mut steps = self.available_steps(&methods, ctx, Factor::Second).await?;
+        if steps.is_empty() && !required {
+            return Ok(steps);
+        }
+
+        // fallbacks (e.g. recovery codes) are offered next to other second factors
+        steps.extend(
+            self.available_steps(&methods, ctx, Factor::Fallback)
+                .await?,
+        );
+        if steps.is_empty() {
+            return Err(APIError::permission_denied("No second factor available"));
+        }
+        Ok(steps)
+    }
+
+    // steps of the methods that can be used as `factor` and were not used yet
+    async fn available_steps(
+        &self,
+        methods: &[Arc<dyn LoginMethod>],
+        ctx: &LoginContext<'_>,
+        factor: Factor,
+    ) -> Result<Vec<LoginStep>, APIError> {
+        let mut steps = vec![];
+        for method in methods {
+            let step = method.step();
+            if method.factors().contains(&factor)
+                && !ctx.completed.contains(&step)
+                && method.available(ctx, factor).await?
+            {
+                steps.push(step);
+            }
+        }
+        Ok(steps)
+    }
+
+    /// First factors the identity can really log in with, apart from logins with one of
+    /// its linked accounts. Nothing is offered just to hide whether the identity exists.
+    pub(crate) async fn first_factors(
+        &self,
+        identity: &Identity,
+    ) -> Result<Vec<LoginStep>, APIError> {
+        let settings = self.keygate.settings.global().await?;
+        let now = time::OffsetDateTime::now_utc();
+        // routed like a login with the identity's username or email
+        let login_name = identity.username.clone().or(identity.primary_email.clone());
+        let legacy = self.keygate.legacy_user_store.load().is_some() && identity.legacy;
+        let ldap = self.keygate.ldap.is_some() && identity.ldap;
+        let sso_domain = match &identity.primary_email {
+            Some(email) => {
+                super::Domains::new(self.keygate.clone())
+                    .route(email)
+                    .await?
+            }
+            None => None,
+        };
+
+        let process = LoginProcess {
+            id: secure_random_id(),
+            created_at: now,
+            updated_at: now,
+            identity_id: identity.id.clone(),
+            ip_address: None,
+            expires_at: None,
+            completed: false,
+            current_step: LoginStep::USERNAME.as_str_name().to_string(),
+            magic_link: None,
+            email_code: None,
+            email_code_expires_at: None,
+            email_code_attempts: 0,
+            device_hash: None,
+            device_approval: None,
+            factors: String::new(),
+            session_id: None,
+            reauthenticate: false,
+            require_second_factor: false,
+            application_id: None,
+            legacy_username: login_name.clone().filter(|_| legacy),
+            upstream_provider: sso_domain
+                .as_ref()
+                .map(|sso_domain| sso_domain.provider_id.clone()),
+            upstream_state: None,
+            upstream_nonce: None,
+            upstream_code_verifier: None,
+            upstream_required: sso_domain
+                .as_ref()
+                .is_some_and(|sso_domain| sso_domain.block_password),
+            ldap_username: login_name.filter(|_| ldap),
+        };
+        let ctx = LoginContext {
+            anti_enumeration: false,
+            ..self.context(&process, Some(identity), &settings, &[])
+        };
+
+        let methods = self.keygate.login_methods.all();
+        let mut steps = self.available_steps(&methods, &ctx, Factor::First).await?;
+        if process.upstream_required {
+            steps.retain(|step| *step == LoginStep::UPSTREAM);
+        }
+        Ok(steps)
+    }
+
+    async fn requires_email_verification(
+        &self,
+        process: &LoginProcess,
+        identity: Option<&Identity>,
+        settings: &GlobalSettings,
+    ) -> Result<bool, APIError> {
+        if process.reauthenticate
+            || !matches!(
+                settings.email_verification,
+                EmailVerification::RequiredForLogin { .. }
+            )
+        {
+            return Ok(false);
+        }
+
+        // identities without an email address have nothing to verify
+        match identity {
+            Some(identity) if identity.primary_email.is_some() => {
+                Ok(verified_email(&self.keygate, &identity.id).await?.is_none())
+            }
+            _ => Ok(false),
+        }
+    }
+
+    // start the session of a completed login process, or update the one it re-authenticated
+    async fn login_session(
+        &self,
+        process: &LoginProcess,
+        completed: &[LoginStep],
+        now: time::OffsetDateTime,
+    ) -> Result<String, APIError> {
+        let methods = completed
+            .iter()
+            .filter_map(|step| self.keygate.login_methods.get(step))
+            .collect::<Vec<_>>();
+        let auth_context = AuthContext::from_methods(&methods, now);
+
+        let session_id = match (&process.session_id, process.reauthenticate) {
+            (Some(session_id), true) => {
+                self.session()
+                    .reauthenticated(session_id, &auth_context)
+                    .await?;
+                session_id.clone()
+            }
+            _ => {
+                let session_id = self
+                    .session()
+                    .start(
+                        &process.identity_id,
+                        process.ip_address.as_deref(),
+                        &auth_context,
+                    )
+                    .await?;
+                sqlx::query!(
+                    "UPDATE LoginProcess SET session_id = $1 WHERE id = $2",
+                    session_id,
+                    process.id
+                )
+                .execute(self.db())
+                .await?;
+                session_id
+            }
+        };
+
+        // only a completed login clears the failures, a correct password alone would
+        // allow unlimited guesses of the second factor
+        LoginThrottle::new(self.keygate.clone())
+            .reset(&identity_key(&process.identity_id))
+            .await?;
+
+        Ok(session_id)
+    }
+
+    async fn requires_device_approval(
+        &self,
+        process: &LoginProcess,
+        settings: &GlobalSettings,
+    ) -> Result<bool, APIError> {
+        // re-authentication happens from an already signed in device
+        if !settings.device_approval || process.reauthenticate {
+            return Ok(false);
+        }
+
+        let known_devices = sqlx::query!(
+            "SELECT device_hash FROM Device WHERE identity_id = $1",
+            process.identity_id
+        )
+        .fetch_all(self.db())
+        .await?;
+
+        // without a known device there is no session that could approve the login
+        Ok(!known_devices.is_empty()
+            && !known_devices
+                .iter()
+                .any(|device| Some(&device.device_hash) == process.device_hash.as_ref()))
+    }
+
+    /// Send a single-use token to reset the password of the identity the email belongs to.
+    /// Only verified addresses receive a token.
+    ///
+    /// This always succeeds, whether the email is known or not, so it can't be used to find out
+    /// which addresses have an account.
+    pub async fn password_reset_request(&self, email: &str) -> Result<(), APIError> {
+        let settings = self.keygate.settings.global().await?;
+        let now = time::OffsetDateTime::now_utc();
+
+        // unverified addresses could belong to anyone
+        let Some(email) = sqlx::query_as!(
+            Email,
+            "SELECT * FROM Email WHERE email = $1 COLLATE NOCASE AND verified = true",
+            email
+        )
+        .fetch_optional(self.db())
+        .await?
+        else {
+            return Ok(());
+        };
+
+        let token = randomid(32);
+        let token_hash = keygate_utils::hash::token(&token);
+        let reset_id = secure_random_id();
+        let expires_at = now + settings.password_reset.token_expires_in;
+        let cooldown_start = now - settings.password_reset.resend_cooldown;
+
+        let mut tx = self.db().begin().await?;
+
+        // the cooldown is checked by the insert itself, so parallel requests can't all pass it
+        let res = sqlx::query!(
+            r#"
+                INSERT INTO PasswordReset (id, identity_id, email, token_hash, created_at, expires_at)
+                SELECT $1, $2, $3, $4, $5, $6
+                WHERE NOT EXISTS (SELECT 1 FROM PasswordReset WHERE identity_id = $2 AND created_at > $7)
+            "#,
+            reset_id,
+            email.identity_id,
+            email.email,
+            token_hash,
+            now,
+            expires_at,
+            cooldown_start
+        )
+        .execute(&mut *tx)
+        .await?;
+        if res.rows_affected() == 0 {
+            return Ok(());
+        }
+
+        // only the latest token can be used
+        sqlx::query!(
+            "DELETE FROM PasswordReset WHERE identity_id = $1 AND used_at IS NULL AND id != $2",
+            email.identity_id,
+            reset_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Audit::new(self.keygate.clone())
+            .write(
+                AuditEntry::new(&email.identity_id, AuditAction::PasswordResetRequested)
+                    .target("password_reset", &reset_id),
+            )
+            .await?;
+
+        // a failure here would reveal that the email exists
+        let res = self.keygate.mailer.load().send(EmailMessage {
+            to: email.email,
+            subject: "Reset your password".to_string(),
+            body: format!(
+                "Use this token to reset your password: {}. It expires in {} minutes. If you didn't request this, you can ignore this email.",
+                token,
+                settings.password_reset.token_expires_in.whole_minutes()
+            ),
+        });
+        if let Err(e) = res {
+            warn!("Failed to send password reset email: {}", e);
+        }
+
+        Ok(())
+    }
+
+    /// Set a new password with a token from [`Auth::password_reset_request`].
+    /// All sessions of the identity are revoked.
+    pub async fn password_reset_confirm(
+        &self,
+        token: &str,
+        new_password: &str,
+    ) -> Result<(), APIError> {
+        let settings = self.keygate.settings.global().await?;
+        let now = time::OffsetDateTime::now_utc();
+        let token_hash = keygate_utils::hash::token(token);
+
+        // the password policy is only checked for valid tokens, the token is claimed below
+        let identity = sqlx::query_as!(
+            Identity,
+            "SELECT * FROM Identity WHERE id = (SELECT identity_id FROM PasswordReset WHERE token_hash = $1 AND used_at IS NULL AND expires_at > $2)",
+            token_hash,
+            now
+        )
+        .fetch_optional(self.db())
+        .await?
+        .ok_or(APIError::invalid_argument("Invalid or expired token"))?;
+        check_new_password(
+            &self.keygate,
+            new_password,
+            &settings,
+            &user_inputs(&identity),
+        )
+        .await?;
+
+        let password_hash = self
+            .keygate
+            .password_hasher
+            .hash(new_password)
+            .map_err(|e| APIError::internal(&format!("Failed to hash password: {}", e)))?;
+
+        let mut tx = self.db().begin().await?;
+        // the token could have been used concurrently
+        let reset = sqlx::query!(
+            "UPDATE PasswordReset SET used_at = $1 WHERE token_hash = $2 AND used_at IS NULL AND expires_at > $1 RETURNING id, identity_id, email",
+            now,
+            token_hash
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(APIError::invalid_argument("Invalid or expired token"))?;
+
+        sqlx::query!(
+            "UPDATE Identity SET password_hash = $1, updated_at = $2 WHERE id = $3",
+            password_hash,
+            now,
+            reset.identity_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            "UPDATE Session SET revoked_at = $1 WHERE identity_id = $2 AND revoked_at IS NULL",
+            now,
+            reset.identity_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        // the identity proved access to its email, so a lockout shouldn't keep it out
+        LoginThrottle::new(self.keygate.clone())
+            .reset(&identity_key(&reset.identity_id))
+            .await?;
+
+        Audit::new(self.keygate.clone())
+            .write(
+                AuditEntry::new(&reset.identity_id, AuditAction::PasswordReset)
+                    .target("password_reset", &reset.id),
+            )
+            .await?;
+
+        // the password is already changed, a missing notification shouldn't fail the request
+        let res = self.keygate.mailer.load().send(EmailMessage {
+            to: reset.email,
+            subject: "Your password was reset".to_string(),
+            body: "The password of your account was just reset and all sessions were signed out. If this wasn't you, contact support immediately.".to_string(),
+        });
+        if let Err(e) = res {
+            warn!("Failed to send password reset notification: {}", e);
+        }
+
+        Ok(())
+    }
+
+    /// Create an identity with the fields [`GlobalSettings::signup_flow`] asks for.
+    ///
+    /// With [`SignupFlow::EmailOnly`] or [`EmailVerification::RequiredForLogin`], a code is
+    /// sent to the email address and the signup is completed with [`Auth::signup_confirm`].
+    pub async fn signup(
+        &self,
+        data: SignupData<'_>,
+        ip_address: IpAddr,
+    ) -> Result<SignupResponse, APIError> {
+        let settings = self.keygate.settings.global().await?;
+        let (username_allowed, email_allowed, password_allowed) = match settings.signup_flow {
+            SignupFlow::UsernamePasswordAndEmail => (true, true, true),
+            SignupFlow::UsernamePassword => (true, false, true),
+            SignupFlow::EmailPassword => (false, true, true),
+            SignupFlow::EmailOnly => (false, true, false),
+        };
+
+        let username = signup_field(data.username, username_allowed, "Username")?;
+        let email = signup_field(data.email, email_allowed, "Email")?;
+        let password = signup_field(data.password, password_allowed, "Password")?;
+
+        let full_name = data
+            .full_name
+            .map(str::trim)
+            .filter(|name| !name.is_empty());
+        match full_name {
+            None if settings.require_full_name => {
+                return Err(APIError::invalid_argument("Full name is required"))
+            }
+            Some(name) if name.chars().count() > 255 => {
+                return Err(APIError::invalid_argument("Full name is too long"))
+            }
+            _ => {}
+        }
+        let now = time::OffsetDateTime::now_utc();
+        let age_attested =
+            check_signup_age(&settings, data.birthdate, data.age_attestation, now.date())?;
+        // without store_birthdate, only the fact that the user is old enough is kept
+        let birthdate = data.birthdate.filter(|_| settings.store_birthdate);
+        let age_attested_at = age_attested.then_some(now);
+
+        if let Some(username) = username {
+            if !is_valid_username(username) {
+                return Err(APIError::invalid_argument("Invalid username"));
+            }
+            if self.identity().exists(username).await? {
+                return Err(APIError::AlreadyExists(
+                    "An account with this username already exists".into(),
+                ));
+            }
+        }
+        if let Some(email) = email {
+            if !is_valid_email(email) {
+                return Err(APIError::invalid_argument("Invalid email"));
+            }
+            let taken = sqlx::query!(
+                "SELECT email FROM Email WHERE email = $1 COLLATE NOCASE",
+                email
+            )
+            .fetch_optional(self.db())
+            .await?;
+            if taken.is_some() {
+                return Err(APIError::AlreadyExists(
+                    "An account with this email already exists".into(),
+                ));
+            }
+        }
+        let password_hash = match password {
+            Some(password) => {
+                let user_inputs: Vec<&str> = username.into_iter().chain(email).collect();
+                check_new_password(&self.keygate, password, &settings, &user_inputs).await?;
+                let password_hash =
+                    self.keygate.password_hasher.hash(password).map_err(|e| {
+                        APIError::internal(&format!("Failed to hash password: {}", e))
+                    })?;
+                Some(password_hash)
+            }
+            None => None,
+        };
+
+        // passwordless signups prove access to their email address before they can log in
+        let send_code = email.is_some()
+            && (matches!(settings.signup_flow, SignupFlow::EmailOnly)
+                || !matches!(settings.email_verification, EmailVerification::None));
+        let requires_verification = email.is_some()
+            && (matches!(settings.signup_flow, SignupFlow::EmailOnly)
+                || matches!(
+                    settings.email_verification,
+                    EmailVerification::RequiredForLogin { .. }
+                ));
+
+        let identity_id = secure_random_id();
+
+        let mut tx = self.db().begin().await?;
+        sqlx::query!(
+            "INSERT INTO Identity (id, username, password_hash, full_name, birthdate, age_attested_at, created_at, updated_at, last_active) VALUES ($1, $2, $3, $4, $5, $6, $7, $7, $7)",
+            identity_id,
+            username,
+            password_hash,
+            full_name,
+            birthdate,
+            age_attested_at,
+            now
+        )
+        .execute(&mut *tx)
+        .await?;
+        if let Some(email) = email {
+            sqlx::query!(
+                "INSERT INTO Email (email, identity_id, verified, created_at, updated_at) VALUES ($1, $2, false, $3, $3)",
+                email,
+                identity_id,
+                now
+            )
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query!(
+                "UPDATE Identity SET primary_email = $1 WHERE id = $2",
+                email,
+                identity_id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+
+        if let (Some(email), true) = (email, send_code) {
+            self.identity()
+                .send_verification_code(email, &settings)
+                .await?;
+        }
+
+        if requires_verification {
+            return Ok(SignupResponse::RequiresEmailVerification);
+        }
+
+        let identity = self
+            .load_identity(&identity_id)
+            .await?
+            .ok_or(APIError::internal("Identity not found"))?;
+        Ok(SignupResponse::Success { identity })
+    }
+
+    /// Verify the email address of a new identity with the code sent by [`Auth::signup`].
+    /// Returns the identity, which can be signed in now.
+    pub async fn signup_confirm(&self, code: &str) -> Result<Identity, APIError> {
+        self.identity().verify_email(code).await
+    }
+}
+
+// fields that aren't part of the signup flow are rejected, all others are required
+fn signup_field<'a>(
+    value: Option<&'a str>,
+    allowed: bool,
+    name: &str,
+) -> Result<Option<&'a str>, APIError> {
+    match (value, allowed) {
+        (Some(_), false) => Err(APIError::InvalidArgument(format!(
+            "{} is not allowed for signup",
+            name
+        ))),
+        (None, true) => Err(APIError::InvalidArgument(format!("{} is required", name))),
+        (value, _) => Ok(value),
+    }
+}
+
+// with a minimum age, the birthdate or, if it isn't required, an attestation has to show the
+// user is old enough. Returns whether the age was confirmed.
+fn check_signup_age(
+    settings: &GlobalSettings,
+    birthdate: Option<time::Date>,
+    attestation: bool,
+    today: time::Date,
+) -> Result<bool, APIError> {
+    match birthdate {
+        None if settings.require_birthdate => {
+            return Err(APIError::invalid_argument("Birthdate is required"))
+        }
+        Some(birthdate) if birthdate > today => {
+            return Err(APIError::invalid_argument("Invalid birthdate"))
+        }
+        _ => {}
+    }
+
+    let Some(minimum_age) = settings.minimum_age else {
+        return Ok(false);
+    };
+    let old_enough = match birthdate {
+        Some(birthdate) => age(birthdate, today) >= minimum_age,
+        None if attestation => true,
+        None => {
+            return Err(APIError::InvalidArgument(format!(
+                "Confirm that you are at least {} years old",
+                minimum_age
+            )))
+        }
+    };
+    if !old_enough {
+        return Err(APIError::PermissionDenied(format!(
+            "You have to be at least {} years old to sign up",
+            minimum_age
+        )));
+    }
+    Ok(true)
+}
+
+// full years between the birthdate and today
+fn age(birthdate: time::Date, today: time::Date) -> i32 {
+    let had_birthday =
+        (today.month() as u8, today.day()) >= (birthdate.month() as u8, birthdate.day());
+    today.year() - birthdate.year() - i32::from(!had_birthday)
+}
+
+// checks every new password has to pass, on signup and when it is changed. `user_inputs`
+// are the username and email the password shouldn't contain.
+pub(crate) async fn check_new_password(
+    keygate: &KeygateInternal,
+    password: &str,
+    settings: &GlobalSettings,
+    user_inputs: &[&str],
+) -> Result<(), APIError> {
+    let issues = settings.password_policy.check(password, user_inputs);
+    if !issues.is_empty() {
+        return Err(APIError::InvalidPassword(issues));
+    }
+
+    if settings.check_haveibeenpwned {
+        let checker = keygate.breached_passwords.load();
+        match checker.is_breached(password).await {
+            Ok(false) => {}
+            Ok(true) => return Err(APIError::InvalidPassword(vec![PasswordIssue::Breached])),
+            // an unreachable breach list shouldn't keep users from choosing a password
+            Err(e) => warn!("Failed to check for breached password: {:?}", e),
+        }
+    }
+
+    Ok(())
+}
+
+// the same for every login with the identifier, so failures of unknown identities add up to
+// delays and lockouts like those of real ones instead of telling them apart
+fn decoy_identity_id(username_or_email: &str) -> String {
+    keygate_utils::hash::token(&format!("decoy:{}", username_or_email.to_lowercase()))
+}
+
+pub(crate) fn user_inputs(identity: &Identity) -> Vec<&str> {
+    identity
+        .username
+        .iter()
+        .chain(identity.primary_email.iter())
+        .map(String::as_str)
+        .collect()
+}
+
+fn ensure_active(process: &LoginProcess) -> Result<(), APIError> {
+    if process.completed {
+        return Err(APIError::invalid_argument(
+            "Login process already completed",
+        ));
+    }
+
+    if process
+        .expires_at
+        .is_some_and(|expires_at| expires_at < time::OffsetDateTime::now_utc())
+    {
+        return Err(APIError::invalid_argument("Login process expired"));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{login::LoginMethods, test_keygate, KeygateConfig};
+
+    #[tokio::test]
+    async fn test_login_decoy() {
+        let keygate = test_keygate(KeygateConfig {
+            anti_enumeration: Some(true),
+            ..Default::default()
+        })
+        .await;
+        keygate.create_admin_app().await.unwrap();
+
+        let LoginResponse::NextStep {
+            step_type,
+            process_id,
+        } = keygate
+            .auth
+            .login_create("admin", "unknown@keygate.io", None, None)
+            .await
+            .expect("decoy process should be created")
+        else {
+            panic!("expected next step");
+        };
+
+        assert_eq!(step_type, vec![LoginStep::PASSWORD]);
+        assert!(keygate_utils::validate::is_valid_id(&process_id));
+        assert!(keygate
+            .auth
+            .login_status("admin", &process_id)
+            .await
+            .is_ok());
+        // other applications can't continue the process
+        assert!(matches!(
+            keygate.auth.login_status("other", &process_id).await,
+            Err(APIError::NotFound(_))
+        ));
+        assert!(matches!(
+            keygate
+                .auth
+                .login_start_step("other", &process_id, &LoginStep::PASSWORD)
+                .await,
+            Err(APIError::NotFound(_))
+        ));
+        assert!(matches!(
+            keygate
+                .auth
+                .login_send_email_code("other", &process_id)
+                .await,
+            Err(APIError::NotFound(_))
+        ));
+
+        let res = keygate
+            .auth
+            .login_step("admin", &process_id, LoginStep::PASSWORD, "password")
+            .await;
+        assert!(matches!(res, Err(APIError::InvalidArgument(msg)) if msg == "Invalid password"));
+
+        // failures add up like those of a real identity
+        let mut settings = keygate.inner.settings.global().await.unwrap();
+        settings.login_throttle.lockout_after = 3;
+        settings.login_throttle.delay_after = 10;
+        keygate
+            .inner
+            .settings
+            .update_global(settings)
+            .await
+            .unwrap();
+        for (identifier, expect_locked) in [
+            ("Unknown@keygate.io", false),
+            ("unknown@keygate.io", false),
+            ("unknown@keygate.io", true),
+        ] {
+            let LoginResponse::NextStep { process_id, .. } = keygate
+                .auth
+                .login_create("admin", identifier, None, None)
+                .await
+                .unwrap()
+            else {
+                panic!("expected next step");
+            };
+            let res = keygate
+                .auth
+                .login_step("admin", &process_id, LoginStep::PASSWORD, "password")
+                .await;
+            match expect_locked {
+                true => assert!(matches!(res, Err(APIError::PermissionDenied(_)))),
+                false => assert!(matches!(res, Err(APIError::InvalidArgument(_)))),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_login_unknown_identity() {
+        let keygate = test_keygate(KeygateConfig {
+            anti_enumeration: Some(false),
+            ..Default::default()
+        })
+        .await;
+        keygate.create_admin_app().await.unwrap();
+
+        let res = keygate
+            .auth
+            .login_create("admin", "unknown@keygate.io", None, None)
+            .await;
+        assert!(matches!(res, Err(APIError::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn test_trust_device_requires_completed_login() {
+        let keygate = test_keygate(KeygateConfig {
+            anti_enumeration: Some(true),
+            ..Default::default()
+        })
+        .await;
+        keygate.create_admin_app().await.unwrap();
+
+        let LoginResponse::NextStep { process_id, .. } = keygate
+            .auth
+            .login_create("admin", "unknown@keygate.io", None, None)
+            .await
+            .unwrap()
+        else {
+            panic!("expected next step");
+        };
+
+        let res = keygate
+            .auth
+            .login_trust_device("admin", "session", &process_id, "Firefox")
+            .await;
+        assert!(matches!(res, Err(APIError::InvalidArgument(_))));
+        let res = keygate
+            .auth
+            .login_trust_device("other", "session", &process_id, "Firefox")
+            .await;
+        assert!(matches!(res, Err(APIError::NotFound(_))));
+
+        let res = keygate
+            .auth
+            .login_trust_device("admin", "session", "unknown", "Firefox")
+            .await;
+        assert!(matches!(res, Err(APIError::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn test_trust_device() {
+        let keygate = test_keygate(KeygateConfig::default()).await;
+        keygate.create_admin_app().await.unwrap();
+        let mut app = keygate.inner.settings.app("admin").await.unwrap().unwrap();
+        app.second_factor = SecondFactorPolicy::Required;
+        keygate
+            .inner
+            .settings
+            .update_app("admin", app)
+            .await
+            .unwrap();
+        let password_hash = keygate_utils::hash::password("correct horse").unwrap();
+        let identity = keygate
+            .identity
+            .create(crate::api::identity::CreateIdentity {
+                username: Some("trusting"),
+                primary_email: Some("trusting@keygate.io"),
+                password_hash: Some(&password_hash),
+            })
+            .await
+            .unwrap();
+        let mut codes = keygate
+            .identity
+            .generate_recovery_codes(&identity.id)
+            .await
+            .unwrap();
+
+        // a login that passed the password and waits for the second factor
+        let password = |device_id: Option<&'static str>| {
+            let keygate = keygate.clone();
+            async move {
+                let LoginResponse::NextStep { process_id, .. } = keygate
+                    .auth
+                    .login_create("admin", "trusting", None, device_id)
+                    .await
+                    .unwrap()
+                else {
+                    panic!("expected next step");
+                };
+                let res = keygate
+                    .auth
+                    .login_step("admin", &process_id, LoginStep::PASSWORD, "correct horse")
+                    .await
+                    .unwrap();
+                let LoginResponse::NextStep { step_type, .. } = res else {
+                    panic!("expected second factor");
+                };
+                (process_id, step_type)
+            }
+        };
+        let session_of = |process_id: String| {
+            let keygate = keygate.clone();
+            async move {
+                sqlx::query_scalar!(
+                    "SELECT session_id FROM LoginProcess WHERE id = $1",
+                    process_id
+                )
+                .fetch_one(&keygate.inner.db)
+                .await
+                .unwrap()
+                .unwrap()
+            }
+        };
+
+        // a login without a device id can't be trusted
+        let (process_id, step_type) = password(None).await;
+        assert!(!step_type.contains(&LoginStep::TRUSTED_DEVICE));
+        let res = keygate
+            .auth
+            .login_step(
+                "admin",
+                &process_id,
+                LoginStep::RECOVERY_CODE,
+                &codes.pop().unwrap(),
+            )
+            .await
+            .unwrap();
+        assert!(matches!(res, LoginResponse::Success { .. }));
+        let session_id = session_of(process_id.clone()).await;
+        let res = keygate
+            .auth
+            .login_trust_device("admin", &session_id, &process_id, "Firefox")
+            .await;
+        assert!(matches!(res, Err(APIError::InvalidArgument(_))));
+
+        let (process_id, step_type) = password(Some("laptop")).await;
+        assert!(step_type.contains(&LoginStep::TRUSTED_DEVICE));
+        keygate
+            .auth
+            .login_step(
+                "admin",
+                &process_id,
+                LoginStep::RECOVERY_CODE,
+                &codes.pop().unwrap(),
+            )
+            .await
+            .unwrap();
+        let session_id = session_of(process_id.clone()).await;
+
+        // only the session created by the login can trust its device
+        let res = keygate
+            .auth
+            .login_trust_device("admin", "other-session", &process_id, "Firefox")
+            .await;
+        assert!(matches!(res, Err(APIError::PermissionDenied(_))));
+        let token = keygate
+            .auth
+            .login_trust_device("admin", &session_id, &process_id, "Firefox")
+            .await
+            .unwrap();
+        let res = keygate
+            .auth
+            .login_trust_device("admin", &session_id, &process_id, "Firefox")
+            .await;
+        assert!(matches!(res, Err(APIError::AlreadyExists(_))));
+
+        // the token skips the second factor on the same device
+        let (process_id, _) = password(Some("laptop")).await;
+        let res = keygate
+            .auth
+            .login_step("admin", &process_id, LoginStep::TRUSTED_DEVICE, &token)
+            .await
+            .unwrap();
+        assert!(matches!(res, LoginResponse::Success { .. }));
+
+        // a login trusted by a device token can't trust another device
+        let session_id = session_of(process_id.clone()).await;
+        let res = keygate
+            .auth
+            .login_trust_device("admin", &session_id, &process_id, "Firefox")
+            .await;
+        assert!(matches!(res, Err(APIError::PermissionDenied(_))));
+
+        // the token doesn't work from another device
+        let (process_id, _) = password(Some("phone")).await;
+        let res = keygate
+            .auth
+            .login_step("admin", &process_id, LoginStep::TRUSTED_DEVICE, &token)
+            .await;
+        assert!(matches!(res, Err(APIError::InvalidArgument(_))));
+
+        // expired tokens stop working
+        let devices = keygate
+            .identity
+            .trusted_devices(&identity.id)
+            .await
+            .unwrap();
+        assert_eq!(devices.len(), 1);
+        assert!(devices[0].last_used_at.is_some());
+        let expired = time::OffsetDateTime::now_utc() - time::Duration::minutes(1);
+        sqlx::query!(
+            "UPDATE TrustedDevice SET expires_at = $1 WHERE id = $2",
+            expired,
+            devices[0].id
+        )
+        .execute(&keygate.inner.db)
+        .await
+        .unwrap();
+        let (process_id, _) = password(Some("laptop")).await;
+        let res = keygate
+            .auth
+            .login_step("admin", &process_id, LoginStep::TRUSTED_DEVICE, &token)
+            .await;
+        assert!(matches!(res, Err(APIError::InvalidArgument(_))));
+
+        // and so do revoked ones
+        let expires_at = time::OffsetDateTime::now_utc() + time::Duration::days(1);
+        sqlx::query!(
+            "UPDATE TrustedDevice SET expires_at = $1 WHERE id = $2",
+            expires_at,
+            devices[0].id
+        )
+        .execute(&keygate.inner.db)
+        .await
+        .unwrap();
+        let res = keygate
+            .auth
+            .login_step("admin", &process_id, LoginStep::TRUSTED_DEVICE, &token)
+            .await
+            .unwrap();
+        assert!(matches!(res, LoginResponse::Success { .. }));
+        keygate
+            .identity
+            .revoke_trusted_device(&identity.id, &devices[0].id)
+            .await
+            .unwrap();
+        let (process_id, _) = password(Some("laptop")).await;
+        let res = keygate
+            .auth
+            .login_step("admin", &process_id, LoginStep::TRUSTED_DEVICE, &token)
+            .await;
+        assert!(matches!(res, Err(APIError::InvalidArgument(_))));
+    }
+
+    #[tokio::test]
+    async fn test_device_approval() {
+        let keygate = test_keygate(KeygateConfig::default()).await;
+        keygate.create_admin_app().await.unwrap();
+        let mut settings = keygate.inner.settings.global().await.unwrap();
+        settings.device_approval = true;
+        keygate
+            .inner
+            .settings
+            .update_global(settings)
+            .await
+            .unwrap();
+        let password_hash = keygate_utils::hash::password("correct horse").unwrap();
+        let identity = keygate
+            .identity
+            .create(crate::api::identity::CreateIdentity {
+                username: Some("approver"),
+                primary_email: Some("approver@keygate.io"),
+                password_hash: Some(&password_hash),
+            })
+            .await
+            .unwrap();
+
+        let login = |device_id: &'static str| {
+            let keygate = keygate.clone();
+            async move {
+                let LoginResponse::NextStep { process_id, .. } = keygate
+                    .auth
+                    .login_create("admin", "approver", None, Some(device_id))
+                    .await
+                    .unwrap()
+                else {
+                    panic!("expected next step");
+                };
+                let res = keygate
+                    .auth
+                    .login_step("admin", &process_id, LoginStep::PASSWORD, "correct horse")
+                    .await
+                    .unwrap();
+                (process_id, res)
+            }
+        };
+        let poll = |process_id: String| {
+            let keygate = keygate.clone();
+            async move {
+                keygate
+                    .auth
+                    .login_step("admin", &process_id, LoginStep::DEVICE_APPROVAL, "")
+                    .await
+            }
+        };
+
+        // the first device has nothing to be approved by
+        let (_, res) = login("laptop").await;
+        assert!(matches!(res, LoginResponse::Success { .. }));
+        let (_, res) = login("laptop").await;
+        assert!(matches!(res, LoginResponse::Success { .. }));
+
+        let (process_id, res) = login("phone").await;
+        let LoginResponse::NextStep { step_type, .. } = res else {
+            panic!("expected device approval");
+        };
+        assert_eq!(step_type, vec![LoginStep::DEVICE_APPROVAL]);
+        let status = keygate
+            .auth
+            .login_status("admin", &process_id)
+            .await
+            .unwrap();
+        assert_eq!(status.current_step, "device_approval");
+        assert_eq!(status.device_approval.as_deref(), Some("pending"));
+        let pending = keygate
+            .auth
+            .login_pending_approvals(&identity.id)
+            .await
+            .unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].process_id, process_id);
+
+        // waiting doesn't complete the login
+        let res = poll(process_id.clone()).await.unwrap();
+        assert!(matches!(res, LoginResponse::NextStep { .. }));
+
+        // only the identity itself can approve
+        let res = keygate
+            .auth
+            .login_approve_device(&process_id, "someone-else", true)
+            .await;
+        assert!(matches!(res, Err(APIError::NotFound(_))));
+
+        keygate
+            .auth
+            .login_approve_device(&process_id, &identity.id, true)
+            .await
+            .unwrap();
+        let status = keygate
+            .auth
+            .login_status("admin", &process_id)
+            .await
+            .unwrap();
+        assert_eq!(status.device_approval.as_deref(), Some("approved"));
+        let res = keygate
+            .auth
+            .login_approve_device(&process_id, &identity.id, false)
+            .await;
+        assert!(matches!(res, Err(APIError::InvalidArgument(_))));
+        assert!(keygate
+            .auth
+            .login_pending_approvals(&identity.id)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // approving completed the login, the device collects the tokens only once
+        let res = poll(process_id.clone()).await.unwrap();
+        let LoginResponse::Success { access_token, .. } = res else {
+            panic!("expected tokens");
+        };
+        let access_token = access_token
+            .strip_prefix(super::super::session::ACCESS_TOKEN_PREFIX)
+            .unwrap();
+        let token = keygate
+            .auth
+            .verify_access_token(access_token)
+            .await
+            .unwrap();
+        assert_eq!(token.subject, identity.id);
+        let res = poll(process_id.clone()).await;
+        assert!(matches!(res, Err(APIError::InvalidArgument(_))));
+        let status = keygate
+            .auth
+            .login_status("admin", &process_id)
+            .await
+            .unwrap();
+        assert_eq!(status.device_approval.as_deref(), Some("collected"));
+
+        // the approved device is known from now on
+        let (_, res) = login("phone").await;
+        assert!(matches!(res, LoginResponse::Success { .. }));
+
+        // a denied login can't be completed
+        let (process_id, _) = login("tablet").await;
+        keygate
+            .auth
+            .login_approve_device(&process_id, &identity.id, false)
+            .await
+            .unwrap();
+        let status = keygate
+            .auth
+            .login_status("admin", &process_id)
+            .await
+            .unwrap();
+        assert_eq!(status.device_approval.as_deref(), Some("denied"));
+        let res = poll(process_id.clone()).await;
+        assert!(matches!(res, Err(APIError::PermissionDenied(_))));
+        let (_, res) = login("tablet").await;
+        assert!(matches!(res, LoginResponse::NextStep { .. }));
+
+        let audit = keygate.audit.list(&identity.id, 0, 100).await.unwrap();
+        let actions = audit
+            .iter()
+            .map(|entry| entry.action.as_str())
+            .collect::<Vec<_>>();
+        assert!(actions.contains(&"device_approved"));
+        assert!(actions.contains(&"device_denied"));
+    }
+
+    #[tokio::test]
+    async fn test_login_recovery_code() {
+        let keygate = test_keygate(KeygateConfig::default()).await;
+        keygate.create_admin_app().await.unwrap();
+        let mut settings = keygate.inner.settings.global().await.unwrap();
+        settings.login_throttle.delay_after = 100;
+        keygate
+            .inner
+            .settings
+            .update_global(settings)
+            .await
+            .unwrap();
+        let mut app = keygate.inner.settings.app("admin").await.unwrap().unwrap();
+        app.second_factor = SecondFactorPolicy::Required;
+        keygate
+            .inner
+            .settings
+            .update_app("admin", app)
+            .await
+            .unwrap();
+        let password_hash = keygate_utils::hash::password("correct horse").unwrap();
+        let identity = keygate
+            .identity
+            .create(crate::api::identity::CreateIdentity {
+                username: Some("recovering"),
+                primary_email: Some("recovering@keygate.io"),
+                password_hash: Some(&password_hash),
+            })
+            .await
+            .unwrap();
+        let codes = keygate
+            .identity
+            .generate_recovery_codes(&identity.id)
+            .await
+            .unwrap();
+
+        // a login that passed the password and continues with a recovery code
+        let recovery_code = |code: String| {
+            let keygate = keygate.clone();
+            async move {
+                let LoginResponse::NextStep {
+                    process_id,
+                    step_type,
+                } = password_login(&keygate, "recovering", "correct horse")
+                    .await
+                    .unwrap()
+                else {
+                    panic!("expected second factor");
+                };
+                assert!(step_type.contains(&LoginStep::RECOVERY_CODE));
+                keygate
+                    .auth
+                    .login_step("admin", &process_id, LoginStep::RECOVERY_CODE, &code)
+                    .await
+            }
+        };
+
+        let res = recovery_code("wrong-code".into()).await;
+        assert!(matches!(res, Err(APIError::InvalidArgument(_))));
+
+        let res = recovery_code(codes[0].clone()).await.unwrap();
+        assert!(matches!(res, LoginResponse::Success { .. }));
+        // codes can only be used once
+        let res = recovery_code(codes[0].clone()).await;
+        assert!(matches!(res, Err(APIError::InvalidArgument(_))));
+        // dashes and case don't matter
+        let res = recovery_code(codes[1].replace('-', "").to_uppercase())
+            .await
+            .unwrap();
+        assert!(matches!(res, LoginResponse::Success { .. }));
+
+        // new codes replace all old ones
+        let new_codes = keygate
+            .identity
+            .generate_recovery_codes(&identity.id)
+            .await
+            .unwrap();
+        let res = recovery_code(codes[2].clone()).await;
+        assert!(matches!(res, Err(APIError::InvalidArgument(_))));
+        let res = recovery_code(new_codes[0].clone()).await.unwrap();
+        assert!(matches!(res, LoginResponse::Success { .. }));
+
+        let audit = keygate.audit.list(&identity.id, 0, 100).await.unwrap();
+        let used = audit
+            .iter()
+            .filter(|entry| entry.action == "recovery_code_used")
+            .count();
+        assert_eq!(used, 3);
+    }
+
+    #[tokio::test]
+    async fn test_login_throttle_second_factor() {
+        let keygate = test_keygate(KeygateConfig::default()).await;
+        keygate.create_admin_app().await.unwrap();
+        let mut settings = keygate.inner.settings.global().await.unwrap();
+        settings.login_throttle.lockout_after = 3;
+        settings.login_throttle.delay_after = 10;
+        keygate
+            .inner
+            .settings
+            .update_global(settings)
+            .await
+            .unwrap();
+        let mut app = keygate.inner.settings.app("admin").await.unwrap().unwrap();
+        app.second_factor = SecondFactorPolicy::Required;
+        keygate
+            .inner
+            .settings
+            .update_app("admin", app)
+            .await
+            .unwrap();
+        let password_hash = keygate_utils::hash::password("correct horse").unwrap();
+        let identity = keygate
+            .identity
+            .create(crate::api::identity::CreateIdentity {
+                username: Some("guarded"),
+                primary_email: Some("guarded@keygate.io"),
+                password_hash: Some(&password_hash),
+            })
+            .await
+            .unwrap();
+        let codes = keygate
+            .identity
+            .generate_recovery_codes(&identity.id)
+            .await
+            .unwrap();
+
+        // a login waiting for the second factor
+        let second_factor = || {
+            let keygate = keygate.clone();
+            async move {
+                let LoginResponse::NextStep { process_id, .. } = keygate
+                    .auth
+                    .login_create("admin", "guarded", None, None)
+                    .await
+                    .unwrap()
+                else {
+                    panic!("expected next step");
+                };
+                let res = keygate
+                    .auth
+                    .login_step("admin", &process_id, LoginStep::PASSWORD, "correct horse")
+                    .await
+                    .unwrap();
+                let LoginResponse::NextStep { step_type, .. } = res else {
+                    panic!("expected second factor");
+                };
+                assert!(step_type.contains(&LoginStep::RECOVERY_CODE));
+                process_id
+            }
+        };
+        let recovery_code = |process_id: String, code: String| {
+            let keygate = keygate.clone();
+            async move {
+                keygate
+                    .auth
+                    .login_step("admin", &process_id, LoginStep::RECOVERY_CODE, &code)
+                    .await
+            }
+        };
+
+        let process_id = second_factor().await;
+        for _ in 0..2 {
+            let res = recovery_code(process_id.clone(), "wrong".into()).await;
+            assert!(matches!(res, Err(APIError::InvalidArgument(_))));
+        }
+
+        // the correct password doesn't clear the failures of the second factor
+        let process_id = second_factor().await;
+        let res = recovery_code(process_id.clone(), "wrong".into()).await;
+        assert!(matches!(res, Err(APIError::InvalidArgument(_))));
+        let res = recovery_code(process_id.clone(), codes[0].clone()).await;
+        assert!(matches!(res, Err(APIError::PermissionDenied(_))));
+
+        let entries = keygate.audit.list(&identity.id, 0, 100).await.unwrap();
+        let failures = entries
+            .iter()
+            .filter(|entry| entry.action == AuditAction::LoginFailed.as_str_name())
+            .count();
+        assert_eq!(failures, 3);
+
+        keygate.auth.unlock(&identity.id).await.unwrap();
+        let entries = keygate.audit.list(&identity.id, 0, 1).await.unwrap();
+        assert_eq!(
+            entries[0].action,
+            AuditAction::IdentityUnlocked.as_str_name()
+        );
+        let res = recovery_code(process_id, codes[0].clone()).await;
+        assert!(matches!(res, Ok(LoginResponse::Success { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_reauthenticate_session() {
+        let keygate = test_keygate(KeygateConfig::default()).await;
+        keygate.create_admin_app().await.unwrap();
+        let password_hash = keygate_utils::hash::password("correct horse").unwrap();
+        let identity = keygate
+            .identity
+            .create(crate::api::identity::CreateIdentity {
+                username: Some("stepup"),
+                primary_email: Some("stepup@keygate.io"),
+                password_hash: Some(&password_hash),
+            })
+            .await
+            .unwrap();
+
+        let earlier = time::OffsetDateTime::now_utc() - time::Duration::hours(1);
+        let session_id = keygate
+            .session
+            .start(
+                &identity.id,
+                None,
+                &AuthContext::from_methods(
+                    &[LoginMethods::default().get(&LoginStep::PASSWORD).unwrap()],
+                    earlier,
+                ),
+            )
+            .await
+            .unwrap();
+
+        let res = keygate
+            .session
+            .require_auth(&session_id, time::Duration::minutes(5), false)
+            .await;
+        assert!(matches!(res, Err(APIError::Unauthenticated(_))));
+
+        // without an enrolled second factor the session cannot be raised
+        let res = keygate.auth.reauth_create("admin", &session_id, true).await;
+        assert!(matches!(res, Err(APIError::PermissionDenied(_))));
+
+        let LoginResponse::NextStep {
+            step_type,
+            process_id,
+        } = keygate
+            .auth
+            .reauth_create("admin", &session_id, false)
+            .await
+            .unwrap()
+        else {
+            panic!("expected next step");
+        };
+        assert_eq!(step_type, vec![LoginStep::PASSWORD]);
+
+        let res = keygate
+            .auth
+            .login_step("admin", &process_id, LoginStep::PASSWORD, "correct horse")
+            .await
+            .unwrap();
+        assert!(matches!(res, LoginResponse::Success { .. }));
+
+        let context = keygate
+            .session
+            .require_auth(&session_id, time::Duration::minutes(5), false)
+            .await
+            .unwrap();
+        assert_eq!(context.amr, vec!["pwd"]);
+
+        let res = keygate
+            .session
+            .require_auth(&session_id, time::Duration::minutes(5), true)
+            .await;
+        assert!(matches!(res, Err(APIError::Unauthenticated(_))));
+
+        // recovery codes can be used as the second factor
+        let codes = keygate
+            .identity
+            .generate_recovery_codes(&identity.id)
+            .await
+            .unwrap();
+        let LoginResponse::NextStep { process_id, .. } = keygate
+            .auth
+            .reauth_create("admin", &session_id, true)
+            .await
+            .unwrap()
+        else {
+            panic!("expected next step");
+        };
+        let LoginResponse::NextStep { step_type, .. } = keygate
+            .auth
+            .login_step("admin", &process_id, LoginStep::PASSWORD, "correct horse")
+            .await
+            .unwrap()
+        else {
+            panic!("expected second factor");
+        };
+        assert_eq!(step_type, vec![LoginStep::RECOVERY_CODE]);
+        keygate
+            .auth
+            .login_step("admin", &process_id, LoginStep::RECOVERY_CODE, &codes[0])
+            .await
+            .unwrap();
+
+        let context = keygate
+            .session
+            .require_auth(&session_id, time::Duration::minutes(5), true)
+            .await
+            .unwrap();
+        assert_eq!(context.amr, vec!["pwd", "otp", "mfa"]);
+    }
+
+    #[tokio::test]
+    async fn test_application_login_policy() {
+        let keygate = test_keygate(KeygateConfig::default()).await;
+        keygate.create_admin_app().await.unwrap();
+        let password_hash = keygate_utils::hash::password("correct horse").unwrap();
+        let identity = keygate
+            .identity
+            .create(crate::api::identity::CreateIdentity {
+                username: Some("policy"),
+                primary_email: Some("policy@keygate.io"),
+                password_hash: Some(&password_hash),
+            })
+            .await
+            .unwrap();
+        keygate
+            .identity
+            .generate_recovery_codes(&identity.id)
+            .await
+            .unwrap();
+        let session_id = keygate
+            .session
+            .start(
+                &identity.id,
+                None,
+                &AuthContext::from_methods(
+                    &[LoginMethods::default().get(&LoginStep::PASSWORD).unwrap()],
+                    time::OffsetDateTime::now_utc(),
+                ),
+            )
+            .await
+            .unwrap();
+
+        let mut app = keygate.inner.settings.app("admin").await.unwrap().unwrap();
+        let set_policy = |app: ApplicationSettings| async {
+            keygate
+                .inner
+                .settings
+                .update_app("admin", app)
+                .await
+                .unwrap();
+        };
+
+        // only allowed first factors are offered
+        app.allowed_first_factors = Some(vec![]);
+        set_policy(app.clone()).await;
+        let res = keygate
+            .auth
+            .reauth_create("admin", &session_id, false)
+            .await;
+        assert!(matches!(res, Err(APIError::PermissionDenied(_))));
+
+        let res = keygate
+            .auth
+            .reauth_create("unknown", &session_id, false)
+            .await;
+        assert!(matches!(res, Err(APIError::NotFound(_))));
+
+        // forbidden skips the enrolled second factor
+        app.allowed_first_factors = Some(vec![LoginStep::PASSWORD.as_str_name().to_string()]);
+        app.second_factor = SecondFactorPolicy::Forbidden;
+        set_policy(app.clone()).await;
+        let LoginResponse::NextStep { process_id, .. } = keygate
+            .auth
+            .reauth_create("admin", &session_id, false)
+            .await
+            .unwrap()
+        else {
+            panic!("expected next step");
+        };
+        let res = keygate
+            .auth
+            .login_step("other", &process_id, LoginStep::PASSWORD, "correct horse")
+            .await;
+        assert!(matches!(res, Err(APIError::NotFound(_))));
+        let res = keygate
+            .auth
+            .login_step("admin", &process_id, LoginStep::PASSWORD, "correct horse")
+            .await
+            .unwrap();
+        assert!(matches!(res, LoginResponse::Success { .. }));
+
+        // required asks for the second factor
+        app.second_factor = SecondFactorPolicy::Required;
+        set_policy(app.clone()).await;
+        let LoginResponse::NextStep { process_id, .. } = keygate
+            .auth
+            .reauth_create("admin", &session_id, false)
+            .await
+            .unwrap()
+        else {
+            panic!("expected next step");
+        };
+        let LoginResponse::NextStep { step_type, .. } = keygate
+            .auth
+            .login_step("admin", &process_id, LoginStep::PASSWORD, "correct horse")
+            .await
+            .unwrap()
+        else {
+            panic!("expected second factor");
+        };
+        assert_eq!(step_type, vec![LoginStep::RECOVERY_CODE]);
+    }
+
+    #[tokio::test]
+    async fn test_login_identifier_and_email_verification() {
+        let keygate = test_keygate(KeygateConfig {
+            anti_enumeration: Some(false),
+            ..Default::default()
+        })
+        .await;
+        keygate.create_admin_app().await.unwrap();
+        let password_hash = keygate_utils::hash::password("correct horse").unwrap();
+        keygate
+            .identity
+            .create(crate::api::identity::CreateIdentity {
+                username: Some("MixedCase"),
+                primary_email: Some("Mixed@Keygate.io"),
+                password_hash: Some(&password_hash),
+            })
+            .await
+            .unwrap();
+
+        let mut settings = keygate.inner.settings.global().await.unwrap();
+        settings.login_identifier = AccountNaming::Email;
+        settings.email_verification = EmailVerification::RequiredForLogin {
+            token_expires_in: time::Duration::hours(1),
+        };
+        keygate
+            .inner
+            .settings
+            .update_global(settings)
+            .await
+            .unwrap();
+
+        let res = keygate
+            .auth
+            .login_create("admin", "mixedcase", None, None)
+            .await;
+        assert!(matches!(res, Err(APIError::InvalidArgument(_))));
+
+        let LoginResponse::NextStep {
+            step_type,
+            process_id,
+        } = keygate
+            .auth
+            .login_create("admin", "mixed@keygate.io", None, None)
+            .await
+            .unwrap()
+        else {
+            panic!("expected next step");
+        };
+        assert_eq!(step_type, vec![LoginStep::PASSWORD]);
+
+        let res = keygate
+            .auth
+            .login_step("admin", &process_id, LoginStep::PASSWORD, "correct horse")
+            .await
+            .unwrap();
+        assert!(matches!(
+            res,
+            LoginResponse::EmailVerificationPending { .. }
+        ));
+
+        let res = keygate
+            .auth
+            .login_step("admin", &process_id, LoginStep::EMAIL_VERIFICATION, "")
+            .await
+            .unwrap();
+        assert!(matches!(
+            res,
+            LoginResponse::EmailVerificationPending { .. }
+        ));
+
+        sqlx::query!("UPDATE Email SET verified = true")
+            .execute(&keygate.inner.db)
+            .await
+            .unwrap();
+        let res = keygate
+            .auth
+            .login_step("admin", &process_id, LoginStep::EMAIL_VERIFICATION, "")
+            .await
+            .unwrap();
+        assert!(matches!(res, LoginResponse::Success { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_login_email_code() {
+        let keygate = test_keygate(KeygateConfig::default()).await;
+        keygate.create_admin_app().await.unwrap();
+        let mailer = crate::mail::TestMailer::default();
+        keygate.set_mailer(mailer.clone());
+        let mut settings = keygate.inner.settings.global().await.unwrap();
+        settings.email_code = Some(crate::database::models::EmailCodeSettings {
+            code_length: 6,
+            token_expires_in: time::Duration::minutes(5),
+            max_attempts: 3,
+            resend_cooldown: time::Duration::ZERO,
+        });
+        settings.login_throttle.delay_after = 100;
+        settings.login_throttle.lockout_after = 100;
+        keygate
+            .inner
+            .settings
+            .update_global(settings)
+            .await
+            .unwrap();
+        let password_hash = keygate_utils::hash::password("correct horse").unwrap();
+        keygate
+            .identity
+            .create(crate::api::identity::CreateIdentity {
+                username: Some("coder"),
+                primary_email: Some("coder@keygate.io"),
+                password_hash: Some(&password_hash),
+            })
+            .await
+            .unwrap();
+        sqlx::query!("UPDATE Email SET verified = true")
+            .execute(&keygate.inner.db)
+            .await
+            .unwrap();
+
+        // a login that was sent a code
+        let send_code = || {
+            let keygate = keygate.clone();
+            let mailer = mailer.clone();
+            async move {
+                let LoginResponse::NextStep {
+                    process_id,
+                    step_type,
+                } = keygate
+                    .auth
+                    .login_create("admin", "coder", None, None)
+                    .await
+                    .unwrap()
+                else {
+                    panic!("expected next step");
+                };
+                assert!(step_type.contains(&LoginStep::EMAIL_CODE));
+                keygate
+                    .auth
+                    .login_send_email_code("admin", &process_id)
+                    .await
+                    .unwrap();
+                let sent = mailer.0.lock().unwrap().pop().unwrap();
+                assert_eq!(sent.to, "coder@keygate.io");
+                let code = sent
+                    .body
+                    .split_whitespace()
+                    .nth(4)
+                    .unwrap()
+                    .trim_end_matches('.')
+                    .to_string();
+                (process_id, code)
+            }
+        };
+        let step = |process_id: String, code: String| {
+            let keygate = keygate.clone();
+            async move {
+                keygate
+                    .auth
+                    .login_step("admin", &process_id, LoginStep::EMAIL_CODE, &code)
+                    .await
+            }
+        };
+
+        // a code has to be requested first
+        let LoginResponse::NextStep { process_id, .. } = keygate
+            .auth
+            .login_create("admin", "coder", None, None)
+            .await
+            .unwrap()
+        else {
+            panic!("expected next step");
+        };
+        let res = step(process_id, "123456".into()).await;
+        assert!(matches!(res, Err(APIError::InvalidArgument(_))));
+
+        let (process_id, code) = send_code().await;
+        assert_eq!(code.len(), 6);
+        let res = step(process_id.clone(), "wrong".into()).await;
+        assert!(matches!(res, Err(APIError::InvalidArgument(_))));
+        let res = step(process_id.clone(), code.clone()).await.unwrap();
+        assert!(matches!(res, LoginResponse::Success { .. }));
+        let res = step(process_id, code).await;
+        assert!(matches!(res, Err(APIError::InvalidArgument(_))));
+
+        // the code stops working after too many wrong attempts
+        let (process_id, code) = send_code().await;
+        for _ in 0..3 {
+            let res = step(process_id.clone(), "wrong".into()).await;
+            assert!(matches!(res, Err(APIError::InvalidArgument(_))));
+        }
+        let res = step(process_id.clone(), code).await;
+        assert!(matches!(res, Err(APIError::PermissionDenied(_))));
+
+        // also for concurrent attempts
+        let (process_id, code) = send_code().await;
+        let attempts = (0..6)
+            .map(|_| tokio::spawn(step(process_id.clone(), "wrong".into())))
+            .collect::<Vec<_>>();
+        let mut denied = 0;
+        for attempt in attempts {
+            match attempt.await.unwrap() {
+                Err(APIError::PermissionDenied(_)) => denied += 1,
+                Err(APIError::InvalidArgument(_)) => {}
+                res => panic!("unexpected result: {:?}", res.is_ok()),
+            }
+        }
+        assert_eq!(denied, 3);
+        let res = step(process_id.clone(), code).await;
+        assert!(matches!(res, Err(APIError::PermissionDenied(_))));
+
+        // expired codes are rejected
+        let (process_id, code) = send_code().await;
+        let expired = time::OffsetDateTime::now_utc() - time::Duration::minutes(1);
+        sqlx::query!(
+            "UPDATE LoginProcess SET email_code_expires_at = $1 WHERE id = $2",
+            expired,
+            process_id
+        )
+        .execute(&keygate.inner.db)
+        .await
+        .unwrap();
+        let res = step(process_id, code).await;
+        assert!(matches!(res, Err(APIError::InvalidArgument(_))));
+    }
+
+    #[tokio::test]
+    async fn test_password_reset() {
+        let keygate = test_keygate(KeygateConfig::default()).await;
+        let mailer = crate::mail::TestMailer::default();
+        keygate.set_mailer(mailer.clone());
+        let password_hash = keygate_utils::hash::password("correct horse").unwrap();
+        let identity = keygate
+            .identity
+            .create(crate::api::identity::CreateIdentity {
+                username: Some("forgetful"),
+                primary_email: Some("forgetful@keygate.io"),
+                password_hash: Some(&password_hash),
+            })
+            .await
+            .unwrap();
+        let session_id = keygate
+            .session
+            .start(
+                &identity.id,
+                None,
+                &AuthContext::from_methods(
+                    &[LoginMethods::default().get(&LoginStep::PASSWORD).unwrap()],
+                    time::OffsetDateTime::now_utc(),
+                ),
+            )
+            .await
+            .unwrap();
+
+        // unknown and unverified emails look the same to the caller
+        keygate
+            .auth
+            .password_reset_request("unknown@keygate.io")
+            .await
+            .unwrap();
+        keygate
+            .auth
+            .password_reset_request("forgetful@keygate.io")
+            .await
+            .unwrap();
+        assert!(mailer.0.lock().unwrap().is_empty());
+
+        sqlx::query!("UPDATE Email SET verified = true")
+            .execute(&keygate.inner.db)
+            .await
+            .unwrap();
+        // within the cooldown no second email is sent, also for parallel requests
+        let requests = [
+            "Forgetful@keygate.io",
+            "forgetful@keygate.io",
+            "forgetful@keygate.io",
+        ]
+        .map(|email| {
+            let keygate = keygate.clone();
+            tokio::spawn(async move { keygate.auth.password_reset_request(email).await })
+        });
+        for request in requests {
+            request.await.unwrap().unwrap();
+        }
+        keygate
+            .auth
+            .password_reset_request("forgetful@keygate.io")
+            .await
+            .unwrap();
+        let token = {
+            let sent = mailer.0.lock().unwrap();
+            assert_eq!(sent.len(), 1);
+            let body = &sent[0].body;
+            let start = body.find(": ").unwrap() + 2;
+            body[start..start + 32].to_string()
+        };
+
+        // the token is checked before the password
+        let res = keygate.auth.password_reset_confirm("wrong", "short").await;
+        assert!(matches!(res, Err(APIError::InvalidArgument(_))));
+        // a rejected password doesn't use up the token
+        let res = keygate.auth.password_reset_confirm(&token, "short").await;
+        assert!(matches!(res, Err(APIError::InvalidPassword(_))));
+        // the password can't contain the username of the identity the token belongs to
+        let res = keygate
+            .auth
+            .password_reset_confirm(&token, "forgetful no more")
+            .await;
+        assert!(
+            matches!(res, Err(APIError::InvalidPassword(reasons)) if reasons == vec![PasswordIssue::ContainsUserInfo])
+        );
+        let res = keygate
+            .auth
+            .password_reset_confirm("wrong", "battery staple")
+            .await;
+        assert!(matches!(res, Err(APIError::InvalidArgument(_))));
+
+        keygate
+            .auth
+            .password_reset_confirm(&token, "battery staple")
+            .await
+            .unwrap();
+        let res = keygate
+            .auth
+            .password_reset_confirm(&token, "battery staple")
+            .await;
+        assert!(matches!(res, Err(APIError::InvalidArgument(_))));
+
+        let identity = keygate
+            .auth
+            .load_identity(&identity.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(
+            keygate_utils::hash::verify("battery staple", &identity.password_hash.unwrap())
+                .unwrap()
+        );
+        let res = keygate.session.auth_context(&session_id).await;
+        assert!(matches!(res, Err(APIError::NotFound(_))));
+        assert_eq!(mailer.0.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_login_must_change_password() {
+        let keygate = test_keygate(KeygateConfig::default()).await;
+        keygate.create_admin_app().await.unwrap();
+        let password_hash = keygate_utils::hash::password("placeholder").unwrap();
+        let identity = keygate
+            .identity
+            .create(crate::api::identity::CreateIdentity {
+                username: Some("temporary"),
+                primary_email: Some("temporary@keygate.io"),
+                password_hash: Some(&password_hash),
+            })
+            .await
+            .unwrap();
+        keygate
+            .identity
+            .set_password(&identity.id, Some("provisional secret"), true)
+            .await
+            .unwrap();
+
+        let LoginResponse::NextStep { process_id, .. } = keygate
+            .auth
+            .login_create("admin", "temporary", None, None)
+            .await
+            .unwrap()
+        else {
+            panic!("expected next step");
+        };
+        let LoginResponse::NextStep { step_type, .. } = keygate
+            .auth
+            .login_step(
+                "admin",
+                &process_id,
+                LoginStep::PASSWORD,
+                "provisional secret",
+            )
+            .await
+            .unwrap()
+        else {
+            panic!("expected password change");
+        };
+        assert_eq!(step_type, vec![LoginStep::CHANGE_PASSWORD]);
+
+        let res = keygate
+            .auth
+            .login_step("admin", &process_id, LoginStep::CHANGE_PASSWORD, "short")
+            .await;
+        assert!(matches!(res, Err(APIError::InvalidPassword(_))));
+        let res = keygate
+            .auth
+            .login_step(
+                "admin",
+                &process_id,
+                LoginStep::CHANGE_PASSWORD,
+                "my own password",
+            )
+            .await
+            .unwrap();
+        assert!(matches!(res, LoginResponse::Success { .. }));
+
+        let identity = keygate
+            .auth
+            .load_identity(&identity.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!identity.must_change_password);
+        assert!(
+            keygate_utils::hash::verify("my own password", &identity.password_hash.unwrap())
+                .unwrap()
+        );
+    }
+
+    // accepts `<anything>:legacy password`, counting the calls
+    #[derive(Debug, Default, Clone)]
+    struct StubLegacyStore(Arc<std::sync::atomic::AtomicUsize>);
+
+    impl crate::legacy::LegacyUserStore for StubLegacyStore {
+        fn verify<'a>(
+            &'a self,
+            username_or_email: &'a str,
+            password: &'a str,
+        ) -> crate::login::BoxFuture<'a, Result<Option<crate::legacy::LegacyUser>, APIError>>
+        {
+            Box::pin(async move {
+                self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                Ok(
+                    (password == "legacy password").then(|| crate::legacy::LegacyUser {
+                        username: Some("migrated".into()),
+                        email: None,
+                        email_verified: true,
+                    }),
+                )
+            })
+        }
+    }
+
+    async fn password_login(
+        keygate: &crate::Keygate,
+        username_or_email: &str,
+        password: &str,
+    ) -> Result<LoginResponse, APIError> {
+        let LoginResponse::NextStep { process_id, .. } = keygate
+            .auth
+            .login_create("admin", username_or_email, None, None)
+            .await?
+        else {
+            panic!("expected next step");
+        };
+        keygate
+            .auth
+            .login_step("admin", &process_id, LoginStep::PASSWORD, password)
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_login_tokens() {
+        let keygate = test_keygate(KeygateConfig::default()).await;
+        keygate.create_admin_app().await.unwrap();
+        let password_hash = keygate_utils::hash::password("correct horse").unwrap();
+        let identity = keygate
+            .identity
+            .create(crate::api::identity::CreateIdentity {
+                username: Some("jane"),
+                primary_email: Some("jane@keygate.io"),
+                password_hash: Some(&password_hash),
+            })
+            .await
+            .unwrap();
+
+        let LoginResponse::Success {
+            access_token,
+            refresh_token,
+        } = password_login(&keygate, "jane", "correct horse")
+            .await
+            .unwrap()
+        else {
+            panic!("expected success");
+        };
+        let access_token = access_token
+            .strip_prefix(super::super::session::ACCESS_TOKEN_PREFIX)
+            .unwrap();
+        let refresh_token = refresh_token
+            .strip_prefix(super::super::session::REFRESH_TOKEN_PREFIX)
+            .unwrap();
+
+        // the claims carry how the session authenticated
+        let token = keygate
+            .auth
+            .verify_access_token(access_token)
+            .await
+            .unwrap();
+        assert_eq!(token.subject, identity.id);
+        assert_eq!(token.audience, "admin");
+        assert_eq!(token.amr, vec!["pwd"]);
+        assert_eq!(token.acr, super::super::session::ACR_SINGLE_FACTOR);
+        assert!(token.auth_time > time::OffsetDateTime::now_utc().unix_timestamp() - 60);
+        keygate
+            .session
+            .require_recent_auth(&token.session_id, false)
+            .await
+            .unwrap();
+
+        let refresh = keygate
+            .auth
+            .verify_refresh_token(refresh_token)
+            .await
+            .unwrap();
+        assert_eq!(refresh.session_id, token.session_id);
+        assert!(keygate
+            .auth
+            .verify_refresh_token(access_token)
+            .await
+            .is_err());
+        assert!(keygate
+            .auth
+            .verify_access_token(refresh_token)
+            .await
+            .is_err());
+
+        let mut tampered = access_token.to_string();
+        tampered.insert(20, 'x');
+        assert!(keygate.auth.verify_access_token(&tampered).await.is_err());
+
+        // revoked sessions end their tokens
+        keygate
+            .session
+            .revoke_all(&identity.id, None)
+            .await
+            .unwrap();
+        assert!(matches!(
+            keygate.auth.verify_access_token(access_token).await,
+            Err(APIError::Unauthenticated(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_legacy_user_migration() {
+        let keygate = test_keygate(KeygateConfig::default()).await;
+        keygate.create_admin_app().await.unwrap();
+        let store = StubLegacyStore::default();
+        keygate.set_legacy_user_store(store.clone());
+
+        let res = password_login(&keygate, "migrated@keygate.io", "wrong").await;
+        assert!(matches!(res, Err(APIError::InvalidArgument(_))));
+        assert!(!keygate.identity.exists("migrated").await.unwrap());
+
+        let res = password_login(&keygate, "migrated@keygate.io", "legacy password").await;
+        assert!(matches!(res, Ok(LoginResponse::Success { .. })));
+        assert_eq!(store.0.load(std::sync::atomic::Ordering::SeqCst), 2);
+
+        let identity = sqlx::query_as!(
+            Identity,
+            "SELECT * FROM Identity WHERE username = 'migrated'"
+        )
+        .fetch_one(&keygate.inner.db)
+        .await
+        .unwrap();
+        assert_eq!(
+            identity.primary_email.as_deref(),
+            Some("migrated@keygate.io")
+        );
+        assert!(identity.password_hash.unwrap().starts_with("$argon2id$"));
+        assert!(verified_email(&keygate.inner, &identity.id)
+            .await
+            .unwrap()
+            .is_some());
+
+        // migrated identities no longer reach the legacy store
+        let res = password_login(&keygate, "migrated", "legacy password").await;
+        assert!(matches!(res, Ok(LoginResponse::Success { .. })));
+        assert_eq!(store.0.load(std::sync::atomic::Ordering::SeqCst), 2);
+
+        // identities flagged as legacy keep their id
+        let imported_hash = keygate_utils::hash::password("unknown").unwrap();
+        let legacy = keygate
+            .identity
+            .create(crate::api::identity::CreateIdentity {
+                username: Some("flagged"),
+                primary_email: Some("flagged@keygate.io"),
+                password_hash: Some(&imported_hash),
+            })
+            .await
+            .unwrap();
+        sqlx::query!("UPDATE Identity SET legacy = true WHERE id = $1", legacy.id)
+            .execute(&keygate.inner.db)
+            .await
+            .unwrap();
+
+        let res = password_login(&keygate, "flagged", "wrong").await;
+        assert!(matches!(res, Err(APIError::InvalidArgument(_))));
+        let res = password_login(&keygate, "flagged", "legacy password").await;
+        assert!(matches!(res, Ok(LoginResponse::Success { .. })));
+
+        let identity = keygate
+            .auth
+            .load_identity(&legacy.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!identity.legacy);
+        assert!(keygate
+            .inner
+            .password_hasher
+            .verify("legacy password", &identity.password_hash.unwrap())
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_upstream_login() {
+        let provider = crate::upstream::mock::MockProvider::start().await;
+        let keygate = test_keygate(KeygateConfig {
+            upstream_providers: vec![provider.config("mock")],
+            ..Default::default()
+        })
+        .await;
+        keygate.create_admin_app().await.unwrap();
+
+        let upstream_login = |code: &'static str, claims: serde_json::Value| {
+            let keygate = keygate.clone();
+            let provider = provider.clone();
+            async move {
+                let LoginResponse::UpstreamRedirect { url, .. } = keygate
+                    .auth
+                    .login_create_upstream("admin", "mock", None, None)
+                    .await
+                    .unwrap()
+                else {
+                    panic!("expected redirect");
+                };
+                provider.authorize(&url, code, claims);
+                let state = reqwest::Url::parse(&url)
+                    .unwrap()
+                    .query_pairs()
+                    .find(|(key, _)| key == "state")
+                    .unwrap()
+                    .1
+                    .to_string();
+                (
+                    state.clone(),
+                    keygate
+                        .auth
+                        .login_upstream_callback("admin", &state, code)
+                        .await,
+                )
+            }
+        };
+        let claims = serde_json::json!({
+            "sub": "upstream-user",
+            "email": "upstream@keygate.io",
+            "email_verified": true,
+            "preferred_username": "upstream",
+        });
+
+        let res = keygate
+            .auth
+            .login_create_upstream("admin", "unknown", None, None)
+            .await;
+        assert!(matches!(res, Err(APIError::NotFound(_))));
+
+        let (state, res) = upstream_login("code-1", claims.clone()).await;
+        assert!(matches!(res, Ok(LoginResponse::Success { .. })));
+        let res = keygate
+            .auth
+            .login_upstream_callback("admin", &state, "code-1")
+            .await;
+        assert!(matches!(res, Err(APIError::NotFound(_))));
+
+        let identity = sqlx::query_as!(
+            Identity,
+            "SELECT * FROM Identity WHERE username = 'upstream'"
+        )
+        .fetch_one(&keygate.inner.db)
+        .await
+        .unwrap();
+        assert_eq!(
+            identity.primary_email.as_deref(),
+            Some("upstream@keygate.io")
+        );
+        assert!(identity.password_hash.is_none());
+        assert!(verified_email(&keygate.inner, &identity.id)
+            .await
+            .unwrap()
+            .is_some());
+
+        // the linked account logs in to the same identity
+        let (_, res) = upstream_login("code-2", claims.clone()).await;
+        assert!(matches!(res, Ok(LoginResponse::Success { .. })));
+        let linked = sqlx::query_as!(LinkedAccount, "SELECT * FROM LinkedAccount")
+            .fetch_all(&keygate.inner.db)
+            .await
+            .unwrap();
+        assert_eq!(linked.len(), 1);
+        assert_eq!(linked[0].identity_id, identity.id);
+        assert_eq!(linked[0].subject, "upstream-user");
+
+        // codes the provider rejects fail like a wrong password
+        let LoginResponse::UpstreamRedirect { url, .. } = keygate
+            .auth
+            .login_create_upstream("admin", "mock", None, None)
+            .await
+            .unwrap()
+        else {
+            panic!("expected redirect");
+        };
+        provider.authorize(&url, "code-3", claims.clone());
+        let state = reqwest::Url::parse(&url)
+            .unwrap()
+            .query_pairs()
+            .find(|(key, _)| key == "state")
+            .unwrap()
+            .1
+            .to_string();
+        let res = keygate
+            .auth
+            .login_upstream_callback("admin", &state, "wrong-code")
+            .await;
+        assert!(matches!(res, Err(APIError::InvalidArgument(_))));
+
+        // a different account with a taken email isn't linked automatically
+        let (_, res) = upstream_login(
+            "code-4",
+            serde_json::json!({
+                "sub": "someone-else",
+                "email": "upstream@keygate.io",
+                "email_verified": true,
+            }),
+        )
+        .await;
+        assert!(matches!(res, Err(APIError::AlreadyExists(_))));
+    }
+
+    #[tokio::test]
+    async fn test_saml_login() {
+        let connection = crate::saml::mock::config("acme");
+        let keygate = test_keygate(KeygateConfig {
+            saml_connections: vec![connection.clone()],
+            saml_signing_key: Some(crate::saml::mock::signing_key()),
+            ..Default::default()
+        })
+        .await;
+        keygate.create_admin_app().await.unwrap();
+
+        let metadata = keygate.auth.saml_metadata("acme").unwrap();
+        assert!(metadata.contains(r#"entityID="https://keygate.example.com""#));
+        assert!(metadata.contains("KeyDescriptor"));
+
+        // the relay state and the id of the AuthnRequest of a new login
+        let saml_login = || {
+            let keygate = keygate.clone();
+            async move {
+                let LoginResponse::UpstreamRedirect { url, process_id } = keygate
+                    .auth
+                    .login_create_upstream("admin", "acme", None, None)
+                    .await
+                    .unwrap()
+                else {
+                    panic!("expected redirect");
+                };
+                let url = reqwest::Url::parse(&url).unwrap();
+                assert_eq!(url.host_str(), Some("idp.example.com"));
+                assert!(url.query_pairs().any(|(key, _)| key == "Signature"));
+                let relay_state = url
+                    .query_pairs()
+                    .find(|(key, _)| key == "RelayState")
+                    .unwrap()
+                    .1
+                    .to_string();
+                let request_id = sqlx::query!(
+                    "SELECT upstream_nonce FROM LoginProcess WHERE id = $1",
+                    process_id
+                )
+                .fetch_one(&keygate.inner.db)
+                .await
+                .unwrap()
+                .upstream_nonce
+                .unwrap();
+                (relay_state, request_id)
+            }
+        };
+
+        let (relay_state, request_id) = saml_login().await;
+        let response =
+            crate::saml::mock::response(&connection, &request_id, "jane@acme.com", "jane");
+        let res = keygate
+            .auth
+            .login_upstream_callback("admin", &relay_state, &response)
+            .await;
+        assert!(matches!(res, Ok(LoginResponse::Success { .. })));
+
+        let identity = sqlx::query_as!(Identity, "SELECT * FROM Identity WHERE username = 'jane'")
+            .fetch_one(&keygate.inner.db)
+            .await
+            .unwrap();
+        assert_eq!(identity.primary_email.as_deref(), Some("jane@acme.com"));
+        assert!(verified_email(&keygate.inner, &identity.id)
+            .await
+            .unwrap()
+            .is_some());
+
+        // the linked account logs in to the same identity
+        let (relay_state, request_id) = saml_login().await;
+        let second_response =
+            crate::saml::mock::response(&connection, &request_id, "jane@acme.com", "jane");
+        let res = keygate
+            .auth
+            .login_upstream_callback("admin", &relay_state, &second_response)
+            .await;
+        assert!(matches!(res, Ok(LoginResponse::Success { .. })));
+        let linked = sqlx::query_as!(LinkedAccount, "SELECT * FROM LinkedAccount")
+            .fetch_all(&keygate.inner.db)
+            .await
+            .unwrap();
+        assert_eq!(linked.len(), 1);
+        assert_eq!(linked[0].identity_id, identity.id);
+        assert_eq!(linked[0].provider_id, "acme");
+        assert_eq!(linked[0].subject, "jane@acme.com");
+
+        // the form post of the IdP carries no token, the process knows the application
+        let (relay_state, request_id) = saml_login().await;
+        let acs_response =
+            crate::saml::mock::response(&connection, &request_id, "jane@acme.com", "jane");
+        assert!(matches!(
+            keygate.auth.login_saml_acs("unknown", &acs_response).await,
+            Err(APIError::NotFound(_))
+        ));
+        let res = keygate
+            .auth
+            .login_saml_acs(&relay_state, &acs_response)
+            .await;
+        assert!(matches!(res, Ok(LoginResponse::Success { .. })));
+        assert!(keygate
+            .auth
+            .login_saml_acs(&relay_state, &acs_response)
+            .await
+            .is_err());
+
+        // responses answer a single request
+        let (relay_state, _) = saml_login().await;
+        let res = keygate
+            .auth
+            .login_upstream_callback("admin", &relay_state, &response)
+            .await;
+        assert!(matches!(res, Err(APIError::InvalidArgument(_))));
+    }
+
+    #[tokio::test]
+    async fn test_home_realm_discovery() {
+        let connection = crate::saml::mock::config("acme");
+        let keygate = test_keygate(KeygateConfig {
+            anti_enumeration: Some(false),
+            saml_connections: vec![connection.clone()],
+            ..Default::default()
+        })
+        .await;
+        keygate.create_admin_app().await.unwrap();
+        let password_hash = keygate_utils::hash::password("correct horse").unwrap();
+        let jane = keygate
+            .identity
+            .create(crate::api::identity::CreateIdentity {
+                username: Some("jane"),
+                primary_email: Some("jane@acme.com"),
+                password_hash: Some(&password_hash),
+            })
+            .await
+            .unwrap();
+
+        let domain = keygate.domains.add("acme.com", "acme", true).await.unwrap();
+        keygate.set_dns_resolver(crate::dns::mock::StaticResolver(vec![format!(
+            "{}{}",
+            crate::api::domain::VERIFICATION_PREFIX,
+            domain.verification_token
+        )]));
+        keygate.domains.verify("acme.com").await.unwrap();
+
+        // the domain blocks passwords, by email and by username
+        for name in ["jane@acme.com", "jane"] {
+            let LoginResponse::NextStep {
+                step_type,
+                process_id,
+            } = keygate
+                .auth
+                .login_create("admin", name, None, None)
+                .await
+                .unwrap()
+            else {
+                panic!("expected next step");
+            };
+            assert_eq!(step_type, vec![LoginStep::UPSTREAM]);
+            let res = keygate
+                .auth
+                .login_step("admin", &process_id, LoginStep::PASSWORD, "correct horse")
+                .await;
+            assert!(matches!(res, Err(APIError::InvalidArgument(_))));
+        }
+
+        // unknown users of the domain are sent to the provider as well
+        let res = keygate
+            .auth
+            .login_create("admin", "new@acme.com", None, None)
+            .await;
+        assert!(matches!(res, Ok(LoginResponse::NextStep { .. })));
+
+        let LoginResponse::NextStep { process_id, .. } = keygate
+            .auth
+            .login_create("admin", "jane@acme.com", None, None)
+            .await
+            .unwrap()
+        else {
+            panic!("expected next step");
+        };
+        let LoginResponse::UpstreamRedirect { url, .. } = keygate
+            .auth
+            .login_upstream_redirect("admin", &process_id)
+            .await
+            .unwrap()
+        else {
+            panic!("expected redirect");
+        };
+        let relay_state = reqwest::Url::parse(&url)
+            .unwrap()
+            .query_pairs()
+            .find(|(key, _)| key == "RelayState")
+            .unwrap()
+            .1
+            .to_string();
+        let request_id = sqlx::query!(
+            "SELECT upstream_nonce FROM LoginProcess WHERE id = $1",
+            process_id
+        )
+        .fetch_one(&keygate.inner.db)
+        .await
+        .unwrap()
+        .upstream_nonce
+        .unwrap();
+
+        // the account of the provider is linked to the identity with the email
+        let response =
+            crate::saml::mock::response(&connection, &request_id, "jane@acme.com", "jane");
+        let res = keygate
+            .auth
+            .login_upstream_callback("admin", &relay_state, &response)
+            .await;
+        assert!(matches!(res, Ok(LoginResponse::Success { .. })));
+        let linked = sqlx::query_as!(LinkedAccount, "SELECT * FROM LinkedAccount")
+            .fetch_all(&keygate.inner.db)
+            .await
+            .unwrap();
+        assert_eq!(linked.len(), 1);
+        assert_eq!(linked[0].identity_id, jane.id);
+
+        // without the block, passwords are offered next to the provider
+        keygate
+            .domains
+            .set_block_password("acme.com", false)
+            .await
+            .unwrap();
+        let LoginResponse::NextStep { step_type, .. } = keygate
+            .auth
+            .login_create("admin", "jane@acme.com", None, None)
+            .await
+            .unwrap()
+        else {
+            panic!("expected next step");
+        };
+        assert!(step_type.contains(&LoginStep::PASSWORD));
+        assert!(step_type.contains(&LoginStep::UPSTREAM));
+    }
+
+    #[tokio::test]
+    async fn test_ldap_login() {
+        let url = crate::ldap::mock::server(vec![crate::ldap::mock::jane()]).await;
+        let keygate = test_keygate(KeygateConfig {
+            anti_enumeration: Some(false),
+            ldap: Some(crate::ldap::mock::config(&url)),
+            ..Default::default()
+        })
+        .await;
+        keygate.create_admin_app().await.unwrap();
+
+        // unknown users are looked up in the directory
+        let res = password_login(&keygate, "jane", "wrong").await;
+        assert!(matches!(res, Err(APIError::InvalidArgument(_))));
+        assert!(!keygate.identity.exists("jane").await.unwrap());
+
+        let res = password_login(&keygate, "jane", "ldap secret").await;
+        assert!(matches!(res, Ok(LoginResponse::Success { .. })));
+        let identity = sqlx::query_as!(Identity, "SELECT * FROM Identity WHERE username = 'jane'")
+            .fetch_one(&keygate.inner.db)
+            .await
+            .unwrap();
+        assert!(identity.ldap);
+        assert!(identity.password_hash.is_none());
+        assert_eq!(identity.primary_email.as_deref(), Some("jane@example.com"));
+        assert!(verified_email(&keygate.inner, &identity.id)
+            .await
+            .unwrap()
+            .is_some());
+        assert_eq!(
+            keygate.identity.roles(&identity.id).await.unwrap(),
+            vec!["admin".to_string()]
+        );
+
+        // the directory keeps checking the password of the identity
+        let res = password_login(&keygate, "jane", "wrong").await;
+        assert!(matches!(res, Err(APIError::InvalidArgument(_))));
+        let res = password_login(&keygate, "jane", "ldap secret").await;
+        assert!(matches!(res, Ok(LoginResponse::Success { .. })));
+        let count = sqlx::query!("SELECT COUNT(*) AS count FROM Identity")
+            .fetch_one(&keygate.inner.db)
+            .await
+            .unwrap()
+            .count;
+        assert_eq!(count, 1);
+
+        // users that aren't in the directory fail like a wrong password
+        let res = password_login(&keygate, "john", "ldap secret").await;
+        assert!(matches!(res, Err(APIError::InvalidArgument(_))));
+    }
+
+    #[tokio::test]
+    async fn test_signup_flows() {
+        let keygate = test_keygate(KeygateConfig::default()).await;
+        let mailer = crate::mail::TestMailer::default();
+        keygate.set_mailer(mailer.clone());
+        let ip_address = IpAddr::from([127, 0, 0, 1]);
+
+        let mut settings = keygate.inner.settings.global().await.unwrap();
+        settings.signup_flow = SignupFlow::UsernamePassword;
+        keygate
+            .inner
+            .settings
+            .update_global(settings.clone())
+            .await
+            .unwrap();
+
+        // fields outside of the flow are rejected
+        let res = keygate
+            .auth
+            .signup(
+                SignupData {
+                    username: Some("alice"),
+                    email: Some("alice@keygate.io"),
+                    password: Some("correct horse"),
+                    ..Default::default()
+                },
+                ip_address,
+            )
+            .await;
+        assert!(matches!(res, Err(APIError::InvalidArgument(_))));
+        let res = keygate
+            .auth
+            .signup(
+                SignupData {
+                    username: Some("alice"),
+                    password: Some("correct horse"),
+                    ..Default::default()
+                },
+                ip_address,
+            )
+            .await
+            .unwrap();
+        let SignupResponse::Success { identity } = res else {
+            panic!("expected success");
+        };
+        assert!(identity.primary_email.is_none());
+        assert!(identity.password_hash.is_some());
+
+        // passwordless signups complete with the emailed code
+        settings.signup_flow = SignupFlow::EmailOnly;
+        keygate
+            .inner
+            .settings
+            .update_global(settings.clone())
+            .await
+            .unwrap();
+        let res = keygate
+            .auth
+            .signup(
+                SignupData {
+                    email: Some("bob@keygate.io"),
+                    password: Some("correct horse"),
+                    ..Default::default()
+                },
+                ip_address,
+            )
+            .await;
+        assert!(matches!(res, Err(APIError::InvalidArgument(_))));
+        let res = keygate
+            .auth
+            .signup(
+                SignupData {
+                    email: Some("bob@keygate.io"),
+                    ..Default::default()
+                },
+                ip_address,
+            )
+            .await
+            .unwrap();
+        assert!(matches!(res, SignupResponse::RequiresEmailVerification));
+
+        let message = mailer.0.lock().unwrap().pop().unwrap();
+        assert_eq!(message.to, "bob@keygate.io");
+        let code = message
+            .body
+            .split(": ")
+            .nth(1)
+            .unwrap()
+            .split('.')
+            .next()
+            .unwrap()
+            .to_string();
+        assert!(matches!(
+            keygate.auth.signup_confirm("wrong").await,
+            Err(APIError::InvalidArgument(_))
+        ));
+        let identity = keygate.auth.signup_confirm(&code).await.unwrap();
+        assert!(identity.password_hash.is_none());
+        assert!(verified_email(&keygate.inner, &identity.id)
+            .await
+            .unwrap()
+            .is_some());
+        // the code can only be used once
+        assert!(keygate.auth.signup_confirm(&code).await.is_err());
+
+        // required verification holds back the session until the email is confirmed
+        settings.signup_flow = SignupFlow::EmailPassword;
+        settings.email_verification = EmailVerification::RequiredForLogin {
+            token_expires_in: time::Duration::hours(1),
+        };
+        keygate
+            .inner
+            .settings
+            .update_global(settings)
+            .await
+            .unwrap();
+        let res = keygate
+            .auth
+            .signup(
+                SignupData {
+                    email: Some("Bob@keygate.io"),
+                    password: Some("correct horse"),
+                    ..Default::default()
+                },
+                ip_address,
+            )
+            .await;
+        assert!(matches!(res, Err(APIError::AlreadyExists(_))));
+        let res = keygate
+            .auth
+            .signup(
+                SignupData {
+                    email: Some("carol@keygate.io"),
+                    password: Some("correct horse"),
+                    ..Default::default()
+                },
+                ip_address,
+            )
+            .await
+            .unwrap();
+        assert!(matches!(res, SignupResponse::RequiresEmailVerification));
+        assert_eq!(mailer.0.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_signup_age_and_full_name() {
+        let keygate = test_keygate(KeygateConfig::default()).await;
+        let ip_address = IpAddr::from([127, 0, 0, 1]);
+        let today = time::OffsetDateTime::now_utc().date();
+        // on February 29th, the birthday is on the 28th in other years
+        let years_ago = |years: i32| {
+            let year = today.year() - years;
+            today
+                .replace_year(year)
+                .or_else(|_| today.previous_day().unwrap().replace_year(year))
+                .unwrap()
+        };
+
+        let mut settings = keygate.inner.settings.global().await.unwrap();
+        settings.signup_flow = SignupFlow::UsernamePassword;
+        settings.minimum_age = Some(16);
+        settings.require_full_name = true;
+        keygate
+            .inner
+            .settings
+            .update_global(settings.clone())
+            .await
+            .unwrap();
+        let signup = |username: &'static str, data: SignupData<'static>| {
+            let keygate = keygate.clone();
+            async move {
+                keygate
+                    .auth
+                    .signup(
+                        SignupData {
+                            username: Some(username),
+                            password: Some("correct horse"),
+                            ..data
+                        },
+                        ip_address,
+                    )
+                    .await
+            }
+        };
+
+        // without a required birthdate, users confirm they are old enough
+        let res = signup("alice", SignupData::default()).await;
+        assert!(matches!(res, Err(APIError::InvalidArgument(_))));
+        let res = signup(
+            "alice",
+            SignupData {
+                age_attestation: true,
+                ..Default::default()
+            },
+        )
+        .await;
+        assert!(matches!(res, Err(APIError::InvalidArgument(_))));
+        let res = signup(
+            "alice",
+            SignupData {
+                full_name: Some("Alice Smith"),
+                age_attestation: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        let SignupResponse::Success { identity } = res else {
+            panic!("expected success");
+        };
+        assert_eq!(identity.full_name.as_deref(), Some("Alice Smith"));
+        assert!(identity.birthdate.is_none());
+        assert!(identity.age_attested_at.is_some());
+
+        // birthdates are checked against the minimum age but only kept if the settings ask for it
+        settings.require_birthdate = true;
+        settings.require_full_name = false;
+        keygate
+            .inner
+            .settings
+            .update_global(settings.clone())
+            .await
+            .unwrap();
+        let res = signup(
+            "bob",
+            SignupData {
+                age_attestation: true,
+                ..Default::default()
+            },
+        )
+        .await;
+        assert!(matches!(res, Err(APIError::InvalidArgument(_))));
+        let res = signup(
+            "bob",
+            SignupData {
+                birthdate: Some(years_ago(16).next_day().unwrap()),
+                ..Default::default()
+            },
+        )
+        .await;
+        assert!(matches!(res, Err(APIError::PermissionDenied(_))));
+        let res = signup(
+            "bob",
+            SignupData {
+                birthdate: Some(years_ago(16)),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        let SignupResponse::Success { identity } = res else {
+            panic!("expected success");
+        };
+        assert!(identity.full_name.is_none());
+        assert!(identity.birthdate.is_none());
+        assert!(identity.age_attested_at.is_some());
+
+        settings.store_birthdate = true;
+        keygate
+            .inner
+            .settings
+            .update_global(settings)
+            .await
+            .unwrap();
+        let res = signup(
+            "carol",
+            SignupData {
+                birthdate: Some(years_ago(30)),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        let SignupResponse::Success { identity } = res else {
+            panic!("expected success");
+        };
+        let profile = keygate.identity.profile(&identity.id).await.unwrap();
+        assert_eq!(profile.birthdate, Some(years_ago(30)));
+        let json = serde_json::to_value(&profile).unwrap();
+        assert_eq!(json["birthdate"], years_ago(30).to_string());
     }
 }
@@ -0,0 +1,283 @@
+use std::sync::Arc;
+
+use keygate_utils::random::randomid;
+
+use super::APIError;
+use crate::{database::DatabasePool, KeygateInternal};
+
+/// DNS name of the TXT record that verifies a domain, below the domain itself
+pub const VERIFICATION_RECORD: &str = "_keygate-challenge";
+/// what the TXT record contains before the verification token
+pub const VERIFICATION_PREFIX: &str = "keygate-domain-verification=";
+
+/// An email domain whose users log in with an upstream provider (home-realm discovery)
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+pub struct SsoDomain {
+    pub domain: String,
+    /// the upstream provider or SAML connection users of the domain are sent to
+    pub provider_id: String,
+    /// published as `keygate-domain-verification=<token>` in a TXT record of
+    /// `_keygate-challenge.<domain>`
+    pub verification_token: String,
+    /// logins are only routed once the domain is verified
+    pub verified_at: Option<time::OffsetDateTime>,
+    /// users of the domain can't log in with a password
+    pub block_password: bool,
+    pub created_at: time::OffsetDateTime,
+}
+
+#[derive(Debug, Clone)]
+pub struct Domains {
+    keygate: Arc<KeygateInternal>,
+}
+
+impl Domains {
+    pub(crate) fn new(keygate: Arc<KeygateInternal>) -> Self {
+        Self { keygate }
+    }
+
+    fn db(&self) -> &DatabasePool {
+        &self.keygate.db
+    }
+
+    pub async fn list(&self) -> Result<Vec<SsoDomain>, APIError> {
+        Ok(
+            sqlx::query_as!(SsoDomain, "SELECT * FROM SsoDomain ORDER BY domain")
+                .fetch_all(self.db())
+                .await?,
+        )
+    }
+
+    pub async fn get(&self, domain: &str) -> Result<SsoDomain, APIError> {
+        let domain = domain.to_lowercase();
+        sqlx::query_as!(
+            SsoDomain,
+            "SELECT * FROM SsoDomain WHERE domain = $1",
+            domain
+        )
+        .fetch_optional(self.db())
+        .await?
+        .ok_or(APIError::not_found("Domain not found"))
+    }
+
+    /// Register a domain for an upstream provider. Logins aren't routed to the provider
+    /// before the domain is verified with [`Domains::verify`].
+    pub async fn add(
+        &self,
+        domain: &str,
+        provider_id: &str,
+        block_password: bool,
+    ) -> Result<SsoDomain, APIError> {
+        let domain =
+            normalize_domain(domain).ok_or(APIError::invalid_argument("Invalid domain"))?;
+        if self.keygate.upstream.get(provider_id).is_none()
+            && self.keygate.saml.get(provider_id).is_none()
+        {
+            return Err(APIError::not_found("Upstream provider not found"));
+        }
+
+        let sso_domain = SsoDomain {
+            domain,
+            provider_id: provider_id.to_string(),
+            verification_token: randomid(32),
+            verified_at: None,
+            block_password,
+            created_at: time::OffsetDateTime::now_utc(),
+        };
+        let res = sqlx::query!(
+            "INSERT INTO SsoDomain (domain, provider_id, verification_token, block_password, created_at) VALUES ($1, $2, $3, $4, $5) ON CONFLICT DO NOTHING",
+            sso_domain.domain,
+            sso_domain.provider_id,
+            sso_domain.verification_token,
+            sso_domain.block_password,
+            sso_domain.created_at
+        )
+        .execute(self.db())
+        .await?;
+        if res.rows_affected() == 0 {
+            return Err(APIError::AlreadyExists(
+                "The domain is already registered".into(),
+            ));
+        }
+
+        Ok(sso_domain)
+    }
+
+    /// Check the TXT record of the domain for its verification token
+    pub async fn verify(&self, domain: &str) -> Result<SsoDomain, APIError> {
+        let sso_domain = self.get(domain).await?;
+        if sso_domain.verified_at.is_some() {
+            return Ok(sso_domain);
+        }
+
+        let resolver = self.keygate.dns_resolver.load();
+        let resolver = resolver
+            .as_ref()
+            .ok_or(APIError::Unimplemented("No DNS resolver configured".into()))?;
+        let records = resolver
+            .txt_records(&format!("{}.{}", VERIFICATION_RECORD, sso_domain.domain))
+            .await?;
+        let expected = format!("{}{}", VERIFICATION_PREFIX, sso_domain.verification_token);
+        if !records.iter().any(|record| record.trim() == expected) {
+            return Err(APIError::invalid_argument("Verification record not found"));
+        }
+
+        let now = time::OffsetDateTime::now_utc();
+        sqlx::query!(
+            "UPDATE SsoDomain SET verified_at = $1 WHERE domain = $2",
+            now,
+            sso_domain.domain
+        )
+        .execute(self.db())
+        .await?;
+
+        Ok(SsoDomain {
+            verified_at: Some(now),
+            ..sso_domain
+        })
+    }
+
+    pub async fn set_block_password(
+        &self,
+        domain: &str,
+        block_password: bool,
+    ) -> Result<SsoDomain, APIError> {
+        let domain = domain.to_lowercase();
+        sqlx::query_as!(
+            SsoDomain,
+            "UPDATE SsoDomain SET block_password = $1 WHERE domain = $2 RETURNING *",
+            block_password,
+            domain
+        )
+        .fetch_optional(self.db())
+        .await?
+        .ok_or(APIError::not_found("Domain not found"))
+    }
+
+    pub async fn remove(&self, domain: &str) -> Result<(), APIError> {
+        let domain = domain.to_lowercase();
+        let res = sqlx::query!("DELETE FROM SsoDomain WHERE domain = $1", domain)
+            .execute(self.db())
+            .await?;
+        if res.rows_affected() == 0 {
+            return Err(APIError::not_found("Domain not found"));
+        }
+        Ok(())
+    }
+
+    /// The verified domain of an email address, if logins with it are routed
+    pub(crate) async fn route(&self, email: &str) -> Result<Option<SsoDomain>, APIError> {
+        let Some((_, domain)) = email.rsplit_once('@') else {
+            return Ok(None);
+        };
+        let domain = domain.to_lowercase();
+        Ok(sqlx::query_as!(
+            SsoDomain,
+            "SELECT * FROM SsoDomain WHERE domain = $1 AND verified_at IS NOT NULL",
+            domain
+        )
+        .fetch_optional(self.db())
+        .await?)
+    }
+}
+
+fn normalize_domain(domain: &str) -> Option<String> {
+    let domain = domain.trim().trim_end_matches('.').to_lowercase();
+    let valid_label = |label: &str| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    };
+    (domain.len() <= 253 && domain.contains('.') && domain.split('.').all(valid_label))
+        .then_some(domain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{dns::mock::StaticResolver, test_keygate, KeygateConfig};
+
+    #[test]
+    fn test_normalize_domain() {
+        assert_eq!(
+            normalize_domain(" Example.COM. ").as_deref(),
+            Some("example.com")
+        );
+        assert_eq!(
+            normalize_domain("sub.example-1.io").as_deref(),
+            Some("sub.example-1.io")
+        );
+        assert!(normalize_domain("localhost").is_none());
+        assert!(normalize_domain("-bad.example.com").is_none());
+        assert!(normalize_domain("a..example.com").is_none());
+        assert!(normalize_domain("user@example.com").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_domain_verification() {
+        let keygate = test_keygate(KeygateConfig {
+            saml_connections: vec![crate::saml::mock::config("acme")],
+            ..Default::default()
+        })
+        .await;
+
+        let res = keygate.domains.add("acme.com", "unknown", false).await;
+        assert!(matches!(res, Err(APIError::NotFound(_))));
+        let domain = keygate
+            .domains
+            .add("ACME.com", "acme", false)
+            .await
+            .unwrap();
+        assert_eq!(domain.domain, "acme.com");
+        assert!(domain.verified_at.is_none());
+        let res = keygate.domains.add("acme.com", "acme", true).await;
+        assert!(matches!(res, Err(APIError::AlreadyExists(_))));
+
+        // unverified domains aren't routed
+        assert!(keygate
+            .domains
+            .route("jane@acme.com")
+            .await
+            .unwrap()
+            .is_none());
+
+        let res = keygate.domains.verify("acme.com").await;
+        assert!(matches!(res, Err(APIError::Unimplemented(_))));
+
+        keygate.set_dns_resolver(StaticResolver(vec![format!(
+            "{}wrong",
+            VERIFICATION_PREFIX
+        )]));
+        let res = keygate.domains.verify("acme.com").await;
+        assert!(matches!(res, Err(APIError::InvalidArgument(_))));
+
+        keygate.set_dns_resolver(StaticResolver(vec![
+            "v=spf1 -all".into(),
+            format!("{}{}", VERIFICATION_PREFIX, domain.verification_token),
+        ]));
+        let verified = keygate.domains.verify("acme.com").await.unwrap();
+        assert!(verified.verified_at.is_some());
+        let routed = keygate
+            .domains
+            .route("Jane@Acme.com")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(routed.provider_id, "acme");
+
+        let updated = keygate
+            .domains
+            .set_block_password("acme.com", true)
+            .await
+            .unwrap();
+        assert!(updated.block_password);
+        assert_eq!(keygate.domains.list().await.unwrap().len(), 1);
+
+        keygate.domains.remove("acme.com").await.unwrap();
+        let res = keygate.domains.get("acme.com").await;
+        assert!(matches!(res, Err(APIError::NotFound(_))));
+    }
+}
@@ -1,16 +1,71 @@
-use std::sync::Arc;
+use std::{net::IpAddr, sync::Arc};
 
 use crate::{
     database::{models, DatabasePool},
+    mail::EmailMessage,
     KeygateInternal,
 };
 
 use keygate_utils::{
-    random::secure_random_id,
+    join_keys,
+    random::{randomid, readable_code, secure_random_id},
     validate::{is_valid_email, is_valid_password, is_valid_username, validate_field},
 };
 
-use super::{APIError, Filter, SortBy, SortOrder, UserIdentifier};
+use super::{
+    audit::{AuditAction, AuditEntry},
+    auth::{check_new_password, user_inputs},
+    throttle::LoginThrottle,
+    APIError, Audit, Filter, SortBy, SortOrder, UserIdentifier,
+};
+use tracing::warn;
+
+/// A device that can skip second factors when logging in
+#[derive(serde::Deserialize, serde::Serialize)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+pub struct TrustedDevice {
+    pub id: String,
+    pub label: String,
+    pub created_at: time::OffsetDateTime,
+    pub last_used_at: Option<time::OffsetDateTime>,
+    pub expires_at: time::OffsetDateTime,
+    pub revoked_at: Option<time::OffsetDateTime>,
+}
+
+/// An account of an upstream provider the identity can log in with
+#[derive(serde::Deserialize, serde::Serialize)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+pub struct LinkedAccount {
+    pub id: String,
+    pub provider_id: String,
+    /// `sub` of the provider's id_token
+    pub subject: String,
+    pub email: Option<String>,
+    pub created_at: time::OffsetDateTime,
+    pub last_login_at: Option<time::OffsetDateTime>,
+}
+
+// birthdates are exchanged as `YYYY-MM-DD`
+time::serde::format_description!(pub iso_date, Date, "[year]-[month]-[day]");
+
+/// An identity as shown by the profile APIs
+#[derive(serde::Deserialize, serde::Serialize)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+pub struct IdentityProfile {
+    pub id: String,
+    pub username: Option<String>,
+    pub primary_email: Option<String>,
+    /// whether the primary email address is verified
+    pub email_verified: bool,
+    pub full_name: Option<String>,
+    /// `YYYY-MM-DD`, only stored if the settings ask for it
+    #[serde(with = "iso_date::option")]
+    pub birthdate: Option<time::Date>,
+    /// when the identity confirmed it is at least the minimum age
+    pub age_attested_at: Option<time::OffsetDateTime>,
+    pub created_at: time::OffsetDateTime,
+    pub last_active: time::OffsetDateTime,
+}
 
 #[derive(Debug, Clone)]
 pub struct Identity {
@@ -28,6 +83,17 @@ const USERNAME_REQUIRED: bool = true;
 const EMAIL_REQUIRED: bool = true;
 const PASSWORD_REQUIRED: bool = true;
 
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_LENGTH: usize = 10;
+
+// recovery codes are shown as `xxxxx-xxxxx`, ignore formatting and case when checking them
+pub(crate) fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
 impl Identity {
     pub(crate) fn new(keygate: Arc<KeygateInternal>) -> Self {
         Self { keygate }
@@ -62,22 +128,54 @@ impl Identity {
         Ok(user.is_some())
     }
 
+    /// [`Identity::exists`] for unauthenticated callers. Every lookup counts against the ip address
+    /// like a failed login, so it can't be used to enumerate accounts.
+    pub async fn exists_throttled(
+        &self,
+        username_or_email: &str,
+        ip_address: IpAddr,
+    ) -> Result<bool, APIError> {
+        let settings = self.keygate.settings.global().await?;
+        let throttle = LoginThrottle::new(self.keygate.clone());
+        let ip_address = ip_address.to_string();
+        throttle
+            .check_ip(&ip_address, &settings.login_throttle)
+            .await?;
+        throttle
+            .record_ip(&ip_address, &settings.login_throttle)
+            .await?;
+
+        self.exists(username_or_email).await
+    }
+
     pub async fn get(&self, user: UserIdentifier) -> Result<Option<models::Identity>, APIError> {
-        let (field, value) = match user {
-            UserIdentifier::Email(email) => ("primary_email", email),
-            UserIdentifier::Username(username) => ("username", username),
-            UserIdentifier::Id(id) => ("id", id),
+        // column names can't be bound as parameters
+        let identity = match user {
+            UserIdentifier::Email(email) => {
+                sqlx::query_as!(
+                    models::Identity,
+                    "SELECT * FROM Identity WHERE primary_email = $1",
+                    email
+                )
+                .fetch_optional(self.db())
+                .await?
+            }
+            UserIdentifier::Username(username) => {
+                sqlx::query_as!(
+                    models::Identity,
+                    "SELECT * FROM Identity WHERE username = $1",
+                    username
+                )
+                .fetch_optional(self.db())
+                .await?
+            }
+            UserIdentifier::Id(id) => {
+                sqlx::query_as!(models::Identity, "SELECT * FROM Identity WHERE id = $1", id)
+                    .fetch_optional(self.db())
+                    .await?
+            }
         };
 
-        let identity = sqlx::query_as!(
-            models::Identity,
-            "SELECT * FROM Identity WHERE $1 = $2",
-            field,
-            value
-        )
-        .fetch_optional(self.db())
-        .await?;
-
         Ok(identity)
     }
 
@@ -86,9 +184,7 @@ impl Identity {
         identity: CreateIdentity<'a>,
     ) -> Result<models::Identity, APIError> {
         let user_id = secure_random_id();
-        let email_token = secure_random_id();
         let now = time::OffsetDateTime::now_utc();
-        let email_expires_at = now + time::Duration::minutes(15);
 
         validate_field(
             &identity.username,
@@ -110,6 +206,7 @@ impl Identity {
             APIError::invalid_argument("Invalid password"),
         )?;
 
+        let primary_email = identity.primary_email;
         let mut tx = self.db().begin().await?;
 
         let mut identity = sqlx::query_as!(
@@ -129,14 +226,12 @@ impl Identity {
         .fetch_one(&mut *tx)
         .await?;
 
-        if let Some(email) = identity.primary_email.clone() {
+        if let Some(email) = primary_email {
             sqlx::query!(
-                "INSERT INTO Email (email, identity_id, verified, verification_code, verification_code_expires_at, created_at, updated_at)
-                    VALUES ($1, $2, false, $3, $4, $5, $6)",
+                "INSERT INTO Email (email, identity_id, verified, created_at, updated_at)
+                    VALUES ($1, $2, false, $3, $4)",
                 email,
                 user_id,
-                email_token,
-                email_expires_at,
                 now,
                 now
             )
@@ -146,7 +241,7 @@ impl Identity {
             identity = sqlx::query_as!(
                 models::Identity,
                 "UPDATE Identity SET primary_email = $1 WHERE id = $2 RETURNING *",
-                identity.primary_email,
+                email,
                 user_id
             )
             .fetch_one(&mut *tx)
@@ -155,9 +250,173 @@ impl Identity {
 
         tx.commit().await?;
 
+        let settings = self.keygate.settings.global().await?;
+        if let (Some(email), false) = (
+            primary_email,
+            matches!(settings.email_verification, models::EmailVerification::None),
+        ) {
+            self.send_verification_code(email, &settings).await?;
+        }
+
         Ok(identity)
     }
 
+    /// The identity with the verification status of its primary email address
+    pub async fn profile(&self, id: &str) -> Result<IdentityProfile, APIError> {
+        let profile = sqlx::query_as!(
+            IdentityProfile,
+            r#"SELECT Identity.id, Identity.username, Identity.primary_email, COALESCE(Email.verified, false) AS "email_verified!: bool", Identity.full_name, Identity.birthdate, Identity.age_attested_at, Identity.created_at, Identity.last_active
+                FROM Identity LEFT JOIN Email ON Email.email = Identity.primary_email
+                WHERE Identity.id = $1"#,
+            id
+        )
+        .fetch_optional(self.db())
+        .await?
+        .ok_or(APIError::not_found("User not found"))?;
+
+        Ok(profile)
+    }
+
+    // replace the verification code of the email address and send it, the code is only
+    // stored hashed and works on its own as well as in a link
+    pub(crate) async fn send_verification_code(
+        &self,
+        email: &str,
+        settings: &models::GlobalSettings,
+    ) -> Result<(), APIError> {
+        let now = time::OffsetDateTime::now_utc();
+        let expires_in = match settings.email_verification {
+            models::EmailVerification::Optional { token_expires_in }
+            | models::EmailVerification::RequiredForLogin { token_expires_in } => token_expires_in,
+            // passwordless signups confirm their email without verification being configured
+            models::EmailVerification::None => settings.signup_process_expires_in,
+        };
+        let code = randomid(32);
+        let code_hash = keygate_utils::hash::token(&code);
+        let expires_at = now + expires_in;
+
+        sqlx::query!(
+            "UPDATE Email SET verification_code = $1, verification_code_expires_at = $2, last_verification_request = $3, updated_at = $3 WHERE email = $4",
+            code_hash,
+            expires_at,
+            now,
+            email
+        )
+        .execute(self.db())
+        .await?;
+
+        let server = &self.keygate.config.server;
+        let link = format!(
+            "https://{}{}/auth/verify-email?code={}",
+            server.keygate_domain,
+            server.public_prefix.as_deref().unwrap_or_default(),
+            code
+        );
+        self.keygate
+            .mailer
+            .load()
+            .send(EmailMessage {
+                to: email.to_string(),
+                subject: "Confirm your email address".to_string(),
+                body: format!(
+                    "Use this code to confirm your email address: {}. It expires in {} minutes.\n\nYou can also open this link: {}",
+                    code,
+                    expires_in.whole_minutes(),
+                    link
+                ),
+            })
+            .map_err(|e| APIError::Internal(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Send a new verification code to an unverified email address.
+    ///
+    /// Like [`super::Auth::password_reset_request`], this always succeeds so it can't be used
+    /// to find out which addresses have an account. Within the cooldown nothing is sent.
+    pub async fn resend_verification(&self, email: &str) -> Result<(), APIError> {
+        let settings = self.keygate.settings.global().await?;
+        let now = time::OffsetDateTime::now_utc();
+
+        let Some(email) = sqlx::query_as!(
+            models::Email,
+            "SELECT * FROM Email WHERE email = $1 COLLATE NOCASE",
+            email
+        )
+        .fetch_optional(self.db())
+        .await?
+        else {
+            return Ok(());
+        };
+
+        if email.verified
+            || email
+                .last_verification_request
+                .is_some_and(|last| last + settings.email_verification_resend_cooldown > now)
+        {
+            return Ok(());
+        }
+
+        // a failure here would reveal that the email exists
+        if let Err(e) = self.send_verification_code(&email.email, &settings).await {
+            warn!("Failed to send verification email: {:?}", e);
+        }
+
+        Ok(())
+    }
+
+    /// Verify an email address with the code sent by [`Identity::resend_verification`] or
+    /// at signup. The code can only be used once. Returns the identity of the address.
+    pub async fn verify_email(&self, code: &str) -> Result<models::Identity, APIError> {
+        let now = time::OffsetDateTime::now_utc();
+        let code_hash = keygate_utils::hash::token(code);
+
+        let email = sqlx::query!(
+            "UPDATE Email SET verified = true, verification_code = NULL, verification_code_expires_at = NULL, updated_at = $1 WHERE verification_code = $2 AND verification_code_expires_at > $1 RETURNING email, identity_id",
+            now,
+            code_hash
+        )
+        .fetch_optional(self.db())
+        .await?
+        .ok_or(APIError::invalid_argument("Invalid or expired code"))?;
+
+        Audit::new(self.keygate.clone())
+            .write(
+                AuditEntry::new(&email.identity_id, AuditAction::EmailVerified)
+                    .target("email", &email.email),
+            )
+            .await?;
+
+        sqlx::query_as!(
+            models::Identity,
+            "SELECT * FROM Identity WHERE id = $1",
+            email.identity_id
+        )
+        .fetch_optional(self.db())
+        .await?
+        .ok_or(APIError::not_found("User not found"))
+    }
+
+    /// Mark an email address of the identity as verified as an admin
+    pub async fn set_email_verified(&self, id: &str, email: &str) -> Result<(), APIError> {
+        let now = time::OffsetDateTime::now_utc();
+        let email = sqlx::query!(
+            "UPDATE Email SET verified = true, verification_code = NULL, verification_code_expires_at = NULL, updated_at = $1 WHERE identity_id = $2 AND email = $3 COLLATE NOCASE RETURNING email",
+            now,
+            id,
+            email
+        )
+        .fetch_optional(self.db())
+        .await?
+        .ok_or(APIError::not_found("Email not found"))?;
+
+        Audit::new(self.keygate.clone())
+            .write(AuditEntry::new(id, AuditAction::EmailVerified).target("email", &email.email))
+            .await?;
+
+        Ok(())
+    }
+
     pub async fn update(
         &self,
         update: impl FnOnce(models::Identity) -> models::Identity,
@@ -265,4 +524,853 @@ impl Identity {
 
         Ok(identities)
     }
+
+    /// Replace all recovery codes of the identity with a new set.
+    /// The codes are only stored hashed, so this is the only time they can be shown.
+    pub async fn generate_recovery_codes(&self, id: &str) -> Result<Vec<String>, APIError> {
+        let now = time::OffsetDateTime::now_utc();
+        let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+            .map(|_| readable_code(RECOVERY_CODE_LENGTH))
+            .collect();
+
+        let mut tx = self.db().begin().await?;
+
+        sqlx::query!("SELECT id FROM Identity WHERE id = $1", id)
+            .fetch_optional(&mut *tx)
+            .await?
+            .ok_or(APIError::not_found("User not found"))?;
+
+        sqlx::query!("DELETE FROM RecoveryCode WHERE identity_id = $1", id)
+            .execute(&mut *tx)
+            .await?;
+
+        for code in &codes {
+            let code_id = secure_random_id();
+            let code_hash = keygate_utils::hash::password(code)
+                .map_err(|e| APIError::internal(&format!("Failed to hash code: {}", e)))?;
+
+            sqlx::query!(
+                "INSERT INTO RecoveryCode (id, created_at, identity_id, code_hash) VALUES ($1, $2, $3, $4)",
+                code_id,
+                now,
+                id,
+                code_hash
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+
+        Audit::new(self.keygate.clone())
+            .write(AuditEntry::new(id, AuditAction::RecoveryCodesGenerated))
+            .await?;
+
+        Ok(codes
+            .iter()
+            .map(|code| {
+                let (a, b) = code.split_at(RECOVERY_CODE_LENGTH / 2);
+                format!("{}-{}", a, b)
+            })
+            .collect())
+    }
+
+    /// Change the password from a session of the identity. Without the current password,
+    /// the session has to have authenticated recently (see [`super::Session::require_recent_auth`]).
+    pub async fn change_password(
+        &self,
+        id: &str,
+        session_id: &str,
+        current_password: Option<&str>,
+        new_password: &str,
+        revoke_other_sessions: bool,
+    ) -> Result<(), APIError> {
+        let settings = self.keygate.settings.global().await?;
+        let identity =
+            sqlx::query_as!(models::Identity, "SELECT * FROM Identity WHERE id = $1", id)
+                .fetch_optional(self.db())
+                .await?
+                .ok_or(APIError::not_found("User not found"))?;
+
+        match current_password {
+            Some(current_password) => {
+                let throttle = LoginThrottle::new(self.keygate.clone());
+                throttle.check(id, None, &settings.login_throttle).await?;
+
+                let hasher = &self.keygate.password_hasher;
+                let valid = match &identity.password_hash {
+                    Some(hash) => hasher.verify(current_password, hash),
+                    None => hasher.verify_dummy(current_password),
+                }
+                .map_err(|e| APIError::internal(&format!("Failed to verify password: {}", e)))?;
+                if !valid {
+                    throttle
+                        .record_failure(id, None, &settings.login_throttle)
+                        .await?;
+                    return Err(APIError::invalid_argument("Invalid password"));
+                }
+            }
+            None => {
+                super::Session::new(self.keygate.clone())
+                    .require_recent_auth(session_id, false)
+                    .await?;
+            }
+        }
+
+        self.store_password(&identity, new_password, &settings)
+            .await?;
+        if revoke_other_sessions {
+            super::Session::new(self.keygate.clone())
+                .revoke_all(id, Some(session_id))
+                .await?;
+        }
+
+        Audit::new(self.keygate.clone())
+            .write(AuditEntry::new(id, AuditAction::PasswordChanged).session(session_id))
+            .await?;
+
+        Ok(())
+    }
+
+    /// Set the password of an identity as an admin, and/or require a new password on the
+    /// next login, which then has to complete [`super::auth::LoginStep::CHANGE_PASSWORD`].
+    pub async fn set_password(
+        &self,
+        id: &str,
+        password: Option<&str>,
+        must_change: bool,
+    ) -> Result<(), APIError> {
+        let settings = self.keygate.settings.global().await?;
+        let identity =
+            sqlx::query_as!(models::Identity, "SELECT * FROM Identity WHERE id = $1", id)
+                .fetch_optional(self.db())
+                .await?
+                .ok_or(APIError::not_found("User not found"))?;
+
+        if let Some(password) = password {
+            self.store_password(&identity, password, &settings).await?;
+        }
+
+        sqlx::query!(
+            "UPDATE Identity SET must_change_password = $1 WHERE id = $2",
+            must_change,
+            id
+        )
+        .execute(self.db())
+        .await?;
+
+        Audit::new(self.keygate.clone())
+            .write(AuditEntry::new(id, AuditAction::PasswordSet))
+            .await?;
+
+        Ok(())
+    }
+
+    /// The new password chosen during a login that had to change it
+    pub(crate) async fn login_change_password(
+        &self,
+        identity: &models::Identity,
+        new_password: &str,
+    ) -> Result<(), APIError> {
+        let settings = self.keygate.settings.global().await?;
+        self.store_password(identity, new_password, &settings)
+            .await?;
+
+        Audit::new(self.keygate.clone())
+            .write(AuditEntry::new(&identity.id, AuditAction::PasswordChanged))
+            .await?;
+
+        Ok(())
+    }
+
+    // validate and hash a new password, this also clears `must_change_password`
+    async fn store_password(
+        &self,
+        identity: &models::Identity,
+        password: &str,
+        settings: &models::GlobalSettings,
+    ) -> Result<(), APIError> {
+        check_new_password(&self.keygate, password, settings, &user_inputs(identity)).await?;
+
+        let hasher = &self.keygate.password_hasher;
+        if let Some(current_hash) = &identity.password_hash {
+            // hashes with an unknown pepper can't be compared, they are replaced either way
+            if hasher.verify(password, current_hash).unwrap_or(false) {
+                return Err(APIError::invalid_argument(
+                    "New password must be different from the current one",
+                ));
+            }
+        }
+
+        let now = time::OffsetDateTime::now_utc();
+        let password_hash = hasher
+            .hash(password)
+            .map_err(|e| APIError::internal(&format!("Failed to hash password: {}", e)))?;
+        sqlx::query!(
+            "UPDATE Identity SET password_hash = $1, must_change_password = false, updated_at = $2 WHERE id = $3",
+            password_hash,
+            now,
+            identity.id
+        )
+        .execute(self.db())
+        .await?;
+
+        Ok(())
+    }
+
+    /// Number of unused recovery codes of the identity
+    pub async fn recovery_codes_remaining(&self, id: &str) -> Result<i64, APIError> {
+        let remaining = sqlx::query!(
+            "SELECT COUNT(*) AS remaining FROM RecoveryCode WHERE identity_id = $1 AND used_at IS NULL",
+            id
+        )
+        .fetch_one(self.db())
+        .await?
+        .remaining;
+
+        Ok(remaining.into())
+    }
+
+    /// Devices that can skip second factors, including expired and revoked ones
+    pub async fn trusted_devices(&self, id: &str) -> Result<Vec<TrustedDevice>, APIError> {
+        let devices = sqlx::query_as!(
+            TrustedDevice,
+            "SELECT id, label, created_at, last_used_at, expires_at, revoked_at FROM TrustedDevice WHERE identity_id = $1 ORDER BY created_at DESC",
+            id
+        )
+        .fetch_all(self.db())
+        .await?;
+
+        Ok(devices)
+    }
+
+    /// Roles of the identity, synced from its LDAP groups when it logs in
+    pub async fn roles(&self, id: &str) -> Result<Vec<String>, APIError> {
+        let roles = sqlx::query!(
+            "SELECT role FROM IdentityRole WHERE identity_id = $1 ORDER BY role",
+            id
+        )
+        .fetch_all(self.db())
+        .await?;
+
+        Ok(roles.into_iter().map(|row| row.role).collect())
+    }
+
+    /// Accounts of upstream providers the identity can log in with
+    pub async fn linked_accounts(&self, id: &str) -> Result<Vec<LinkedAccount>, APIError> {
+        let accounts = sqlx::query_as!(
+            LinkedAccount,
+            "SELECT id, provider_id, subject, email, created_at, last_login_at FROM LinkedAccount WHERE identity_id = $1 ORDER BY created_at",
+            id
+        )
+        .fetch_all(self.db())
+        .await?;
+
+        Ok(accounts)
+    }
+
+    /// Start linking an account of an upstream provider, the session has to have authenticated
+    /// recently. Returns the url to send the user to, the provider redirects back with the
+    /// `state` and `code` for [`Identity::link_account_complete`].
+    pub async fn link_account_start(
+        &self,
+        id: &str,
+        session_id: &str,
+        provider_id: &str,
+    ) -> Result<String, APIError> {
+        super::Session::new(self.keygate.clone())
+            .require_recent_auth(session_id, false)
+            .await?;
+        let settings = self.keygate.settings.global().await?;
+        let request = self
+            .keygate
+            .upstream
+            .authorization_request(provider_id)
+            .await?;
+
+        let now = time::OffsetDateTime::now_utc();
+        let request_id = secure_random_id();
+        let state_hash = keygate_utils::hash::token(&request.state);
+        let expires_at = now + settings.login_process_expires_in;
+        sqlx::query!(
+            "INSERT INTO LinkRequest (id, identity_id, provider_id, state, nonce, code_verifier, created_at, expires_at) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+            request_id,
+            id,
+            provider_id,
+            state_hash,
+            request.nonce,
+            request.code_verifier,
+            now,
+            expires_at
+        )
+        .execute(self.db())
+        .await?;
+
+        Ok(request.url)
+    }
+
+    /// Link the account the provider redirected back with. Accounts that are linked to a
+    /// different identity, or whose email belongs to one, are refused instead of merged.
+    pub async fn link_account_complete(
+        &self,
+        id: &str,
+        state: &str,
+        code: &str,
+    ) -> Result<LinkedAccount, APIError> {
+        // the state can only be used once
+        let state_hash = keygate_utils::hash::token(state);
+        let request = sqlx::query!(
+            "DELETE FROM LinkRequest WHERE state = $1 AND identity_id = $2 RETURNING provider_id, nonce, code_verifier, expires_at",
+            state_hash,
+            id
+        )
+        .fetch_optional(self.db())
+        .await?
+        .ok_or(APIError::not_found("Link request not found"))?;
+
+        let now = time::OffsetDateTime::now_utc();
+        if request.expires_at < now {
+            return Err(APIError::not_found("Link request not found"));
+        }
+
+        let claims = self
+            .keygate
+            .upstream
+            .exchange(
+                &request.provider_id,
+                code,
+                &request.code_verifier,
+                &request.nonce,
+            )
+            .await?
+            .ok_or(APIError::invalid_argument("Upstream login failed"))?;
+
+        let mut tx = self.db().begin().await?;
+        let linked = sqlx::query!(
+            "SELECT identity_id FROM LinkedAccount WHERE provider_id = $1 AND subject = $2",
+            request.provider_id,
+            claims.sub
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        match linked {
+            Some(linked) if linked.identity_id != id => {
+                return Err(APIError::AlreadyExists(
+                    "This account is linked to a different identity".into(),
+                ))
+            }
+            Some(_) => return Err(APIError::AlreadyExists("Account is already linked".into())),
+            None => {}
+        }
+
+        if let Some(email) = &claims.email {
+            let owner = sqlx::query!(
+                "SELECT identity_id FROM Email WHERE email = $1 COLLATE NOCASE",
+                email
+            )
+            .fetch_optional(&mut *tx)
+            .await?;
+            if owner.is_some_and(|owner| owner.identity_id != id) {
+                return Err(APIError::AlreadyExists(
+                    "The email of this account belongs to a different identity".into(),
+                ));
+            }
+        }
+
+        let account = LinkedAccount {
+            id: secure_random_id(),
+            provider_id: request.provider_id,
+            subject: claims.sub,
+            email: claims.email,
+            created_at: now,
+            last_login_at: None,
+        };
+        sqlx::query!(
+            "INSERT INTO LinkedAccount (id, identity_id, provider_id, subject, email, created_at) VALUES ($1, $2, $3, $4, $5, $6)",
+            account.id,
+            id,
+            account.provider_id,
+            account.subject,
+            account.email,
+            account.created_at
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|err| match err {
+            // linked by a request that completed in the meantime
+            sqlx::Error::Database(db) if db.is_unique_violation() => {
+                APIError::AlreadyExists("Account is already linked".into())
+            }
+            err => err.into(),
+        })?;
+        tx.commit().await?;
+
+        Audit::new(self.keygate.clone())
+            .write(
+                AuditEntry::new(id, AuditAction::AccountLinked)
+                    .target("linked_account", &account.id)
+                    .data(join_keys!("provider", &account.provider_id)),
+            )
+            .await?;
+
+        Ok(account)
+    }
+
+    /// Remove a linked account, the session has to have authenticated recently. The last
+    /// linked account can't be removed if the identity has no other login method, nothing
+    /// could be used to log in after that.
+    pub async fn unlink_account(
+        &self,
+        id: &str,
+        session_id: &str,
+        linked_account_id: &str,
+    ) -> Result<(), APIError> {
+        super::Session::new(self.keygate.clone())
+            .require_recent_auth(session_id, false)
+            .await?;
+        let identity =
+            sqlx::query_as!(models::Identity, "SELECT * FROM Identity WHERE id = $1", id)
+                .fetch_optional(self.db())
+                .await?
+                .ok_or(APIError::not_found("User not found"))?;
+
+        let accounts = self.linked_accounts(id).await?;
+        if !accounts
+            .iter()
+            .any(|account| account.id == linked_account_id)
+        {
+            return Err(APIError::not_found("Linked account not found"));
+        }
+        // other linked accounts, a password, the directory or the domain's provider
+        if !accounts
+            .iter()
+            .any(|account| account.id != linked_account_id)
+            && super::Auth::new(self.keygate.clone())
+                .first_factors(&identity)
+                .await?
+                .is_empty()
+        {
+            return Err(APIError::permission_denied(
+                "The last way to log in can't be removed, set a password first",
+            ));
+        }
+
+        sqlx::query!(
+            "DELETE FROM LinkedAccount WHERE id = $1 AND identity_id = $2",
+            linked_account_id,
+            id
+        )
+        .execute(self.db())
+        .await?;
+
+        Audit::new(self.keygate.clone())
+            .write(
+                AuditEntry::new(id, AuditAction::AccountUnlinked)
+                    .session(session_id)
+                    .target("linked_account", linked_account_id),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn revoke_trusted_device(&self, id: &str, device_id: &str) -> Result<(), APIError> {
+        let now = time::OffsetDateTime::now_utc();
+        let res = sqlx::query!(
+            "UPDATE TrustedDevice SET revoked_at = $1 WHERE id = $2 AND identity_id = $3 AND revoked_at IS NULL",
+            now,
+            device_id,
+            id
+        )
+        .execute(self.db())
+        .await?;
+
+        if res.rows_affected() == 0 {
+            return Err(APIError::not_found("Trusted device not found"));
+        }
+
+        Audit::new(self.keygate.clone())
+            .write(
+                AuditEntry::new(id, AuditAction::TrustedDeviceRevoked)
+                    .target("trusted_device", device_id),
+            )
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        api::{auth::LoginStep, session::AuthContext},
+        login::LoginMethods,
+        test_keygate, KeygateConfig,
+    };
+
+    #[tokio::test]
+    async fn test_exists_throttled() {
+        let keygate = test_keygate(KeygateConfig::default()).await;
+        let mut settings = keygate.inner.settings.global().await.unwrap();
+        settings.login_throttle.ip_lockout_after = 3;
+        keygate
+            .inner
+            .settings
+            .update_global(settings)
+            .await
+            .unwrap();
+        let ip = IpAddr::from([10, 0, 0, 1]);
+
+        for _ in 0..3 {
+            let exists = keygate
+                .identity
+                .exists_throttled("nobody", ip)
+                .await
+                .unwrap();
+            assert!(!exists);
+        }
+        let res = keygate.identity.exists_throttled("nobody", ip).await;
+        assert!(matches!(res, Err(APIError::PermissionDenied(_))));
+
+        // other addresses are not affected
+        let res = keygate
+            .identity
+            .exists_throttled("nobody", IpAddr::from([10, 0, 0, 2]))
+            .await;
+        assert!(matches!(res, Ok(false)));
+    }
+
+    #[tokio::test]
+    async fn test_change_password() {
+        let keygate = test_keygate(KeygateConfig::default()).await;
+        let password_hash = keygate_utils::hash::password("correct horse").unwrap();
+        let identity = keygate
+            .identity
+            .create(CreateIdentity {
+                username: Some("changer"),
+                primary_email: Some("changer@keygate.io"),
+                password_hash: Some(&password_hash),
+            })
+            .await
+            .unwrap();
+
+        let password = LoginMethods::default().get(&LoginStep::PASSWORD).unwrap();
+        let earlier = time::OffsetDateTime::now_utc() - time::Duration::hours(1);
+        let mut sessions = vec![];
+        for _ in 0..2 {
+            let session_id = keygate
+                .session
+                .start(
+                    &identity.id,
+                    None,
+                    &AuthContext::from_methods(std::slice::from_ref(&password), earlier),
+                )
+                .await
+                .unwrap();
+            sessions.push(session_id);
+        }
+
+        // an old session needs the current password
+        let res = keygate
+            .identity
+            .change_password(&identity.id, &sessions[0], None, "battery staple", true)
+            .await;
+        assert!(matches!(res, Err(APIError::Unauthenticated(_))));
+        let res = keygate
+            .identity
+            .change_password(
+                &identity.id,
+                &sessions[0],
+                Some("wrong"),
+                "battery staple",
+                true,
+            )
+            .await;
+        assert!(matches!(res, Err(APIError::InvalidArgument(_))));
+        let res = keygate
+            .identity
+            .change_password(
+                &identity.id,
+                &sessions[0],
+                Some("correct horse"),
+                "correct horse",
+                true,
+            )
+            .await;
+        assert!(matches!(res, Err(APIError::InvalidArgument(_))));
+
+        keygate
+            .identity
+            .change_password(
+                &identity.id,
+                &sessions[0],
+                Some("correct horse"),
+                "battery staple",
+                true,
+            )
+            .await
+            .unwrap();
+
+        assert!(keygate.session.auth_context(&sessions[0]).await.is_ok());
+        let res = keygate.session.auth_context(&sessions[1]).await;
+        assert!(matches!(res, Err(APIError::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn test_link_accounts() {
+        let provider = crate::upstream::mock::MockProvider::start().await;
+        let keygate = test_keygate(KeygateConfig {
+            upstream_providers: vec![provider.config("mock")],
+            ..Default::default()
+        })
+        .await;
+        let password_hash = keygate_utils::hash::password("correct horse").unwrap();
+        let mut identities = vec![];
+        for name in ["linker", "other"] {
+            let identity = keygate
+                .identity
+                .create(CreateIdentity {
+                    username: Some(name),
+                    primary_email: Some(&format!("{}@keygate.io", name)),
+                    password_hash: Some(&password_hash),
+                })
+                .await
+                .unwrap();
+            identities.push(identity.id);
+        }
+        let id = &identities[0];
+
+        let password = LoginMethods::default().get(&LoginStep::PASSWORD).unwrap();
+        let start_session = |auth_time| {
+            let keygate = keygate.clone();
+            let password = password.clone();
+            let id = id.clone();
+            async move {
+                keygate
+                    .session
+                    .start(
+                        &id,
+                        None,
+                        &AuthContext::from_methods(std::slice::from_ref(&password), auth_time),
+                    )
+                    .await
+                    .unwrap()
+            }
+        };
+        let now = time::OffsetDateTime::now_utc();
+        let session_id = start_session(now).await;
+        let old_session_id = start_session(now - time::Duration::hours(1)).await;
+
+        let link = |sub: &'static str, email: &'static str| {
+            let keygate = keygate.clone();
+            let provider = provider.clone();
+            let session_id = session_id.clone();
+            let id = id.clone();
+            async move {
+                let url = keygate
+                    .identity
+                    .link_account_start(&id, &session_id, "mock")
+                    .await
+                    .unwrap();
+                provider.authorize(&url, sub, serde_json::json!({ "sub": sub, "email": email }));
+                let state = reqwest::Url::parse(&url)
+                    .unwrap()
+                    .query_pairs()
+                    .find(|(key, _)| key == "state")
+                    .unwrap()
+                    .1
+                    .to_string();
+                keygate
+                    .identity
+                    .link_account_complete(&id, &state, sub)
+                    .await
+            }
+        };
+
+        // linking needs a recent authentication
+        let res = keygate
+            .identity
+            .link_account_start(id, &old_session_id, "mock")
+            .await;
+        assert!(matches!(res, Err(APIError::Unauthenticated(_))));
+
+        let account = link("sub-1", "linker@example.com").await.unwrap();
+        assert_eq!(account.subject, "sub-1");
+        let res = link("sub-1", "linker@example.com").await;
+        assert!(matches!(res, Err(APIError::AlreadyExists(_))));
+
+        // the email of a different identity isn't merged into this one
+        let res = link("sub-2", "other@keygate.io").await;
+        assert!(matches!(res, Err(APIError::AlreadyExists(_))));
+        assert_eq!(keygate.identity.linked_accounts(id).await.unwrap().len(), 1);
+
+        // without a password, the last linked account is the only way to log in
+        sqlx::query!("UPDATE Identity SET password_hash = NULL WHERE id = $1", id)
+            .execute(&keygate.inner.db)
+            .await
+            .unwrap();
+        let res = keygate
+            .identity
+            .unlink_account(id, &session_id, &account.id)
+            .await;
+        assert!(matches!(res, Err(APIError::PermissionDenied(_))));
+
+        let second = link("sub-3", "linker@example.com").await.unwrap();
+        keygate
+            .identity
+            .unlink_account(id, &session_id, &account.id)
+            .await
+            .unwrap();
+        let accounts = keygate.identity.linked_accounts(id).await.unwrap();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].id, second.id);
+
+        let res = keygate
+            .identity
+            .unlink_account(&identities[1], &session_id, &second.id)
+            .await;
+        assert!(res.is_err());
+
+        // codes sent to the verified email are another way to log in
+        let mut settings = keygate.inner.settings.global().await.unwrap();
+        settings.email_code = Some(models::EmailCodeSettings {
+            code_length: 6,
+            token_expires_in: time::Duration::minutes(5),
+            max_attempts: 3,
+            resend_cooldown: time::Duration::ZERO,
+        });
+        keygate
+            .inner
+            .settings
+            .update_global(settings)
+            .await
+            .unwrap();
+        let res = keygate
+            .identity
+            .unlink_account(id, &session_id, &second.id)
+            .await;
+        assert!(matches!(res, Err(APIError::PermissionDenied(_))));
+        sqlx::query!("UPDATE Email SET verified = true WHERE email = 'linker@keygate.io'")
+            .execute(&keygate.inner.db)
+            .await
+            .unwrap();
+        keygate
+            .identity
+            .unlink_account(id, &session_id, &second.id)
+            .await
+            .unwrap();
+        assert!(keygate
+            .identity
+            .linked_accounts(id)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_email_verification() {
+        let keygate = test_keygate(KeygateConfig::default()).await;
+        let mailer = crate::mail::TestMailer::default();
+        keygate.set_mailer(mailer.clone());
+        let mut settings = keygate.inner.settings.global().await.unwrap();
+        settings.email_verification = models::EmailVerification::Optional {
+            token_expires_in: time::Duration::hours(1),
+        };
+        keygate
+            .inner
+            .settings
+            .update_global(settings)
+            .await
+            .unwrap();
+        let code = |mailer: &crate::mail::TestMailer| {
+            let message = mailer.0.lock().unwrap().pop().unwrap();
+            let (_, code) = message.body.split_once("?code=").unwrap();
+            code.to_string()
+        };
+
+        let password_hash = keygate_utils::hash::password("correct horse").unwrap();
+        let identity = keygate
+            .identity
+            .create(CreateIdentity {
+                username: Some("verifier"),
+                primary_email: Some("verifier@keygate.io"),
+                password_hash: Some(&password_hash),
+            })
+            .await
+            .unwrap();
+        let first_code = code(&mailer);
+        let profile = keygate.identity.profile(&identity.id).await.unwrap();
+        assert!(!profile.email_verified);
+
+        // within the cooldown no second email is sent
+        keygate
+            .identity
+            .resend_verification("Verifier@keygate.io")
+            .await
+            .unwrap();
+        assert!(mailer.0.lock().unwrap().is_empty());
+
+        sqlx::query!("UPDATE Email SET last_verification_request = NULL")
+            .execute(&keygate.inner.db)
+            .await
+            .unwrap();
+        keygate
+            .identity
+            .resend_verification("verifier@keygate.io")
+            .await
+            .unwrap();
+        let second_code = code(&mailer);
+
+        // only the latest code is valid, and only once
+        let res = keygate.identity.verify_email(&first_code).await;
+        assert!(matches!(res, Err(APIError::InvalidArgument(_))));
+        let verified = keygate.identity.verify_email(&second_code).await.unwrap();
+        assert_eq!(verified.id, identity.id);
+        assert!(keygate.identity.verify_email(&second_code).await.is_err());
+        let profile = keygate.identity.profile(&identity.id).await.unwrap();
+        assert!(profile.email_verified);
+
+        // verified addresses and unknown addresses get nothing
+        keygate
+            .identity
+            .resend_verification("verifier@keygate.io")
+            .await
+            .unwrap();
+        keygate
+            .identity
+            .resend_verification("unknown@keygate.io")
+            .await
+            .unwrap();
+        assert!(mailer.0.lock().unwrap().is_empty());
+
+        // admins can skip the code
+        let other = keygate
+            .identity
+            .create(CreateIdentity {
+                username: Some("trusted"),
+                primary_email: Some("trusted@keygate.io"),
+                password_hash: Some(&password_hash),
+            })
+            .await
+            .unwrap();
+        let res = keygate
+            .identity
+            .set_email_verified(&identity.id, "trusted@keygate.io")
+            .await;
+        assert!(matches!(res, Err(APIError::NotFound(_))));
+        keygate
+            .identity
+            .set_email_verified(&other.id, "trusted@keygate.io")
+            .await
+            .unwrap();
+        assert!(
+            keygate
+                .identity
+                .profile(&other.id)
+                .await
+                .unwrap()
+                .email_verified
+        );
+    }
 }
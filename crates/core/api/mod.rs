@@ -1,11 +1,17 @@
+pub mod audit;
 pub mod auth;
+pub mod domain;
 pub mod identity;
 pub mod session;
+mod throttle;
 
+pub use audit::Audit;
 pub use auth::Auth;
+pub use domain::Domains;
 pub use identity::Identity;
 pub use session::Session;
 
+use keygate_utils::password_policy::PasswordIssue;
 use thiserror::Error;
 #[derive(Error, Debug)]
 pub enum APIError {
@@ -41,6 +47,15 @@ pub enum APIError {
 
     #[error("The request does not have valid authentication credentials.")]
     Unauthenticated(String),
+
+    #[error("The password does not meet the password policy.")]
+    InvalidPassword(Vec<PasswordIssue>),
+}
+
+impl From<crate::settings::SettingsError> for APIError {
+    fn from(e: crate::settings::SettingsError) -> Self {
+        Self::Internal(e.to_string())
+    }
 }
 
 impl APIError {
@@ -53,6 +68,9 @@ impl APIError {
     fn internal(text: &str) -> Self {
         Self::Internal(text.to_string())
     }
+    fn permission_denied(text: &str) -> Self {
+        Self::PermissionDenied(text.to_string())
+    }
 }
 
 pub enum UserIdentifier {
@@ -2,20 +2,74 @@ use std::sync::Arc;
 
 use keygate_utils::{
     random::secure_random_id,
-    tokens::{RawAccessToken, RawRefreshToken},
+    tokens::{
+        formats::paseto::Paseto, GenerateAccessToken, GenerateRefreshToken, RawAccessToken,
+        RawRefreshToken, TokenFormat,
+    },
 };
 pub use keygate_utils::{tokens::AccessToken, tokens::RefreshToken};
+use time::{Duration, OffsetDateTime};
 
-use crate::{database::DatabasePool, KeygateInternal};
+use crate::{database::DatabasePool, login::LoginMethod, KeygateInternal};
 
 use super::APIError;
 
+/// `acr` of sessions that authenticated with a single factor
+pub const ACR_SINGLE_FACTOR: &str = "1";
+/// `acr` of sessions that authenticated with a second factor
+pub const ACR_MULTI_FACTOR: &str = "2";
+
+/// Prefix of issued access tokens, sent as `Bearer kg0s.<token>`
+pub const ACCESS_TOKEN_PREFIX: &str = "kg0s.";
+/// Prefix of issued refresh tokens, sent as `Bearer kg0r.<token>`
+pub const REFRESH_TOKEN_PREFIX: &str = "kg0r.";
+
 #[derive(Debug, Clone)]
 pub struct Session {
     keygate: Arc<KeygateInternal>,
 }
 
-// pub static KEYPAIR: KeygateKeypair = KeygateKeypair::generate(keygate_utils::tokens::Algorithm::Ed25519);
+/// How and when a session last authenticated
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthContext {
+    pub auth_time: OffsetDateTime,
+    /// authentication method references (RFC 8176)
+    pub amr: Vec<String>,
+    pub acr: String,
+}
+
+impl AuthContext {
+    /// Context of a login completed with the given methods
+    pub(crate) fn from_methods(
+        methods: &[Arc<dyn LoginMethod>],
+        auth_time: OffsetDateTime,
+    ) -> Self {
+        let mut amr: Vec<String> = Vec::new();
+        for method in methods.iter().filter_map(|method| method.amr()) {
+            if !amr.iter().any(|m| m == method) {
+                amr.push(method.to_string());
+            }
+        }
+
+        let acr = match methods.len() > 1 {
+            true => {
+                amr.push("mfa".to_string());
+                ACR_MULTI_FACTOR
+            }
+            false => ACR_SINGLE_FACTOR,
+        };
+
+        Self {
+            auth_time,
+            amr,
+            acr: acr.to_string(),
+        }
+    }
+
+    pub fn multi_factor(&self) -> bool {
+        self.acr == ACR_MULTI_FACTOR
+    }
+}
 
 impl Session {
     pub(crate) fn new(keygate: Arc<KeygateInternal>) -> Self {
@@ -26,26 +80,288 @@ impl Session {
         &self.keygate.db
     }
 
+    /// Start a session for a new identity that just signed up and issue its tokens
     pub async fn create(
         &self,
-        identity_id: String,
+        identity_id: &str,
+        application_id: &str,
+        ip_address: Option<&str>,
     ) -> Result<(RawRefreshToken, RawAccessToken), APIError> {
+        let context = AuthContext {
+            auth_time: OffsetDateTime::now_utc(),
+            amr: vec![],
+            acr: ACR_SINGLE_FACTOR.to_string(),
+        };
+        let session_id = self.start(identity_id, ip_address, &context).await?;
+        self.issue_tokens(&session_id, application_id).await
+    }
+
+    /// Tokens for `application_id` that carry the current [`AuthContext`] of the session
+    pub(crate) async fn issue_tokens(
+        &self,
+        session_id: &str,
+        application_id: &str,
+    ) -> Result<(RawRefreshToken, RawAccessToken), APIError> {
+        let settings = self.keygate.settings.global().await?;
+        let application = self
+            .keygate
+            .settings
+            .app(application_id)
+            .await?
+            .ok_or(APIError::not_found("Application not found"))?;
+        let keypair = self
+            .keygate
+            .secrets
+            .signing_keypair()
+            .ok_or(APIError::internal("No signing key"))?;
+
+        let access_token = self
+            .access_token(
+                session_id,
+                application_id,
+                application
+                    .access_token_expires_in
+                    .unwrap_or(settings.default_access_token_expires_in),
+            )
+            .await?;
+        let refresh_token = GenerateRefreshToken {
+            duration: application
+                .refresh_token_expires_in
+                .unwrap_or(settings.default_refresh_token_expires_in),
+            audience: access_token.audience.clone(),
+            subject: access_token.subject.clone(),
+            issuer: access_token.issuer.clone(),
+            session_id: session_id.to_string(),
+        };
+
+        let failed = |e: keygate_utils::tokens::TokenError| {
+            APIError::internal(&format!("Failed to generate token: {}", e))
+        };
+        let access_token =
+            Paseto::generate_access_token(keypair.clone(), access_token).map_err(failed)?;
+        let refresh_token =
+            Paseto::generate_refresh_token(keypair, refresh_token).map_err(failed)?;
+
+        Ok((
+            RawRefreshToken(format!("{}{}", REFRESH_TOKEN_PREFIX, refresh_token.0)),
+            RawAccessToken(format!("{}{}", ACCESS_TOKEN_PREFIX, access_token.0)),
+        ))
+    }
+
+    /// Verify a token issued by [`Session::issue_tokens`], without its prefix. The session
+    /// of the token must not be revoked.
+    pub async fn verify_access_token(&self, token: &str) -> Result<AccessToken, APIError> {
+        let public_key = self.token_public_key(token).await?;
+        let token = Paseto::verify_access_token(&public_key, token)
+            .map_err(|_| APIError::Unauthenticated("Invalid token".into()))?;
+        self.ensure_token_session(&token.session_id, &token.subject, &token.issuer)
+            .await?;
+        Ok(token)
+    }
+
+    /// [`Session::verify_access_token`] for refresh tokens
+    pub async fn verify_refresh_token(&self, token: &str) -> Result<RefreshToken, APIError> {
+        let public_key = self.token_public_key(token).await?;
+        let token = Paseto::verify_refresh_token(&public_key, token)
+            .map_err(|_| APIError::Unauthenticated("Invalid token".into()))?;
+        self.ensure_token_session(&token.session_id, &token.subject, &token.issuer)
+            .await?;
+        Ok(token)
+    }
+
+    // the stored public key of the key the token names, if it is still valid
+    async fn token_public_key(&self, token: &str) -> Result<Vec<u8>, APIError> {
+        let invalid = || APIError::Unauthenticated("Invalid token".into());
+        let key_id =
+            keygate_utils::tokens::formats::paseto::get_key_id(token).map_err(|_| invalid())?;
+        let key = self
+            .keygate
+            .secrets
+            .get_public_key(&key_id)
+            .await
+            .map_err(|e| APIError::internal(&e.to_string()))?
+            .ok_or_else(invalid)?;
+
+        if key.revoked_at.is_some() || key.valid_until < OffsetDateTime::now_utc() {
+            return Err(invalid());
+        }
+        Ok(key.key.to_bytes())
+    }
+
+    async fn ensure_token_session(
+        &self,
+        session_id: &str,
+        subject: &str,
+        issuer: &str,
+    ) -> Result<(), APIError> {
+        let identity_id = sqlx::query!(
+            "SELECT identity_id FROM Session WHERE id = $1 AND revoked_at IS NULL",
+            session_id
+        )
+        .fetch_optional(self.db())
+        .await?
+        .map(|session| session.identity_id);
+
+        if identity_id.as_deref() != Some(subject)
+            || issuer != self.keygate.config.server.keygate_domain
+        {
+            return Err(APIError::Unauthenticated("Invalid token".into()));
+        }
+        Ok(())
+    }
+
+    /// Store the session of a completed login
+    pub(crate) async fn start(
+        &self,
+        identity_id: &str,
+        ip_address: Option<&str>,
+        context: &AuthContext,
+    ) -> Result<String, APIError> {
         let session_id = secure_random_id();
-        let exp = time::OffsetDateTime::now_utc() + time::Duration::minutes(15);
-        // let refresh_token = KEYPAIR;
+        let refresh_token = secure_random_id();
+        let now = OffsetDateTime::now_utc();
+        let amr = context.amr.join(",");
 
-        // sqlx::query!(
-        //     r#"
-        //     INSERT INTO Sessions (
+        sqlx::query!(
+            "INSERT INTO Session (id, created_at, updated_at, initial_ip_address, node_id, refresh_token, identity_id, token_type, auth_time, amr, acr) VALUES ($1, $2, $2, $3, $4, $5, $6, 'paseto', $7, $8, $9)",
+            session_id,
+            now,
+            ip_address,
+            self.keygate.config.node_id,
+            refresh_token,
+            identity_id,
+            context.auth_time,
+            amr,
+            context.acr
+        )
+        .execute(self.db())
+        .await?;
 
-        //     )
-        //     VALUES (?, ?, ?, ?, ?, ?)
-        // "#,
-        // )
-        // .execute(self.db())
-        // .await?;
+        Ok(session_id)
+    }
 
-        unimplemented!()
+    /// Record a re-authentication of the session, this never lowers the `acr`
+    pub(crate) async fn reauthenticated(
+        &self,
+        session_id: &str,
+        context: &AuthContext,
+    ) -> Result<(), APIError> {
+        let current = self.auth_context(session_id).await?;
+        let acr = match current.multi_factor() {
+            true => ACR_MULTI_FACTOR,
+            false => context.acr.as_str(),
+        };
+        let amr = context.amr.join(",");
+
+        sqlx::query!(
+            "UPDATE Session SET auth_time = $1, amr = $2, acr = $3, updated_at = $1 WHERE id = $4",
+            context.auth_time,
+            amr,
+            acr,
+            session_id
+        )
+        .execute(self.db())
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn auth_context(&self, session_id: &str) -> Result<AuthContext, APIError> {
+        let session = sqlx::query!(
+            r#"SELECT auth_time, amr, acr, created_at as "created_at!: OffsetDateTime" FROM Session WHERE id = $1 AND revoked_at IS NULL"#,
+            session_id
+        )
+        .fetch_optional(self.db())
+        .await?
+        .ok_or(APIError::not_found("Session not found"))?;
+
+        Ok(AuthContext {
+            auth_time: session.auth_time.unwrap_or(session.created_at),
+            amr: session
+                .amr
+                .split(',')
+                .filter(|method| !method.is_empty())
+                .map(String::from)
+                .collect(),
+            acr: session.acr,
+        })
+    }
+
+    /// Errors with [`APIError::Unauthenticated`] unless the session authenticated within
+    /// `max_age` (and with a second factor if `second_factor` is set). The session can
+    /// be raised to the required level with [`super::Auth::reauth_create`].
+    pub async fn require_auth(
+        &self,
+        session_id: &str,
+        max_age: Duration,
+        second_factor: bool,
+    ) -> Result<AuthContext, APIError> {
+        let context = self.auth_context(session_id).await?;
+
+        if context.auth_time + max_age < OffsetDateTime::now_utc()
+            || (second_factor && !context.multi_factor())
+        {
+            return Err(APIError::Unauthenticated(
+                "Re-authentication required".to_string(),
+            ));
+        }
+
+        Ok(context)
+    }
+
+    /// [`Session::require_auth`] with the configured window for sensitive actions
+    pub async fn require_recent_auth(
+        &self,
+        session_id: &str,
+        second_factor: bool,
+    ) -> Result<AuthContext, APIError> {
+        let settings = self.keygate.settings.global().await?;
+        self.require_auth(session_id, settings.reauthenticate_after, second_factor)
+            .await
+    }
+
+    /// Revoke all sessions of the identity, except `keep` if set
+    pub async fn revoke_all(&self, identity_id: &str, keep: Option<&str>) -> Result<(), APIError> {
+        let now = OffsetDateTime::now_utc();
+        sqlx::query!(
+            "UPDATE Session SET revoked_at = $1 WHERE identity_id = $2 AND revoked_at IS NULL AND id IS NOT $3",
+            now,
+            identity_id,
+            keep
+        )
+        .execute(self.db())
+        .await?;
+
+        Ok(())
+    }
+
+    /// Claims of an access token for the session
+    pub async fn access_token(
+        &self,
+        session_id: &str,
+        audience: &str,
+        duration: Duration,
+    ) -> Result<GenerateAccessToken, APIError> {
+        let identity_id = sqlx::query!(
+            "SELECT identity_id FROM Session WHERE id = $1 AND revoked_at IS NULL",
+            session_id
+        )
+        .fetch_optional(self.db())
+        .await?
+        .ok_or(APIError::not_found("Session not found"))?
+        .identity_id;
+        let context = self.auth_context(session_id).await?;
+
+        Ok(GenerateAccessToken {
+            duration,
+            audience: audience.to_string(),
+            subject: identity_id,
+            issuer: self.keygate.config.server.keygate_domain.clone(),
+            session_id: session_id.to_string(),
+            auth_time: context.auth_time.unix_timestamp(),
+            amr: context.amr,
+            acr: context.acr,
+        })
     }
 
     async fn rotate_refresh(
@@ -63,3 +379,25 @@ impl Session {
         unimplemented!()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{api::auth::LoginStep, login::LoginMethods};
+
+    #[test]
+    fn test_auth_context_from_methods() {
+        let now = OffsetDateTime::now_utc();
+        let methods = LoginMethods::default();
+        let password = methods.get(&LoginStep::PASSWORD).unwrap();
+        let email_code = methods.get(&LoginStep::EMAIL_CODE).unwrap();
+
+        let context = AuthContext::from_methods(std::slice::from_ref(&password), now);
+        assert_eq!(context.amr, vec!["pwd"]);
+        assert!(!context.multi_factor());
+
+        let context = AuthContext::from_methods(&[password, email_code], now);
+        assert_eq!(context.amr, vec!["pwd", "otp", "mfa"]);
+        assert!(context.multi_factor());
+    }
+}
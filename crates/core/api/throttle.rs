@@ -0,0 +1,313 @@
+use std::sync::Arc;
+
+use keygate_utils::join_keys;
+use time::OffsetDateTime;
+
+use super::APIError;
+use crate::{
+    database::{
+        models::{LoginThrottle as LoginThrottleRow, LoginThrottleSettings},
+        DatabasePool,
+    },
+    KeygateInternal,
+};
+
+// Failed login attempts are counted per identity and per ip address. The counters
+// are stored in the database so all nodes share the same view.
+#[derive(Debug, Clone)]
+pub(crate) struct LoginThrottle {
+    keygate: Arc<KeygateInternal>,
+}
+
+pub(crate) fn identity_key(identity_id: &str) -> String {
+    join_keys!("identity", identity_id)
+}
+
+pub(crate) fn ip_key(ip_address: &str) -> String {
+    join_keys!("ip", ip_address)
+}
+
+impl LoginThrottle {
+    pub(crate) fn new(keygate: Arc<KeygateInternal>) -> Self {
+        Self { keygate }
+    }
+
+    fn db(&self) -> &DatabasePool {
+        &self.keygate.db
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<LoginThrottleRow>, APIError> {
+        let row = sqlx::query_as!(
+            LoginThrottleRow,
+            "SELECT * FROM LoginThrottle WHERE key = $1",
+            key
+        )
+        .fetch_optional(self.db())
+        .await?;
+
+        Ok(row)
+    }
+
+    /// Errors if the identity or ip address has to wait before the next attempt
+    pub(crate) async fn check(
+        &self,
+        identity_id: &str,
+        ip_address: Option<&str>,
+        settings: &LoginThrottleSettings,
+    ) -> Result<(), APIError> {
+        let now = OffsetDateTime::now_utc();
+
+        if let Some(row) = self.get(&identity_key(identity_id)).await? {
+            if blocked_until(&row, settings, true).is_some_and(|until| until > now) {
+                return Err(APIError::permission_denied(
+                    "Too many failed attempts, try again later",
+                ));
+            }
+        }
+
+        if let Some(ip_address) = ip_address {
+            self.check_ip(ip_address, settings).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Errors if the ip address is locked
+    pub(crate) async fn check_ip(
+        &self,
+        ip_address: &str,
+        settings: &LoginThrottleSettings,
+    ) -> Result<(), APIError> {
+        let now = OffsetDateTime::now_utc();
+        if let Some(row) = self.get(&ip_key(ip_address)).await? {
+            if blocked_until(&row, settings, false).is_some_and(|until| until > now) {
+                return Err(APIError::permission_denied(
+                    "Too many failed attempts, try again later",
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Count an attempt of the ip address that isn't tied to an identity
+    pub(crate) async fn record_ip(
+        &self,
+        ip_address: &str,
+        settings: &LoginThrottleSettings,
+    ) -> Result<(), APIError> {
+        self.increment(&ip_key(ip_address), settings.ip_lockout_after, settings)
+            .await
+    }
+
+    pub(crate) async fn record_failure(
+        &self,
+        identity_id: &str,
+        ip_address: Option<&str>,
+        settings: &LoginThrottleSettings,
+    ) -> Result<(), APIError> {
+        self.increment(&identity_key(identity_id), settings.lockout_after, settings)
+            .await?;
+
+        if let Some(ip_address) = ip_address {
+            self.increment(&ip_key(ip_address), settings.ip_lockout_after, settings)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn increment(
+        &self,
+        key: &str,
+        lockout_after: i64,
+        settings: &LoginThrottleSettings,
+    ) -> Result<(), APIError> {
+        let now = OffsetDateTime::now_utc();
+        let window_start = now - settings.failure_window;
+        let locked_until = now + settings.lockout_duration;
+
+        // the counter is reset once the last failure is outside of the window
+        let failures = sqlx::query!(
+            r#"
+                INSERT INTO LoginThrottle (key, failures, last_failure_at) VALUES ($1, 1, $2)
+                ON CONFLICT (key) DO UPDATE SET
+                    failures = CASE WHEN last_failure_at < $3 THEN 1 ELSE failures + 1 END,
+                    last_failure_at = $2
+                RETURNING failures
+            "#,
+            key,
+            now,
+            window_start
+        )
+        .fetch_one(self.db())
+        .await?
+        .failures;
+
+        if failures >= lockout_after {
+            sqlx::query!(
+                "UPDATE LoginThrottle SET locked_until = $1 WHERE key = $2",
+                locked_until,
+                key
+            )
+            .execute(self.db())
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) async fn reset(&self, key: &str) -> Result<bool, APIError> {
+        let res = sqlx::query!("DELETE FROM LoginThrottle WHERE key = $1", key)
+            .execute(self.db())
+            .await?;
+
+        Ok(res.rows_affected() > 0)
+    }
+}
+
+// time until the next attempt is allowed, delays are only applied to identities
+fn blocked_until(
+    row: &LoginThrottleRow,
+    settings: &LoginThrottleSettings,
+    with_delay: bool,
+) -> Option<OffsetDateTime> {
+    let now = OffsetDateTime::now_utc();
+    if let Some(locked_until) = row.locked_until.filter(|locked_until| *locked_until > now) {
+        return Some(locked_until);
+    }
+
+    // after the lock expired, the delays still apply
+    if row.last_failure_at < now - settings.failure_window {
+        return None;
+    }
+
+    if !with_delay || row.failures < settings.delay_after {
+        return None;
+    }
+
+    let exponent = (row.failures - settings.delay_after).clamp(0, 16) as u32;
+    let delay = (settings.base_delay * 2_i32.pow(exponent)).min(settings.max_delay);
+    Some(row.last_failure_at + delay)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_keygate, KeygateConfig};
+    use time::Duration;
+
+    fn row(failures: i64, last_failure: Duration) -> LoginThrottleRow {
+        LoginThrottleRow {
+            key: identity_key("test"),
+            failures,
+            last_failure_at: OffsetDateTime::now_utc() - last_failure,
+            locked_until: None,
+        }
+    }
+
+    #[test]
+    fn test_blocked_until() {
+        let settings = LoginThrottleSettings::default();
+        let now = OffsetDateTime::now_utc();
+
+        assert!(blocked_until(&row(1, Duration::ZERO), &settings, true).is_none());
+
+        // delays double with every failure
+        let first = blocked_until(&row(3, Duration::ZERO), &settings, true).unwrap();
+        let second = blocked_until(&row(4, Duration::ZERO), &settings, true).unwrap();
+        assert!(first > now && second > first);
+
+        // and are capped
+        let capped = blocked_until(&row(9, Duration::ZERO), &settings, true).unwrap();
+        assert!(capped <= OffsetDateTime::now_utc() + settings.max_delay);
+
+        // ip addresses are only locked, never delayed
+        assert!(blocked_until(&row(5, Duration::ZERO), &settings, false).is_none());
+
+        // failures outside of the window are ignored
+        assert!(blocked_until(&row(9, Duration::hours(2)), &settings, true).is_none());
+
+        // expired locks fall back to the delay
+        let expired = LoginThrottleRow {
+            locked_until: Some(now - Duration::minutes(1)),
+            ..row(10, Duration::ZERO)
+        };
+        assert!(blocked_until(&expired, &settings, true).is_some_and(|until| until > now));
+        assert!(blocked_until(&expired, &settings, false).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_login_throttle() {
+        let keygate = test_keygate(KeygateConfig::default()).await;
+        let throttle = LoginThrottle::new(keygate.inner.clone());
+        let settings = LoginThrottleSettings {
+            delay_after: 2,
+            base_delay: Duration::minutes(1),
+            lockout_after: 4,
+            ip_lockout_after: 7,
+            ..Default::default()
+        };
+        let ip = Some("127.0.0.1");
+        let is_blocked = |identity_id: &'static str, ip: Option<&'static str>| {
+            let throttle = throttle.clone();
+            let settings = settings.clone();
+            async move { throttle.check(identity_id, ip, &settings).await.is_err() }
+        };
+
+        throttle
+            .record_failure("alice", ip, &settings)
+            .await
+            .unwrap();
+        assert!(!is_blocked("alice", ip).await);
+        throttle
+            .record_failure("alice", ip, &settings)
+            .await
+            .unwrap();
+        assert!(is_blocked("alice", ip).await);
+        // other identities from the same address aren't delayed
+        assert!(!is_blocked("bob", ip).await);
+
+        assert!(throttle.reset(&identity_key("alice")).await.unwrap());
+        assert!(!is_blocked("alice", ip).await);
+        assert!(!throttle.reset(&identity_key("alice")).await.unwrap());
+
+        for _ in 0..4 {
+            throttle
+                .record_failure("alice", ip, &settings)
+                .await
+                .unwrap();
+        }
+        let row = throttle.get(&identity_key("alice")).await.unwrap().unwrap();
+        assert!(row.locked_until.is_some());
+
+        // once the lock expired, the delay of the last failure still applies
+        let earlier = OffsetDateTime::now_utc() - Duration::minutes(2);
+        let key = identity_key("alice");
+        sqlx::query!(
+            "UPDATE LoginThrottle SET locked_until = $1 WHERE key = $2",
+            earlier,
+            key
+        )
+        .execute(&keygate.inner.db)
+        .await
+        .unwrap();
+        assert!(is_blocked("alice", None).await);
+        let long_ago = OffsetDateTime::now_utc() - Duration::minutes(10);
+        sqlx::query!(
+            "UPDATE LoginThrottle SET last_failure_at = $1 WHERE key = $2",
+            long_ago,
+            key
+        )
+        .execute(&keygate.inner.db)
+        .await
+        .unwrap();
+        assert!(!is_blocked("alice", None).await);
+
+        // the address is locked after failures across identities
+        assert!(!is_blocked("carol", ip).await);
+        throttle.record_failure("bob", ip, &settings).await.unwrap();
+        assert!(is_blocked("carol", ip).await);
+        assert!(!is_blocked("carol", Some("127.0.0.2")).await);
+    }
+}
@@ -0,0 +1,152 @@
+use std::{fmt::Debug, sync::Arc, time::Duration};
+
+use keygate_utils::pwned::{PwnedApi, PwnedError, PwnedFile};
+
+use crate::{api::APIError, config::BreachedPasswordsConfig, login::BoxFuture};
+
+/// Checks new passwords against passwords known from data breaches, on signup,
+/// password changes and resets if [`crate::database::models::GlobalSettings::check_haveibeenpwned`] is set
+pub trait BreachedPasswordChecker: Send + Sync + Debug {
+    /// Whether the password appeared in a breach
+    fn is_breached<'a>(&'a self, password: &'a str) -> BoxFuture<'a, Result<bool, APIError>>;
+}
+
+/// Asks the k-anonymity range API of Have I Been Pwned or a mirror of it
+#[derive(Debug, Clone, Default)]
+pub struct PwnedApiChecker(PwnedApi);
+
+impl PwnedApiChecker {
+    pub fn new(url: impl Into<String>, timeout: Duration) -> Self {
+        Self(PwnedApi::new(url, timeout))
+    }
+}
+
+impl BreachedPasswordChecker for PwnedApiChecker {
+    fn is_breached<'a>(&'a self, password: &'a str) -> BoxFuture<'a, Result<bool, APIError>> {
+        Box::pin(async move { pwned_result(self.0.check(password).await) })
+    }
+}
+
+/// Searches a local file of SHA-1 hashes, for deployments that can't reach the API
+#[derive(Debug, Clone)]
+pub struct HashFileChecker(Arc<PwnedFile>);
+
+impl HashFileChecker {
+    pub fn open(path: &str) -> std::io::Result<Self> {
+        PwnedFile::open(path).map(|file| Self(Arc::new(file)))
+    }
+}
+
+impl BreachedPasswordChecker for HashFileChecker {
+    fn is_breached<'a>(&'a self, password: &'a str) -> BoxFuture<'a, Result<bool, APIError>> {
+        // the search reads from disk, it must not block the runtime
+        let file = self.0.clone();
+        let password = password.to_string();
+        Box::pin(async move {
+            let result = tokio::task::spawn_blocking(move || file.check(&password))
+                .await
+                .map_err(|e| APIError::Internal(format!("Failed to check password: {}", e)))?;
+            pwned_result(result)
+        })
+    }
+}
+
+/// Accepts every password
+#[derive(Debug, Clone, Default)]
+pub struct NoopChecker;
+
+impl BreachedPasswordChecker for NoopChecker {
+    fn is_breached<'a>(&'a self, _password: &'a str) -> BoxFuture<'a, Result<bool, APIError>> {
+        Box::pin(async { Ok(false) })
+    }
+}
+
+fn pwned_result(result: Result<(), PwnedError>) -> Result<bool, APIError> {
+    match result {
+        Ok(()) => Ok(false),
+        Err(PwnedError::Pwned) => Ok(true),
+        Err(PwnedError::Internal(e)) => Err(APIError::Internal(e)),
+    }
+}
+
+pub(crate) fn checker(
+    config: &BreachedPasswordsConfig,
+) -> std::io::Result<Box<dyn BreachedPasswordChecker>> {
+    Ok(match config {
+        BreachedPasswordsConfig::Api { url, timeout_ms } => Box::new(PwnedApiChecker::new(
+            url.as_str(),
+            Duration::from_millis(*timeout_ms),
+        )),
+        BreachedPasswordsConfig::File { path } => Box::new(HashFileChecker::open(path)?),
+        BreachedPasswordsConfig::Disabled => Box::new(NoopChecker),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        api::auth::{SignupData, SignupResponse},
+        test_keygate, KeygateConfig,
+    };
+    use keygate_utils::password_policy::PasswordIssue;
+
+    #[tokio::test]
+    async fn test_breached_password_file() {
+        let keygate = test_keygate(KeygateConfig {
+            breached_passwords: BreachedPasswordsConfig::Disabled,
+            ..Default::default()
+        })
+        .await;
+        let hash: String = keygate_utils::sha1::hash("correct horse")
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect();
+        let path = std::env::temp_dir().join(format!(
+            "keygate-breached-{}.txt",
+            keygate_utils::random::secure_random_id()
+        ));
+        std::fs::write(&path, format!("{}:42\r\n", hash)).unwrap();
+        keygate
+            .set_breached_password_checker(HashFileChecker::open(path.to_str().unwrap()).unwrap());
+
+        let signup = |username: &'static str, password: &'static str| {
+            let keygate = keygate.clone();
+            async move {
+                keygate
+                    .auth
+                    .signup(
+                        SignupData {
+                            username: Some(username),
+                            email: Some(&format!("{}@keygate.io", username)),
+                            password: Some(password),
+                            ..Default::default()
+                        },
+                        std::net::IpAddr::from([127, 0, 0, 1]),
+                    )
+                    .await
+            }
+        };
+
+        // the list is only consulted once the settings ask for it
+        let res = signup("first", "correct horse").await;
+        assert!(matches!(res, Ok(SignupResponse::Success { .. })));
+
+        let mut settings = keygate.inner.settings.global().await.unwrap();
+        settings.check_haveibeenpwned = true;
+        keygate
+            .inner
+            .settings
+            .update_global(settings)
+            .await
+            .unwrap();
+        let res = signup("second", "correct horse").await;
+        assert!(
+            matches!(res, Err(APIError::InvalidPassword(reasons)) if reasons == vec![PasswordIssue::Breached])
+        );
+        let res = signup("third", "battery staple").await;
+        assert!(matches!(res, Ok(SignupResponse::Success { .. })));
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
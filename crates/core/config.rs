@@ -1,4 +1,6 @@
+use keygate_utils::{hash::PasswordHashParams, legacy_hash::FirebaseScryptParams};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Environment {
@@ -48,6 +50,275 @@ pub struct Config {
 
     /// server configuration
     pub server: ServerConfig,
+
+    /// hide whether an identity exists from unauthenticated clients,
+    /// defaults to true in production
+    pub anti_enumeration: Option<bool>,
+
+    /// how passwords are hashed
+    pub password_hashing: PasswordHashingConfig,
+
+    /// check unknown users against the user store of a previous system on login
+    pub legacy_user_store: Option<LegacyUserStoreConfig>,
+
+    /// OpenID Connect providers users can log in with, e.g. Google or a company's IdP
+    pub upstream_providers: Vec<UpstreamProviderConfig>,
+
+    /// SAML identity providers users can log in with, like upstream providers.
+    /// Their ids share a namespace with [`Config::upstream_providers`].
+    pub saml_connections: Vec<SamlConnectionConfig>,
+
+    /// key AuthnRequests to SAML identity providers are signed with
+    pub saml_signing_key: Option<SamlSigningKeyConfig>,
+
+    /// DNS-over-HTTPS server (JSON API) used to verify the ownership of SSO domains,
+    /// e.g. `https://cloudflare-dns.com/dns-query`
+    pub dns_over_https: Option<String>,
+
+    /// LDAP or Active Directory server whose users log in with their directory password
+    pub ldap: Option<LdapConfig>,
+
+    /// where new passwords are looked up when the settings enable `check_haveibeenpwned`
+    pub breached_passwords: BreachedPasswordsConfig,
+}
+
+/// An OpenID Connect provider, its endpoints are discovered from the issuer
+#[derive(Clone, Serialize, Deserialize)]
+pub struct UpstreamProviderConfig {
+    /// stored with linked accounts, don't change it once users logged in
+    pub id: String,
+    /// e.g. `https://accounts.google.com`
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    /// `openid` is always requested
+    #[serde(default = "default_upstream_scopes")]
+    pub scopes: Vec<String>,
+    /// where the provider sends users back to, has to pass the `code` and `state`
+    /// query parameters on to the login API
+    pub redirect_uri: String,
+}
+
+fn default_upstream_scopes() -> Vec<String> {
+    vec!["email".to_string(), "profile".to_string()]
+}
+
+impl std::fmt::Debug for UpstreamProviderConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UpstreamProviderConfig")
+            .field("id", &self.id)
+            .field("issuer", &self.issuer)
+            .field("client_id", &self.client_id)
+            .field(
+                "client_secret",
+                &self.client_secret.as_ref().map(|_| "<redacted>"),
+            )
+            .field("scopes", &self.scopes)
+            .field("redirect_uri", &self.redirect_uri)
+            .finish()
+    }
+}
+
+/// A SAML identity provider, e.g. the Okta or Azure AD of a customer
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SamlConnectionConfig {
+    /// stored with linked accounts, don't change it once users logged in
+    pub id: String,
+    /// the `EntityDescriptor` XML of the IdP
+    pub idp_metadata: String,
+    /// our entity id towards the IdP, the audience of its assertions
+    pub entity_id: String,
+    /// where the IdP posts its responses to, usually `/auth/login/saml/acs` of the public API,
+    /// which takes the `SAMLResponse` and `RelayState` form fields directly
+    pub acs_url: String,
+    #[serde(default)]
+    pub attribute_mapping: SamlAttributeMapping,
+    /// emails asserted by the IdP count as verified. Only enable this for IdPs that
+    /// control the domains of the emails they assert.
+    #[serde(default)]
+    pub trust_email: bool,
+}
+
+/// Names of the SAML attributes that fill the fields of new identities.
+/// Users are identified by the `NameID` of the assertion, which should be persistent.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SamlAttributeMapping {
+    pub username: Option<String>,
+    /// defaults to the `NameID` if it's an email address
+    pub email: Option<String>,
+}
+
+/// PEM encoded RSA key and certificate, see [`keygate_utils::saml::SigningKey`]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SamlSigningKeyConfig {
+    /// PKCS#8
+    pub private_key: String,
+    pub certificate: String,
+}
+
+impl std::fmt::Debug for SamlSigningKeyConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SamlSigningKeyConfig")
+            .field("private_key", &"<redacted>")
+            .field("certificate", &self.certificate)
+            .finish()
+    }
+}
+
+/// HTTP endpoint of a legacy user store, see [`crate::legacy::HttpLegacyUserStore`]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct LegacyUserStoreConfig {
+    pub url: String,
+    /// sent as the `Authorization` header
+    pub authorization: Option<String>,
+}
+
+impl std::fmt::Debug for LegacyUserStoreConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LegacyUserStoreConfig")
+            .field("url", &self.url)
+            .field(
+                "authorization",
+                &self.authorization.as_ref().map(|_| "<redacted>"),
+            )
+            .finish()
+    }
+}
+
+/// An LDAP directory users are authenticated against with search-then-bind: the service
+/// account searches for the user, then the user's entry is bound with their password
+#[derive(Clone, Serialize, Deserialize)]
+pub struct LdapConfig {
+    /// `ldap://` or `ldaps://`
+    pub url: String,
+    /// upgrade `ldap://` connections with StartTLS
+    #[serde(default)]
+    pub starttls: bool,
+    /// DN of the service account that searches for users
+    pub bind_dn: String,
+    pub bind_password: String,
+    /// where users are searched, e.g. `ou=people,dc=example,dc=com`
+    pub base_dn: String,
+    /// `{username}` is replaced with what the user logged in with, e.g.
+    /// `(&(objectClass=person)(uid={username}))` or `(sAMAccountName={username})`.
+    /// Match the email too to let users log in with it, e.g. `(|(uid={username})(mail={username}))`.
+    pub user_filter: String,
+    #[serde(default)]
+    pub attribute_mapping: LdapAttributeMapping,
+    /// roles of the members of a group, keyed by the DN of the group
+    #[serde(default)]
+    pub group_roles: BTreeMap<String, String>,
+    /// emails from the directory count as verified
+    #[serde(default)]
+    pub trust_email: bool,
+}
+
+impl std::fmt::Debug for LdapConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LdapConfig")
+            .field("url", &self.url)
+            .field("starttls", &self.starttls)
+            .field("bind_dn", &self.bind_dn)
+            .field("bind_password", &"<redacted>")
+            .field("base_dn", &self.base_dn)
+            .field("user_filter", &self.user_filter)
+            .field("attribute_mapping", &self.attribute_mapping)
+            .field("group_roles", &self.group_roles)
+            .field("trust_email", &self.trust_email)
+            .finish()
+    }
+}
+
+/// Names of the LDAP attributes that fill the fields of new identities
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LdapAttributeMapping {
+    pub username: Option<String>,
+    pub email: Option<String>,
+    /// DNs of the groups the user is a member of
+    pub groups: Option<String>,
+}
+
+impl Default for LdapAttributeMapping {
+    fn default() -> Self {
+        Self {
+            username: Some("uid".into()),
+            email: Some("mail".into()),
+            groups: Some("memberOf".into()),
+        }
+    }
+}
+
+/// A source of passwords known from data breaches
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum BreachedPasswordsConfig {
+    /// the k-anonymity range API of Have I Been Pwned, or a mirror of it
+    Api {
+        #[serde(default = "default_pwned_url")]
+        url: String,
+        /// requests that take longer fail
+        #[serde(default = "default_pwned_timeout_ms")]
+        timeout_ms: u64,
+    },
+    /// a local file with a `SHA1:COUNT` line per password, sorted by hash
+    File { path: String },
+    /// every password is accepted
+    Disabled,
+}
+
+impl Default for BreachedPasswordsConfig {
+    fn default() -> Self {
+        Self::Api {
+            url: default_pwned_url(),
+            timeout_ms: default_pwned_timeout_ms(),
+        }
+    }
+}
+
+fn default_pwned_url() -> String {
+    keygate_utils::pwned::PWNED_URL.to_string()
+}
+
+fn default_pwned_timeout_ms() -> u64 {
+    5000
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PasswordHashingConfig {
+    /// Argon2id parameters for new hashes, older hashes are replaced on the next login
+    #[serde(flatten)]
+    pub params: PasswordHashParams,
+
+    /// secret mixed into every password hash, keep it out of the database.
+    /// Hashes made with a pepper can't be verified without it.
+    pub pepper: Option<String>,
+
+    /// pick the iterations at startup so hashing takes about this many milliseconds.
+    /// All nodes should use the same parameters, so prefer configuring the calibrated values.
+    pub calibrate_ms: Option<u64>,
+
+    /// needed to verify password hashes imported from Firebase
+    pub firebase_scrypt: Option<FirebaseScryptParams>,
+}
+
+impl std::fmt::Debug for PasswordHashingConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PasswordHashingConfig")
+            .field("params", &self.params)
+            .field("pepper", &self.pepper.as_ref().map(|_| "<redacted>"))
+            .field("calibrate_ms", &self.calibrate_ms)
+            .field("firebase_scrypt", &self.firebase_scrypt)
+            .finish()
+    }
+}
+
+impl Config {
+    pub fn anti_enumeration(&self) -> bool {
+        self.anti_enumeration
+            .unwrap_or(self.environment == Environment::Production)
+    }
 }
 
 impl Default for Config {
@@ -61,6 +332,15 @@ impl Default for Config {
             },
             storage_options: StorageOptions::default(),
             server: ServerConfig::default(),
+            anti_enumeration: None,
+            password_hashing: PasswordHashingConfig::default(),
+            legacy_user_store: None,
+            upstream_providers: Vec::new(),
+            saml_connections: Vec::new(),
+            saml_signing_key: None,
+            dns_over_https: None,
+            ldap: None,
+            breached_passwords: BreachedPasswordsConfig::default(),
         }
     }
 }
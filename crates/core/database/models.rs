@@ -1,3 +1,4 @@
+use keygate_utils::password_policy::PasswordPolicy;
 use serde::{Deserialize, Serialize};
 use sqlx::{types::time::OffsetDateTime, FromRow};
 use time::Duration;
@@ -10,9 +11,19 @@ pub struct Identity {
     pub last_active: OffsetDateTime,
     pub username: Option<String>,
     pub primary_email: Option<String>,
+    pub full_name: Option<String>,
+    /// only stored if [`GlobalSettings::store_birthdate`] is set
+    pub birthdate: Option<time::Date>,
+    /// when the identity confirmed it is at least [`GlobalSettings::minimum_age`]
+    pub age_attested_at: Option<OffsetDateTime>,
 
     #[serde(skip_serializing)]
     pub password_hash: Option<String>,
+    pub must_change_password: bool,
+    /// the password is checked against the legacy user store until the next successful login
+    pub legacy: bool,
+    /// the password is checked with a bind to the LDAP directory, there is no password hash
+    pub ldap: bool,
 }
 
 #[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
@@ -27,10 +38,55 @@ pub struct Email {
     pub verification_code_expires_at: Option<OffsetDateTime>,
 }
 
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+pub struct RecoveryCode {
+    pub id: String,
+    pub created_at: OffsetDateTime,
+    pub identity_id: String,
+    #[serde(skip_serializing)]
+    pub code_hash: String,
+    pub used_at: Option<OffsetDateTime>,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+pub struct TrustedDevice {
+    pub id: String,
+    pub identity_id: String,
+    pub login_process_id: String,
+    pub label: String,
+    #[serde(skip_serializing)]
+    pub token_hash: String,
+    pub created_at: OffsetDateTime,
+    pub last_used_at: Option<OffsetDateTime>,
+    pub expires_at: OffsetDateTime,
+    pub revoked_at: Option<OffsetDateTime>,
+    /// hash of the device id of the login the device was trusted from
+    #[serde(skip_serializing)]
+    pub device_hash: Option<String>,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+pub struct PasswordReset {
+    pub id: String,
+    pub identity_id: String,
+    /// the address the token was sent to
+    pub email: String,
+    #[serde(skip_serializing)]
+    pub token_hash: String,
+    pub created_at: OffsetDateTime,
+    pub expires_at: OffsetDateTime,
+    pub used_at: Option<OffsetDateTime>,
+}
+
 #[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
 pub struct LinkedAccount {
     pub id: String,
+    pub identity_id: String,
     pub provider_id: String,
+    pub subject: String,
+    pub email: Option<String>,
+    pub created_at: OffsetDateTime,
+    pub last_login_at: Option<OffsetDateTime>,
 }
 
 #[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
@@ -44,6 +100,42 @@ pub struct LoginProcess {
     pub completed: bool,
     pub current_step: String,
     pub magic_link: Option<String>,
+    pub email_code: Option<String>,
+    pub email_code_expires_at: Option<OffsetDateTime>,
+    pub email_code_attempts: i64,
+    pub device_hash: Option<String>,
+    pub device_approval: Option<String>,
+    pub factors: String,
+    pub session_id: Option<String>,
+    pub reauthenticate: bool,
+    pub require_second_factor: bool,
+    pub application_id: Option<String>,
+    pub legacy_username: Option<String>,
+    pub upstream_provider: Option<String>,
+    pub upstream_state: Option<String>,
+    pub upstream_nonce: Option<String>,
+    pub upstream_code_verifier: Option<String>,
+    pub upstream_required: bool,
+    /// what the user logged in with, if the password is checked with the LDAP directory
+    pub ldap_username: Option<String>,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+pub struct Device {
+    pub id: String,
+    pub identity_id: String,
+    #[serde(skip_serializing)]
+    pub device_hash: String,
+    pub created_at: OffsetDateTime,
+    pub last_login_at: OffsetDateTime,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+pub struct LoginThrottle {
+    pub key: String,
+    pub failures: i64,
+    pub last_failure_at: OffsetDateTime,
+    pub locked_until: Option<OffsetDateTime>,
 }
 
 #[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
@@ -92,6 +184,9 @@ pub struct Session {
     pub refresh_token: String,
     pub token_type: String,
     pub identity_id: String,
+    pub auth_time: Option<OffsetDateTime>,
+    pub amr: String,
+    pub acr: String,
 }
 
 #[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
@@ -125,6 +220,65 @@ pub struct MagicLinkSettings {
     pub token_expires_in: Duration,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmailCodeSettings {
+    /// number of digits in the code
+    pub code_length: usize,
+    pub token_expires_in: Duration,
+    /// failed attempts before the code is invalidated
+    pub max_attempts: i64,
+    /// minimum time between two codes sent to the same address
+    pub resend_cooldown: Duration,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoginThrottleSettings {
+    /// failed attempts before each new attempt has to wait
+    pub delay_after: i64,
+    /// wait time after `delay_after` failures, doubles with every further failure
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+
+    /// failed attempts before the identity is locked
+    pub lockout_after: i64,
+    /// failed attempts from a single ip address before it is locked
+    pub ip_lockout_after: i64,
+    pub lockout_duration: Duration,
+
+    /// failures older than this are forgotten
+    pub failure_window: Duration,
+}
+
+impl Default for LoginThrottleSettings {
+    fn default() -> Self {
+        Self {
+            delay_after: 3,
+            base_delay: Duration::seconds(1),
+            max_delay: Duration::seconds(30),
+            lockout_after: 10,
+            ip_lockout_after: 100,
+            lockout_duration: Duration::minutes(15),
+            failure_window: Duration::hours(1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PasswordResetSettings {
+    pub token_expires_in: Duration,
+    /// minimum time between two reset emails for the same identity
+    pub resend_cooldown: Duration,
+}
+
+impl Default for PasswordResetSettings {
+    fn default() -> Self {
+        Self {
+            token_expires_in: Duration::minutes(30),
+            resend_cooldown: Duration::minutes(1),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum EmailVerification {
     None,
@@ -161,10 +315,51 @@ pub struct GlobalSettings {
     pub enable_multiple_emails_per_account: bool,
     pub check_haveibeenpwned: bool,
 
+    /// rules new passwords have to follow
+    #[serde(default)]
+    pub password_policy: PasswordPolicy,
+
     /// What identifier to use for login
     pub login_identifier: AccountNaming,
 
     pub magic_link: Option<MagicLinkSettings>,
+
+    #[serde(default)]
+    pub email_code: Option<EmailCodeSettings>,
+
+    #[serde(default)]
+    pub login_throttle: LoginThrottleSettings,
+
+    #[serde(default)]
+    pub password_reset: PasswordResetSettings,
+
+    /// logins from unrecognized devices have to be approved from an existing session
+    #[serde(default)]
+    pub device_approval: bool,
+
+    /// how long a device can skip second factors after the user chose to trust it
+    #[serde(default = "default_trusted_device_expires_in")]
+    pub trusted_device_expires_in: Duration,
+
+    /// how recently a session has to have authenticated for sensitive actions
+    #[serde(default = "default_reauthenticate_after")]
+    pub reauthenticate_after: Duration,
+
+    /// minimum time between two verification emails sent to the same address
+    #[serde(default = "default_email_verification_resend_cooldown")]
+    pub email_verification_resend_cooldown: Duration,
+}
+
+fn default_trusted_device_expires_in() -> Duration {
+    Duration::days(30)
+}
+
+fn default_reauthenticate_after() -> Duration {
+    Duration::minutes(10)
+}
+
+fn default_email_verification_resend_cooldown() -> Duration {
+    Duration::minutes(1)
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -174,9 +369,26 @@ pub enum TokenFormat {
     BiscuitV2,
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SecondFactorPolicy {
+    /// Second factors are used once the identity enrolled one
+    #[default]
+    Optional,
+    /// Every login has to complete a second factor
+    Required,
+    /// Logins are completed after the first factor
+    Forbidden,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ApplicationSettings {
     pub access_token_format: TokenFormat,
     pub access_token_expires_in: Option<Duration>,
     pub refresh_token_expires_in: Option<Duration>,
+
+    /// login steps that can be used as the first factor (e.g. `password`), all if not set
+    #[serde(default)]
+    pub allowed_first_factors: Option<Vec<String>>,
+    #[serde(default)]
+    pub second_factor: SecondFactorPolicy,
 }
@@ -0,0 +1,187 @@
+use std::fmt::Debug;
+
+use serde::Deserialize;
+
+use crate::{api::APIError, login::BoxFuture};
+
+/// Looks up DNS records, e.g. to check that the owner of a domain published a
+/// verification token
+pub trait DnsResolver: Send + Sync + Debug {
+    /// The TXT records of `name`, empty if there are none or the name doesn't exist
+    fn txt_records<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<String>, APIError>>;
+}
+
+/// Resolves with the JSON API of a DNS-over-HTTPS server, e.g.
+/// `https://cloudflare-dns.com/dns-query` or `https://dns.google/resolve`
+#[derive(Debug, Clone)]
+pub struct DohResolver {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl DohResolver {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct DohResponse {
+    #[serde(rename = "Status")]
+    status: u16,
+    #[serde(rename = "Answer", default)]
+    answer: Vec<DohAnswer>,
+}
+
+#[derive(Deserialize)]
+struct DohAnswer {
+    #[serde(rename = "type")]
+    record_type: u16,
+    data: String,
+}
+
+const TYPE_TXT: u16 = 16;
+const NXDOMAIN: u16 = 3;
+
+impl DnsResolver for DohResolver {
+    fn txt_records<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<String>, APIError>> {
+        Box::pin(async move {
+            let response: DohResponse = self
+                .client
+                .get(&self.url)
+                .query(&[("name", name), ("type", "TXT")])
+                .header(reqwest::header::ACCEPT, "application/dns-json")
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map_err(|e| APIError::Internal(format!("DNS lookup failed: {}", e)))?
+                .json()
+                .await
+                .map_err(|e| APIError::Internal(format!("Invalid DNS response: {}", e)))?;
+
+            match response.status {
+                0 => Ok(response
+                    .answer
+                    .iter()
+                    .filter(|answer| answer.record_type == TYPE_TXT)
+                    .map(|answer| txt_data(&answer.data))
+                    .collect()),
+                NXDOMAIN => Ok(Vec::new()),
+                status => Err(APIError::Internal(format!(
+                    "DNS lookup failed with status {}",
+                    status
+                ))),
+            }
+        })
+    }
+}
+
+// TXT data is one or more quoted strings, which make up the record together
+fn txt_data(data: &str) -> String {
+    if !data.starts_with('"') {
+        return data.to_string();
+    }
+
+    let mut value = String::new();
+    let mut quoted = false;
+    let mut chars = data.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => quoted = !quoted,
+            '\\' if quoted => value.extend(chars.next()),
+            c if quoted => value.push(c),
+            _ => {}
+        }
+    }
+    value
+}
+
+/// A resolver for tests that answers every name with the same records
+#[cfg(test)]
+pub(crate) mod mock {
+    use super::*;
+
+    #[derive(Debug, Clone, Default)]
+    pub struct StaticResolver(pub Vec<String>);
+
+    impl DnsResolver for StaticResolver {
+        fn txt_records<'a>(
+            &'a self,
+            _name: &'a str,
+        ) -> BoxFuture<'a, Result<Vec<String>, APIError>> {
+            Box::pin(async move { Ok(self.0.clone()) })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    // answers with a TXT record for `example.com` and NXDOMAIN for anything else
+    async fn stub_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !String::from_utf8_lossy(&request).contains("\r\n\r\n") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                }
+
+                let request = String::from_utf8_lossy(&request).to_lowercase();
+                let body = match request.contains("name=example.com&type=txt")
+                    && request.contains("accept: application/dns-json")
+                {
+                    true => {
+                        r#"{"Status":0,"Answer":[{"name":"example.com","type":16,"TTL":300,"data":"\"token=\" \"abc\""},{"name":"example.com","type":5,"TTL":300,"data":"other.example.com."}]}"#
+                    }
+                    false => r#"{"Status":3}"#,
+                };
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: application/dns-json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+                stream.shutdown().await.ok();
+            }
+        });
+
+        format!("http://{}/dns-query", addr)
+    }
+
+    #[test]
+    fn test_txt_data() {
+        assert_eq!(txt_data(r#""v=spf1 -all""#), "v=spf1 -all");
+        assert_eq!(txt_data(r#""a" "b\"c""#), r#"ab"c"#);
+        assert_eq!(txt_data("unquoted"), "unquoted");
+    }
+
+    #[tokio::test]
+    async fn test_doh_resolver() {
+        let resolver = DohResolver::new(stub_server().await);
+        assert_eq!(
+            resolver.txt_records("example.com").await.unwrap(),
+            vec!["token=abc".to_string()]
+        );
+        assert!(resolver
+            .txt_records("missing.example.com")
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
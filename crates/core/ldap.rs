@@ -0,0 +1,406 @@
+use std::time::Duration;
+
+use ldap3::{ldap_escape, Ldap, LdapConnAsync, LdapConnSettings, Scope, SearchEntry};
+
+use crate::{api::APIError, config::LdapConfig};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+const INVALID_CREDENTIALS: u32 = 49;
+
+/// A directory user whose password was accepted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct LdapUser {
+    pub dn: String,
+    pub username: Option<String>,
+    pub email: Option<String>,
+    /// mapped from the groups of the user with [`LdapConfig::group_roles`]
+    pub roles: Vec<String>,
+}
+
+/// The configured LDAP directory, see [`LdapConfig`]
+#[derive(Debug)]
+pub(crate) struct LdapDirectory {
+    config: LdapConfig,
+}
+
+impl LdapDirectory {
+    pub fn new(config: LdapConfig) -> Self {
+        Self { config }
+    }
+
+    pub fn trust_email(&self) -> bool {
+        self.config.trust_email
+    }
+
+    /// Search for the user with the service account, then bind as the user. Returns `None`
+    /// if there is no such user, more than one, or the password is wrong.
+    pub async fn authenticate(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> Result<Option<LdapUser>, APIError> {
+        // servers accept a bind without a password as anonymous
+        if password.is_empty() {
+            return Ok(None);
+        }
+
+        let settings = LdapConnSettings::new()
+            .set_conn_timeout(TIMEOUT)
+            .set_starttls(self.config.starttls);
+        let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &self.config.url)
+            .await
+            .map_err(ldap_error)?;
+        ldap3::drive!(conn);
+
+        let user = self.search_and_bind(&mut ldap, username, password).await;
+        ldap.unbind().await.ok();
+        user
+    }
+
+    async fn search_and_bind(
+        &self,
+        ldap: &mut Ldap,
+        username: &str,
+        password: &str,
+    ) -> Result<Option<LdapUser>, APIError> {
+        let bind = ldap
+            .with_timeout(TIMEOUT)
+            .simple_bind(&self.config.bind_dn, &self.config.bind_password)
+            .await
+            .map_err(ldap_error)?;
+        if bind.rc != 0 {
+            return Err(APIError::Internal(format!(
+                "LDAP service account bind failed: {}",
+                bind
+            )));
+        }
+
+        let mapping = &self.config.attribute_mapping;
+        let attributes: Vec<&str> = [&mapping.username, &mapping.email, &mapping.groups]
+            .into_iter()
+            .flatten()
+            .map(String::as_str)
+            .collect();
+        let filter = self
+            .config
+            .user_filter
+            .replace("{username}", &ldap_escape(username));
+        let (entries, _) = ldap
+            .with_timeout(TIMEOUT)
+            .search(&self.config.base_dn, Scope::Subtree, &filter, attributes)
+            .await
+            .and_then(|res| res.success())
+            .map_err(ldap_error)?;
+
+        let mut entries = entries.into_iter();
+        let (Some(entry), None) = (entries.next(), entries.next()) else {
+            return Ok(None);
+        };
+        let entry = SearchEntry::construct(entry);
+
+        let bind = ldap
+            .with_timeout(TIMEOUT)
+            .simple_bind(&entry.dn, password)
+            .await
+            .map_err(ldap_error)?;
+        match bind.rc {
+            0 => {}
+            INVALID_CREDENTIALS => return Ok(None),
+            _ => return Err(APIError::Internal(format!("LDAP bind failed: {}", bind))),
+        }
+
+        // attribute names are case-insensitive
+        let values = |attribute: &Option<String>| -> Vec<String> {
+            attribute
+                .as_deref()
+                .and_then(|attribute| {
+                    entry
+                        .attrs
+                        .iter()
+                        .find(|(name, _)| name.eq_ignore_ascii_case(attribute))
+                })
+                .map(|(_, values)| values.clone())
+                .unwrap_or_default()
+        };
+        let mut roles: Vec<String> = values(&mapping.groups)
+            .iter()
+            .filter_map(|group| {
+                self.config
+                    .group_roles
+                    .iter()
+                    .find(|(dn, _)| dn.eq_ignore_ascii_case(group))
+                    .map(|(_, role)| role.clone())
+            })
+            .collect();
+        roles.sort();
+        roles.dedup();
+
+        Ok(Some(LdapUser {
+            username: values(&mapping.username).into_iter().next(),
+            email: values(&mapping.email).into_iter().next(),
+            dn: entry.dn,
+            roles,
+        }))
+    }
+}
+
+fn ldap_error(e: ldap3::LdapError) -> APIError {
+    APIError::Internal(format!("LDAP request failed: {}", e))
+}
+
+/// An in-process LDAP server for tests. It understands just enough of the protocol for
+/// simple binds and searches with an equality filter.
+#[cfg(test)]
+pub(crate) mod mock {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use crate::config::{LdapAttributeMapping, LdapConfig};
+
+    pub const SERVICE_DN: &str = "cn=keygate,dc=example,dc=com";
+    pub const SERVICE_PASSWORD: &str = "service secret";
+    pub const ADMINS_DN: &str = "cn=admins,ou=groups,dc=example,dc=com";
+
+    /// An entry of the directory, with a password and attributes
+    #[derive(Debug, Clone)]
+    pub struct Entry {
+        pub dn: String,
+        pub password: String,
+        pub attributes: Vec<(String, Vec<String>)>,
+    }
+
+    /// `uid=jane` with the password `ldap secret`, a member of the admins group
+    pub fn jane() -> Entry {
+        Entry {
+            dn: "uid=jane,ou=people,dc=example,dc=com".into(),
+            password: "ldap secret".into(),
+            attributes: vec![
+                ("uid".into(), vec!["jane".into()]),
+                ("mail".into(), vec!["jane@example.com".into()]),
+                (
+                    "memberOf".into(),
+                    vec![
+                        ADMINS_DN.into(),
+                        "cn=staff,ou=groups,dc=example,dc=com".into(),
+                    ],
+                ),
+            ],
+        }
+    }
+
+    pub fn config(url: &str) -> LdapConfig {
+        LdapConfig {
+            url: url.into(),
+            starttls: false,
+            bind_dn: SERVICE_DN.into(),
+            bind_password: SERVICE_PASSWORD.into(),
+            base_dn: "ou=people,dc=example,dc=com".into(),
+            user_filter: "(uid={username})".into(),
+            attribute_mapping: LdapAttributeMapping::default(),
+            group_roles: [(ADMINS_DN.to_string(), "admin".to_string())].into(),
+            trust_email: true,
+        }
+    }
+
+    /// Serve the entries, returns the `ldap://` url of the server
+    pub async fn server(entries: Vec<Entry>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream, entries.clone()));
+            }
+        });
+
+        format!("ldap://{}", addr)
+    }
+
+    async fn serve(mut stream: TcpStream, entries: Vec<Entry>) {
+        let mut buf = Vec::new();
+        let mut bound_as_service = false;
+        loop {
+            let (message, rest) = loop {
+                if let Some((tag, value, rest)) = tlv(&buf) {
+                    assert_eq!(tag, 0x30);
+                    let message = value.to_vec();
+                    let rest = rest.to_vec();
+                    break (message, rest);
+                }
+                let mut chunk = [0; 1024];
+                match stream.read(&mut chunk).await {
+                    Ok(0) | Err(_) => return,
+                    Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                }
+            };
+            buf = rest;
+
+            let (_, id, op) = tlv(&message).unwrap();
+            let message_id = [0x02, id.len() as u8]
+                .into_iter()
+                .chain(id.iter().copied())
+                .collect::<Vec<_>>();
+            let (op_tag, op, _) = tlv(op).unwrap();
+
+            let responses = match op_tag {
+                // BindRequest: version, name, [0] simple password
+                0x60 => {
+                    let fields = children(op);
+                    let (dn, password) = (fields[1].1, fields[2].1);
+                    let service = dn == SERVICE_DN.as_bytes();
+                    let accepted = match service {
+                        true => password == SERVICE_PASSWORD.as_bytes(),
+                        false => entries.iter().any(|entry| {
+                            entry.dn.as_bytes() == dn && entry.password.as_bytes() == password
+                        }),
+                    };
+                    bound_as_service = accepted && service;
+                    vec![encode(0x61, &result(if accepted { 0 } else { 49 }))]
+                }
+                // SearchRequest: base, scope, deref, size, time, types only, filter, attributes
+                0x63 => {
+                    let fields = children(op);
+                    let mut responses = vec![];
+                    if bound_as_service {
+                        // equality filter `(attribute=value)`
+                        let filter = children(fields[6].1);
+                        let (attribute, value) = (filter[0].1, filter[1].1);
+                        for entry in &entries {
+                            let matches = entry.attributes.iter().any(|(name, values)| {
+                                name.as_bytes().eq_ignore_ascii_case(attribute)
+                                    && values.iter().any(|v| v.as_bytes() == value)
+                            });
+                            if matches {
+                                responses.push(search_entry(entry));
+                            }
+                        }
+                    }
+                    let code = if bound_as_service { 0 } else { 50 };
+                    responses.push(encode(0x65, &result(code)));
+                    responses
+                }
+                // UnbindRequest
+                0x42 => return,
+                _ => vec![encode(0x65, &result(53))],
+            };
+
+            for response in responses {
+                let message = encode(0x30, &[message_id.clone(), response].concat());
+                if stream.write_all(&message).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    fn search_entry(entry: &Entry) -> Vec<u8> {
+        let attributes: Vec<u8> = entry
+            .attributes
+            .iter()
+            .flat_map(|(name, values)| {
+                let values: Vec<u8> = values
+                    .iter()
+                    .flat_map(|value| encode(0x04, value.as_bytes()))
+                    .collect();
+                encode(
+                    0x30,
+                    &[encode(0x04, name.as_bytes()), encode(0x31, &values)].concat(),
+                )
+            })
+            .collect();
+        encode(
+            0x64,
+            &[encode(0x04, entry.dn.as_bytes()), encode(0x30, &attributes)].concat(),
+        )
+    }
+
+    // resultCode, matchedDN, diagnosticMessage
+    fn result(code: u8) -> Vec<u8> {
+        [vec![0x0a, 0x01, code], encode(0x04, b""), encode(0x04, b"")].concat()
+    }
+
+    fn encode(tag: u8, value: &[u8]) -> Vec<u8> {
+        let mut encoded = vec![tag];
+        match value.len() {
+            len @ 0..=0x7f => encoded.push(len as u8),
+            len @ 0x80..=0xff => encoded.extend([0x81, len as u8]),
+            len => encoded.extend([0x82, (len >> 8) as u8, len as u8]),
+        }
+        encoded.extend_from_slice(value);
+        encoded
+    }
+
+    // tag, value and the rest of the input, `None` if the element is incomplete
+    fn tlv(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+        let (&tag, input) = input.split_first()?;
+        let (&first, input) = input.split_first()?;
+        let (len, input) = match first {
+            0..=0x7f => (first as usize, input),
+            _ => {
+                let n = (first & 0x7f) as usize;
+                let bytes = input.get(..n)?;
+                let len = bytes.iter().fold(0, |len, &b| len << 8 | b as usize);
+                (len, &input[n..])
+            }
+        };
+        let value = input.get(..len)?;
+        Some((tag, value, &input[len..]))
+    }
+
+    fn children(mut input: &[u8]) -> Vec<(u8, &[u8])> {
+        let mut children = vec![];
+        while let Some((tag, value, rest)) = tlv(input) {
+            children.push((tag, value));
+            input = rest;
+        }
+        children
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_ldap_authenticate() {
+        let url = mock::server(vec![mock::jane()]).await;
+        let directory = LdapDirectory::new(mock::config(&url));
+
+        let user = directory
+            .authenticate("jane", "ldap secret")
+            .await
+            .unwrap()
+            .expect("jane should be accepted");
+        assert_eq!(user.dn, "uid=jane,ou=people,dc=example,dc=com");
+        assert_eq!(user.username.as_deref(), Some("jane"));
+        assert_eq!(user.email.as_deref(), Some("jane@example.com"));
+        assert_eq!(user.roles, vec!["admin".to_string()]);
+
+        assert!(directory
+            .authenticate("jane", "wrong")
+            .await
+            .unwrap()
+            .is_none());
+        assert!(directory.authenticate("jane", "").await.unwrap().is_none());
+        assert!(directory
+            .authenticate("unknown", "ldap secret")
+            .await
+            .unwrap()
+            .is_none());
+        // the filter can't be changed with the username
+        assert!(directory
+            .authenticate("*", "ldap secret")
+            .await
+            .unwrap()
+            .is_none());
+
+        let mut config = mock::config(&url);
+        config.bind_password = "wrong".into();
+        let res = LdapDirectory::new(config)
+            .authenticate("jane", "ldap secret")
+            .await;
+        assert!(matches!(res, Err(APIError::Internal(_))));
+    }
+}
@@ -0,0 +1,164 @@
+use std::fmt::Debug;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{api::APIError, login::BoxFuture};
+
+/// A user as known to the legacy user store
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LegacyUser {
+    /// defaults to what the user logged in with, if it's not an email
+    pub username: Option<String>,
+    /// defaults to what the user logged in with, if it's an email
+    pub email: Option<String>,
+    /// whether the legacy system verified the email
+    pub email_verified: bool,
+}
+
+/// The user store of a system keygate replaces, for users whose password hashes
+/// can't be exported.
+///
+/// When a user that is unknown (or flagged as legacy) logs in with a password, the
+/// credentials are checked here once. Accepted users get an identity with a fresh password
+/// hash and never reach the legacy store again.
+pub trait LegacyUserStore: Send + Sync + Debug {
+    /// Returns the user if the legacy system accepts the credentials, `None` if it doesn't
+    fn verify<'a>(
+        &'a self,
+        username_or_email: &'a str,
+        password: &'a str,
+    ) -> BoxFuture<'a, Result<Option<LegacyUser>, APIError>>;
+}
+
+/// Checks credentials with a `POST` of `{"username_or_email": "..", "password": ".."}`.
+///
+/// The endpoint answers with `200` and a [`LegacyUser`] if it accepts the credentials,
+/// and with `401`, `403` or `404` if it doesn't.
+#[derive(Debug, Clone)]
+pub struct HttpLegacyUserStore {
+    url: String,
+    authorization: Option<String>,
+    client: reqwest::Client,
+}
+
+#[derive(Serialize)]
+struct VerifyRequest<'a> {
+    username_or_email: &'a str,
+    password: &'a str,
+}
+
+impl HttpLegacyUserStore {
+    /// `authorization` is sent as the `Authorization` header, if set
+    pub fn new(url: impl Into<String>, authorization: Option<String>) -> Self {
+        Self {
+            url: url.into(),
+            authorization,
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+impl LegacyUserStore for HttpLegacyUserStore {
+    fn verify<'a>(
+        &'a self,
+        username_or_email: &'a str,
+        password: &'a str,
+    ) -> BoxFuture<'a, Result<Option<LegacyUser>, APIError>> {
+        Box::pin(async move {
+            let mut request = self.client.post(&self.url).json(&VerifyRequest {
+                username_or_email,
+                password,
+            });
+            if let Some(authorization) = &self.authorization {
+                request = request.header(reqwest::header::AUTHORIZATION, authorization);
+            }
+
+            let response = request
+                .send()
+                .await
+                .map_err(|e| APIError::Internal(format!("Legacy user store failed: {}", e)))?;
+
+            match response.status() {
+                reqwest::StatusCode::OK => response.json().await.map(Some).map_err(|e| {
+                    APIError::Internal(format!("Invalid legacy user store response: {}", e))
+                }),
+                reqwest::StatusCode::UNAUTHORIZED
+                | reqwest::StatusCode::FORBIDDEN
+                | reqwest::StatusCode::NOT_FOUND => Ok(None),
+                status => Err(APIError::Internal(format!(
+                    "Legacy user store responded with {}",
+                    status
+                ))),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    // answers a single request, accepting only `user:secret`
+    async fn stub_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                // the body is small enough to arrive with the headers
+                while !String::from_utf8_lossy(&request).contains("}") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                }
+
+                let request = String::from_utf8_lossy(&request).to_lowercase();
+                let accepted = request.contains("authorization: bearer stub")
+                    && request.contains(r#"{"username_or_email":"user","password":"secret"}"#);
+                let response = match accepted {
+                    true => {
+                        let body = r#"{"email":"user@example.com","email_verified":true}"#;
+                        format!(
+                            "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                            body.len(),
+                            body
+                        )
+                    }
+                    false => "HTTP/1.1 401 Unauthorized\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+                        .to_string(),
+                };
+                stream.write_all(response.as_bytes()).await.unwrap();
+                stream.shutdown().await.ok();
+            }
+        });
+
+        format!("http://{}/verify", addr)
+    }
+
+    #[tokio::test]
+    async fn test_http_legacy_user_store() {
+        let url = stub_server().await;
+        let store = HttpLegacyUserStore::new(url, Some("Bearer stub".into()));
+
+        let user = store.verify("user", "secret").await.unwrap();
+        assert_eq!(
+            user,
+            Some(LegacyUser {
+                username: None,
+                email: Some("user@example.com".into()),
+                email_verified: true,
+            })
+        );
+
+        assert_eq!(store.verify("user", "wrong").await.unwrap(), None);
+    }
+}
@@ -6,16 +6,30 @@ use std::sync::Arc;
 use std::{fmt::Debug, path::Path};
 
 pub mod api;
+pub mod breached;
 pub mod database;
+pub mod dns;
+pub mod legacy;
+pub mod login;
+pub mod mail;
 pub mod settings;
 
 pub mod config;
+mod ldap;
+mod saml;
 mod secrets;
-use arc_swap::ArcSwap;
+mod upstream;
+use arc_swap::{ArcSwap, ArcSwapOption};
+use breached::BreachedPasswordChecker;
 use config::Config;
 pub use config::Config as KeygateConfig;
 
 use database::DatabasePool;
+use dns::DnsResolver;
+use keygate_utils::hash::PasswordHasher;
+use legacy::LegacyUserStore;
+use login::{LoginMethod, LoginMethods};
+use mail::Mailer;
 use secrets::Secrets;
 use settings::KeygateSettings;
 use thiserror::Error;
@@ -61,6 +75,15 @@ pub(crate) struct KeygateInternal {
     pub db: DatabasePool,
     pub health: ArcSwap<Health>,
     pub settings: KeygateSettings,
+    pub mailer: ArcSwap<Box<dyn Mailer>>,
+    pub login_methods: LoginMethods,
+    pub password_hasher: PasswordHasher,
+    pub legacy_user_store: ArcSwapOption<Box<dyn LegacyUserStore>>,
+    pub upstream: upstream::UpstreamProviders,
+    pub saml: saml::SamlConnections,
+    pub dns_resolver: ArcSwapOption<Box<dyn DnsResolver>>,
+    pub ldap: Option<ldap::LdapDirectory>,
+    pub breached_passwords: ArcSwap<Box<dyn BreachedPasswordChecker>>,
 }
 
 impl KeygateInternal {
@@ -72,12 +95,56 @@ impl KeygateInternal {
 #[derive(Debug, Clone)]
 pub struct Keygate {
     inner: Arc<KeygateInternal>,
+    pub audit: Arc<api::Audit>,
     pub auth: Arc<api::Auth>,
     pub session: Arc<api::Session>,
     pub identity: Arc<api::Identity>,
+    pub domains: Arc<api::Domains>,
 }
 
 impl Keygate {
+    pub fn config(&self) -> &Config {
+        &self.inner.config
+    }
+
+    /// Replace the mailer used to deliver emails, defaults to [`mail::LogMailer`]
+    pub fn set_mailer(&self, mailer: impl Mailer + 'static) {
+        self.inner.mailer.store(Arc::new(Box::new(mailer)));
+    }
+
+    /// Check unknown users against the user store of a previous system, replaces the
+    /// store configured with [`config::Config::legacy_user_store`]
+    pub fn set_legacy_user_store(&self, store: impl LegacyUserStore + 'static) {
+        self.inner
+            .legacy_user_store
+            .store(Some(Arc::new(Box::new(store))));
+    }
+
+    /// Resolver used to verify the ownership of SSO domains, replaces the
+    /// DNS-over-HTTPS server configured with [`config::Config::dns_over_https`]
+    pub fn set_dns_resolver(&self, resolver: impl DnsResolver + 'static) {
+        self.inner
+            .dns_resolver
+            .store(Some(Arc::new(Box::new(resolver))));
+    }
+
+    /// Checker new passwords are looked up with, replaces the one configured with
+    /// [`config::Config::breached_passwords`]
+    pub fn set_breached_password_checker(&self, checker: impl BreachedPasswordChecker + 'static) {
+        self.inner
+            .breached_passwords
+            .store(Arc::new(Box::new(checker)));
+    }
+
+    /// Add a login method, e.g. a hardware token or an external directory.
+    /// Errors if a method with the same step name is already registered.
+    pub fn register_login_method(&self, method: impl LoginMethod + 'static) -> KeygateResult<()> {
+        self.inner
+            .login_methods
+            .register(Arc::new(method))
+            .map_err(KeygateError::ValidationError)
+    }
+
     pub async fn create_admin_app(&self) -> KeygateResult<()> {
         let app_created = self
             .inner
@@ -88,6 +155,8 @@ impl Keygate {
                     access_token_expires_in: Some(time::Duration::minutes(10)),
                     refresh_token_expires_in: Some(time::Duration::days(1)),
                     access_token_format: database::models::TokenFormat::Jwt25519,
+                    allowed_first_factors: None,
+                    second_factor: database::models::SecondFactorPolicy::Optional,
                 },
             )
             .await?;
@@ -106,7 +175,7 @@ impl Keygate {
             return Ok(());
         }
 
-        let user_pw = keygate_utils::hash::password("admin")?;
+        let user_pw = self.inner.password_hasher.hash("admin")?;
         let user = self
             .identity
             .create(api::identity::CreateIdentity {
@@ -169,12 +238,54 @@ impl Keygate {
     }
 
     pub async fn new_with_storage(config: Config, db: DatabasePool) -> Self {
+        let password_hasher =
+            password_hasher(&config.password_hashing).expect("Invalid password hashing config");
+
+        let legacy_user_store = config.legacy_user_store.as_ref().map(|store| {
+            Box::new(legacy::HttpLegacyUserStore::new(
+                &store.url,
+                store.authorization.clone(),
+            )) as Box<dyn LegacyUserStore>
+        });
+
+        let dns_resolver = config
+            .dns_over_https
+            .as_ref()
+            .map(|url| Box::new(dns::DohResolver::new(url)) as Box<dyn DnsResolver>);
+
+        let ldap = config.ldap.clone().map(ldap::LdapDirectory::new);
+        let breached_passwords = breached::checker(&config.breached_passwords)
+            .expect("Invalid breached passwords config");
+
+        let upstream = upstream::UpstreamProviders::new(config.upstream_providers.clone());
+        let saml = saml::SamlConnections::new(
+            config.saml_connections.clone(),
+            config.saml_signing_key.as_ref(),
+        )
+        .expect("Invalid SAML config");
+        assert!(
+            config
+                .saml_connections
+                .iter()
+                .all(|connection| upstream.get(&connection.id).is_none()),
+            "SAML connections and upstream providers need distinct ids"
+        );
+
         let internal = Arc::new(KeygateInternal {
             config: Arc::new(config),
             secrets: Arc::new(Secrets::new()),
             db,
             health: ArcSwap::from_pointee(Health::Starting),
             settings: KeygateSettings::new(),
+            mailer: ArcSwap::from_pointee(Box::new(mail::LogMailer)),
+            login_methods: LoginMethods::default(),
+            password_hasher,
+            legacy_user_store: ArcSwapOption::from_pointee(legacy_user_store),
+            upstream,
+            saml,
+            dns_resolver: ArcSwapOption::from_pointee(dns_resolver),
+            ldap,
+            breached_passwords: ArcSwap::from_pointee(breached_passwords),
         });
 
         internal.settings.set_keygate(internal.clone());
@@ -188,13 +299,52 @@ impl Keygate {
             .expect("Failed to load global settings");
 
         // ensure keypair exists
-        internal.secrets.ensure_keypair().await;
+        internal
+            .secrets
+            .ensure_keypair()
+            .await
+            .expect("Failed to create a signing key");
 
         Keygate {
             inner: internal.clone(),
+            audit: Arc::new(api::Audit::new(internal.clone())),
             identity: Arc::new(api::Identity::new(internal.clone())),
             auth: Arc::new(api::Auth::new(internal.clone())),
+            domains: Arc::new(api::Domains::new(internal.clone())),
             session: Arc::new(api::Session::new(internal)),
         }
     }
 }
+
+fn password_hasher(config: &config::PasswordHashingConfig) -> std::io::Result<PasswordHasher> {
+    let params = match config.calibrate_ms {
+        Some(target) => {
+            let params = keygate_utils::hash::calibrate(
+                std::time::Duration::from_millis(target),
+                config.params.memory_cost,
+                config.params.parallelism,
+            )?;
+            info!(
+                "Calibrated password hashing to {} iterations",
+                params.iterations
+            );
+            params
+        }
+        None => config.params,
+    };
+
+    Ok(PasswordHasher::new(params, config.pepper.as_deref())?
+        .with_firebase_scrypt(config.firebase_scrypt.clone()))
+}
+
+#[cfg(test)]
+pub(crate) async fn test_keygate(config: Config) -> Keygate {
+    Keygate::new(Config {
+        storage_options: config::StorageOptions::Sqlite {
+            database_path: "sqlite://:memory:".into(),
+        },
+        ..config
+    })
+    .await
+    .expect("Failed to create keygate")
+}
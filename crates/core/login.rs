@@ -0,0 +1,860 @@
+use std::{
+    fmt::Debug,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, RwLock},
+};
+
+use keygate_utils::random::numeric_code;
+use tracing::warn;
+
+use crate::{
+    api::{
+        audit::{AuditAction, AuditEntry},
+        auth::LoginStep,
+        identity::normalize_recovery_code,
+        APIError, Audit,
+    },
+    database::models::{Email, GlobalSettings, Identity, LoginProcess, RecoveryCode},
+    mail::EmailMessage,
+    KeygateInternal,
+};
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// The role a [`LoginMethod`] can take in a login
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Factor {
+    /// Proves the identity on its own, e.g. a password
+    First,
+    /// Required after the first factor once the identity enrolled it, e.g. a hardware token
+    Second,
+    /// Only offered next to an enrolled second factor, e.g. recovery codes
+    Fallback,
+}
+
+/// Everything a [`LoginMethod`] can look at while a login is in progress
+pub struct LoginContext<'a> {
+    pub(crate) keygate: &'a Arc<KeygateInternal>,
+    pub process: &'a LoginProcess,
+    /// Missing for the decoy processes of unknown identities
+    pub identity: Option<&'a Identity>,
+    pub settings: &'a GlobalSettings,
+    /// Steps completed so far
+    pub completed: &'a [LoginStep],
+    // off when checking which methods an identity can really log in with
+    pub(crate) anti_enumeration: bool,
+}
+
+impl LoginContext<'_> {
+    pub fn identity_id(&self) -> &str {
+        &self.process.identity_id
+    }
+
+    /// Unknown identities have to look like known ones
+    pub fn anti_enumeration(&self) -> bool {
+        self.anti_enumeration
+    }
+
+    /// Whether the login raises the authentication level of an existing session
+    pub fn reauthenticate(&self) -> bool {
+        self.process.reauthenticate
+    }
+}
+
+/// A way to complete a step of a login, e.g. a password or a one-time code.
+///
+/// Methods are registered with [`crate::Keygate::register_login_method`] and offered
+/// by name as the next step of a login once they are available for the identity.
+pub trait LoginMethod: Send + Sync + Debug {
+    /// Name of the step, has to be unique
+    fn step(&self) -> LoginStep;
+
+    /// Roles the method can take, a method is only used once per login
+    fn factors(&self) -> &'static [Factor];
+
+    /// Authentication method reference (RFC 8176) recorded on the session
+    fn amr(&self) -> Option<&'static str> {
+        None
+    }
+
+    /// Error message when [`LoginMethod::verify`] fails
+    fn invalid_message(&self) -> &'static str {
+        "Invalid code"
+    }
+
+    /// Whether the method can be offered to the identity as the given factor
+    fn available<'a>(
+        &'a self,
+        ctx: &'a LoginContext<'a>,
+        factor: Factor,
+    ) -> BoxFuture<'a, Result<bool, APIError>>;
+
+    /// Prepare the step, e.g. send a code. Only needed by some methods.
+    fn start<'a>(&'a self, ctx: &'a LoginContext<'a>) -> BoxFuture<'a, Result<(), APIError>> {
+        Box::pin(async { Err(APIError::InvalidArgument("Step cannot be started".into())) })
+    }
+
+    /// Check the data submitted for the step. Returns false for wrong credentials,
+    /// which count as failed attempts, and errors if the step cannot be checked at all.
+    fn verify<'a>(
+        &'a self,
+        ctx: &'a LoginContext<'a>,
+        data: &'a str,
+    ) -> BoxFuture<'a, Result<bool, APIError>>;
+}
+
+// steps of the login process that are not login methods
+const RESERVED_STEPS: [LoginStep; 5] = [
+    LoginStep::EMAIL,
+    LoginStep::USERNAME,
+    LoginStep::DEVICE_APPROVAL,
+    LoginStep::EMAIL_VERIFICATION,
+    LoginStep::CHANGE_PASSWORD,
+];
+
+/// The login methods known to keygate, in the order they are offered
+#[derive(Debug)]
+pub(crate) struct LoginMethods {
+    methods: RwLock<Vec<Arc<dyn LoginMethod>>>,
+}
+
+impl Default for LoginMethods {
+    fn default() -> Self {
+        Self {
+            methods: RwLock::new(vec![
+                Arc::new(Password),
+                Arc::new(EmailCode),
+                Arc::new(RecoveryCodes),
+                Arc::new(TrustedDevice),
+                Arc::new(Upstream),
+            ]),
+        }
+    }
+}
+
+impl LoginMethods {
+    pub(crate) fn register(&self, method: Arc<dyn LoginMethod>) -> Result<(), String> {
+        let step = method.step();
+        let mut methods = self.methods.write().expect("login methods lock poisoned");
+
+        if RESERVED_STEPS.contains(&step) || methods.iter().any(|m| m.step() == step) {
+            return Err(format!(
+                "Login step {} is already registered",
+                step.as_str_name()
+            ));
+        }
+
+        methods.push(method);
+        Ok(())
+    }
+
+    pub(crate) fn get(&self, step: &LoginStep) -> Option<Arc<dyn LoginMethod>> {
+        self.methods
+            .read()
+            .expect("login methods lock poisoned")
+            .iter()
+            .find(|method| method.step() == *step)
+            .cloned()
+    }
+
+    pub(crate) fn all(&self) -> Vec<Arc<dyn LoginMethod>> {
+        self.methods
+            .read()
+            .expect("login methods lock poisoned")
+            .clone()
+    }
+}
+
+pub(crate) async fn verified_email(
+    keygate: &KeygateInternal,
+    identity_id: &str,
+) -> Result<Option<Email>, APIError> {
+    let email = sqlx::query_as!(
+        Email,
+        "SELECT Email.* FROM Email JOIN Identity ON Identity.primary_email = Email.email WHERE Identity.id = $1 AND Email.verified = true",
+        identity_id
+    )
+    .fetch_optional(&keygate.db)
+    .await?;
+
+    Ok(email)
+}
+
+#[derive(Debug)]
+struct Password;
+
+impl LoginMethod for Password {
+    fn step(&self) -> LoginStep {
+        LoginStep::PASSWORD
+    }
+
+    fn factors(&self) -> &'static [Factor] {
+        &[Factor::First]
+    }
+
+    fn amr(&self) -> Option<&'static str> {
+        Some("pwd")
+    }
+
+    fn invalid_message(&self) -> &'static str {
+        "Invalid password"
+    }
+
+    fn available<'a>(
+        &'a self,
+        ctx: &'a LoginContext<'a>,
+        _factor: Factor,
+    ) -> BoxFuture<'a, Result<bool, APIError>> {
+        // identities without a password can still use the one of the legacy user store
+        // or the directory
+        Box::pin(async move {
+            Ok(ctx.anti_enumeration()
+                || ctx.process.legacy_username.is_some()
+                || ctx.process.ldap_username.is_some()
+                || ctx
+                    .identity
+                    .is_none_or(|identity| identity.password_hash.is_some()))
+        })
+    }
+
+    fn verify<'a>(
+        &'a self,
+        ctx: &'a LoginContext<'a>,
+        data: &'a str,
+    ) -> BoxFuture<'a, Result<bool, APIError>> {
+        Box::pin(async move {
+            let password_hash = ctx
+                .identity
+                .and_then(|identity| identity.password_hash.as_deref());
+
+            let hasher = &ctx.keygate.password_hasher;
+            let valid = match password_hash {
+                Some(password_hash) => hasher.verify(data, password_hash),
+                // unknown and legacy identities the legacy user store didn't accept
+                None if ctx.anti_enumeration()
+                    || ctx.identity.is_none_or(|identity| identity.legacy) =>
+                {
+                    hasher.verify_dummy(data)
+                }
+                None => return Err(APIError::InvalidArgument("Password not set".into())),
+            }
+            .map_err(|e| APIError::Internal(format!("Failed to verify password: {}", e)))?;
+
+            if let (true, Some(password_hash)) = (valid, password_hash) {
+                if hasher.needs_rehash(password_hash) {
+                    if let Err(e) = rehash_password(ctx, password_hash, data).await {
+                        warn!("Failed to rehash password: {:?}", e);
+                    }
+                }
+            }
+
+            Ok(valid)
+        })
+    }
+}
+
+/// Logins with an upstream OpenID Connect provider, started with
+/// [`crate::api::Auth::login_create_upstream`]. The provider is asked before this
+/// method, the identity is only known once it vouched for the user.
+#[derive(Debug)]
+struct Upstream;
+
+impl LoginMethod for Upstream {
+    fn step(&self) -> LoginStep {
+        LoginStep::UPSTREAM
+    }
+
+    fn factors(&self) -> &'static [Factor] {
+        &[Factor::First]
+    }
+
+    fn invalid_message(&self) -> &'static str {
+        "Upstream login failed"
+    }
+
+    fn available<'a>(
+        &'a self,
+        ctx: &'a LoginContext<'a>,
+        _factor: Factor,
+    ) -> BoxFuture<'a, Result<bool, APIError>> {
+        Box::pin(async move { Ok(ctx.process.upstream_provider.is_some()) })
+    }
+
+    fn verify<'a>(
+        &'a self,
+        ctx: &'a LoginContext<'a>,
+        _data: &'a str,
+    ) -> BoxFuture<'a, Result<bool, APIError>> {
+        Box::pin(async move { Ok(ctx.identity.is_some()) })
+    }
+}
+
+// replace a hash made with older parameters, unless the password changed in the meantime
+async fn rehash_password(
+    ctx: &LoginContext<'_>,
+    old_hash: &str,
+    password: &str,
+) -> Result<(), APIError> {
+    let password_hash = ctx
+        .keygate
+        .password_hasher
+        .hash(password)
+        .map_err(|e| APIError::Internal(format!("Failed to hash password: {}", e)))?;
+
+    sqlx::query!(
+        "UPDATE Identity SET password_hash = $1 WHERE id = $2 AND password_hash = $3",
+        password_hash,
+        ctx.process.identity_id,
+        old_hash
+    )
+    .execute(&ctx.keygate.db)
+    .await?;
+
+    Ok(())
+}
+
+/// One-time codes sent to the verified email address of the identity
+#[derive(Debug)]
+struct EmailCode;
+
+impl LoginMethod for EmailCode {
+    fn step(&self) -> LoginStep {
+        LoginStep::EMAIL_CODE
+    }
+
+    fn factors(&self) -> &'static [Factor] {
+        &[Factor::First, Factor::Fallback]
+    }
+
+    fn amr(&self) -> Option<&'static str> {
+        Some("otp")
+    }
+
+    fn available<'a>(
+        &'a self,
+        ctx: &'a LoginContext<'a>,
+        factor: Factor,
+    ) -> BoxFuture<'a, Result<bool, APIError>> {
+        Box::pin(async move {
+            if ctx.settings.email_code.is_none() {
+                return Ok(false);
+            }
+
+            // with anti-enumeration, every identity is offered the same first factors
+            if factor == Factor::First && ctx.anti_enumeration() {
+                return Ok(true);
+            }
+
+            Ok(verified_email(ctx.keygate, ctx.identity_id())
+                .await?
+                .is_some())
+        })
+    }
+
+    fn start<'a>(&'a self, ctx: &'a LoginContext<'a>) -> BoxFuture<'a, Result<(), APIError>> {
+        Box::pin(async move {
+            let code_settings = ctx
+                .settings
+                .email_code
+                .clone()
+                .ok_or(APIError::InvalidArgument("Email codes are disabled".into()))?;
+            let now = time::OffsetDateTime::now_utc();
+
+            let email = match verified_email(ctx.keygate, ctx.identity_id()).await? {
+                Some(email) => email,
+                // pretend the code was sent
+                None if ctx.anti_enumeration() => return Ok(()),
+                None => return Err(APIError::InvalidArgument("No verified email".into())),
+            };
+
+            if email
+                .last_verification_request
+                .is_some_and(|last| last + code_settings.resend_cooldown > now)
+            {
+                return Err(APIError::PermissionDenied(
+                    "Code was sent too recently".into(),
+                ));
+            }
+
+            // short codes need the slow hash, with the configured parameters and pepper
+            let code = numeric_code(code_settings.code_length);
+            let code_hash = ctx
+                .keygate
+                .password_hasher
+                .hash(&code)
+                .map_err(|e| APIError::Internal(format!("Failed to hash code: {}", e)))?;
+            let expires_at = now + code_settings.token_expires_in;
+
+            let mut tx = ctx.keygate.db.begin().await?;
+            sqlx::query!(
+                "UPDATE LoginProcess SET email_code = $1, email_code_expires_at = $2, email_code_attempts = 0, updated_at = $3 WHERE id = $4",
+                code_hash,
+                expires_at,
+                now,
+                ctx.process.id
+            )
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query!(
+                "UPDATE Email SET last_verification_request = $1, updated_at = $2 WHERE email = $3",
+                now,
+                now,
+                email.email
+            )
+            .execute(&mut *tx)
+            .await?;
+            tx.commit().await?;
+
+            ctx.keygate
+                .mailer
+                .load()
+                .send(EmailMessage {
+                    to: email.email,
+                    subject: "Your login code".to_string(),
+                    body: format!(
+                        "Your login code is {}. It expires in {} minutes.",
+                        code,
+                        code_settings.token_expires_in.whole_minutes()
+                    ),
+                })
+                .map_err(|e| APIError::Internal(e.to_string()))?;
+
+            Ok(())
+        })
+    }
+
+    fn verify<'a>(
+        &'a self,
+        ctx: &'a LoginContext<'a>,
+        data: &'a str,
+    ) -> BoxFuture<'a, Result<bool, APIError>> {
+        Box::pin(async move {
+            let (code_hash, max_attempts) = requested_email_code(ctx.process, ctx.settings)?;
+
+            // the attempt is counted before the code is checked, so concurrent guesses
+            // can't get past the limit
+            let now = time::OffsetDateTime::now_utc();
+            let attempt = sqlx::query!(
+                "UPDATE LoginProcess SET email_code_attempts = email_code_attempts + 1, updated_at = $1 WHERE id = $2 AND email_code_attempts < $3 RETURNING email_code_attempts",
+                now,
+                ctx.process.id,
+                max_attempts
+            )
+            .fetch_optional(&ctx.keygate.db)
+            .await?;
+            if attempt.is_none() {
+                return Err(APIError::PermissionDenied("Too many attempts".into()));
+            }
+
+            ctx.keygate
+                .password_hasher
+                .verify(data, code_hash)
+                .map_err(|e| APIError::Internal(format!("Failed to verify code: {}", e)))
+        })
+    }
+}
+
+// the hash of the code that was sent and the allowed attempts, errors if no valid code can be checked
+fn requested_email_code<'a>(
+    process: &'a LoginProcess,
+    settings: &GlobalSettings,
+) -> Result<(&'a str, i64), APIError> {
+    let code_settings = settings
+        .email_code
+        .as_ref()
+        .ok_or(APIError::InvalidArgument("Email codes are disabled".into()))?;
+
+    let (Some(code_hash), Some(expires_at)) = (&process.email_code, process.email_code_expires_at)
+    else {
+        return Err(APIError::InvalidArgument("No code was requested".into()));
+    };
+
+    if expires_at < time::OffsetDateTime::now_utc() {
+        return Err(APIError::InvalidArgument("Code expired".into()));
+    }
+
+    Ok((code_hash, code_settings.max_attempts))
+}
+
+#[derive(Debug)]
+struct RecoveryCodes;
+
+impl LoginMethod for RecoveryCodes {
+    fn step(&self) -> LoginStep {
+        LoginStep::RECOVERY_CODE
+    }
+
+    fn factors(&self) -> &'static [Factor] {
+        &[Factor::Fallback]
+    }
+
+    fn amr(&self) -> Option<&'static str> {
+        Some("otp")
+    }
+
+    fn invalid_message(&self) -> &'static str {
+        "Invalid recovery code"
+    }
+
+    fn available<'a>(
+        &'a self,
+        ctx: &'a LoginContext<'a>,
+        _factor: Factor,
+    ) -> BoxFuture<'a, Result<bool, APIError>> {
+        Box::pin(async move {
+            let remaining = sqlx::query!(
+                "SELECT COUNT(*) as remaining FROM RecoveryCode WHERE identity_id = $1 AND used_at IS NULL",
+                ctx.process.identity_id
+            )
+            .fetch_one(&ctx.keygate.db)
+            .await?
+            .remaining;
+
+            Ok(remaining > 0)
+        })
+    }
+
+    fn verify<'a>(
+        &'a self,
+        ctx: &'a LoginContext<'a>,
+        data: &'a str,
+    ) -> BoxFuture<'a, Result<bool, APIError>> {
+        Box::pin(async move {
+            let code = normalize_recovery_code(data);
+            let recovery_codes = sqlx::query_as!(
+                RecoveryCode,
+                "SELECT * FROM RecoveryCode WHERE identity_id = $1 AND used_at IS NULL",
+                ctx.process.identity_id
+            )
+            .fetch_all(&ctx.keygate.db)
+            .await?;
+
+            let mut used_code = None;
+            for recovery_code in recovery_codes {
+                if keygate_utils::hash::verify(&code, &recovery_code.code_hash)
+                    .map_err(|e| APIError::Internal(format!("Failed to verify code: {}", e)))?
+                {
+                    used_code = Some(recovery_code.id);
+                    break;
+                }
+            }
+
+            let Some(code_id) = used_code else {
+                return Ok(false);
+            };
+
+            // a code submitted twice at the same time must only be accepted once
+            let now = time::OffsetDateTime::now_utc();
+            let res = sqlx::query!(
+                "UPDATE RecoveryCode SET used_at = $1 WHERE id = $2 AND used_at IS NULL",
+                now,
+                code_id
+            )
+            .execute(&ctx.keygate.db)
+            .await?;
+            if res.rows_affected() == 0 {
+                return Ok(false);
+            }
+
+            Audit::new(ctx.keygate.clone())
+                .write(
+                    AuditEntry::new(ctx.identity_id(), AuditAction::RecoveryCodeUsed)
+                        .target("recovery_code", &code_id)
+                        .data(format!("login_process:{}", ctx.process.id)),
+                )
+                .await?;
+
+            Ok(true)
+        })
+    }
+}
+
+/// Tokens of devices the identity chose to trust, see [`crate::api::Auth::login_trust_device`]
+#[derive(Debug)]
+struct TrustedDevice;
+
+impl LoginMethod for TrustedDevice {
+    fn step(&self) -> LoginStep {
+        LoginStep::TRUSTED_DEVICE
+    }
+
+    fn factors(&self) -> &'static [Factor] {
+        &[Factor::Fallback]
+    }
+
+    fn amr(&self) -> Option<&'static str> {
+        Some("swk")
+    }
+
+    fn invalid_message(&self) -> &'static str {
+        "Invalid device token"
+    }
+
+    fn available<'a>(
+        &'a self,
+        ctx: &'a LoginContext<'a>,
+        _factor: Factor,
+    ) -> BoxFuture<'a, Result<bool, APIError>> {
+        // re-authentication has to prove the second factor again, and tokens are bound to a device id
+        Box::pin(async move { Ok(!ctx.reauthenticate() && ctx.process.device_hash.is_some()) })
+    }
+
+    fn verify<'a>(
+        &'a self,
+        ctx: &'a LoginContext<'a>,
+        data: &'a str,
+    ) -> BoxFuture<'a, Result<bool, APIError>> {
+        Box::pin(async move {
+            let now = time::OffsetDateTime::now_utc();
+            let token_hash = keygate_utils::hash::token(data);
+            // the token only works from the device it was issued to
+            let Some(device_hash) = &ctx.process.device_hash else {
+                return Ok(false);
+            };
+
+            let res = sqlx::query!(
+                "UPDATE TrustedDevice SET last_used_at = $1 WHERE token_hash = $2 AND identity_id = $3 AND device_hash = $4 AND revoked_at IS NULL AND expires_at > $1",
+                now,
+                token_hash,
+                ctx.process.identity_id,
+                device_hash
+            )
+            .execute(&ctx.keygate.db)
+            .await?;
+
+            Ok(res.rows_affected() > 0)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        api::auth::LoginResponse, api::identity::CreateIdentity, test_keygate, KeygateConfig,
+    };
+
+    #[derive(Debug)]
+    struct HardwareToken;
+
+    impl LoginMethod for HardwareToken {
+        fn step(&self) -> LoginStep {
+            LoginStep::new("hardware_token")
+        }
+
+        fn factors(&self) -> &'static [Factor] {
+            &[Factor::Second]
+        }
+
+        fn amr(&self) -> Option<&'static str> {
+            Some("hwk")
+        }
+
+        fn available<'a>(
+            &'a self,
+            _ctx: &'a LoginContext<'a>,
+            _factor: Factor,
+        ) -> BoxFuture<'a, Result<bool, APIError>> {
+            Box::pin(async { Ok(true) })
+        }
+
+        fn verify<'a>(
+            &'a self,
+            _ctx: &'a LoginContext<'a>,
+            data: &'a str,
+        ) -> BoxFuture<'a, Result<bool, APIError>> {
+            Box::pin(async move { Ok(data == "touched") })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_login_method() {
+        let keygate = test_keygate(KeygateConfig::default()).await;
+        keygate.create_admin_app().await.unwrap();
+        keygate.register_login_method(HardwareToken).unwrap();
+        assert!(keygate.register_login_method(HardwareToken).is_err());
+
+        let password_hash = keygate_utils::hash::password("correct horse").unwrap();
+        let identity = keygate
+            .identity
+            .create(CreateIdentity {
+                username: Some("hardware"),
+                primary_email: Some("hardware@keygate.io"),
+                password_hash: Some(&password_hash),
+            })
+            .await
+            .unwrap();
+        let password = keygate.inner.login_methods.get(&LoginStep::PASSWORD);
+        let session_id = keygate
+            .session
+            .start(
+                &identity.id,
+                None,
+                &crate::api::session::AuthContext::from_methods(
+                    &[password.unwrap()],
+                    time::OffsetDateTime::now_utc(),
+                ),
+            )
+            .await
+            .unwrap();
+
+        let LoginResponse::NextStep { process_id, .. } = keygate
+            .auth
+            .reauth_create("admin", &session_id, true)
+            .await
+            .unwrap()
+        else {
+            panic!("expected next step");
+        };
+        let LoginResponse::NextStep { step_type, .. } = keygate
+            .auth
+            .login_step("admin", &process_id, LoginStep::PASSWORD, "correct horse")
+            .await
+            .unwrap()
+        else {
+            panic!("expected second factor");
+        };
+        assert_eq!(step_type, vec![LoginStep::new("hardware_token")]);
+
+        let res = keygate
+            .auth
+            .login_step(
+                "admin",
+                &process_id,
+                LoginStep::new("hardware_token"),
+                "wrong",
+            )
+            .await;
+        assert!(matches!(res, Err(APIError::InvalidArgument(_))));
+
+        keygate
+            .auth
+            .login_step(
+                "admin",
+                &process_id,
+                LoginStep::new("hardware_token"),
+                "touched",
+            )
+            .await
+            .unwrap();
+
+        let context = keygate.session.auth_context(&session_id).await.unwrap();
+        assert_eq!(context.amr, vec!["pwd", "hwk", "mfa"]);
+    }
+
+    #[tokio::test]
+    async fn test_password_rehash_on_login() {
+        let keygate = test_keygate(KeygateConfig {
+            password_hashing: crate::config::PasswordHashingConfig {
+                params: keygate_utils::hash::PasswordHashParams {
+                    memory_cost: 1024,
+                    iterations: 1,
+                    parallelism: 1,
+                },
+                pepper: Some("pepper".into()),
+                calibrate_ms: None,
+                firebase_scrypt: None,
+            },
+            ..Default::default()
+        })
+        .await;
+        keygate.create_admin_app().await.unwrap();
+
+        // stored before the parameters and pepper were configured
+        let password_hash = keygate_utils::hash::password("correct horse").unwrap();
+        let identity = keygate
+            .identity
+            .create(CreateIdentity {
+                username: Some("rehash"),
+                primary_email: Some("rehash@keygate.io"),
+                password_hash: Some(&password_hash),
+            })
+            .await
+            .unwrap();
+
+        let LoginResponse::NextStep { process_id, .. } = keygate
+            .auth
+            .login_create("admin", "rehash", None, None)
+            .await
+            .unwrap()
+        else {
+            panic!("expected next step");
+        };
+        let res = keygate
+            .auth
+            .login_step("admin", &process_id, LoginStep::PASSWORD, "correct horse")
+            .await
+            .unwrap();
+        assert!(matches!(res, LoginResponse::Success { .. }));
+
+        let stored = sqlx::query!(
+            "SELECT password_hash FROM Identity WHERE id = $1",
+            identity.id
+        )
+        .fetch_one(&keygate.inner.db)
+        .await
+        .unwrap()
+        .password_hash
+        .unwrap();
+        let hasher = &keygate.inner.password_hasher;
+        assert_ne!(stored, password_hash);
+        assert!(!hasher.needs_rehash(&stored));
+        assert!(hasher.verify("correct horse", &stored).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_legacy_hash_upgrade_on_login() {
+        let keygate = test_keygate(KeygateConfig::default()).await;
+        keygate.create_admin_app().await.unwrap();
+
+        // exported from django
+        let password_hash =
+            "pbkdf2_sha256$1000$seasalt$mQnueSakb748zqBAC1tmWVZsZbi2zPGZarEzTGdfmso=";
+        let identity = keygate
+            .identity
+            .create(CreateIdentity {
+                username: Some("imported"),
+                primary_email: Some("imported@keygate.io"),
+                password_hash: Some(password_hash),
+            })
+            .await
+            .unwrap();
+
+        let LoginResponse::NextStep { process_id, .. } = keygate
+            .auth
+            .login_create("admin", "imported", None, None)
+            .await
+            .unwrap()
+        else {
+            panic!("expected next step");
+        };
+        let res = keygate
+            .auth
+            .login_step("admin", &process_id, LoginStep::PASSWORD, "battery staple")
+            .await;
+        assert!(matches!(res, Err(APIError::InvalidArgument(_))));
+        let res = keygate
+            .auth
+            .login_step("admin", &process_id, LoginStep::PASSWORD, "correct horse")
+            .await
+            .unwrap();
+        assert!(matches!(res, LoginResponse::Success { .. }));
+
+        let stored = sqlx::query!(
+            "SELECT password_hash FROM Identity WHERE id = $1",
+            identity.id
+        )
+        .fetch_one(&keygate.inner.db)
+        .await
+        .unwrap()
+        .password_hash
+        .unwrap();
+        assert!(stored.starts_with("$argon2id$"));
+        assert!(keygate
+            .inner
+            .password_hasher
+            .verify("correct horse", &stored)
+            .unwrap());
+    }
+}
@@ -0,0 +1,52 @@
+use std::fmt::Debug;
+
+use thiserror::Error;
+use tracing::info;
+
+#[derive(Error, Debug)]
+pub enum MailerError {
+    #[error("failed to send email: {0}")]
+    SendFailed(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct EmailMessage {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Delivers emails sent by keygate (login codes, notifications, ...).
+///
+/// Sending should not block for long, implementations that talk to a remote
+/// service should queue the message and deliver it in the background.
+pub trait Mailer: Send + Sync + Debug {
+    fn send(&self, message: EmailMessage) -> Result<(), MailerError>;
+}
+
+/// Default mailer, writes all emails to the log. Only useful during development.
+#[derive(Debug, Default)]
+pub struct LogMailer;
+
+impl Mailer for LogMailer {
+    fn send(&self, message: EmailMessage) -> Result<(), MailerError> {
+        info!(
+            "Email to {}: {}\n{}",
+            message.to, message.subject, message.body
+        );
+        Ok(())
+    }
+}
+
+/// Collects sent emails so tests can read them
+#[cfg(test)]
+#[derive(Debug, Default, Clone)]
+pub(crate) struct TestMailer(pub std::sync::Arc<std::sync::Mutex<Vec<EmailMessage>>>);
+
+#[cfg(test)]
+impl Mailer for TestMailer {
+    fn send(&self, message: EmailMessage) -> Result<(), MailerError> {
+        self.0.lock().unwrap().push(message);
+        Ok(())
+    }
+}
@@ -0,0 +1,3 @@
+ALTER TABLE LoginProcess ADD COLUMN email_code VARCHAR(255);
+ALTER TABLE LoginProcess ADD COLUMN email_code_expires_at TIMESTAMP;
+ALTER TABLE LoginProcess ADD COLUMN email_code_attempts INTEGER NOT NULL DEFAULT 0;
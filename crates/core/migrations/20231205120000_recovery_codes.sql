@@ -0,0 +1,11 @@
+CREATE TABLE
+    RecoveryCode (
+        id VARCHAR(36) PRIMARY KEY NOT NULL,
+        created_at TIMESTAMP NOT NULL,
+        identity_id VARCHAR(36) NOT NULL,
+        code_hash VARCHAR(255) NOT NULL,
+        used_at TIMESTAMP,
+        FOREIGN KEY (identity_id) REFERENCES Identity (id) ON DELETE CASCADE
+    );
+
+CREATE INDEX RecoveryCode_identity_id ON RecoveryCode (identity_id);
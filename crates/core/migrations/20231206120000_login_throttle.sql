@@ -0,0 +1,7 @@
+CREATE TABLE
+    LoginThrottle (
+        key VARCHAR(255) PRIMARY KEY NOT NULL,
+        failures INTEGER NOT NULL,
+        last_failure_at TIMESTAMP NOT NULL,
+        locked_until TIMESTAMP
+    );
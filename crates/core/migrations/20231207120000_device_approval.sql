@@ -0,0 +1,13 @@
+CREATE TABLE
+    Device (
+        id VARCHAR(36) PRIMARY KEY NOT NULL,
+        identity_id VARCHAR(36) NOT NULL,
+        device_hash VARCHAR(255) NOT NULL,
+        created_at TIMESTAMP NOT NULL,
+        last_login_at TIMESTAMP NOT NULL,
+        UNIQUE (identity_id, device_hash),
+        FOREIGN KEY (identity_id) REFERENCES Identity (id) ON DELETE CASCADE
+    );
+
+ALTER TABLE LoginProcess ADD COLUMN device_hash VARCHAR(255);
+ALTER TABLE LoginProcess ADD COLUMN device_approval VARCHAR(255);
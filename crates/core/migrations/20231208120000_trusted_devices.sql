@@ -0,0 +1,16 @@
+CREATE TABLE
+    TrustedDevice (
+        id VARCHAR(36) PRIMARY KEY NOT NULL,
+        identity_id VARCHAR(36) NOT NULL,
+        login_process_id VARCHAR(36) NOT NULL UNIQUE,
+        label VARCHAR(255) NOT NULL,
+        token_hash VARCHAR(255) NOT NULL UNIQUE,
+        created_at TIMESTAMP NOT NULL,
+        last_used_at TIMESTAMP,
+        expires_at TIMESTAMP NOT NULL,
+        revoked_at TIMESTAMP,
+        FOREIGN KEY (identity_id) REFERENCES Identity (id) ON DELETE CASCADE
+    );
+
+-- comma separated list of the factors used to complete the login
+ALTER TABLE LoginProcess ADD COLUMN factors VARCHAR(255) NOT NULL DEFAULT '';
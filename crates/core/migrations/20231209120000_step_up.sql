@@ -0,0 +1,9 @@
+-- how and when the session last authenticated, raised by re-authenticating
+ALTER TABLE Session ADD COLUMN auth_time TIMESTAMP;
+ALTER TABLE Session ADD COLUMN amr VARCHAR(255) NOT NULL DEFAULT '';
+ALTER TABLE Session ADD COLUMN acr VARCHAR(255) NOT NULL DEFAULT '';
+
+-- the session created by the login, or the session being re-authenticated
+ALTER TABLE LoginProcess ADD COLUMN session_id VARCHAR(36);
+ALTER TABLE LoginProcess ADD COLUMN reauthenticate BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE LoginProcess ADD COLUMN require_second_factor BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,2 @@
+-- the application the login is for, its settings decide which steps are allowed
+ALTER TABLE LoginProcess ADD COLUMN application_id VARCHAR(36);
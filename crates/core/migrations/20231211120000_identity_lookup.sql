@@ -0,0 +1,3 @@
+-- logins look up usernames and emails case-insensitively
+CREATE INDEX Identity_username_nocase ON Identity (username COLLATE NOCASE);
+CREATE INDEX Identity_primary_email_nocase ON Identity (primary_email COLLATE NOCASE);
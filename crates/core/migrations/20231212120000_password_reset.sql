@@ -0,0 +1,13 @@
+CREATE TABLE
+    PasswordReset (
+        id VARCHAR(36) PRIMARY KEY NOT NULL,
+        identity_id VARCHAR(36) NOT NULL,
+        email VARCHAR(255) NOT NULL,
+        token_hash VARCHAR(255) NOT NULL UNIQUE,
+        created_at TIMESTAMP NOT NULL,
+        expires_at TIMESTAMP NOT NULL,
+        used_at TIMESTAMP,
+        FOREIGN KEY (identity_id) REFERENCES Identity (id) ON DELETE CASCADE
+    );
+
+CREATE INDEX PasswordReset_identity_id ON PasswordReset (identity_id);
@@ -0,0 +1,2 @@
+-- set by admins, the next login has to choose a new password before it completes
+ALTER TABLE Identity ADD COLUMN must_change_password BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,5 @@
+-- identities imported without a usable password hash, checked against the legacy user store on login
+ALTER TABLE Identity ADD COLUMN legacy BOOLEAN NOT NULL DEFAULT false;
+
+-- what the user logged in with, forwarded to the legacy user store
+ALTER TABLE LoginProcess ADD COLUMN legacy_username VARCHAR(255);
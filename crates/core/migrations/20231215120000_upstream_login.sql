@@ -0,0 +1,26 @@
+-- linked accounts were never used, recreate them with the identity they belong to
+DROP TABLE LinkedAccount;
+
+CREATE TABLE
+    LinkedAccount (
+        id VARCHAR(36) PRIMARY KEY NOT NULL,
+        identity_id VARCHAR(36) NOT NULL,
+        provider_id VARCHAR(255) NOT NULL,
+        -- `sub` of the provider's id_token
+        subject VARCHAR(255) NOT NULL,
+        email VARCHAR(255),
+        created_at TIMESTAMP NOT NULL,
+        last_login_at TIMESTAMP,
+        FOREIGN KEY (identity_id) REFERENCES Identity (id),
+        UNIQUE (provider_id, subject)
+    );
+
+CREATE INDEX LinkedAccount_identity_id ON LinkedAccount (identity_id);
+
+-- authorization request of a login with an upstream provider, the state is hashed
+ALTER TABLE LoginProcess ADD COLUMN upstream_provider VARCHAR(255);
+ALTER TABLE LoginProcess ADD COLUMN upstream_state VARCHAR(255);
+ALTER TABLE LoginProcess ADD COLUMN upstream_nonce VARCHAR(255);
+ALTER TABLE LoginProcess ADD COLUMN upstream_code_verifier VARCHAR(255);
+
+CREATE UNIQUE INDEX LoginProcess_upstream_state ON LoginProcess (upstream_state);
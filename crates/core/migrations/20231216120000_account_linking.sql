@@ -0,0 +1,15 @@
+-- authorization request of a signed in identity that links an upstream provider, the state is hashed
+CREATE TABLE
+    LinkRequest (
+        id VARCHAR(36) PRIMARY KEY NOT NULL,
+        identity_id VARCHAR(36) NOT NULL,
+        provider_id VARCHAR(255) NOT NULL,
+        state VARCHAR(255) NOT NULL UNIQUE,
+        nonce VARCHAR(255) NOT NULL,
+        code_verifier VARCHAR(255) NOT NULL,
+        created_at TIMESTAMP NOT NULL,
+        expires_at TIMESTAMP NOT NULL,
+        FOREIGN KEY (identity_id) REFERENCES Identity (id)
+    );
+
+CREATE INDEX LinkRequest_identity_id ON LinkRequest (identity_id);
@@ -0,0 +1,10 @@
+-- assertions of SAML connections that were used to log in, kept until they expire so they can't be replayed
+CREATE TABLE
+    SamlAssertion (
+        connection_id VARCHAR(255) NOT NULL,
+        id VARCHAR(255) NOT NULL,
+        expires_at TIMESTAMP NOT NULL,
+        PRIMARY KEY (connection_id, id)
+    );
+
+CREATE INDEX SamlAssertion_expires_at ON SamlAssertion (expires_at);
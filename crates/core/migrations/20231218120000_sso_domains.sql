@@ -0,0 +1,13 @@
+-- email domains whose users log in with an upstream provider, once the owner of the domain published the verification token
+CREATE TABLE
+    SsoDomain (
+        domain VARCHAR(255) PRIMARY KEY NOT NULL,
+        provider_id VARCHAR(255) NOT NULL,
+        verification_token VARCHAR(255) NOT NULL,
+        verified_at TIMESTAMP,
+        block_password BOOLEAN NOT NULL DEFAULT false,
+        created_at TIMESTAMP NOT NULL
+    );
+
+-- the process can only be completed with the upstream provider
+ALTER TABLE LoginProcess ADD COLUMN upstream_required BOOLEAN NOT NULL DEFAULT false;
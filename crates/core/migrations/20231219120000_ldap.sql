@@ -0,0 +1,14 @@
+-- identities whose password is checked with a bind to the LDAP directory
+ALTER TABLE Identity ADD COLUMN ldap BOOLEAN NOT NULL DEFAULT false;
+
+-- what the user logged in with, searched for in the LDAP directory
+ALTER TABLE LoginProcess ADD COLUMN ldap_username VARCHAR(255);
+
+-- roles of an identity, synced from its LDAP groups on login
+CREATE TABLE IdentityRole (
+    identity_id VARCHAR(255) NOT NULL,
+    role VARCHAR(255) NOT NULL,
+    created_at TIMESTAMP NOT NULL,
+    PRIMARY KEY (identity_id, role),
+    FOREIGN KEY (identity_id) REFERENCES Identity(id) ON DELETE CASCADE
+);
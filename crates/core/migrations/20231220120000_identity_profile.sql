@@ -0,0 +1,6 @@
+-- collected on signup if GlobalSettings.require_full_name is set
+ALTER TABLE Identity ADD COLUMN full_name VARCHAR(255);
+-- only stored if GlobalSettings.store_birthdate is set
+ALTER TABLE Identity ADD COLUMN birthdate DATE;
+-- when the identity confirmed it is at least GlobalSettings.minimum_age, kept instead of the birthdate
+ALTER TABLE Identity ADD COLUMN age_attested_at TIMESTAMP;
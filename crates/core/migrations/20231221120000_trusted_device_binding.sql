@@ -0,0 +1,2 @@
+-- trusted device tokens only work from the device they were issued to
+ALTER TABLE TrustedDevice ADD COLUMN device_hash VARCHAR(255);
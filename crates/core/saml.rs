@@ -0,0 +1,237 @@
+use keygate_utils::{
+    random::randomid,
+    saml::{
+        decode_response, verify_response, Assertion, IdpMetadata, ResponseValidation,
+        ServiceProvider, SigningKey, NAMEID_EMAIL,
+    },
+};
+use tracing::warn;
+
+use crate::{
+    api::APIError,
+    config::{SamlConnectionConfig, SamlSigningKeyConfig},
+    upstream::UpstreamUser,
+};
+
+/// Where to send the user to log in with a SAML IdP, the rest is kept with the login process
+#[derive(Debug, Clone)]
+pub(crate) struct SamlRequest {
+    pub url: String,
+    pub relay_state: String,
+    /// the response has to answer it
+    pub request_id: String,
+}
+
+#[derive(Debug)]
+struct Connection {
+    config: SamlConnectionConfig,
+    idp: IdpMetadata,
+}
+
+impl Connection {
+    fn sp(&self) -> ServiceProvider<'_> {
+        ServiceProvider {
+            entity_id: &self.config.entity_id,
+            acs_url: &self.config.acs_url,
+        }
+    }
+}
+
+/// The configured SAML identity providers
+#[derive(Debug)]
+pub(crate) struct SamlConnections {
+    connections: Vec<Connection>,
+    signing_key: Option<SigningKey>,
+}
+
+impl SamlConnections {
+    /// Errors if the metadata of an IdP or the signing key is invalid
+    pub fn new(
+        connections: Vec<SamlConnectionConfig>,
+        signing_key: Option<&SamlSigningKeyConfig>,
+    ) -> Result<Self, String> {
+        let signing_key = signing_key
+            .map(|key| SigningKey::from_pem(&key.private_key, &key.certificate))
+            .transpose()
+            .map_err(|e| format!("Invalid SAML signing key: {}", e))?;
+        let connections = connections
+            .into_iter()
+            .map(|config| match IdpMetadata::parse(&config.idp_metadata) {
+                Ok(idp) => Ok(Connection { config, idp }),
+                Err(e) => Err(format!(
+                    "Invalid metadata of SAML connection {}: {}",
+                    config.id, e
+                )),
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            connections,
+            signing_key,
+        })
+    }
+
+    pub fn get(&self, connection_id: &str) -> Option<&SamlConnectionConfig> {
+        self.connection(connection_id)
+            .ok()
+            .map(|connection| &connection.config)
+    }
+
+    fn connection(&self, connection_id: &str) -> Result<&Connection, APIError> {
+        self.connections
+            .iter()
+            .find(|connection| connection.config.id == connection_id)
+            .ok_or(APIError::NotFound("SAML connection not found".into()))
+    }
+
+    /// Our metadata for the IdP of a connection
+    pub fn metadata(&self, connection_id: &str) -> Result<String, APIError> {
+        let connection = self.connection(connection_id)?;
+        Ok(connection.sp().metadata(self.signing_key.as_ref()))
+    }
+
+    /// An AuthnRequest with the HTTP-Redirect binding, signed if a key is configured
+    pub fn authn_request(&self, connection_id: &str) -> Result<SamlRequest, APIError> {
+        let connection = self.connection(connection_id)?;
+        let relay_state = randomid(32);
+        let request = connection.sp().authn_request(
+            &connection.idp,
+            &relay_state,
+            self.signing_key.as_ref(),
+            time::OffsetDateTime::now_utc(),
+        );
+
+        Ok(SamlRequest {
+            url: request.url,
+            relay_state,
+            request_id: request.id,
+        })
+    }
+
+    /// Verify the `SAMLResponse` to a request. Returns `None` if it is invalid.
+    pub fn verify(
+        &self,
+        connection_id: &str,
+        saml_response: &str,
+        request_id: &str,
+    ) -> Result<Option<Assertion>, APIError> {
+        let connection = self.connection(connection_id)?;
+        let validation = ResponseValidation {
+            sp: connection.sp(),
+            request_id,
+            now: time::OffsetDateTime::now_utc().unix_timestamp(),
+        };
+
+        match decode_response(saml_response)
+            .and_then(|xml| verify_response(&xml, &connection.idp, &validation))
+        {
+            Ok(assertion) => Ok(Some(assertion)),
+            Err(e) => {
+                warn!(
+                    "Invalid response from SAML connection {}: {}",
+                    connection_id, e
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    /// Map the attributes of an assertion onto the fields of an identity
+    pub fn user(
+        &self,
+        connection_id: &str,
+        assertion: &Assertion,
+    ) -> Result<UpstreamUser, APIError> {
+        let config = &self.connection(connection_id)?.config;
+        let mapping = &config.attribute_mapping;
+
+        let email = match &mapping.email {
+            Some(attribute) => assertion.attribute(attribute),
+            None => (assertion.name_id_format.as_deref() == Some(NAMEID_EMAIL))
+                .then_some(assertion.name_id.as_str()),
+        };
+        let username = mapping
+            .username
+            .as_deref()
+            .and_then(|attribute| assertion.attribute(attribute));
+
+        Ok(UpstreamUser {
+            subject: assertion.name_id.clone(),
+            email: email.map(str::to_string),
+            email_verified: email.is_some() && config.trust_email,
+            username: username.map(str::to_string),
+        })
+    }
+}
+
+/// A SAML IdP for tests, signing with the RSA test key
+#[cfg(test)]
+pub(crate) mod mock {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    use crate::config::{SamlConnectionConfig, SamlSigningKeyConfig};
+
+    pub const KEY: &str = include_str!("../utils/testdata/oidc_rsa.pem");
+    pub const CERTIFICATE: &str = include_str!("../utils/testdata/saml_idp.crt");
+    pub const ENTITY_ID: &str = "https://idp.example.com";
+
+    const NS_PROTOCOL: &str = "urn:oasis:names:tc:SAML:2.0:protocol";
+    const NS_ASSERTION: &str = "urn:oasis:names:tc:SAML:2.0:assertion";
+
+    pub fn signing_key() -> SamlSigningKeyConfig {
+        SamlSigningKeyConfig {
+            private_key: KEY.into(),
+            certificate: CERTIFICATE.into(),
+        }
+    }
+
+    pub fn config(id: &str) -> SamlConnectionConfig {
+        let certificate: String = CERTIFICATE
+            .lines()
+            .filter(|line| !line.starts_with("-----"))
+            .collect();
+        SamlConnectionConfig {
+            id: id.into(),
+            idp_metadata: format!(
+                r#"<md:EntityDescriptor xmlns:md="urn:oasis:names:tc:SAML:2.0:metadata" entityID="{ENTITY_ID}"><md:IDPSSODescriptor protocolSupportEnumeration="{NS_PROTOCOL}"><md:KeyDescriptor use="signing"><ds:KeyInfo xmlns:ds="http://www.w3.org/2000/09/xmldsig#"><ds:X509Data><ds:X509Certificate>{certificate}</ds:X509Certificate></ds:X509Data></ds:KeyInfo></md:KeyDescriptor><md:SingleSignOnService Binding="urn:oasis:names:tc:SAML:2.0:bindings:HTTP-Redirect" Location="https://idp.example.com/sso"/></md:IDPSSODescriptor></md:EntityDescriptor>"#
+            ),
+            entity_id: "https://keygate.example.com".into(),
+            acs_url: "https://keygate.example.com/acs".into(),
+            attribute_mapping: crate::config::SamlAttributeMapping {
+                username: Some("uid".into()),
+                email: None,
+            },
+            trust_email: true,
+        }
+    }
+
+    /// A signed `SAMLResponse` for the user with the email `name_id`, answering `request_id`
+    pub fn response(
+        config: &SamlConnectionConfig,
+        request_id: &str,
+        name_id: &str,
+        username: &str,
+    ) -> String {
+        let now = time::OffsetDateTime::now_utc();
+        let timestamp = |instant: time::OffsetDateTime| {
+            instant
+                .replace_nanosecond(0)
+                .unwrap()
+                .format(&time::format_description::well_known::Rfc3339)
+                .unwrap()
+        };
+        let issued = timestamp(now);
+        let expires = timestamp(now + time::Duration::minutes(5));
+        let assertion_id = format!("_{}", keygate_utils::random::randomid(32));
+
+        let xml = format!(
+            r#"<samlp:Response xmlns:samlp="{NS_PROTOCOL}" xmlns:saml="{NS_ASSERTION}" ID="_response" Version="2.0" IssueInstant="{issued}" Destination="{acs_url}" InResponseTo="{request_id}"><saml:Issuer>{ENTITY_ID}</saml:Issuer><samlp:Status><samlp:StatusCode Value="urn:oasis:names:tc:SAML:2.0:status:Success"/></samlp:Status><saml:Assertion ID="{assertion_id}" Version="2.0" IssueInstant="{issued}"><saml:Issuer>{ENTITY_ID}</saml:Issuer><saml:Subject><saml:NameID Format="urn:oasis:names:tc:SAML:1.1:nameid-format:emailAddress">{name_id}</saml:NameID><saml:SubjectConfirmation Method="urn:oasis:names:tc:SAML:2.0:cm:bearer"><saml:SubjectConfirmationData InResponseTo="{request_id}" Recipient="{acs_url}" NotOnOrAfter="{expires}"/></saml:SubjectConfirmation></saml:Subject><saml:Conditions NotBefore="{issued}" NotOnOrAfter="{expires}"><saml:AudienceRestriction><saml:Audience>{entity_id}</saml:Audience></saml:AudienceRestriction></saml:Conditions><saml:AttributeStatement><saml:Attribute Name="uid"><saml:AttributeValue>{username}</saml:AttributeValue></saml:Attribute></saml:AttributeStatement></saml:Assertion></samlp:Response>"#,
+            acs_url = config.acs_url,
+            entity_id = config.entity_id,
+        );
+
+        let key = rsa::pkcs8::DecodePrivateKey::from_pkcs8_pem(KEY).unwrap();
+        let signed = keygate_utils::saml::sign(&xml, &assertion_id, &key).unwrap();
+        STANDARD.encode(signed)
+    }
+}
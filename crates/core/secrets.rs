@@ -4,10 +4,7 @@ use std::{
 };
 
 use dashmap::DashMap;
-use keygate_utils::{
-    random::secure_random_id,
-    tokens::{ed25519, SignatureAlgorithm},
-};
+use keygate_utils::tokens::{ed25519, Algorithm, KeygateKeypair};
 use time::OffsetDateTime;
 
 use crate::{
@@ -15,15 +12,22 @@ use crate::{
     KeygateError, KeygateInternal, KeygateResult,
 };
 
-pub enum Keypair {
-    Ed25519(ed25519::Ed25519Keypair),
-}
+// tokens signed with a key are accepted until it expires
+const SIGNING_KEY_VALIDITY: time::Duration = time::Duration::days(90);
 
 #[derive(Clone)]
 pub enum PublicKey {
     Ed25519(ed25519::VerifyingKey),
 }
 
+impl PublicKey {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            PublicKey::Ed25519(key) => key.to_bytes().to_vec(),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct PublicKeyData {
     pub node_id: String,
@@ -36,7 +40,7 @@ pub struct PublicKeyData {
 
 pub struct Secrets {
     keygate: OnceLock<Arc<KeygateInternal>>,
-    active_keypairs: DashMap<String, Keypair>,
+    active_keypairs: DashMap<String, KeygateKeypair>,
     public_keys: DashMap<String, PublicKeyData>,
 }
 
@@ -87,13 +91,16 @@ impl Secrets {
     }
 
     async fn public_key_from_db(&self, key_id: &str) -> KeygateResult<Option<PublicKeyData>> {
-        let key: models::PublicKey = sqlx::query_as!(
+        let Some(key) = sqlx::query_as!(
             models::PublicKey,
-            r#"SELECT * FROM PublicKey WHERE id = $0"#,
+            r#"SELECT * FROM PublicKey WHERE id = $1"#,
             key_id
         )
-        .fetch_one(self.db())
-        .await?;
+        .fetch_optional(self.db())
+        .await?
+        else {
+            return Ok(None);
+        };
 
         let key = PublicKeyData {
             key: match key.key_type.as_str() {
@@ -137,19 +144,48 @@ impl Secrets {
         self.keygate.set(keygate).unwrap();
     }
 
-    pub(crate) async fn ensure_keypair(&self) {
+    pub(crate) async fn ensure_keypair(&self) -> KeygateResult<()> {
         if self.active_keypairs.is_empty() {
             tracing::debug!("No signing keys are configured, generating a new one");
-            let new_key_id = self.generate_signing_key();
+            self.generate_signing_key().await?;
         }
+        Ok(())
     }
 
-    fn generate_signing_key(&self) -> String {
-        let keypair = ed25519::Ed25519Keypair::generate();
-        let public_key = keypair.public_key();
-        let key_id = secure_random_id();
+    /// The key new tokens are signed with
+    pub(crate) fn signing_keypair(&self) -> Option<KeygateKeypair> {
         self.active_keypairs
-            .insert(key_id.clone(), Keypair::Ed25519(keypair));
-        key_id
+            .iter()
+            .next()
+            .map(|keypair| keypair.value().clone())
+    }
+
+    // the public key is stored so every node can verify the tokens signed with it
+    async fn generate_signing_key(&self) -> KeygateResult<String> {
+        let keypair = KeygateKeypair::generate(Algorithm::Ed25519);
+        let now = OffsetDateTime::now_utc();
+        let valid_until = now + SIGNING_KEY_VALIDITY;
+        let public_key = keypair.public_key();
+        let node_id = &self
+            .keygate
+            .get()
+            .expect("Keygate not initialized")
+            .config
+            .node_id;
+
+        sqlx::query!(
+            "INSERT INTO PublicKey (id, created_at, key_type, node_id, valid_until, public_key) VALUES ($1, $2, 'ed25519', $3, $4, $5)",
+            keypair.id,
+            now,
+            node_id,
+            valid_until,
+            public_key
+        )
+        .execute(self.db())
+        .await?;
+
+        let key_id = keypair.id.clone();
+        self.active_keypairs.insert(key_id.clone(), keypair);
+        Ok(key_id)
     }
 }
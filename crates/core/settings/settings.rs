@@ -231,6 +231,7 @@ fn default_global_settings() -> GlobalSettings {
     GlobalSettings {
         login_identifier: crate::database::models::AccountNaming::UsernameOrEmail,
         check_haveibeenpwned: false,
+        password_policy: Default::default(),
 
         login_process_expires_in: Duration::minutes(5),
         signup_process_expires_in: Duration::minutes(5),
@@ -241,6 +242,13 @@ fn default_global_settings() -> GlobalSettings {
         email_verification: crate::database::models::EmailVerification::None,
         enable_multiple_emails_per_account: false,
         magic_link: None,
+        email_code: None,
+        login_throttle: Default::default(),
+        password_reset: Default::default(),
+        device_approval: false,
+        trusted_device_expires_in: Duration::days(30),
+        reauthenticate_after: Duration::minutes(10),
+        email_verification_resend_cooldown: Duration::minutes(1),
 
         minimum_age: None,
         require_birthdate: false,
@@ -0,0 +1,470 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use keygate_utils::{
+    oidc::{pkce_challenge, verify_id_token, IdTokenClaims, IdTokenValidation, Jwks, OidcError},
+    random::randomid,
+};
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::{api::APIError, config::UpstreamProviderConfig};
+
+/// how long discovered endpoints and keys are used before they are fetched again
+const DISCOVERY_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// The endpoints of a provider, from `/.well-known/openid-configuration`
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProviderMetadata {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+}
+
+#[derive(Debug)]
+struct Discovered {
+    metadata: ProviderMetadata,
+    jwks: Jwks,
+    fetched_at: Instant,
+}
+
+/// Where to send the user to log in with a provider, the rest is kept with the login process
+#[derive(Debug, Clone)]
+pub(crate) struct AuthorizationRequest {
+    pub url: String,
+    pub state: String,
+    pub nonce: String,
+    pub code_verifier: String,
+}
+
+/// A user as identified by an upstream provider or SAML connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct UpstreamUser {
+    /// unique per provider
+    pub subject: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub username: Option<String>,
+}
+
+impl From<IdTokenClaims> for UpstreamUser {
+    fn from(claims: IdTokenClaims) -> Self {
+        Self {
+            subject: claims.sub,
+            email: claims.email,
+            email_verified: claims.email_verified,
+            username: claims.preferred_username,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// The configured upstream OpenID Connect providers
+#[derive(Debug)]
+pub(crate) struct UpstreamProviders {
+    providers: Vec<UpstreamProviderConfig>,
+    client: reqwest::Client,
+    discovered: DashMap<String, Arc<Discovered>>,
+}
+
+impl UpstreamProviders {
+    pub fn new(providers: Vec<UpstreamProviderConfig>) -> Self {
+        Self {
+            providers,
+            client: reqwest::Client::new(),
+            discovered: DashMap::new(),
+        }
+    }
+
+    pub fn get(&self, provider_id: &str) -> Option<&UpstreamProviderConfig> {
+        self.providers
+            .iter()
+            .find(|provider| provider.id == provider_id)
+    }
+
+    fn provider(&self, provider_id: &str) -> Result<&UpstreamProviderConfig, APIError> {
+        self.get(provider_id)
+            .ok_or(APIError::NotFound("Upstream provider not found".into()))
+    }
+
+    async fn fetch<T: serde::de::DeserializeOwned>(&self, url: &str) -> Result<T, APIError> {
+        self.client
+            .get(url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| APIError::Internal(format!("Upstream provider failed: {}", e)))?
+            .json()
+            .await
+            .map_err(|e| APIError::Internal(format!("Invalid upstream provider response: {}", e)))
+    }
+
+    // endpoints and keys of the provider, `refresh` fetches them even if they are cached
+    async fn discover(
+        &self,
+        provider: &UpstreamProviderConfig,
+        refresh: bool,
+    ) -> Result<Arc<Discovered>, APIError> {
+        if let Some(discovered) = self.discovered.get(&provider.id) {
+            if !refresh && discovered.fetched_at.elapsed() < DISCOVERY_TTL {
+                return Ok(discovered.clone());
+            }
+        }
+
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            provider.issuer.trim_end_matches('/')
+        );
+        let metadata: ProviderMetadata = self.fetch(&url).await?;
+        if metadata.issuer != provider.issuer {
+            return Err(APIError::Internal(format!(
+                "Upstream provider {} announced a different issuer",
+                provider.id
+            )));
+        }
+        let jwks = self.fetch(&metadata.jwks_uri).await?;
+
+        let discovered = Arc::new(Discovered {
+            metadata,
+            jwks,
+            fetched_at: Instant::now(),
+        });
+        self.discovered
+            .insert(provider.id.clone(), discovered.clone());
+        Ok(discovered)
+    }
+
+    /// Start an authorization code flow with PKCE
+    pub async fn authorization_request(
+        &self,
+        provider_id: &str,
+    ) -> Result<AuthorizationRequest, APIError> {
+        let provider = self.provider(provider_id)?;
+        let discovered = self.discover(provider, false).await?;
+
+        let state = randomid(32);
+        let nonce = randomid(32);
+        let code_verifier = randomid(64);
+        let mut scopes = vec!["openid"];
+        scopes.extend(
+            provider
+                .scopes
+                .iter()
+                .map(String::as_str)
+                .filter(|scope| *scope != "openid"),
+        );
+
+        let url = reqwest::Url::parse_with_params(
+            &discovered.metadata.authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", &provider.client_id),
+                ("redirect_uri", &provider.redirect_uri),
+                ("scope", &scopes.join(" ")),
+                ("state", &state),
+                ("nonce", &nonce),
+                ("code_challenge", &pkce_challenge(&code_verifier)),
+                ("code_challenge_method", "S256"),
+            ],
+        )
+        .map_err(|e| APIError::Internal(format!("Invalid authorization endpoint: {}", e)))?;
+
+        Ok(AuthorizationRequest {
+            url: url.to_string(),
+            state,
+            nonce,
+            code_verifier,
+        })
+    }
+
+    /// Redeem the authorization code for the claims of the user. Returns `None` if the
+    /// provider rejects the code or its `id_token` is invalid.
+    pub async fn exchange(
+        &self,
+        provider_id: &str,
+        code: &str,
+        code_verifier: &str,
+        nonce: &str,
+    ) -> Result<Option<IdTokenClaims>, APIError> {
+        let provider = self.provider(provider_id)?;
+        let discovered = self.discover(provider, false).await?;
+
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &provider.redirect_uri),
+            ("client_id", &provider.client_id),
+            ("code_verifier", code_verifier),
+        ];
+        if let Some(client_secret) = &provider.client_secret {
+            form.push(("client_secret", client_secret));
+        }
+
+        let response = self
+            .client
+            .post(&discovered.metadata.token_endpoint)
+            .form(&form)
+            .send()
+            .await
+            .map_err(|e| APIError::Internal(format!("Upstream provider failed: {}", e)))?;
+        if response.status().is_client_error() {
+            return Ok(None);
+        }
+        let response: TokenResponse = response
+            .error_for_status()
+            .map_err(|e| APIError::Internal(format!("Upstream provider failed: {}", e)))?
+            .json()
+            .await
+            .map_err(|e| APIError::Internal(format!("Invalid token response: {}", e)))?;
+
+        let validation = IdTokenValidation {
+            issuer: &provider.issuer,
+            client_id: &provider.client_id,
+            nonce,
+            now: time::OffsetDateTime::now_utc().unix_timestamp(),
+        };
+        let claims = match verify_id_token(&response.id_token, &discovered.jwks, &validation) {
+            // the provider might have rotated its keys
+            Err(OidcError::UnknownKey) => {
+                let discovered = self.discover(provider, true).await?;
+                verify_id_token(&response.id_token, &discovered.jwks, &validation)
+            }
+            result => result,
+        };
+
+        match claims {
+            Ok(claims) => Ok(Some(claims)),
+            Err(e) => {
+                warn!(
+                    "Invalid id_token from upstream provider {}: {}",
+                    provider.id, e
+                );
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// A minimal OpenID Connect provider for tests, signing with Ed25519
+#[cfg(test)]
+pub(crate) mod mock {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use ed25519_dalek::{Signer, SigningKey};
+    use keygate_utils::oidc::pkce_challenge;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use crate::config::UpstreamProviderConfig;
+
+    #[derive(Debug, Clone)]
+    pub(crate) struct MockProvider {
+        pub issuer: String,
+        // code -> (code challenge, id_token claims)
+        codes: Arc<Mutex<HashMap<String, (String, serde_json::Value)>>>,
+    }
+
+    impl MockProvider {
+        pub async fn start() -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let provider = Self {
+                issuer: format!("http://{}", listener.local_addr().unwrap()),
+                codes: Default::default(),
+            };
+
+            let server = provider.clone();
+            tokio::spawn(async move {
+                while let Ok((mut stream, _)) = listener.accept().await {
+                    let server = server.clone();
+                    tokio::spawn(async move {
+                        let (path, body) = read_request(&mut stream).await;
+                        let (status, body) = server.respond(&path, &body);
+                        let response = format!(
+                            "HTTP/1.1 {}\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                            status,
+                            body.len(),
+                            body
+                        );
+                        stream.write_all(response.as_bytes()).await.ok();
+                        stream.shutdown().await.ok();
+                    });
+                }
+            });
+
+            provider
+        }
+
+        pub fn config(&self, id: &str) -> UpstreamProviderConfig {
+            UpstreamProviderConfig {
+                id: id.to_string(),
+                issuer: self.issuer.clone(),
+                client_id: "keygate".to_string(),
+                client_secret: Some("secret".to_string()),
+                scopes: vec!["email".to_string()],
+                redirect_uri: "https://app.localhost/callback".to_string(),
+            }
+        }
+
+        /// Let the user of the authorization request log in, `claims` are added to the id_token
+        pub fn authorize(&self, authorization_url: &str, code: &str, claims: serde_json::Value) {
+            let url = reqwest::Url::parse(authorization_url).unwrap();
+            let param = |name: &str| {
+                url.query_pairs()
+                    .find(|(key, _)| key == name)
+                    .map(|(_, value)| value.to_string())
+                    .unwrap()
+            };
+
+            let now = time::OffsetDateTime::now_utc().unix_timestamp();
+            let mut id_token = serde_json::json!({
+                "iss": self.issuer,
+                "aud": param("client_id"),
+                "exp": now + 300,
+                "iat": now,
+                "nonce": param("nonce"),
+            });
+            for (key, value) in claims.as_object().unwrap() {
+                id_token[key] = value.clone();
+            }
+
+            self.codes
+                .lock()
+                .unwrap()
+                .insert(code.to_string(), (param("code_challenge"), id_token));
+        }
+
+        fn signing_key() -> SigningKey {
+            SigningKey::from_bytes(&[7; 32])
+        }
+
+        fn respond(&self, path: &str, body: &str) -> (&'static str, String) {
+            match path {
+                "/.well-known/openid-configuration" => (
+                    "200 OK",
+                    serde_json::json!({
+                        "issuer": self.issuer,
+                        "authorization_endpoint": format!("{}/authorize", self.issuer),
+                        "token_endpoint": format!("{}/token", self.issuer),
+                        "jwks_uri": format!("{}/jwks", self.issuer),
+                    })
+                    .to_string(),
+                ),
+                "/jwks" => (
+                    "200 OK",
+                    serde_json::json!({ "keys": [{
+                        "kty": "OKP",
+                        "crv": "Ed25519",
+                        "kid": "mock",
+                        "x": URL_SAFE_NO_PAD.encode(Self::signing_key().verifying_key().to_bytes()),
+                    }]})
+                    .to_string(),
+                ),
+                "/token" => {
+                    let form: HashMap<String, String> =
+                        reqwest::Url::parse(&format!("http://form?{}", body))
+                            .unwrap()
+                            .query_pairs()
+                            .into_owned()
+                            .collect();
+                    let code = form.get("code").cloned().unwrap_or_default();
+                    let verifier = form.get("code_verifier").cloned().unwrap_or_default();
+
+                    match self.codes.lock().unwrap().remove(&code) {
+                        Some((challenge, claims))
+                            if challenge == pkce_challenge(&verifier)
+                                && form.get("client_secret").map(String::as_str)
+                                    == Some("secret") =>
+                        {
+                            (
+                                "200 OK",
+                                serde_json::json!({
+                                    "access_token": "mock",
+                                    "token_type": "Bearer",
+                                    "id_token": Self::sign(&claims),
+                                })
+                                .to_string(),
+                            )
+                        }
+                        _ => (
+                            "400 Bad Request",
+                            r#"{"error":"invalid_grant"}"#.to_string(),
+                        ),
+                    }
+                }
+                _ => ("404 Not Found", "{}".to_string()),
+            }
+        }
+
+        fn sign(claims: &serde_json::Value) -> String {
+            let header = serde_json::json!({ "alg": "EdDSA", "kid": "mock" });
+            let signed = format!(
+                "{}.{}",
+                URL_SAFE_NO_PAD.encode(header.to_string()),
+                URL_SAFE_NO_PAD.encode(claims.to_string())
+            );
+            let signature = Self::signing_key().sign(signed.as_bytes());
+            format!(
+                "{}.{}",
+                signed,
+                URL_SAFE_NO_PAD.encode(signature.to_bytes())
+            )
+        }
+    }
+
+    // path and body of an HTTP/1.1 request
+    async fn read_request(stream: &mut tokio::net::TcpStream) -> (String, String) {
+        let mut request = Vec::new();
+        let mut buf = [0; 4096];
+        loop {
+            let n = stream.read(&mut buf).await.unwrap_or(0);
+            if n == 0 {
+                break;
+            }
+            request.extend_from_slice(&buf[..n]);
+
+            let text = String::from_utf8_lossy(&request);
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let length = head
+                    .lines()
+                    .find_map(|line| {
+                        let (name, value) = line.split_once(':')?;
+                        name.eq_ignore_ascii_case("content-length")
+                            .then(|| value.trim().parse::<usize>().ok())?
+                    })
+                    .unwrap_or(0);
+                if body.len() >= length {
+                    break;
+                }
+            }
+        }
+
+        let text = String::from_utf8_lossy(&request);
+        let path = text
+            .split_whitespace()
+            .nth(1)
+            .unwrap_or_default()
+            .split('?')
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        let body = text
+            .split_once("\r\n\r\n")
+            .map(|(_, body)| body.to_string())
+            .unwrap_or_default();
+        (path, body)
+    }
+}
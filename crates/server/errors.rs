@@ -6,7 +6,7 @@ use axum::{
 use keygate_core::api::APIError;
 use serde_json::json;
 use utoipa::{
-    openapi::{ObjectBuilder, SchemaType},
+    openapi::{ArrayBuilder, ObjectBuilder, SchemaType},
     ToSchema,
 };
 
@@ -28,6 +28,30 @@ impl<'s> ToSchema<'s> for AppError {
                     "error",
                     ObjectBuilder::new().schema_type(SchemaType::String).build(),
                 )
+                .property(
+                    "reasons",
+                    ArrayBuilder::new()
+                        .items(
+                            ObjectBuilder::new()
+                                .property(
+                                    "code",
+                                    ObjectBuilder::new()
+                                        .schema_type(SchemaType::String)
+                                        .enum_values(Some([
+                                            "too_short",
+                                            "too_long",
+                                            "too_weak",
+                                            "contains_user_info",
+                                            "common",
+                                            "breached",
+                                        ]))
+                                        .build(),
+                                )
+                                .required("code"),
+                        )
+                        .description(Some("Why a password was rejected"))
+                        .build(),
+                )
                 .description(Some("An error that can be returned by the API"))
                 .into(),
         )
@@ -42,6 +66,15 @@ impl From<APIError> for AppError {
 
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
+        // clients explain each reason, e.g. next to the password field of a signup form
+        if let Self::APIError(APIError::InvalidPassword(reasons)) = &self {
+            let body = Json(json!({
+                "error": "Invalid password",
+                "reasons": reasons,
+            }));
+            return (StatusCode::BAD_REQUEST, body).into_response();
+        }
+
         let (status, error_message) = match self {
             Self::Generic(status, message) => (status, message),
             Self::APIError(e) => match e {
@@ -56,6 +89,7 @@ impl IntoResponse for AppError {
                 APIError::Unimplemented(_) => (StatusCode::NOT_IMPLEMENTED, "Not implemented"),
                 APIError::Internal(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Internal error"),
                 APIError::Unauthenticated(_) => (StatusCode::UNAUTHORIZED, "Unauthenticated"),
+                APIError::InvalidPassword(_) => (StatusCode::BAD_REQUEST, "Invalid password"),
             },
         };
 
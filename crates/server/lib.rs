@@ -40,7 +40,7 @@ pub async fn run(mut config: KeygateConfig) -> color_eyre::Result<()> {
         .merge(private::new())
         .with_state(keygate.clone());
     let public_app = Router::new()
-        .merge(public::new())
+        .merge(public::new(keygate.clone()))
         .with_state(keygate.clone());
 
     let socket = tokio::net::TcpListener::bind(&"127.0.0.1:3000").await?;
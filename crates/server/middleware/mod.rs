@@ -1 +1 @@
-mod session;
+pub mod session;
@@ -21,6 +21,19 @@ pub enum AppToken {
     RefreshToken(RefreshToken),
 }
 
+impl AppToken {
+    /// The session of a request authenticated with an access token
+    pub fn session_id(&self) -> Result<&str, AppError> {
+        match self {
+            AppToken::AccessToken(token) => Ok(&token.session_id),
+            _ => Err(AppError::Generic(
+                StatusCode::UNAUTHORIZED,
+                "Not authenticated",
+            )),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ApplicationID(pub String);
 
@@ -86,6 +99,8 @@ pub enum ReqIdentity {
     RefreshIdentity(keygate_core::database::models::Identity),
 }
 
+/// Load the identity of the token. Tokens of identities that don't exist (anymore) are rejected
+/// here, handlers extracting [`ReqIdentity`] would otherwise fail with a 500 for the missing extension.
 pub async fn query_identity(
     State(keygate): State<Keygate>,
     mut req: Request,
@@ -116,9 +131,79 @@ pub async fn query_identity(
         }
     };
 
-    if let Some(identity) = identity {
-        req.extensions_mut().insert(identity);
-    }
+    let Some(identity) = identity else {
+        return Err(AppError::Generic(
+            StatusCode::UNAUTHORIZED,
+            "Not authenticated",
+        ));
+    };
 
+    req.extensions_mut().insert(identity);
     Ok(next.run(req).await)
 }
+
+impl ReqIdentity {
+    /// The identity of a request authenticated with an access token
+    pub fn identity(&self) -> Result<&keygate_core::database::models::Identity, AppError> {
+        match self {
+            ReqIdentity::Identity(identity) => Ok(identity),
+            _ => Err(AppError::Generic(
+                StatusCode::UNAUTHORIZED,
+                "Not authenticated",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, middleware, routing::get, Extension, Router};
+    use keygate_core::{config::StorageOptions, KeygateConfig};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_query_identity_unknown_identity() {
+        let keygate = Keygate::new(KeygateConfig {
+            storage_options: StorageOptions::Sqlite {
+                database_path: "sqlite://:memory:".into(),
+            },
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+        // a valid token whose identity was deleted
+        let token = AppToken::AccessToken(AccessToken {
+            audience: "admin".into(),
+            subject: "deleted".into(),
+            issuer: "keygate".into(),
+            session_id: "session".into(),
+            key_id: "key".into(),
+            auth_time: 0,
+            amr: vec!["pwd".into()],
+            acr: "1".into(),
+        });
+        let router = Router::new()
+            .route(
+                "/",
+                get(|Extension(identity): Extension<ReqIdentity>| async move {
+                    identity.identity().map(|_| ())
+                }),
+            )
+            .route_layer(middleware::from_fn_with_state(
+                keygate.clone(),
+                query_identity,
+            ))
+            .layer(Extension(token))
+            .with_state(keygate);
+
+        let status = router
+            .oneshot(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status();
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+}
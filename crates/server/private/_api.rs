@@ -1,4 +1,4 @@
-use super::auth;
+use super::{auth, domain, identity};
 use axum::Router;
 use keygate_core::Keygate;
 use utoipa::OpenApi;
@@ -6,8 +6,32 @@ use utoipa_swagger_ui::SwaggerUi;
 
 #[derive(OpenApi)]
 #[openapi(
-    paths(auth::validate),
-    components(schemas(auth::ValidateRequest, auth::ValidateResponse, crate::errors::AppError,))
+    paths(
+        auth::validate,
+        identity::profile,
+        identity::unlock,
+        identity::set_password,
+        identity::set_email_verified,
+        identity::roles,
+        identity::trusted_devices,
+        identity::revoke_trusted_device,
+        domain::list_domains,
+        domain::add_domain,
+        domain::verify_domain,
+        domain::update_domain,
+        domain::remove_domain
+    ),
+    components(schemas(
+        auth::ValidateRequest,
+        auth::ValidateResponse,
+        identity::SetPasswordRequest,
+        keygate_core::api::identity::IdentityProfile,
+        keygate_core::api::identity::TrustedDevice,
+        domain::AddDomainRequest,
+        domain::UpdateDomainRequest,
+        keygate_core::api::domain::SsoDomain,
+        crate::errors::AppError,
+    ))
 )]
 pub struct PrivateAPI;
 
@@ -0,0 +1,112 @@
+use axum::extract::{Path, State};
+use axum::routing::*;
+use axum::{Json, Router};
+
+use keygate_core::api::domain::SsoDomain;
+use keygate_core::Keygate;
+
+use crate::errors::AppError;
+
+pub fn new() -> Router<Keygate> {
+    Router::new()
+        .route("/", get(list_domains).post(add_domain))
+        .route("/:domain", put(update_domain).delete(remove_domain))
+        .route("/:domain/verify", post(verify_domain))
+}
+
+/// List Domains
+///
+/// Email domains whose users log in with an upstream provider.
+#[utoipa::path(get, path = "/domains", tag = "domains", responses(
+    (status = 200, body = Vec<SsoDomain>, description = "Registered domains."),
+))]
+pub(super) async fn list_domains(
+    State(keygate): State<Keygate>,
+) -> Result<Json<Vec<SsoDomain>>, AppError> {
+    Ok(Json(keygate.domains.list().await?))
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+pub struct AddDomainRequest {
+    domain: String,
+    /// id of a configured upstream provider or SAML connection
+    provider_id: String,
+    /// users of the domain can't log in with a password
+    #[serde(default)]
+    block_password: bool,
+}
+
+/// Add Domain
+///
+/// Register an email domain for an upstream provider. Logins are routed to the provider once the domain is verified.
+#[utoipa::path(post, path = "/domains", tag = "domains", request_body = AddDomainRequest, responses(
+    (status = 200, body = SsoDomain, description = "Domain registered, publish the verification token to verify it."),
+    (status = 400, body = AppError, description = "Invalid domain."),
+    (status = 404, body = AppError, description = "Upstream provider not found."),
+    (status = 409, body = AppError, description = "The domain is already registered."),
+))]
+pub(super) async fn add_domain(
+    State(keygate): State<Keygate>,
+    Json(data): Json<AddDomainRequest>,
+) -> Result<Json<SsoDomain>, AppError> {
+    let domain = keygate
+        .domains
+        .add(&data.domain, &data.provider_id, data.block_password)
+        .await?;
+    Ok(Json(domain))
+}
+
+/// Verify Domain
+///
+/// Check that `_keygate-challenge.<domain>` has a TXT record `keygate-domain-verification=<token>`.
+#[utoipa::path(post, path = "/domains/:domain/verify", tag = "domains", responses(
+    (status = 200, body = SsoDomain, description = "Domain verified."),
+    (status = 400, body = AppError, description = "Verification record not found."),
+    (status = 404, body = AppError, description = "Domain not found."),
+))]
+pub(super) async fn verify_domain(
+    State(keygate): State<Keygate>,
+    Path(domain): Path<String>,
+) -> Result<Json<SsoDomain>, AppError> {
+    Ok(Json(keygate.domains.verify(&domain).await?))
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+pub struct UpdateDomainRequest {
+    /// users of the domain can't log in with a password
+    block_password: bool,
+}
+
+/// Update Domain
+///
+/// Block or allow password logins for users of a domain.
+#[utoipa::path(put, path = "/domains/:domain", tag = "domains", request_body = UpdateDomainRequest, responses(
+    (status = 200, body = SsoDomain, description = "Domain updated."),
+    (status = 404, body = AppError, description = "Domain not found."),
+))]
+pub(super) async fn update_domain(
+    State(keygate): State<Keygate>,
+    Path(domain): Path<String>,
+    Json(data): Json<UpdateDomainRequest>,
+) -> Result<Json<SsoDomain>, AppError> {
+    let domain = keygate
+        .domains
+        .set_block_password(&domain, data.block_password)
+        .await?;
+    Ok(Json(domain))
+}
+
+/// Remove Domain
+///
+/// Stop routing logins of a domain to its upstream provider.
+#[utoipa::path(delete, path = "/domains/:domain", tag = "domains", responses(
+    (status = 200, description = "Domain removed."),
+    (status = 404, body = AppError, description = "Domain not found."),
+))]
+pub(super) async fn remove_domain(
+    State(keygate): State<Keygate>,
+    Path(domain): Path<String>,
+) -> Result<(), AppError> {
+    keygate.domains.remove(&domain).await?;
+    Ok(())
+}
@@ -0,0 +1,150 @@
+use axum::extract::{Path, State};
+use axum::routing::*;
+use axum::{Json, Router};
+
+use keygate_core::api::identity::{IdentityProfile, TrustedDevice};
+use keygate_core::Keygate;
+
+use crate::errors::AppError;
+
+pub fn new() -> Router<Keygate> {
+    Router::new()
+        .route("/:identity_id", get(profile))
+        .route("/:identity_id/unlock", post(unlock))
+        .route("/:identity_id/password", put(set_password))
+        .route(
+            "/:identity_id/emails/:email/verify",
+            post(set_email_verified),
+        )
+        .route("/:identity_id/roles", get(roles))
+        .route("/:identity_id/trusted-devices", get(trusted_devices))
+        .route(
+            "/:identity_id/trusted-devices/:device_id",
+            delete(revoke_trusted_device),
+        )
+}
+
+/// Profile
+///
+/// Get an identity.
+#[utoipa::path(get, path = "/identity/:identity_id", tag = "identity", responses(
+    (status = 200, body = IdentityProfile, description = "The identity."),
+    (status = 404, body = AppError, description = "Identity not found."),
+))]
+pub(super) async fn profile(
+    State(keygate): State<Keygate>,
+    Path(identity_id): Path<String>,
+) -> Result<Json<IdentityProfile>, AppError> {
+    let profile = keygate.identity.profile(&identity_id).await?;
+    Ok(Json(profile))
+}
+
+/// Unlock
+///
+/// Clear all failed login attempts of an identity and lift any lockout.
+#[utoipa::path(post, path = "/identity/:identity_id/unlock", tag = "identity", responses(
+    (status = 200, description = "Identity unlocked."),
+    (status = 400, body = AppError, description = "Invalid request."),
+))]
+pub(super) async fn unlock(
+    State(keygate): State<Keygate>,
+    Path(identity_id): Path<String>,
+) -> Result<(), AppError> {
+    keygate.auth.unlock(&identity_id).await?;
+    Ok(())
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+pub struct SetPasswordRequest {
+    /// leave out to keep the current password
+    password: Option<String>,
+    /// the next login has to choose a new password before it completes
+    #[serde(default)]
+    must_change: bool,
+}
+
+/// Set Password
+///
+/// Set the password of an identity and/or require it to choose a new one on the next login.
+#[utoipa::path(put, path = "/identity/:identity_id/password", tag = "identity", request_body = SetPasswordRequest, responses(
+    (status = 200, description = "Password updated."),
+    (status = 400, body = AppError, description = "Invalid password."),
+    (status = 404, body = AppError, description = "Identity not found."),
+))]
+pub(super) async fn set_password(
+    State(keygate): State<Keygate>,
+    Path(identity_id): Path<String>,
+    Json(data): Json<SetPasswordRequest>,
+) -> Result<(), AppError> {
+    keygate
+        .identity
+        .set_password(&identity_id, data.password.as_deref(), data.must_change)
+        .await?;
+    Ok(())
+}
+
+/// Mark Email Verified
+///
+/// Mark an email address of an identity as verified without sending a code.
+#[utoipa::path(post, path = "/identity/:identity_id/emails/:email/verify", tag = "identity", responses(
+    (status = 200, description = "Email verified."),
+    (status = 404, body = AppError, description = "Email not found."),
+))]
+pub(super) async fn set_email_verified(
+    State(keygate): State<Keygate>,
+    Path((identity_id, email)): Path<(String, String)>,
+) -> Result<(), AppError> {
+    keygate
+        .identity
+        .set_email_verified(&identity_id, &email)
+        .await?;
+    Ok(())
+}
+
+/// Roles
+///
+/// Roles of an identity, synced from its LDAP groups when it logs in.
+#[utoipa::path(get, path = "/identity/:identity_id/roles", tag = "identity", responses(
+    (status = 200, body = Vec<String>, description = "Roles."),
+    (status = 400, body = AppError, description = "Invalid request."),
+))]
+pub(super) async fn roles(
+    State(keygate): State<Keygate>,
+    Path(identity_id): Path<String>,
+) -> Result<Json<Vec<String>>, AppError> {
+    let roles = keygate.identity.roles(&identity_id).await?;
+    Ok(Json(roles))
+}
+
+/// Trusted Devices
+///
+/// List devices of an identity that can skip the second factor when logging in.
+#[utoipa::path(get, path = "/identity/:identity_id/trusted-devices", tag = "identity", responses(
+    (status = 200, body = Vec<TrustedDevice>, description = "Trusted devices."),
+    (status = 400, body = AppError, description = "Invalid request."),
+))]
+pub(super) async fn trusted_devices(
+    State(keygate): State<Keygate>,
+    Path(identity_id): Path<String>,
+) -> Result<Json<Vec<TrustedDevice>>, AppError> {
+    let devices = keygate.identity.trusted_devices(&identity_id).await?;
+    Ok(Json(devices))
+}
+
+/// Revoke Trusted Device
+///
+/// Require the second factor again for logins from a trusted device of an identity.
+#[utoipa::path(delete, path = "/identity/:identity_id/trusted-devices/:device_id", tag = "identity", responses(
+    (status = 200, description = "Device revoked."),
+    (status = 404, body = AppError, description = "Device not found."),
+))]
+pub(super) async fn revoke_trusted_device(
+    State(keygate): State<Keygate>,
+    Path((identity_id, device_id)): Path<(String, String)>,
+) -> Result<(), AppError> {
+    keygate
+        .identity
+        .revoke_trusted_device(&identity_id, &device_id)
+        .await?;
+    Ok(())
+}
@@ -3,8 +3,14 @@ use keygate_core::Keygate;
 
 mod _api;
 mod auth;
+mod domain;
+mod identity;
 pub use _api::PrivateAPI;
 
 pub fn new() -> Router<Keygate> {
-    Router::new().merge(_api::new()).nest("/auth", auth::new())
+    Router::new()
+        .merge(_api::new())
+        .nest("/auth", auth::new())
+        .nest("/domains", domain::new())
+        .nest("/identity", identity::new())
 }
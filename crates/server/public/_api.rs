@@ -1,4 +1,4 @@
-use super::login;
+use super::{email, identity, login, password, signup};
 use axum::Router;
 use keygate_core::Keygate;
 use utoipa::OpenApi;
@@ -6,13 +6,74 @@ use utoipa_swagger_ui::SwaggerUi;
 
 #[derive(OpenApi)]
 #[openapi(
-    paths(login::login, login::login_step, login::login_status),
+    paths(
+        login::login,
+        login::login_step,
+        login::login_upstream,
+        login::login_upstream_redirect,
+        login::login_upstream_callback,
+        login::login_saml_callback,
+        login::login_saml_acs,
+        login::saml_metadata,
+        login::login_email_code,
+        login::login_start_step,
+        login::login_trust_device,
+        login::login_status,
+        login::device_approvals,
+        login::device_approval,
+        login::reauth,
+        password::password_reset,
+        password::password_reset_confirm,
+        signup::signup,
+        signup::signup_confirm,
+        email::verify_email,
+        email::verify_email_link,
+        email::resend_verification,
+        identity::profile,
+        identity::recovery_codes,
+        identity::regenerate_recovery_codes,
+        identity::change_password,
+        identity::trusted_devices,
+        identity::revoke_trusted_device,
+        identity::linked_accounts,
+        identity::link_account_start,
+        identity::link_account_complete,
+        identity::unlink_account,
+    ),
     components(schemas(
         login::LoginRequest,
         login::LoginStepRequest,
+        login::LoginUpstreamRequest,
+        login::LoginUpstreamRedirectRequest,
+        login::LoginUpstreamCallbackRequest,
+        login::LoginSamlCallbackRequest,
+        login::LoginSamlAcsForm,
+        login::LoginEmailCodeRequest,
+        login::LoginStartStepRequest,
+        login::DeviceApprovalBody,
+        login::LoginTrustDeviceRequest,
+        login::LoginTrustDeviceResponse,
+        login::ReauthRequest,
+        password::PasswordResetRequest,
+        password::PasswordResetConfirmRequest,
+        signup::SignupRequest,
+        signup::SignupResponse,
+        signup::SignupConfirmRequest,
+        email::VerifyEmailRequest,
+        email::ResendVerificationRequest,
+        keygate_core::api::identity::IdentityProfile,
+        keygate_core::api::auth::DeviceApprovalRequest,
         keygate_core::api::auth::LoginStep,
         keygate_core::api::auth::LoginResponse,
         keygate_core::api::auth::LoginStatusResponse,
+        identity::RecoveryCodesResponse,
+        identity::RegenerateRecoveryCodesResponse,
+        identity::ChangePasswordRequest,
+        keygate_core::api::identity::TrustedDevice,
+        identity::LinkAccountRequest,
+        identity::LinkAccountResponse,
+        identity::LinkAccountCallbackRequest,
+        keygate_core::api::identity::LinkedAccount,
         crate::errors::AppError,
     ))
 )]
@@ -0,0 +1,68 @@
+use axum::extract::{Query, State};
+use axum::routing::*;
+use axum::{Json, Router};
+
+use keygate_core::Keygate;
+
+use crate::errors::AppError;
+
+pub fn new() -> Router<Keygate> {
+    Router::new()
+        .route("/", get(verify_email_link).post(verify_email))
+        .route("/resend", post(resend_verification))
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema, utoipa::IntoParams)]
+pub struct VerifyEmailRequest {
+    /// code from the verification email, it can only be used once
+    code: String,
+}
+
+/// Verify Email
+///
+/// Verify an email address with the code from the verification email.
+#[utoipa::path(post, path = "/auth/verify-email", tag = "auth", request_body = VerifyEmailRequest, responses(
+    (status = 200, description = "Email verified."),
+    (status = 400, body = AppError, description = "Invalid or expired code."),
+))]
+async fn verify_email(
+    State(keygate): State<Keygate>,
+    Json(data): Json<VerifyEmailRequest>,
+) -> Result<(), AppError> {
+    keygate.identity.verify_email(&data.code).await?;
+    Ok(())
+}
+
+/// Verify Email Link
+///
+/// Verify an email address with the link from the verification email.
+#[utoipa::path(get, path = "/auth/verify-email", tag = "auth", params(VerifyEmailRequest), responses(
+    (status = 200, description = "Email verified."),
+    (status = 400, body = AppError, description = "Invalid or expired code."),
+))]
+async fn verify_email_link(
+    State(keygate): State<Keygate>,
+    Query(data): Query<VerifyEmailRequest>,
+) -> Result<(), AppError> {
+    keygate.identity.verify_email(&data.code).await?;
+    Ok(())
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+pub struct ResendVerificationRequest {
+    email: String,
+}
+
+/// Resend Verification
+///
+/// Send a new verification code to an unverified email address. The response is the same whether the address belongs to an identity or not.
+#[utoipa::path(post, path = "/auth/verify-email/resend", tag = "auth", request_body = ResendVerificationRequest, responses(
+    (status = 200, description = "Verification requested."),
+))]
+async fn resend_verification(
+    State(keygate): State<Keygate>,
+    Json(data): Json<ResendVerificationRequest>,
+) -> Result<(), AppError> {
+    keygate.identity.resend_verification(&data.email).await?;
+    Ok(())
+}
@@ -1,10 +1,49 @@
-use axum::{extract::State, routing::post, Json, Router};
-use keygate_core::Keygate;
+use axum::{
+    extract::{ConnectInfo, Path, State},
+    http::StatusCode,
+    middleware,
+    routing::{delete, get, post},
+    Extension, Json, Router,
+};
+use keygate_core::{
+    api::identity::{IdentityProfile, LinkedAccount, TrustedDevice},
+    Keygate,
+};
 
-use crate::errors::AppError;
+use crate::{
+    errors::AppError,
+    middleware::session::{query_identity, validate_token, AppToken, ReqIdentity},
+};
+use std::net::SocketAddr;
 
-pub fn new() -> Router<Keygate> {
-    Router::new().route("/exists", post(exists))
+pub fn new(keygate: Keygate) -> Router<Keygate> {
+    let authenticated = Router::new()
+        .route("/", get(profile))
+        .route(
+            "/recovery-codes",
+            get(recovery_codes).post(regenerate_recovery_codes),
+        )
+        .route("/password", post(change_password))
+        .route("/trusted-devices", get(trusted_devices))
+        .route("/trusted-devices/:device_id", delete(revoke_trusted_device))
+        .route(
+            "/linked-accounts",
+            get(linked_accounts).post(link_account_start),
+        )
+        .route("/linked-accounts/callback", post(link_account_complete))
+        .route(
+            "/linked-accounts/:linked_account_id",
+            delete(unlink_account),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            keygate.clone(),
+            query_identity,
+        ))
+        .route_layer(middleware::from_fn_with_state(keygate, validate_token));
+
+    Router::new()
+        .route("/exists", post(exists))
+        .merge(authenticated)
 }
 
 #[derive(serde::Deserialize)]
@@ -19,8 +58,374 @@ struct ExistsResponse {
 
 async fn exists(
     keygate: State<Keygate>,
+    ConnectInfo(ip): ConnectInfo<SocketAddr>,
     Json(data): Json<ExistsRequest>,
 ) -> Result<Json<ExistsResponse>, AppError> {
-    let exists = keygate.identity.exists(&data.username_or_email).await?;
+    // answering this would defeat anti-enumeration
+    if keygate.config().anti_enumeration() {
+        return Err(AppError::Generic(StatusCode::NOT_FOUND, "Not found"));
+    }
+
+    // lookups are throttled per ip like failed logins
+    let exists = keygate
+        .identity
+        .exists_throttled(&data.username_or_email, ip.ip())
+        .await?;
     Ok(Json(ExistsResponse { exists }))
 }
+
+/// Profile
+///
+/// Get the current identity.
+#[utoipa::path(get, path = "/identity", tag = "identity", responses(
+    (status = 200, body = IdentityProfile, description = "The current identity."),
+    (status = 401, body = AppError, description = "Not authenticated."),
+))]
+async fn profile(
+    State(keygate): State<Keygate>,
+    Extension(identity): Extension<ReqIdentity>,
+) -> Result<Json<IdentityProfile>, AppError> {
+    let identity = identity.identity()?;
+    let profile = keygate.identity.profile(&identity.id).await?;
+    Ok(Json(profile))
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+pub struct RecoveryCodesResponse {
+    remaining: i64,
+}
+
+/// Recovery Codes
+///
+/// Get the number of unused recovery codes.
+#[utoipa::path(get, path = "/identity/recovery-codes", tag = "identity", responses(
+    (status = 200, body = RecoveryCodesResponse, description = "Number of unused recovery codes."),
+    (status = 401, body = AppError, description = "Not authenticated."),
+))]
+async fn recovery_codes(
+    State(keygate): State<Keygate>,
+    Extension(identity): Extension<ReqIdentity>,
+) -> Result<Json<RecoveryCodesResponse>, AppError> {
+    let identity = identity.identity()?;
+    let remaining = keygate
+        .identity
+        .recovery_codes_remaining(&identity.id)
+        .await?;
+    Ok(Json(RecoveryCodesResponse { remaining }))
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+pub struct RegenerateRecoveryCodesResponse {
+    codes: Vec<String>,
+}
+
+/// Regenerate Recovery Codes
+///
+/// Replace all recovery codes with a new set. The codes are only shown once. Requires a recent authentication.
+#[utoipa::path(post, path = "/identity/recovery-codes", tag = "identity", responses(
+    (status = 200, body = RegenerateRecoveryCodesResponse, description = "New recovery codes."),
+    (status = 401, body = AppError, description = "Not authenticated or the session has to re-authenticate."),
+))]
+async fn regenerate_recovery_codes(
+    State(keygate): State<Keygate>,
+    Extension(identity): Extension<ReqIdentity>,
+    Extension(token): Extension<AppToken>,
+) -> Result<Json<RegenerateRecoveryCodesResponse>, AppError> {
+    let identity = identity.identity()?;
+    keygate
+        .session
+        .require_recent_auth(token.session_id()?, false)
+        .await?;
+    let codes = keygate
+        .identity
+        .generate_recovery_codes(&identity.id)
+        .await?;
+    Ok(Json(RegenerateRecoveryCodesResponse { codes }))
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+pub struct ChangePasswordRequest {
+    /// can be left out if the session authenticated recently
+    current_password: Option<String>,
+    new_password: String,
+    /// sign out all other sessions of the identity
+    #[serde(default)]
+    revoke_other_sessions: bool,
+}
+
+/// Change Password
+///
+/// Change the password of the current identity. Requires the current password or a recent authentication.
+#[utoipa::path(post, path = "/identity/password", tag = "identity", request_body = ChangePasswordRequest, responses(
+    (status = 200, description = "Password changed."),
+    (status = 400, body = AppError, description = "Invalid current or new password."),
+    (status = 401, body = AppError, description = "Not authenticated or the session has to re-authenticate."),
+))]
+async fn change_password(
+    State(keygate): State<Keygate>,
+    Extension(identity): Extension<ReqIdentity>,
+    Extension(token): Extension<AppToken>,
+    Json(data): Json<ChangePasswordRequest>,
+) -> Result<(), AppError> {
+    let identity = identity.identity()?;
+    keygate
+        .identity
+        .change_password(
+            &identity.id,
+            token.session_id()?,
+            data.current_password.as_deref(),
+            &data.new_password,
+            data.revoke_other_sessions,
+        )
+        .await?;
+    Ok(())
+}
+
+/// Trusted Devices
+///
+/// List devices that can skip the second factor when logging in.
+#[utoipa::path(get, path = "/identity/trusted-devices", tag = "identity", responses(
+    (status = 200, body = Vec<TrustedDevice>, description = "Trusted devices."),
+    (status = 401, body = AppError, description = "Not authenticated."),
+))]
+async fn trusted_devices(
+    State(keygate): State<Keygate>,
+    Extension(identity): Extension<ReqIdentity>,
+) -> Result<Json<Vec<TrustedDevice>>, AppError> {
+    let identity = identity.identity()?;
+    let devices = keygate.identity.trusted_devices(&identity.id).await?;
+    Ok(Json(devices))
+}
+
+/// Revoke Trusted Device
+///
+/// Require the second factor again for logins from a trusted device.
+#[utoipa::path(delete, path = "/identity/trusted-devices/:device_id", tag = "identity", responses(
+    (status = 200, description = "Device revoked."),
+    (status = 401, body = AppError, description = "Not authenticated."),
+    (status = 404, body = AppError, description = "Device not found."),
+))]
+async fn revoke_trusted_device(
+    State(keygate): State<Keygate>,
+    Extension(identity): Extension<ReqIdentity>,
+    Path(device_id): Path<String>,
+) -> Result<(), AppError> {
+    let identity = identity.identity()?;
+    keygate
+        .identity
+        .revoke_trusted_device(&identity.id, &device_id)
+        .await?;
+    Ok(())
+}
+
+/// Linked Accounts
+///
+/// List the accounts of upstream providers the current identity can log in with.
+#[utoipa::path(get, path = "/identity/linked-accounts", tag = "identity", responses(
+    (status = 200, body = Vec<LinkedAccount>, description = "Linked accounts."),
+    (status = 401, body = AppError, description = "Not authenticated."),
+))]
+async fn linked_accounts(
+    State(keygate): State<Keygate>,
+    Extension(identity): Extension<ReqIdentity>,
+) -> Result<Json<Vec<LinkedAccount>>, AppError> {
+    let identity = identity.identity()?;
+    let accounts = keygate.identity.linked_accounts(&identity.id).await?;
+    Ok(Json(accounts))
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+pub struct LinkAccountRequest {
+    /// id of a configured upstream provider
+    provider: String,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+pub struct LinkAccountResponse {
+    /// where to send the user to log in with the provider
+    url: String,
+}
+
+/// Link Account
+///
+/// Start linking an account of an upstream provider. Requires a recent authentication.
+#[utoipa::path(post, path = "/identity/linked-accounts", tag = "identity", request_body = LinkAccountRequest, responses(
+    (status = 200, body = LinkAccountResponse, description = "Link started, the user has to be sent to the url."),
+    (status = 401, body = AppError, description = "Not authenticated or the session has to re-authenticate."),
+    (status = 404, body = AppError, description = "Upstream provider not found."),
+))]
+async fn link_account_start(
+    State(keygate): State<Keygate>,
+    Extension(identity): Extension<ReqIdentity>,
+    Extension(token): Extension<AppToken>,
+    Json(data): Json<LinkAccountRequest>,
+) -> Result<Json<LinkAccountResponse>, AppError> {
+    let identity = identity.identity()?;
+    let url = keygate
+        .identity
+        .link_account_start(&identity.id, token.session_id()?, &data.provider)
+        .await?;
+    Ok(Json(LinkAccountResponse { url }))
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+pub struct LinkAccountCallbackRequest {
+    /// `state` query parameter the provider redirected back with
+    state: String,
+    /// `code` query parameter the provider redirected back with
+    code: String,
+}
+
+/// Link Account Callback
+///
+/// Link the account once the provider redirected the user back. Accounts that belong to a different identity are refused.
+#[utoipa::path(post, path = "/identity/linked-accounts/callback", tag = "identity", request_body = LinkAccountCallbackRequest, responses(
+    (status = 200, body = LinkedAccount, description = "Account linked."),
+    (status = 400, body = AppError, description = "The provider did not accept the login."),
+    (status = 401, body = AppError, description = "Not authenticated."),
+    (status = 404, body = AppError, description = "Link request not found."),
+    (status = 409, body = AppError, description = "The account or its email belongs to a different identity."),
+))]
+async fn link_account_complete(
+    State(keygate): State<Keygate>,
+    Extension(identity): Extension<ReqIdentity>,
+    Json(data): Json<LinkAccountCallbackRequest>,
+) -> Result<Json<LinkedAccount>, AppError> {
+    let identity = identity.identity()?;
+    let account = keygate
+        .identity
+        .link_account_complete(&identity.id, &data.state, &data.code)
+        .await?;
+    Ok(Json(account))
+}
+
+/// Unlink Account
+///
+/// Remove a linked account. Requires a recent authentication, the last way to log in can't be removed.
+#[utoipa::path(delete, path = "/identity/linked-accounts/:linked_account_id", tag = "identity", responses(
+    (status = 200, description = "Account unlinked."),
+    (status = 401, body = AppError, description = "Not authenticated or the session has to re-authenticate."),
+    (status = 403, body = AppError, description = "The identity has no other way to log in."),
+    (status = 404, body = AppError, description = "Linked account not found."),
+))]
+async fn unlink_account(
+    State(keygate): State<Keygate>,
+    Extension(identity): Extension<ReqIdentity>,
+    Extension(token): Extension<AppToken>,
+    Path(linked_account_id): Path<String>,
+) -> Result<(), AppError> {
+    let identity = identity.identity()?;
+    keygate
+        .identity
+        .unlink_account(&identity.id, token.session_id()?, &linked_account_id)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        extract::{connect_info::MockConnectInfo, Request},
+    };
+    use keygate_core::{
+        api::auth::{LoginResponse, LoginStep},
+        config::StorageOptions,
+        KeygateConfig,
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+
+    async fn exists_status(anti_enumeration: bool) -> StatusCode {
+        let keygate = Keygate::new(KeygateConfig {
+            storage_options: StorageOptions::Sqlite {
+                database_path: "sqlite://:memory:".into(),
+            },
+            anti_enumeration: Some(anti_enumeration),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+        let request = Request::post("/exists")
+            .header("content-type", "application/json")
+            .body(Body::from(r#"{"username_or_email":"admin"}"#))
+            .unwrap();
+
+        new(keygate.clone())
+            .with_state(keygate)
+            .layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 0))))
+            .oneshot(request)
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn test_profile_with_access_token() {
+        let keygate = Keygate::new(KeygateConfig {
+            storage_options: StorageOptions::Sqlite {
+                database_path: "sqlite://:memory:".into(),
+            },
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        keygate.create_admin_app().await.unwrap();
+        let identity = keygate
+            .identity
+            .create(keygate_core::api::identity::CreateIdentity {
+                username: Some("profiled"),
+                primary_email: Some("profiled@keygate.io"),
+                password_hash: Some("replaced below"),
+            })
+            .await
+            .unwrap();
+        keygate
+            .identity
+            .set_password(&identity.id, Some("correct horse battery"), false)
+            .await
+            .unwrap();
+
+        let LoginResponse::NextStep { process_id, .. } = keygate
+            .auth
+            .login_create("admin", "profiled", None, None)
+            .await
+            .unwrap()
+        else {
+            panic!("expected next step");
+        };
+        let LoginResponse::Success { access_token, .. } = keygate
+            .auth
+            .login_step(
+                "admin",
+                &process_id,
+                LoginStep::PASSWORD,
+                "correct horse battery",
+            )
+            .await
+            .unwrap()
+        else {
+            panic!("expected tokens");
+        };
+
+        let request = Request::get("/")
+            .header("authorization", format!("Bearer {}", access_token))
+            .body(Body::empty())
+            .unwrap();
+        let status = new(keygate.clone())
+            .with_state(keygate)
+            .oneshot(request)
+            .await
+            .unwrap()
+            .status();
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_exists_anti_enumeration() {
+        assert_eq!(exists_status(false).await, StatusCode::OK);
+        assert_eq!(exists_status(true).await, StatusCode::NOT_FOUND);
+    }
+}
@@ -1,40 +1,83 @@
 use axum::extract::{ConnectInfo, Path, State};
+use axum::http::header;
+use axum::response::IntoResponse;
 use axum::routing::*;
-use axum::{Json, Router};
+use axum::{middleware, Extension, Form, Json, Router};
 
-use keygate_core::api::auth::{LoginResponse, LoginStatusResponse, LoginStep};
+use keygate_core::api::auth::{
+    DeviceApprovalRequest, LoginResponse, LoginStatusResponse, LoginStep,
+};
 use keygate_core::Keygate;
 
 use crate::errors::AppError;
+use crate::middleware::session::{
+    query_identity, validate_token, AppToken, ApplicationID, ReqIdentity,
+};
 use std::net::SocketAddr;
 
-pub fn new() -> Router<Keygate> {
+pub fn new(keygate: Keygate) -> Router<Keygate> {
+    let authenticated = Router::new()
+        .route(
+            "/device-approval",
+            get(device_approvals).post(device_approval),
+        )
+        .route("/reauth", post(reauth))
+        .route("/trust-device", post(login_trust_device))
+        .route_layer(middleware::from_fn_with_state(
+            keygate.clone(),
+            query_identity,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            keygate.clone(),
+            validate_token,
+        ));
+
+    // the application's login policy applies to the process
     Router::new()
         .route("/", post(login))
         .route("/step", post(login_step))
+        .route("/upstream", post(login_upstream))
+        .route("/upstream/redirect", post(login_upstream_redirect))
+        .route("/upstream/callback", post(login_upstream_callback))
+        .route("/saml/callback", post(login_saml_callback))
+        .route("/email-code", post(login_email_code))
+        .route("/start", post(login_start_step))
         .route("/:process_id", get(login_status))
+        .route_layer(middleware::from_fn_with_state(keygate, validate_token))
+        .route("/saml/acs", post(login_saml_acs))
+        .route("/saml/:connection_id/metadata", get(saml_metadata))
+        .merge(authenticated)
 }
 
 #[derive(serde::Deserialize, utoipa::ToSchema)]
 pub struct LoginRequest {
     username_or_email: String,
+    /// random id the client stores for this device, used to recognize known devices
+    device_id: Option<String>,
 }
 
 /// Login
 ///
-/// Create a new login process.
+/// Create a new login process. The application is taken from the (anonymous) token and decides which login steps are allowed.
 #[utoipa::path(post, path = "/auth/login", tag = "auth", request_body = LoginRequest, responses(
     (status = 200, body = LoginResponse, description = "Login process created."),
     (status = 400, body = AppError, description = "Invalid request."),
+    (status = 403, body = AppError, description = "No login method is allowed for this application."),
 ))]
 async fn login(
     State(keygate): State<Keygate>,
     ConnectInfo(ip): ConnectInfo<SocketAddr>,
+    Extension(application_id): Extension<ApplicationID>,
     Json(data): Json<LoginRequest>,
 ) -> Result<Json<LoginResponse>, AppError> {
     let res = keygate
         .auth
-        .login_create(&data.username_or_email, Some(ip.ip()))
+        .login_create(
+            &application_id.0,
+            &data.username_or_email,
+            Some(ip.ip()),
+            data.device_id.as_deref(),
+        )
         .await?;
     Ok(Json(res))
 }
@@ -48,18 +91,348 @@ pub struct LoginStepRequest {
 
 /// Login Step
 ///
-/// Complete a login step.
+/// Complete a login step. Only the application that created the process can continue it.
 #[utoipa::path(post, path = "/auth/login/step", tag = "auth", request_body = LoginStepRequest, responses(
     (status = 200, body = LoginResponse, description = "Login step completed."),
     (status = 400, body = AppError, description = "Invalid request."),
 ))]
 async fn login_step(
     State(keygate): State<Keygate>,
+    Extension(application_id): Extension<ApplicationID>,
     Json(data): Json<LoginStepRequest>,
 ) -> Result<Json<LoginResponse>, AppError> {
     let res = keygate
         .auth
-        .login_step(&data.process_id, data.step_type, &data.data)
+        .login_step(
+            &application_id.0,
+            &data.process_id,
+            data.step_type,
+            &data.data,
+        )
+        .await?;
+    Ok(Json(res))
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+pub struct LoginUpstreamRequest {
+    /// id of a configured upstream provider or SAML connection
+    provider: String,
+    /// random id the client stores for this device, used to recognize known devices
+    device_id: Option<String>,
+}
+
+/// Login Upstream
+///
+/// Create a login process with an upstream OpenID Connect provider or SAML connection. The user has to be sent to the returned url.
+#[utoipa::path(post, path = "/auth/login/upstream", tag = "auth", request_body = LoginUpstreamRequest, responses(
+    (status = 200, body = LoginResponse, description = "Login process created."),
+    (status = 403, body = AppError, description = "Upstream login is not allowed for this application."),
+    (status = 404, body = AppError, description = "Upstream provider not found."),
+))]
+async fn login_upstream(
+    State(keygate): State<Keygate>,
+    ConnectInfo(ip): ConnectInfo<SocketAddr>,
+    Extension(application_id): Extension<ApplicationID>,
+    Json(data): Json<LoginUpstreamRequest>,
+) -> Result<Json<LoginResponse>, AppError> {
+    let res = keygate
+        .auth
+        .login_create_upstream(
+            &application_id.0,
+            &data.provider,
+            Some(ip.ip()),
+            data.device_id.as_deref(),
+        )
+        .await?;
+    Ok(Json(res))
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+pub struct LoginUpstreamRedirectRequest {
+    process_id: String,
+}
+
+/// Login Upstream Redirect
+///
+/// Send a user whose email domain is routed to an upstream provider to the provider. Possible while `upstream` is one of the next steps. The user has to be sent to the returned url.
+#[utoipa::path(post, path = "/auth/login/upstream/redirect", tag = "auth", request_body = LoginUpstreamRedirectRequest, responses(
+    (status = 200, body = LoginResponse, description = "Upstream request created."),
+    (status = 400, body = AppError, description = "The login process is not routed to an upstream provider."),
+    (status = 404, body = AppError, description = "Login process not found."),
+))]
+async fn login_upstream_redirect(
+    State(keygate): State<Keygate>,
+    Extension(application_id): Extension<ApplicationID>,
+    Json(data): Json<LoginUpstreamRedirectRequest>,
+) -> Result<Json<LoginResponse>, AppError> {
+    let res = keygate
+        .auth
+        .login_upstream_redirect(&application_id.0, &data.process_id)
+        .await?;
+    Ok(Json(res))
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+pub struct LoginUpstreamCallbackRequest {
+    /// `state` query parameter the provider redirected back with
+    state: String,
+    /// `code` query parameter the provider redirected back with
+    code: String,
+}
+
+/// Login Upstream Callback
+///
+/// Continue a login with an upstream provider once the user was redirected back.
+#[utoipa::path(post, path = "/auth/login/upstream/callback", tag = "auth", request_body = LoginUpstreamCallbackRequest, responses(
+    (status = 200, body = LoginResponse, description = "Upstream login step completed."),
+    (status = 400, body = AppError, description = "The provider did not accept the login."),
+    (status = 404, body = AppError, description = "Login process not found."),
+))]
+async fn login_upstream_callback(
+    State(keygate): State<Keygate>,
+    Extension(application_id): Extension<ApplicationID>,
+    Json(data): Json<LoginUpstreamCallbackRequest>,
+) -> Result<Json<LoginResponse>, AppError> {
+    let res = keygate
+        .auth
+        .login_upstream_callback(&application_id.0, &data.state, &data.code)
+        .await?;
+    Ok(Json(res))
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+pub struct LoginSamlCallbackRequest {
+    /// `SAMLResponse` form field the IdP posted
+    saml_response: String,
+    /// `RelayState` form field the IdP posted
+    relay_state: String,
+}
+
+/// Login SAML Callback
+///
+/// Continue a login with a SAML connection with the response the IdP posted to the assertion consumer service.
+#[utoipa::path(post, path = "/auth/login/saml/callback", tag = "auth", request_body = LoginSamlCallbackRequest, responses(
+    (status = 200, body = LoginResponse, description = "Upstream login step completed."),
+    (status = 400, body = AppError, description = "The IdP did not accept the login or its response is invalid."),
+    (status = 404, body = AppError, description = "Login process not found."),
+))]
+async fn login_saml_callback(
+    State(keygate): State<Keygate>,
+    Extension(application_id): Extension<ApplicationID>,
+    Json(data): Json<LoginSamlCallbackRequest>,
+) -> Result<Json<LoginResponse>, AppError> {
+    let res = keygate
+        .auth
+        .login_upstream_callback(&application_id.0, &data.relay_state, &data.saml_response)
+        .await?;
+    Ok(Json(res))
+}
+
+/// The form the IdP posts to the assertion consumer service with the HTTP-POST binding
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+pub struct LoginSamlAcsForm {
+    #[serde(rename = "SAMLResponse")]
+    saml_response: String,
+    #[serde(rename = "RelayState")]
+    relay_state: String,
+}
+
+/// Login SAML ACS
+///
+/// Assertion consumer service the IdP posts its response to. The application is the one that started the login process of the relay state.
+#[utoipa::path(post, path = "/auth/login/saml/acs", tag = "auth", request_body(content = LoginSamlAcsForm, content_type = "application/x-www-form-urlencoded"), responses(
+    (status = 200, body = LoginResponse, description = "Upstream login step completed."),
+    (status = 400, body = AppError, description = "The IdP did not accept the login or its response is invalid."),
+    (status = 404, body = AppError, description = "Login process not found."),
+))]
+async fn login_saml_acs(
+    State(keygate): State<Keygate>,
+    Form(data): Form<LoginSamlAcsForm>,
+) -> Result<Json<LoginResponse>, AppError> {
+    let res = keygate
+        .auth
+        .login_saml_acs(&data.relay_state, &data.saml_response)
+        .await?;
+    Ok(Json(res))
+}
+
+/// SAML Metadata
+///
+/// Service provider metadata of a SAML connection, to import into its IdP.
+#[utoipa::path(get, path = "/auth/login/saml/:connection_id/metadata", tag = "auth", responses(
+    (status = 200, body = String, content_type = "application/samlmetadata+xml", description = "Service provider metadata."),
+    (status = 404, body = AppError, description = "SAML connection not found."),
+))]
+async fn saml_metadata(
+    Path(connection_id): Path<String>,
+    State(keygate): State<Keygate>,
+) -> Result<impl IntoResponse, AppError> {
+    let metadata = keygate.auth.saml_metadata(&connection_id)?;
+    Ok((
+        [(header::CONTENT_TYPE, "application/samlmetadata+xml")],
+        metadata,
+    ))
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+pub struct LoginEmailCodeRequest {
+    process_id: String,
+}
+
+/// Login Email Code
+///
+/// Send a one-time login code to the verified email address of the identity.
+#[utoipa::path(post, path = "/auth/login/email-code", tag = "auth", request_body = LoginEmailCodeRequest, responses(
+    (status = 200, description = "Code sent."),
+    (status = 400, body = AppError, description = "Invalid request."),
+    (status = 403, body = AppError, description = "A code was sent too recently."),
+))]
+async fn login_email_code(
+    State(keygate): State<Keygate>,
+    Extension(application_id): Extension<ApplicationID>,
+    Json(data): Json<LoginEmailCodeRequest>,
+) -> Result<(), AppError> {
+    keygate
+        .auth
+        .login_send_email_code(&application_id.0, &data.process_id)
+        .await?;
+    Ok(())
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+pub struct LoginStartStepRequest {
+    process_id: String,
+    step_type: LoginStep,
+}
+
+/// Login Start Step
+///
+/// Prepare a login step before completing it, e.g. send a one-time code.
+#[utoipa::path(post, path = "/auth/login/start", tag = "auth", request_body = LoginStartStepRequest, responses(
+    (status = 200, description = "Step started."),
+    (status = 400, body = AppError, description = "Invalid request."),
+))]
+async fn login_start_step(
+    State(keygate): State<Keygate>,
+    Extension(application_id): Extension<ApplicationID>,
+    Json(data): Json<LoginStartStepRequest>,
+) -> Result<(), AppError> {
+    keygate
+        .auth
+        .login_start_step(&application_id.0, &data.process_id, &data.step_type)
+        .await?;
+    Ok(())
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+pub struct LoginTrustDeviceRequest {
+    process_id: String,
+    /// name shown in the list of trusted devices, e.g. the browser and os
+    label: String,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+pub struct LoginTrustDeviceResponse {
+    /// secret to complete the `trusted_device` step of future logins, only shown once
+    token: String,
+}
+
+/// Login Trust Device
+///
+/// Trust the device of a login that was completed with a second factor, so future logins can skip the second factor.
+/// Has to be called with the session the login created, the token only works with the same `device_id`.
+#[utoipa::path(post, path = "/auth/login/trust-device", tag = "auth", request_body = LoginTrustDeviceRequest, responses(
+    (status = 200, body = LoginTrustDeviceResponse, description = "Device trusted."),
+    (status = 400, body = AppError, description = "Invalid request."),
+    (status = 401, body = AppError, description = "Not authenticated."),
+    (status = 403, body = AppError, description = "The login was not completed with a second factor by this session."),
+))]
+async fn login_trust_device(
+    State(keygate): State<Keygate>,
+    Extension(token): Extension<AppToken>,
+    Extension(application_id): Extension<ApplicationID>,
+    Json(data): Json<LoginTrustDeviceRequest>,
+) -> Result<Json<LoginTrustDeviceResponse>, AppError> {
+    let token = keygate
+        .auth
+        .login_trust_device(
+            &application_id.0,
+            token.session_id()?,
+            &data.process_id,
+            &data.label,
+        )
+        .await?;
+    Ok(Json(LoginTrustDeviceResponse { token }))
+}
+
+/// Device Approvals
+///
+/// List logins from unrecognized devices that wait for approval.
+#[utoipa::path(get, path = "/auth/login/device-approval", tag = "auth", responses(
+    (status = 200, body = Vec<DeviceApprovalRequest>, description = "Pending logins."),
+    (status = 401, body = AppError, description = "Not authenticated."),
+))]
+async fn device_approvals(
+    State(keygate): State<Keygate>,
+    Extension(identity): Extension<ReqIdentity>,
+) -> Result<Json<Vec<DeviceApprovalRequest>>, AppError> {
+    let identity = identity.identity()?;
+    let res = keygate.auth.login_pending_approvals(&identity.id).await?;
+    Ok(Json(res))
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+pub struct DeviceApprovalBody {
+    process_id: String,
+    approved: bool,
+}
+
+/// Device Approval
+///
+/// Approve or deny a login from an unrecognized device.
+/// Approving completes the login, the waiting device receives its tokens from the next `device_approval` step.
+#[utoipa::path(post, path = "/auth/login/device-approval", tag = "auth", request_body = DeviceApprovalBody, responses(
+    (status = 200, description = "Login approved or denied."),
+    (status = 400, body = AppError, description = "Invalid request."),
+    (status = 401, body = AppError, description = "Not authenticated."),
+))]
+async fn device_approval(
+    State(keygate): State<Keygate>,
+    Extension(identity): Extension<ReqIdentity>,
+    Json(data): Json<DeviceApprovalBody>,
+) -> Result<(), AppError> {
+    let identity = identity.identity()?;
+    keygate
+        .auth
+        .login_approve_device(&data.process_id, &identity.id, data.approved)
+        .await?;
+    Ok(())
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+pub struct ReauthRequest {
+    /// whether the session has to be raised to multi-factor authentication
+    #[serde(default)]
+    second_factor: bool,
+}
+
+/// Re-authenticate
+///
+/// Start a login process that refreshes the authentication of the current session instead of creating a new one. Complete it with the login step endpoint.
+#[utoipa::path(post, path = "/auth/login/reauth", tag = "auth", request_body = ReauthRequest, responses(
+    (status = 200, body = LoginResponse, description = "Login process created."),
+    (status = 401, body = AppError, description = "Not authenticated."),
+    (status = 403, body = AppError, description = "No second factor available."),
+))]
+async fn reauth(
+    State(keygate): State<Keygate>,
+    Extension(token): Extension<AppToken>,
+    Extension(application_id): Extension<ApplicationID>,
+    Json(data): Json<ReauthRequest>,
+) -> Result<Json<LoginResponse>, AppError> {
+    let res = keygate
+        .auth
+        .reauth_create(&application_id.0, token.session_id()?, data.second_factor)
         .await?;
     Ok(Json(res))
 }
@@ -70,11 +443,52 @@ async fn login_step(
 #[utoipa::path(get, path = "/auth/login/:process_id", tag = "auth", responses(
     (status = 200, body = LoginStatusResponse, description = "Login process status."),
     (status = 400, body = AppError, description = "Invalid request."),
+    (status = 404, body = AppError, description = "Login process not found."),
 ))]
 async fn login_status(
     Path(process_id): Path<String>,
     State(keygate): State<Keygate>,
+    Extension(application_id): Extension<ApplicationID>,
 ) -> Result<Json<LoginStatusResponse>, AppError> {
-    let res = keygate.auth.login_status(&process_id).await?;
+    let res = keygate
+        .auth
+        .login_status(&application_id.0, &process_id)
+        .await?;
     Ok(Json(res))
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, extract::Request, http::StatusCode};
+    use keygate_core::{config::StorageOptions, KeygateConfig};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_saml_acs_form_post() {
+        let keygate = Keygate::new(KeygateConfig {
+            storage_options: StorageOptions::Sqlite {
+                database_path: "sqlite://:memory:".into(),
+            },
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+        // the browser posts the form without a token, the relay state decides the process
+        let request = Request::post("/saml/acs")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body(Body::from(
+                "SAMLResponse=PHNhbWxwOlJlc3BvbnNlLz4%3D&RelayState=unknown",
+            ))
+            .unwrap();
+        let status = new(keygate.clone())
+            .with_state(keygate)
+            .oneshot(request)
+            .await
+            .unwrap()
+            .status();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
@@ -2,15 +2,19 @@ use axum::Router;
 use keygate_core::Keygate;
 
 mod _api;
+mod email;
 mod identity;
 mod login;
+mod password;
 mod signup;
 pub use _api::PublicAPI;
 
-pub fn new() -> Router<Keygate> {
+pub fn new(keygate: Keygate) -> Router<Keygate> {
     Router::new()
         .merge(_api::new())
-        .nest("/auth/login", login::new())
-        .nest("/auth/signup", signup::new())
-        .nest("/identity", identity::new())
+        .nest("/auth/login", login::new(keygate.clone()))
+        .nest("/auth/password-reset", password::new())
+        .nest("/auth/signup", signup::new(keygate.clone()))
+        .nest("/auth/verify-email", email::new())
+        .nest("/identity", identity::new(keygate))
 }
@@ -0,0 +1,57 @@
+use axum::extract::State;
+use axum::routing::*;
+use axum::{Json, Router};
+
+use keygate_core::Keygate;
+
+use crate::errors::AppError;
+
+pub fn new() -> Router<Keygate> {
+    Router::new()
+        .route("/", post(password_reset))
+        .route("/confirm", post(password_reset_confirm))
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+pub struct PasswordResetRequest {
+    email: String,
+}
+
+/// Password Reset
+///
+/// Send a password reset token to the email address if it is verified. The response is the same whether the address belongs to an identity or not.
+#[utoipa::path(post, path = "/auth/password-reset", tag = "auth", request_body = PasswordResetRequest, responses(
+    (status = 200, description = "Reset requested."),
+))]
+async fn password_reset(
+    State(keygate): State<Keygate>,
+    Json(data): Json<PasswordResetRequest>,
+) -> Result<(), AppError> {
+    keygate.auth.password_reset_request(&data.email).await?;
+    Ok(())
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+pub struct PasswordResetConfirmRequest {
+    /// token from the reset email, it can only be used once
+    token: String,
+    new_password: String,
+}
+
+/// Password Reset Confirm
+///
+/// Set a new password with a reset token. All sessions of the identity are signed out.
+#[utoipa::path(post, path = "/auth/password-reset/confirm", tag = "auth", request_body = PasswordResetConfirmRequest, responses(
+    (status = 200, description = "Password changed."),
+    (status = 400, body = AppError, description = "Invalid password or invalid or expired token."),
+))]
+async fn password_reset_confirm(
+    State(keygate): State<Keygate>,
+    Json(data): Json<PasswordResetConfirmRequest>,
+) -> Result<(), AppError> {
+    keygate
+        .auth
+        .password_reset_confirm(&data.token, &data.new_password)
+        .await?;
+    Ok(())
+}
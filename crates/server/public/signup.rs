@@ -1,43 +1,125 @@
 use axum::extract::{ConnectInfo, State};
 use axum::routing::*;
-use axum::{Json, Router};
+use axum::{middleware, Extension, Json, Router};
 
+use keygate_core::api::auth::{SignupData, SignupResponse as CoreSignupResponse};
+use keygate_core::api::identity::iso_date;
 use keygate_core::Keygate;
 
 use crate::errors::AppError;
+use crate::middleware::session::{validate_token, ApplicationID};
 use std::net::SocketAddr;
 
-pub fn new() -> Router<Keygate> {
-    Router::new().route("/", post(signup))
+// the tokens of the new identity are issued for the application of the (anonymous) token
+pub fn new(keygate: Keygate) -> Router<Keygate> {
+    Router::new()
+        .route("/", post(signup))
+        .route("/confirm", post(signup_confirm))
+        .route_layer(middleware::from_fn_with_state(keygate, validate_token))
 }
 
-#[derive(serde::Deserialize)]
-struct SignupRequest {
-    username: String,
-    password: String,
-    email: String,
+/// Which fields are required depends on the configured signup flow,
+/// fields that aren't part of it are rejected
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+pub struct SignupRequest {
+    username: Option<String>,
+    password: Option<String>,
+    email: Option<String>,
+    /// required if the settings ask for it
+    full_name: Option<String>,
+    /// `YYYY-MM-DD`, required if the settings ask for it
+    #[serde(default, with = "iso_date::option")]
+    #[schema(value_type = Option<String>, format = Date)]
+    birthdate: Option<time::Date>,
+    /// confirms the user is at least the minimum age, needed if no birthdate is required
+    #[serde(default)]
+    age_attestation: bool,
 }
 
-#[derive(serde::Serialize)]
-enum SignupResponse {
+#[derive(serde::Serialize, utoipa::ToSchema)]
+pub enum SignupResponse {
     Success {
         access_token: String,
         refresh_token: String,
     },
+    /// A code was sent to the email address, continue with `/auth/signup/confirm`
     RequiresEmailVerification,
 }
 
+/// Signup
+///
+/// Create a new identity. Depending on the settings, the email address has to be confirmed before the identity is signed in.
+#[utoipa::path(post, path = "/auth/signup", tag = "auth", request_body = SignupRequest, responses(
+    (status = 200, body = SignupResponse, description = "Identity created."),
+    (status = 400, body = AppError, description = "Missing or invalid fields."),
+    (status = 403, body = AppError, description = "The user is younger than the minimum age."),
+    (status = 409, body = AppError, description = "The username or email is already taken."),
+))]
 async fn signup(
     State(keygate): State<Keygate>,
     ConnectInfo(ip): ConnectInfo<SocketAddr>,
+    Extension(application_id): Extension<ApplicationID>,
     Json(data): Json<SignupRequest>,
 ) -> Result<Json<SignupResponse>, AppError> {
-    let identity = keygate
+    let res = keygate
         .auth
-        .signup(&data.username, &data.password, &data.email, ip.ip())
+        .signup(
+            SignupData {
+                username: data.username.as_deref(),
+                email: data.email.as_deref(),
+                password: data.password.as_deref(),
+                full_name: data.full_name.as_deref(),
+                birthdate: data.birthdate,
+                age_attestation: data.age_attestation,
+            },
+            ip.ip(),
+        )
         .await?;
 
-    let (refresh_token, access_token) = keygate.session.create(identity.id).await?;
+    let identity = match res {
+        CoreSignupResponse::Success { identity } => identity,
+        CoreSignupResponse::RequiresEmailVerification => {
+            return Ok(Json(SignupResponse::RequiresEmailVerification))
+        }
+    };
+
+    let ip_address = ip.ip().to_string();
+    let (refresh_token, access_token) = keygate
+        .session
+        .create(&identity.id, &application_id.0, Some(&ip_address))
+        .await?;
+
+    Ok(Json(SignupResponse::Success {
+        access_token: access_token.0,
+        refresh_token: refresh_token.0,
+    }))
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+pub struct SignupConfirmRequest {
+    /// code from the confirmation email
+    code: String,
+}
+
+/// Signup Confirm
+///
+/// Confirm the email address of a new identity and sign it in.
+#[utoipa::path(post, path = "/auth/signup/confirm", tag = "auth", request_body = SignupConfirmRequest, responses(
+    (status = 200, body = SignupResponse, description = "Email confirmed."),
+    (status = 400, body = AppError, description = "Invalid or expired code."),
+))]
+async fn signup_confirm(
+    State(keygate): State<Keygate>,
+    ConnectInfo(ip): ConnectInfo<SocketAddr>,
+    Extension(application_id): Extension<ApplicationID>,
+    Json(data): Json<SignupConfirmRequest>,
+) -> Result<Json<SignupResponse>, AppError> {
+    let identity = keygate.auth.signup_confirm(&data.code).await?;
+    let ip_address = ip.ip().to_string();
+    let (refresh_token, access_token) = keygate
+        .session
+        .create(&identity.id, &application_id.0, Some(&ip_address))
+        .await?;
 
     Ok(Json(SignupResponse::Success {
         access_token: access_token.0,
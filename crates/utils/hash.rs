@@ -1,5 +1,21 @@
-use argon2::{password_hash::SaltString, PasswordHash, PasswordHasher, PasswordVerifier};
+use std::{
+    fmt::Debug,
+    sync::{Arc, OnceLock},
+    time::{Duration, Instant},
+};
+
+use argon2::{
+    password_hash::SaltString, Algorithm, Argon2, KeyId, Params, ParamsBuilder, PasswordHash,
+    PasswordHasher as _, PasswordVerifier, Version,
+};
 use rand_core::OsRng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
+
+use crate::legacy_hash::{self, FirebaseScryptParams, LegacyScheme};
+
+static DUMMY_HASH: OnceLock<String> = OnceLock::new();
 
 pub fn password(password: &str) -> std::io::Result<String> {
     let salt = SaltString::generate(&mut OsRng);
@@ -7,23 +23,242 @@ pub fn password(password: &str) -> std::io::Result<String> {
     let argon2 = argon2::Argon2::default();
     let hash = argon2
         .hash_password(password.as_bytes(), &salt)
-        .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "failed to hash password"))?
+        .map_err(|_| std::io::Error::other("failed to hash password"))?
         .to_string();
     Ok(hash)
 }
 
+/// Also verifies the legacy formats of [`legacy_hash`], except Firebase scrypt
 pub fn verify(password: &str, hash: &str) -> std::io::Result<bool> {
+    if LegacyScheme::detect(hash).is_some() {
+        return legacy_hash::verify(password, hash, None);
+    }
+
     let argon2 = argon2::Argon2::default();
-    let hash = PasswordHash::new(hash)
-        .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "failed to parse hash"))?;
+    let hash =
+        PasswordHash::new(hash).map_err(|_| std::io::Error::other("failed to parse hash"))?;
     let result = argon2
         .verify_password(password.as_bytes(), &hash)
-        .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "failed to verify password"))
+        .map_err(|_| std::io::Error::other("failed to verify password"))
         .is_ok();
 
     Ok(result)
 }
 
+// hash for random, high-entropy tokens (e.g. device ids) that have to be looked up by their hash.
+// never use this for passwords or short codes.
+pub fn token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+// verify against a fixed hash so checks for unknown identities take as long as real ones
+pub fn verify_dummy(password: &str) -> std::io::Result<bool> {
+    let hash = match DUMMY_HASH.get() {
+        Some(hash) => hash,
+        None => {
+            let hash = self::password(&crate::random::secure_random_id())?;
+            DUMMY_HASH.get_or_init(|| hash)
+        }
+    };
+
+    verify(password, hash)?;
+    Ok(false)
+}
+
+/// Argon2id cost parameters, the defaults follow the OWASP recommendation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PasswordHashParams {
+    /// memory in KiB
+    pub memory_cost: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for PasswordHashParams {
+    fn default() -> Self {
+        Self {
+            memory_cost: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+/// Hashes passwords with configurable parameters and an optional pepper.
+///
+/// Hashes keep their own parameters, so older hashes still verify after the parameters
+/// change and can be replaced once [`PasswordHasher::needs_rehash`] says so. Hashes made
+/// with a pepper carry a short id derived from it and can only be verified with the same pepper.
+/// Hashes imported from other systems ([`legacy_hash`]) are verified too and always need a rehash.
+#[derive(Clone, Default)]
+pub struct PasswordHasher {
+    params: PasswordHashParams,
+    pepper: Option<Zeroizing<Vec<u8>>>,
+    firebase_scrypt: Option<FirebaseScryptParams>,
+    dummy_hash: Arc<OnceLock<String>>,
+}
+
+impl Debug for PasswordHasher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PasswordHasher")
+            .field("params", &self.params)
+            .field("pepper", &self.pepper.is_some())
+            .finish()
+    }
+}
+
+impl PasswordHasher {
+    pub fn new(params: PasswordHashParams, pepper: Option<&str>) -> std::io::Result<Self> {
+        let hasher = Self {
+            params,
+            pepper: pepper.map(|pepper| Zeroizing::new(pepper.as_bytes().to_vec())),
+            firebase_scrypt: None,
+            dummy_hash: Default::default(),
+        };
+        // fail early on invalid parameters
+        hasher.argon2_params()?;
+        Ok(hasher)
+    }
+
+    /// Project parameters to verify hashes exported from Firebase
+    pub fn with_firebase_scrypt(mut self, params: Option<FirebaseScryptParams>) -> Self {
+        self.firebase_scrypt = params;
+        self
+    }
+
+    pub fn params(&self) -> PasswordHashParams {
+        self.params
+    }
+
+    // identifies the pepper in the hash without revealing it, empty without a pepper
+    fn key_id(&self) -> Vec<u8> {
+        match &self.pepper {
+            Some(pepper) => Sha256::digest(pepper.as_slice())[..4].to_vec(),
+            None => Vec::new(),
+        }
+    }
+
+    fn argon2_params(&self) -> std::io::Result<Params> {
+        let mut builder = ParamsBuilder::new();
+        builder
+            .m_cost(self.params.memory_cost)
+            .t_cost(self.params.iterations)
+            .p_cost(self.params.parallelism);
+        if self.pepper.is_some() {
+            builder.keyid(
+                KeyId::new(&self.key_id()).map_err(|e| std::io::Error::other(e.to_string()))?,
+            );
+        }
+        builder
+            .build()
+            .map_err(|e| std::io::Error::other(e.to_string()))
+    }
+
+    fn argon2(&self, params: Params, peppered: bool) -> std::io::Result<Argon2<'_>> {
+        match (&self.pepper, peppered) {
+            (Some(pepper), true) => {
+                Argon2::new_with_secret(pepper, Algorithm::Argon2id, Version::V0x13, params)
+                    .map_err(|e| std::io::Error::other(e.to_string()))
+            }
+            _ => Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params)),
+        }
+    }
+
+    pub fn hash(&self, password: &str) -> std::io::Result<String> {
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = self
+            .argon2(self.argon2_params()?, true)?
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(|_| std::io::Error::other("failed to hash password"))?
+            .to_string();
+        Ok(hash)
+    }
+
+    /// Errors if the hash can't be parsed or was made with a different pepper
+    pub fn verify(&self, password: &str, hash: &str) -> std::io::Result<bool> {
+        if LegacyScheme::detect(hash).is_some() {
+            return legacy_hash::verify(password, hash, self.firebase_scrypt.as_ref());
+        }
+
+        let hash =
+            PasswordHash::new(hash).map_err(|_| std::io::Error::other("failed to parse hash"))?;
+        let params = Params::try_from(&hash).map_err(|e| std::io::Error::other(e.to_string()))?;
+
+        let peppered = !params.keyid().is_empty();
+        if peppered && params.keyid() != self.key_id() {
+            return Err(std::io::Error::other("hash was made with another pepper"));
+        }
+
+        Ok(self
+            .argon2(params, peppered)?
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok())
+    }
+
+    /// Whether the hash was made with other parameters, another algorithm or pepper
+    pub fn needs_rehash(&self, hash: &str) -> bool {
+        if LegacyScheme::detect(hash).is_some() {
+            return true;
+        }
+
+        let Ok(hash) = PasswordHash::new(hash) else {
+            return true;
+        };
+        let Ok(params) = Params::try_from(&hash) else {
+            return true;
+        };
+
+        hash.algorithm != Algorithm::Argon2id.ident()
+            || hash.version != Some(Version::V0x13.into())
+            || params.m_cost() != self.params.memory_cost
+            || params.t_cost() != self.params.iterations
+            || params.p_cost() != self.params.parallelism
+            || params.keyid() != self.key_id()
+    }
+
+    /// Takes as long as [`PasswordHasher::verify`] but always fails,
+    /// for checks of identities that don't exist
+    pub fn verify_dummy(&self, password: &str) -> std::io::Result<bool> {
+        let hash = match self.dummy_hash.get() {
+            Some(hash) => hash,
+            None => {
+                let hash = self.hash(&crate::random::secure_random_id())?;
+                self.dummy_hash.get_or_init(|| hash)
+            }
+        };
+
+        self.verify(password, hash)?;
+        Ok(false)
+    }
+}
+
+/// Find the number of iterations that makes hashing take about `target` on this machine.
+/// Run this once per deployment and configure the result, nodes with different
+/// parameters would keep rehashing each other's hashes.
+pub fn calibrate(
+    target: Duration,
+    memory_cost: u32,
+    parallelism: u32,
+) -> std::io::Result<PasswordHashParams> {
+    let mut params = PasswordHashParams {
+        memory_cost,
+        iterations: 1,
+        parallelism,
+    };
+
+    // time a single iteration, the cost grows about linearly with the iterations
+    let hasher = PasswordHasher::new(params, None)?;
+    hasher.hash("calibration")?;
+    let start = Instant::now();
+    hasher.hash("calibration")?;
+    let elapsed = start.elapsed().max(Duration::from_micros(1));
+
+    params.iterations = (target.as_secs_f64() / elapsed.as_secs_f64()).round() as u32;
+    params.iterations = params.iterations.max(Params::MIN_T_COST);
+    Ok(params)
+}
+
 // test password
 #[cfg(test)]
 mod tests {
@@ -36,4 +271,72 @@ mod tests {
         assert!(!verify("password2", &hash)?);
         Ok(())
     }
+
+    #[test]
+    fn test_token() {
+        assert_eq!(token("token"), token("token"));
+        assert_ne!(token("token"), token("token2"));
+        assert_eq!(token("token").len(), 64);
+    }
+
+    #[test]
+    fn test_verify_dummy() -> std::io::Result<()> {
+        assert!(!verify_dummy("password")?);
+        assert!(!verify_dummy("password")?);
+        Ok(())
+    }
+
+    fn weak_params() -> PasswordHashParams {
+        PasswordHashParams {
+            memory_cost: 1024,
+            iterations: 1,
+            parallelism: 1,
+        }
+    }
+
+    #[test]
+    fn test_hasher_rehash() -> std::io::Result<()> {
+        let old = PasswordHasher::new(weak_params(), None)?;
+        let current = PasswordHasher::new(
+            PasswordHashParams {
+                iterations: 2,
+                ..weak_params()
+            },
+            None,
+        )?;
+
+        let hash = old.hash("password")?;
+        assert!(current.verify("password", &hash)?);
+        assert!(current.needs_rehash(&hash));
+        assert!(!old.needs_rehash(&hash));
+        assert!(!current.needs_rehash(&current.hash("password")?));
+        Ok(())
+    }
+
+    #[test]
+    fn test_hasher_pepper() -> std::io::Result<()> {
+        let plain = PasswordHasher::new(weak_params(), None)?;
+        let peppered = PasswordHasher::new(weak_params(), Some("pepper"))?;
+        let other = PasswordHasher::new(weak_params(), Some("other pepper"))?;
+
+        let hash = peppered.hash("password")?;
+        assert!(peppered.verify("password", &hash)?);
+        assert!(!peppered.verify("password2", &hash)?);
+        assert!(plain.verify("password", &hash).is_err());
+        assert!(other.verify("password", &hash).is_err());
+
+        // hashes from before the pepper was configured still verify
+        let hash = plain.hash("password")?;
+        assert!(peppered.verify("password", &hash)?);
+        assert!(peppered.needs_rehash(&hash));
+        Ok(())
+    }
+
+    #[test]
+    fn test_calibrate() -> std::io::Result<()> {
+        let params = calibrate(Duration::from_millis(1), 1024, 1)?;
+        assert!(params.iterations >= 1);
+        assert_eq!(params.memory_cost, 1024);
+        Ok(())
+    }
 }
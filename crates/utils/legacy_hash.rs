@@ -0,0 +1,313 @@
+//! Password hashes exported from other systems. They can be verified, but new hashes are
+//! always Argon2, see [`crate::hash::PasswordHasher::needs_rehash`].
+//!
+//! Supported formats:
+//! - bcrypt: `$2a$`, `$2b$`, `$2x$` and `$2y$` (e.g. Auth0)
+//! - Firebase scrypt: `$firebase-scrypt$<salt>$<hash>` with the base64 salt and hash of the
+//!   user, the project parameters are configured with [`FirebaseScryptParams`]
+//! - PBKDF2-SHA256: `pbkdf2_sha256$<iterations>$<salt>$<hash>` (Django)
+//! - SHA-512-crypt: `$6$<salt>$<hash>` or `$6$rounds=<rounds>$<salt>$<hash>`
+
+use aes::cipher::{KeyIvInit, StreamCipher};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+
+type Aes256Ctr = ctr::Ctr128BE<aes::Aes256>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LegacyScheme {
+    Bcrypt,
+    FirebaseScrypt,
+    Pbkdf2Sha256,
+    Sha512Crypt,
+}
+
+impl LegacyScheme {
+    pub fn detect(hash: &str) -> Option<Self> {
+        if ["$2a$", "$2b$", "$2x$", "$2y$"]
+            .iter()
+            .any(|prefix| hash.starts_with(prefix))
+        {
+            Some(Self::Bcrypt)
+        } else if hash.starts_with("$firebase-scrypt$") {
+            Some(Self::FirebaseScrypt)
+        } else if hash.starts_with("pbkdf2_sha256$") {
+            Some(Self::Pbkdf2Sha256)
+        } else if hash.starts_with("$6$") {
+            Some(Self::Sha512Crypt)
+        } else {
+            None
+        }
+    }
+}
+
+/// Parameters of a Firebase project, shown in the console when exporting users
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FirebaseScryptParams {
+    /// base64
+    pub signer_key: String,
+    /// base64
+    pub salt_separator: String,
+    pub rounds: u32,
+    pub mem_cost: u32,
+}
+
+impl std::fmt::Debug for FirebaseScryptParams {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FirebaseScryptParams")
+            .field("rounds", &self.rounds)
+            .field("mem_cost", &self.mem_cost)
+            .finish_non_exhaustive()
+    }
+}
+
+fn invalid(scheme: &str) -> std::io::Error {
+    std::io::Error::other(format!("invalid {} hash", scheme))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Verify a password against a legacy hash. Firebase hashes need the project parameters.
+pub fn verify(
+    password: &str,
+    hash: &str,
+    firebase: Option<&FirebaseScryptParams>,
+) -> std::io::Result<bool> {
+    match LegacyScheme::detect(hash) {
+        Some(LegacyScheme::Bcrypt) => {
+            bcrypt::verify(password, hash).map_err(|e| std::io::Error::other(e.to_string()))
+        }
+        Some(LegacyScheme::FirebaseScrypt) => {
+            let params = firebase.ok_or(std::io::Error::other(
+                "firebase scrypt parameters are not configured",
+            ))?;
+            verify_firebase_scrypt(password, hash, params)
+        }
+        Some(LegacyScheme::Pbkdf2Sha256) => verify_pbkdf2_sha256(password, hash),
+        Some(LegacyScheme::Sha512Crypt) => verify_sha512_crypt(password, hash),
+        None => Err(std::io::Error::other("unknown hash format")),
+    }
+}
+
+fn verify_firebase_scrypt(
+    password: &str,
+    hash: &str,
+    params: &FirebaseScryptParams,
+) -> std::io::Result<bool> {
+    let (salt, expected) = hash
+        .strip_prefix("$firebase-scrypt$")
+        .and_then(|rest| rest.split_once('$'))
+        .ok_or(invalid("firebase scrypt"))?;
+    let decode = |value: &str| {
+        STANDARD
+            .decode(value)
+            .map_err(|_| invalid("firebase scrypt"))
+    };
+
+    let mut salt = decode(salt)?;
+    salt.extend(decode(&params.salt_separator)?);
+    let expected = decode(expected)?;
+
+    let scrypt_params = scrypt::Params::new(params.mem_cost as u8, params.rounds, 1, 32)
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    let mut key = [0u8; 32];
+    scrypt::scrypt(password.as_bytes(), &salt, &scrypt_params, &mut key)
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+
+    // the hash is the project's signer key encrypted with the derived key
+    let mut signed = decode(&params.signer_key)?;
+    Aes256Ctr::new(&key.into(), &[0u8; 16].into()).apply_keystream(&mut signed);
+
+    Ok(constant_time_eq(&signed, &expected))
+}
+
+fn verify_pbkdf2_sha256(password: &str, hash: &str) -> std::io::Result<bool> {
+    let mut parts = hash.split('$').skip(1);
+    let (Some(iterations), Some(salt), Some(expected), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(invalid("pbkdf2"));
+    };
+    let iterations: u32 = iterations.parse().map_err(|_| invalid("pbkdf2"))?;
+    let expected = STANDARD.decode(expected).map_err(|_| invalid("pbkdf2"))?;
+
+    let mut derived = vec![0u8; expected.len()];
+    pbkdf2::pbkdf2_hmac::<Sha256>(
+        password.as_bytes(),
+        salt.as_bytes(),
+        iterations,
+        &mut derived,
+    );
+
+    Ok(constant_time_eq(&derived, &expected))
+}
+
+const CRYPT_ALPHABET: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+const SHA512_CRYPT_ORDER: [(usize, usize, usize); 21] = [
+    (0, 21, 42),
+    (22, 43, 1),
+    (44, 2, 23),
+    (3, 24, 45),
+    (25, 46, 4),
+    (47, 5, 26),
+    (6, 27, 48),
+    (28, 49, 7),
+    (50, 8, 29),
+    (9, 30, 51),
+    (31, 52, 10),
+    (53, 11, 32),
+    (12, 33, 54),
+    (34, 55, 13),
+    (56, 14, 35),
+    (15, 36, 57),
+    (37, 58, 16),
+    (59, 17, 38),
+    (18, 39, 60),
+    (40, 61, 19),
+    (62, 20, 41),
+];
+
+fn verify_sha512_crypt(password: &str, hash: &str) -> std::io::Result<bool> {
+    let rest = hash.strip_prefix("$6$").ok_or(invalid("sha512-crypt"))?;
+    let (rounds, rest) = match rest.strip_prefix("rounds=") {
+        Some(rest) => {
+            let (rounds, rest) = rest.split_once('$').ok_or(invalid("sha512-crypt"))?;
+            let rounds: usize = rounds.parse().map_err(|_| invalid("sha512-crypt"))?;
+            (rounds.clamp(1000, 999_999_999), rest)
+        }
+        None => (5000, rest),
+    };
+    let (salt, expected) = rest.rsplit_once('$').ok_or(invalid("sha512-crypt"))?;
+    let salt = &salt.as_bytes()[..salt.len().min(16)];
+
+    let encoded = sha512_crypt(password.as_bytes(), salt, rounds);
+    Ok(constant_time_eq(&encoded, expected.as_bytes()))
+}
+
+// the algorithm from https://www.akkadia.org/drepper/SHA-crypt.txt, returns the encoded hash
+fn sha512_crypt(password: &[u8], salt: &[u8], rounds: usize) -> Vec<u8> {
+    let repeat = |digest: &[u8], len: usize| -> Vec<u8> {
+        digest.iter().copied().cycle().take(len).collect()
+    };
+
+    let alternate = Sha512::new()
+        .chain_update(password)
+        .chain_update(salt)
+        .chain_update(password)
+        .finalize();
+
+    let mut a = Sha512::new()
+        .chain_update(password)
+        .chain_update(salt)
+        .chain_update(repeat(&alternate, password.len()));
+    let mut length = password.len();
+    while length > 0 {
+        match length & 1 {
+            1 => a.update(alternate),
+            _ => a.update(password),
+        }
+        length >>= 1;
+    }
+    let a = a.finalize();
+
+    let mut p = Sha512::new();
+    for _ in 0..password.len() {
+        p.update(password);
+    }
+    let p = repeat(&p.finalize(), password.len());
+
+    let mut s = Sha512::new();
+    for _ in 0..16 + a[0] as usize {
+        s.update(salt);
+    }
+    let s = repeat(&s.finalize(), salt.len());
+
+    let mut c = a;
+    for round in 0..rounds {
+        let mut digest = Sha512::new();
+        match round % 2 {
+            1 => digest.update(&p),
+            _ => digest.update(c),
+        }
+        if round % 3 != 0 {
+            digest.update(&s);
+        }
+        if round % 7 != 0 {
+            digest.update(&p);
+        }
+        match round % 2 {
+            1 => digest.update(c),
+            _ => digest.update(&p),
+        }
+        c = digest.finalize();
+    }
+
+    let mut encoded = Vec::with_capacity(86);
+    let mut push = |value: u32, chars: usize| {
+        let mut value = value;
+        for _ in 0..chars {
+            encoded.push(CRYPT_ALPHABET[(value & 0x3f) as usize]);
+            value >>= 6;
+        }
+    };
+    for (b2, b1, b0) in SHA512_CRYPT_ORDER {
+        push((c[b2] as u32) << 16 | (c[b1] as u32) << 8 | c[b0] as u32, 4);
+    }
+    push(c[63] as u32, 2);
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bcrypt() -> std::io::Result<()> {
+        let hash = bcrypt::hash("correct horse", 4).unwrap();
+        assert_eq!(LegacyScheme::detect(&hash), Some(LegacyScheme::Bcrypt));
+        assert!(verify("correct horse", &hash, None)?);
+        assert!(!verify("battery staple", &hash, None)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_firebase_scrypt() -> std::io::Result<()> {
+        // example from https://github.com/firebase/scrypt
+        let params = FirebaseScryptParams {
+            signer_key: "jxspr8Ki0RYycVU8zykbdLGjFQ3McFUH0uiiTvC8pVMXAn210wjLNmdZJzxUECKbm0QsEmYUSDzZvpjeJ9WmXA==".into(),
+            salt_separator: "Bw==".into(),
+            rounds: 8,
+            mem_cost: 14,
+        };
+        let hash = "$firebase-scrypt$42xEC+ixf3L2lw==$lSrfV15cpx95/sZS2W9c9Kp6i/LVgQNDNC/qzrCnh1SAyZvqmZqAjTdn3aoItz+VHjoZilo78198JAdRuid5lQ==";
+
+        assert!(verify("user1password", hash, Some(&params))?);
+        assert!(!verify("user2password", hash, Some(&params))?);
+        assert!(verify("user1password", hash, None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_pbkdf2_sha256() -> std::io::Result<()> {
+        let hash = "pbkdf2_sha256$1000$seasalt$mQnueSakb748zqBAC1tmWVZsZbi2zPGZarEzTGdfmso=";
+        assert!(verify("correct horse", hash, None)?);
+        assert!(!verify("battery staple", hash, None)?);
+        assert!(verify("correct horse", "pbkdf2_sha256$1000$seasalt", None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sha512_crypt() -> std::io::Result<()> {
+        // examples from the specification
+        let hash = "$6$saltstring$svn8UoSVapNtMuq1ukKS4tPQd8iKwSMHWjl/O817G3uBnIFNjnQJuesI68u4OTLiBFdcbYEdFCoEOfaS35inz1";
+        assert!(verify("Hello world!", hash, None)?);
+        assert!(!verify("Hello world", hash, None)?);
+
+        let hash = "$6$rounds=10000$saltstringsaltst$OW1/O6BYHV6BcXZu8QVeXbDWra3Oeqh0sbHbbMCVNSnCM/UrjmM0Dp8vOuZeHBy/YTBmSK6H9qs/y3RnOaw5v.";
+        assert!(verify("Hello world!", hash, None)?);
+        Ok(())
+    }
+}
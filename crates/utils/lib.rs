@@ -1,9 +1,13 @@
 pub mod atomic;
 pub mod encode;
 pub mod hash;
+pub mod legacy_hash;
 pub mod macros;
+pub mod oidc;
+pub mod password_policy;
 pub mod pwned;
 pub mod random;
+pub mod saml;
 pub mod sha1;
 pub mod tokens;
 pub mod validate;
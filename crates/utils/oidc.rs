@@ -0,0 +1,358 @@
+//! Logging in with upstream OpenID Connect providers: PKCE and `id_token` validation.
+//!
+//! Supported signature algorithms are `RS256` and `EdDSA` (Ed25519).
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rsa::{pkcs1v15, signature::Verifier, BigUint, RsaPublicKey};
+use serde::{Deserialize, Deserializer, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+/// clock skew tolerated when checking `exp` and `iat`
+const LEEWAY_SECONDS: i64 = 60;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum OidcError {
+    #[error("malformed token")]
+    MalformedToken,
+    #[error("unsupported algorithm: {0}")]
+    UnsupportedAlgorithm(String),
+    #[error("no matching signing key")]
+    UnknownKey,
+    #[error("invalid signature")]
+    InvalidSignature,
+    #[error("invalid claim: {0}")]
+    InvalidClaim(&'static str),
+    #[error("expired token")]
+    ExpiredToken,
+}
+
+/// `S256` code challenge of a PKCE code verifier (RFC 7636)
+pub fn pkce_challenge(code_verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()))
+}
+
+/// Signing keys of a provider, served at its `jwks_uri`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Jwks {
+    pub keys: Vec<Jwk>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Jwk {
+    pub kty: String,
+    pub kid: Option<String>,
+    pub alg: Option<String>,
+    #[serde(rename = "use")]
+    pub key_use: Option<String>,
+    /// RSA modulus
+    pub n: Option<String>,
+    /// RSA exponent
+    pub e: Option<String>,
+    /// OKP curve
+    pub crv: Option<String>,
+    /// OKP public key
+    pub x: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Audience {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl Audience {
+    pub fn contains(&self, audience: &str) -> bool {
+        match self {
+            Self::Single(aud) => aud == audience,
+            Self::Multiple(auds) => auds.iter().any(|aud| aud == audience),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdTokenClaims {
+    pub iss: String,
+    pub sub: String,
+    pub aud: Audience,
+    pub exp: i64,
+    pub iat: Option<i64>,
+    pub nonce: Option<String>,
+    pub azp: Option<String>,
+    pub email: Option<String>,
+    #[serde(default, deserialize_with = "bool_or_string")]
+    pub email_verified: bool,
+    pub preferred_username: Option<String>,
+    pub name: Option<String>,
+}
+
+// some providers send `"true"` instead of `true`
+fn bool_or_string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum BoolOrString {
+        Bool(bool),
+        String(String),
+    }
+
+    Ok(match BoolOrString::deserialize(deserializer)? {
+        BoolOrString::Bool(value) => value,
+        BoolOrString::String(value) => value == "true",
+    })
+}
+
+/// What the `id_token` has to match
+#[derive(Debug, Clone)]
+pub struct IdTokenValidation<'a> {
+    pub issuer: &'a str,
+    pub client_id: &'a str,
+    /// the nonce sent with the authorization request
+    pub nonce: &'a str,
+    /// unix timestamp
+    pub now: i64,
+}
+
+#[derive(Deserialize)]
+struct Header {
+    alg: String,
+    kid: Option<String>,
+}
+
+/// Verify the signature and claims of an `id_token`.
+///
+/// Errors with [`OidcError::UnknownKey`] if no key of `jwks` matches, the provider may
+/// have rotated its keys.
+pub fn verify_id_token(
+    token: &str,
+    jwks: &Jwks,
+    validation: &IdTokenValidation,
+) -> Result<IdTokenClaims, OidcError> {
+    let mut parts = token.split('.');
+    let (Some(header_part), Some(payload_part), Some(signature_part), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(OidcError::MalformedToken);
+    };
+
+    let decode = |part: &str| {
+        URL_SAFE_NO_PAD
+            .decode(part)
+            .map_err(|_| OidcError::MalformedToken)
+    };
+    let header: Header =
+        serde_json::from_slice(&decode(header_part)?).map_err(|_| OidcError::MalformedToken)?;
+    let signature = decode(signature_part)?;
+    let signed = &token[..header_part.len() + 1 + payload_part.len()];
+
+    let kty = match header.alg.as_str() {
+        "RS256" => "RSA",
+        "EdDSA" => "OKP",
+        alg => return Err(OidcError::UnsupportedAlgorithm(alg.to_string())),
+    };
+    let key = jwks
+        .keys
+        .iter()
+        .filter(|key| key.kty == kty)
+        .filter(|key| key.key_use.as_deref().unwrap_or("sig") == "sig")
+        .filter(|key| key.alg.as_ref().is_none_or(|alg| *alg == header.alg))
+        .find(|key| header.kid.is_none() || key.kid == header.kid)
+        .ok_or(OidcError::UnknownKey)?;
+
+    match kty {
+        "RSA" => verify_rs256(key, signed.as_bytes(), &signature)?,
+        _ => verify_eddsa(key, signed.as_bytes(), &signature)?,
+    }
+
+    let claims: IdTokenClaims =
+        serde_json::from_slice(&decode(payload_part)?).map_err(|_| OidcError::MalformedToken)?;
+    validate_claims(&claims, validation)?;
+    Ok(claims)
+}
+
+fn validate_claims(
+    claims: &IdTokenClaims,
+    validation: &IdTokenValidation,
+) -> Result<(), OidcError> {
+    if claims.iss != validation.issuer {
+        return Err(OidcError::InvalidClaim("iss"));
+    }
+    if !claims.aud.contains(validation.client_id) {
+        return Err(OidcError::InvalidClaim("aud"));
+    }
+    // with several audiences, the token has to be issued to us
+    if matches!(claims.aud, Audience::Multiple(ref auds) if auds.len() > 1)
+        && claims.azp.as_deref() != Some(validation.client_id)
+    {
+        return Err(OidcError::InvalidClaim("azp"));
+    }
+    if claims.nonce.as_deref() != Some(validation.nonce) {
+        return Err(OidcError::InvalidClaim("nonce"));
+    }
+    if claims.exp + LEEWAY_SECONDS < validation.now {
+        return Err(OidcError::ExpiredToken);
+    }
+    if claims
+        .iat
+        .is_some_and(|iat| iat > validation.now + LEEWAY_SECONDS)
+    {
+        return Err(OidcError::InvalidClaim("iat"));
+    }
+    Ok(())
+}
+
+fn verify_rs256(key: &Jwk, message: &[u8], signature: &[u8]) -> Result<(), OidcError> {
+    let component = |value: &Option<String>| {
+        value
+            .as_deref()
+            .and_then(|value| URL_SAFE_NO_PAD.decode(value).ok())
+            .map(|bytes| BigUint::from_bytes_be(&bytes))
+            .ok_or(OidcError::UnknownKey)
+    };
+    let public_key = RsaPublicKey::new(component(&key.n)?, component(&key.e)?)
+        .map_err(|_| OidcError::UnknownKey)?;
+    let signature =
+        pkcs1v15::Signature::try_from(signature).map_err(|_| OidcError::InvalidSignature)?;
+
+    pkcs1v15::VerifyingKey::<Sha256>::new(public_key)
+        .verify(message, &signature)
+        .map_err(|_| OidcError::InvalidSignature)
+}
+
+fn verify_eddsa(key: &Jwk, message: &[u8], signature: &[u8]) -> Result<(), OidcError> {
+    if key.crv.as_deref() != Some("Ed25519") {
+        return Err(OidcError::UnknownKey);
+    }
+    let public_key = key
+        .x
+        .as_deref()
+        .and_then(|x| URL_SAFE_NO_PAD.decode(x).ok())
+        .and_then(|x| <[u8; 32]>::try_from(x).ok())
+        .and_then(|x| ed25519_dalek::VerifyingKey::from_bytes(&x).ok())
+        .ok_or(OidcError::UnknownKey)?;
+    let signature =
+        ed25519_dalek::Signature::from_slice(signature).map_err(|_| OidcError::InvalidSignature)?;
+
+    public_key
+        .verify_strict(message, &signature)
+        .map_err(|_| OidcError::InvalidSignature)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rsa::{pkcs8::DecodePrivateKey, signature::SignatureEncoding, signature::Signer};
+    use rsa::{traits::PublicKeyParts, RsaPrivateKey};
+
+    const TEST_KEY: &str = include_str!("testdata/oidc_rsa.pem");
+
+    fn claims(now: i64) -> serde_json::Value {
+        serde_json::json!({
+            "iss": "https://issuer.example.com",
+            "sub": "248289761001",
+            "aud": "client",
+            "exp": now + 300,
+            "iat": now,
+            "nonce": "n-0S6_WzA2Mj",
+            "email": "jane@example.com",
+            "email_verified": "true",
+        })
+    }
+
+    fn sign_rs256(key: &RsaPrivateKey, kid: &str, claims: &serde_json::Value) -> String {
+        let header = serde_json::json!({ "alg": "RS256", "kid": kid, "typ": "JWT" });
+        let signed = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(header.to_string()),
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        );
+        let signature = pkcs1v15::SigningKey::<Sha256>::new(key.clone()).sign(signed.as_bytes());
+        format!(
+            "{}.{}",
+            signed,
+            URL_SAFE_NO_PAD.encode(signature.to_bytes())
+        )
+    }
+
+    #[test]
+    fn test_pkce_challenge() {
+        // RFC 7636, appendix B
+        assert_eq!(
+            pkce_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+
+    #[test]
+    fn test_verify_id_token() {
+        let key = RsaPrivateKey::from_pkcs8_pem(TEST_KEY).unwrap();
+        let jwks = Jwks {
+            keys: vec![Jwk {
+                kty: "RSA".into(),
+                kid: Some("key-1".into()),
+                alg: Some("RS256".into()),
+                key_use: Some("sig".into()),
+                n: Some(URL_SAFE_NO_PAD.encode(key.n().to_bytes_be())),
+                e: Some(URL_SAFE_NO_PAD.encode(key.e().to_bytes_be())),
+                ..Default::default()
+            }],
+        };
+        let now = 1_700_000_000;
+        let validation = IdTokenValidation {
+            issuer: "https://issuer.example.com",
+            client_id: "client",
+            nonce: "n-0S6_WzA2Mj",
+            now,
+        };
+
+        let token = sign_rs256(&key, "key-1", &claims(now));
+        let verified = verify_id_token(&token, &jwks, &validation).unwrap();
+        assert_eq!(verified.sub, "248289761001");
+        assert!(verified.email_verified);
+
+        let tampered = format!("{}x", &token[..token.len() - 1]);
+        assert!(verify_id_token(&tampered, &jwks, &validation).is_err());
+
+        let token = sign_rs256(&key, "key-2", &claims(now));
+        assert_eq!(
+            verify_id_token(&token, &jwks, &validation),
+            Err(OidcError::UnknownKey)
+        );
+
+        let token = sign_rs256(&key, "key-1", &claims(now));
+        let wrong_nonce = IdTokenValidation {
+            nonce: "other",
+            ..validation.clone()
+        };
+        assert_eq!(
+            verify_id_token(&token, &jwks, &wrong_nonce),
+            Err(OidcError::InvalidClaim("nonce"))
+        );
+        let later = IdTokenValidation {
+            now: now + 3600,
+            ..validation.clone()
+        };
+        assert_eq!(
+            verify_id_token(&token, &jwks, &later),
+            Err(OidcError::ExpiredToken)
+        );
+
+        let mut other_audience = claims(now);
+        other_audience["aud"] = serde_json::json!(["client", "other"]);
+        let token = sign_rs256(&key, "key-1", &other_audience);
+        assert_eq!(
+            verify_id_token(&token, &jwks, &validation),
+            Err(OidcError::InvalidClaim("azp"))
+        );
+
+        let token = format!(
+            "{}.{}.",
+            URL_SAFE_NO_PAD.encode(r#"{"alg":"none"}"#),
+            URL_SAFE_NO_PAD.encode(claims(now).to_string())
+        );
+        assert_eq!(
+            verify_id_token(&token, &jwks, &validation),
+            Err(OidcError::UnsupportedAlgorithm("none".into()))
+        );
+    }
+}
@@ -0,0 +1,195 @@
+use serde::{Deserialize, Serialize};
+
+// most common passwords from public breach corpora, one per line
+static COMMON_PASSWORDS: &str = include_str!("common_passwords.txt");
+
+/// Rules new passwords have to follow, along the lines of NIST SP 800-63B: length and
+/// known weak passwords matter, composition rules like "one digit and one symbol" don't.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PasswordPolicy {
+    /// in Unicode characters
+    pub min_length: usize,
+    /// in Unicode characters, long passphrases should always fit
+    pub max_length: usize,
+    /// estimated strength in bits, see [`entropy_bits`]. 0 skips the estimate
+    pub min_entropy_bits: u32,
+    /// reject passwords that contain the username or the local part of the email
+    pub reject_user_info: bool,
+    /// reject passwords from the built-in list of common passwords
+    pub reject_common: bool,
+    /// further passwords to reject, e.g. the name of the service
+    pub blocklist: Vec<String>,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self {
+            min_length: 8,
+            max_length: 128,
+            min_entropy_bits: 30,
+            reject_user_info: true,
+            reject_common: true,
+            blocklist: Vec::new(),
+        }
+    }
+}
+
+/// Why a password was rejected, serialized with a `code` so clients can explain it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "code", rename_all = "snake_case")]
+pub enum PasswordIssue {
+    TooShort {
+        min_length: usize,
+    },
+    TooLong {
+        max_length: usize,
+    },
+    /// easy to guess, e.g. repeated characters or sequences like `abcd`
+    TooWeak,
+    /// contains the username or email
+    ContainsUserInfo,
+    /// on the list of common passwords or the policy's blocklist
+    Common,
+    /// found in a data breach
+    Breached,
+}
+
+impl PasswordPolicy {
+    /// Everything wrong with the password, empty if it is acceptable. `user_inputs` are values
+    /// like the username or email the password shouldn't contain.
+    pub fn check(&self, password: &str, user_inputs: &[&str]) -> Vec<PasswordIssue> {
+        let mut issues = Vec::new();
+        let length = password.chars().count();
+
+        if length < self.min_length {
+            issues.push(PasswordIssue::TooShort {
+                min_length: self.min_length,
+            });
+        }
+        if length > self.max_length {
+            issues.push(PasswordIssue::TooLong {
+                max_length: self.max_length,
+            });
+        }
+
+        let lowercase = password.to_lowercase();
+        if self.reject_user_info
+            && user_inputs
+                .iter()
+                .map(|input| input.split('@').next().unwrap_or_default().to_lowercase())
+                // short names would match too many passwords by chance
+                .filter(|input| input.chars().count() >= 3)
+                .any(|input| lowercase.contains(&input))
+        {
+            issues.push(PasswordIssue::ContainsUserInfo);
+        }
+
+        let common = self.reject_common && COMMON_PASSWORDS.lines().any(|line| line == lowercase);
+        if common
+            || self
+                .blocklist
+                .iter()
+                .any(|blocked| blocked.to_lowercase() == lowercase)
+        {
+            issues.push(PasswordIssue::Common);
+        }
+
+        if self.min_entropy_bits > 0 && entropy_bits(password) < self.min_entropy_bits as f64 {
+            issues.push(PasswordIssue::TooWeak);
+        }
+
+        issues
+    }
+}
+
+/// A rough estimate of how hard the password is to guess: every character adds the bits of
+/// the character classes in use, except repeats and runs like `abc` or `321`, which add 1 bit
+pub fn entropy_bits(password: &str) -> f64 {
+    let chars: Vec<char> = password.chars().collect();
+
+    let mut pool = 0;
+    if chars.iter().any(|c| c.is_ascii_lowercase()) {
+        pool += 26;
+    }
+    if chars.iter().any(|c| c.is_ascii_uppercase()) {
+        pool += 26;
+    }
+    if chars.iter().any(|c| c.is_ascii_digit()) {
+        pool += 10;
+    }
+    if chars.iter().any(|c| c.is_ascii_punctuation() || *c == ' ') {
+        pool += 33;
+    }
+    if chars.iter().any(|c| !c.is_ascii()) {
+        pool += 100;
+    }
+    let bits_per_char = match pool {
+        0 => return 0.0,
+        pool => (pool as f64).log2(),
+    };
+
+    chars
+        .iter()
+        .enumerate()
+        .map(|(i, c)| {
+            let predictable = i > 0 && (*c as i64 - chars[i - 1] as i64).abs() <= 1;
+            match predictable {
+                true => 1.0,
+                false => bits_per_char,
+            }
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_password_policy() {
+        let policy = PasswordPolicy::default();
+        let check = |password: &str| policy.check(password, &["alice", "alice.smith@example.com"]);
+
+        assert!(check("correct horse battery").is_empty());
+        // length counts characters, not bytes
+        assert_eq!(
+            check("äöüßéè"),
+            vec![PasswordIssue::TooShort { min_length: 8 }]
+        );
+        assert!(check("äöüßéèñç").is_empty());
+        assert_eq!(
+            check(&"x7".repeat(65)),
+            vec![PasswordIssue::TooLong { max_length: 128 }]
+        );
+        assert_eq!(
+            check("my name is alice"),
+            vec![PasswordIssue::ContainsUserInfo]
+        );
+        assert_eq!(
+            check("ALICE.SMITH rocks"),
+            vec![PasswordIssue::ContainsUserInfo]
+        );
+        assert_eq!(check("Password123"), vec![PasswordIssue::Common]);
+        assert_eq!(check("aaaaaaaaaa"), vec![PasswordIssue::TooWeak]);
+        assert_eq!(check("abcdefghijkl"), vec![PasswordIssue::TooWeak]);
+
+        let policy = PasswordPolicy {
+            blocklist: vec!["Keygate Rocks".into()],
+            reject_common: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            policy.check("keygate rocks", &[]),
+            vec![PasswordIssue::Common]
+        );
+        assert!(policy.check("Password123", &[]).is_empty());
+    }
+
+    #[test]
+    fn test_entropy_bits() {
+        assert_eq!(entropy_bits(""), 0.0);
+        assert!(entropy_bits("12345678") < 15.0);
+        assert!(entropy_bits("correct horse battery staple") > 100.0);
+    }
+}
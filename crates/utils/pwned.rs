@@ -1,3 +1,5 @@
+use std::{cmp::Ordering, fs::File, path::Path, time::Duration};
+
 use super::sha1::hash;
 use thiserror::Error;
 
@@ -9,31 +11,177 @@ pub enum PwnedError {
     Pwned,
 }
 
-static PWNED_URL: &str = "https://api.pwnedpasswords.com/range/";
+pub static PWNED_URL: &str = "https://api.pwnedpasswords.com/range/";
 
 pub async fn pwned_check(password: &str) -> Result<(), PwnedError> {
-    let pw_hash = hash(password);
-    let pw_str = hex::encode_upper(pw_hash);
+    PwnedApi::default().check(password).await
+}
+
+fn hash_hex(password: &str) -> String {
+    hex::encode_upper(hash(password))
+}
 
-    let (prefix, suffex) = pw_str.split_at(5);
-    let response = reqwest::get(PWNED_URL.to_owned() + prefix)
-        .await
-        .map_err(|e| PwnedError::Internal("reqwest failed: ".to_string() + &e.to_string()))?;
-    let response_body = response
-        .text()
-        .await
-        .map_err(|e| PwnedError::Internal("text error: ".to_string() + &e.to_string()))?;
+/// Client of the k-anonymity range API of Have I Been Pwned, or a mirror of it.
+/// Only the first 5 characters of the SHA-1 hash leave the machine.
+#[derive(Debug, Clone)]
+pub struct PwnedApi {
+    url: String,
+    client: reqwest::Client,
+}
 
-    if response_body.split('\n').any(|row| row.starts_with(suffex)) {
-        return Err(PwnedError::Pwned);
+impl Default for PwnedApi {
+    fn default() -> Self {
+        Self::new(PWNED_URL, Duration::from_secs(5))
+    }
+}
+
+impl PwnedApi {
+    /// `url` is the range endpoint the hash prefix is appended to
+    pub fn new(url: impl Into<String>, timeout: Duration) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(timeout)
+            .connect_timeout(timeout)
+            .build()
+            .unwrap_or_default();
+
+        Self {
+            url: url.into(),
+            client,
+        }
+    }
+
+    pub async fn check(&self, password: &str) -> Result<(), PwnedError> {
+        let pw_str = hash_hex(password);
+        let (prefix, suffix) = pw_str.split_at(5);
+
+        // padding hides the size of the response, which could hint at the prefix
+        let response = self
+            .client
+            .get(self.url.clone() + prefix)
+            .header("Add-Padding", "true")
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| PwnedError::Internal("reqwest failed: ".to_string() + &e.to_string()))?;
+        let response_body = response
+            .text()
+            .await
+            .map_err(|e| PwnedError::Internal("text error: ".to_string() + &e.to_string()))?;
+
+        if range_contains(&response_body, suffix) {
+            return Err(PwnedError::Pwned);
+        }
+        Ok(())
+    }
+}
+
+// a range response has a `SUFFIX:COUNT` line per hash, padding lines have a count of 0
+fn range_contains(body: &str, suffix: &str) -> bool {
+    body.lines().any(|row| match row.trim().split_once(':') {
+        Some((row_suffix, count)) => {
+            row_suffix.eq_ignore_ascii_case(suffix) && count.trim().parse::<u64>().unwrap_or(1) > 0
+        }
+        None => row.trim().eq_ignore_ascii_case(suffix),
+    })
+}
+
+/// A local list of breached passwords for deployments that can't reach the API: one
+/// `SHA1:COUNT` line per password, sorted by hash, like the "ordered by hash" download of
+/// Have I Been Pwned. The file is binary searched, so it is never read into memory.
+/// Reads don't move a shared cursor, a file can be checked from several threads at once.
+#[derive(Debug)]
+pub struct PwnedFile {
+    file: File,
+    len: u64,
+}
+
+impl PwnedFile {
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+        Ok(Self { file, len })
+    }
+
+    pub fn check(&self, password: &str) -> Result<(), PwnedError> {
+        let pw_str = hash_hex(password);
+
+        // `low` is always the start of a line, the line of the hash starts before `high`
+        let (mut low, mut high) = (0, self.len);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let Some((line, end)) = self
+                .line_from(mid)
+                .map_err(|e| PwnedError::Internal("read error: ".to_string() + &e.to_string()))?
+            else {
+                high = mid;
+                continue;
+            };
+
+            let (line_hash, count) = line.split_once(':').unwrap_or((&line, "1"));
+            match line_hash.to_ascii_uppercase().as_str().cmp(&pw_str) {
+                Ordering::Equal if count.trim().parse::<u64>().unwrap_or(1) > 0 => {
+                    return Err(PwnedError::Pwned)
+                }
+                Ordering::Equal => return Ok(()),
+                Ordering::Less => low = end,
+                Ordering::Greater => high = mid,
+            }
+        }
+
+        Ok(())
+    }
+
+    // the first line that starts at or after `offset` and the offset after it
+    fn line_from(&self, offset: u64) -> std::io::Result<Option<(String, u64)>> {
+        let mut start = offset;
+        if offset > 0 {
+            // skip the rest of the line `offset` is in
+            start = offset - 1 + self.read_line_at(offset - 1)?.len() as u64;
+        }
+
+        let line = self.read_line_at(start)?;
+        match line.len() {
+            0 => Ok(None),
+            read => Ok(Some((
+                String::from_utf8_lossy(&line).trim().to_string(),
+                start + read as u64,
+            ))),
+        }
+    }
+
+    // the bytes from `offset` up to and including the next newline
+    fn read_line_at(&self, mut offset: u64) -> std::io::Result<Vec<u8>> {
+        let mut line = Vec::new();
+        let mut chunk = [0; 128];
+        loop {
+            let read = self.read_at(&mut chunk, offset)?;
+            if read == 0 {
+                return Ok(line);
+            }
+            if let Some(newline) = chunk[..read].iter().position(|&byte| byte == b'\n') {
+                line.extend_from_slice(&chunk[..=newline]);
+                return Ok(line);
+            }
+            line.extend_from_slice(&chunk[..read]);
+            offset += read as u64;
+        }
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::FileExt::read_at(&self.file, buf, offset)
+        }
+        #[cfg(windows)]
+        {
+            std::os::windows::fs::FileExt::seek_read(&self.file, buf, offset)
+        }
     }
-    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use super::pwned_check;
-    use super::PwnedError;
+    use super::*;
 
     #[tokio::test]
     async fn test_pwned_check() -> Result<(), PwnedError> {
@@ -54,4 +202,54 @@ mod tests {
         );
         Ok(())
     }
+
+    #[test]
+    fn test_range_contains() {
+        let body = "0018A45C4D1DEF81644B54AB7F969B88D65:1\r\n00D4F6E8FA6EECAD2A3AA415EEC418D38EC:0\r\n011053FD0102E94D6AE2F8B83D76FAF94F6:13\r\n";
+        assert!(range_contains(body, "0018A45C4D1DEF81644B54AB7F969B88D65"));
+        assert!(range_contains(body, "011053fd0102e94d6ae2f8b83d76faf94f6"));
+        // padding
+        assert!(!range_contains(body, "00D4F6E8FA6EECAD2A3AA415EEC418D38EC"));
+        assert!(!range_contains(body, "FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF"));
+    }
+
+    #[test]
+    fn test_pwned_file() {
+        let breached = ["password", "adminadmin", "123456", "letmein", "qwerty"];
+        let mut lines: Vec<String> = breached
+            .iter()
+            .enumerate()
+            .map(|(i, password)| format!("{}:{}", hash_hex(password), i + 1))
+            .collect();
+        lines.push(format!("{}:0", hash_hex("padding")));
+        lines.sort();
+
+        let path = std::env::temp_dir().join(format!(
+            "keygate-pwned-{}.txt",
+            crate::random::secure_random_id()
+        ));
+        std::fs::write(&path, lines.join("\r\n") + "\r\n").unwrap();
+        let file = PwnedFile::open(&path).unwrap();
+
+        for password in breached {
+            assert_eq!(file.check(password), Err(PwnedError::Pwned), "{}", password);
+        }
+        assert_eq!(file.check("padding"), Ok(()));
+        assert_eq!(file.check("flkatoihkvdjnasdjölewm"), Ok(()));
+
+        // concurrent checks don't read each other's lines
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..100 {
+                        for password in breached {
+                            assert_eq!(file.check(password), Err(PwnedError::Pwned));
+                        }
+                        assert_eq!(file.check("flkatoihkvdjnasdjölewm"), Ok(()));
+                    }
+                });
+            }
+        });
+        std::fs::remove_file(path).unwrap();
+    }
 }
@@ -39,6 +39,46 @@ pub fn secure_random_id() -> String {
     randomid(21)
 }
 
+// numeric one-time code, e.g. for codes sent by email
+pub fn numeric_code(size: usize) -> String {
+    let mut code = String::with_capacity(size);
+    while code.len() < size {
+        for &byte in &random(size) {
+            // reject bytes that would skew the distribution
+            if byte < 250 {
+                code.push(char::from(b'0' + byte % 10));
+                if code.len() == size {
+                    break;
+                }
+            }
+        }
+    }
+    code
+}
+
+// lowercase letters and digits without easily confused characters (0/o, 1/l/i)
+pub const READABLE_ALPHABET: [char; 31] = [
+    'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'j', 'k', 'm', 'n', 'p', 'q', 'r', 's', 't', 'u', 'v',
+    'w', 'x', 'y', 'z', '2', '3', '4', '5', '6', '7', '8', '9',
+];
+
+// code that is meant to be written down by a human, e.g. recovery codes
+pub fn readable_code(size: usize) -> String {
+    let mut code = String::with_capacity(size);
+    while code.len() < size {
+        for &byte in &random(size) {
+            // reject bytes that would skew the distribution
+            if (byte as usize) < 248 {
+                code.push(READABLE_ALPHABET[byte as usize % READABLE_ALPHABET.len()]);
+                if code.len() == size {
+                    break;
+                }
+            }
+        }
+    }
+    code
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -48,4 +88,18 @@ mod tests {
         let id = secure_random_id();
         assert_eq!(id.len(), 21);
     }
+
+    #[test]
+    fn test_numeric_code() {
+        let code = numeric_code(6);
+        assert_eq!(code.len(), 6);
+        assert!(code.chars().all(|c| c.is_ascii_digit()));
+    }
+
+    #[test]
+    fn test_readable_code() {
+        let code = readable_code(10);
+        assert_eq!(code.len(), 10);
+        assert!(code.chars().all(|c| READABLE_ALPHABET.contains(&c)));
+    }
 }
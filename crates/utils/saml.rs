@@ -0,0 +1,1151 @@
+//! SAML 2.0 for a service provider: IdP metadata, AuthnRequests (HTTP-Redirect binding)
+//! and validation of responses (HTTP-POST binding).
+//!
+//! Only enveloped `RSA-SHA256` signatures with exclusive canonicalization and `SHA-256`
+//! digests are supported. The signature has to cover the response or the assertion, and
+//! is only checked against the certificates of the IdP metadata, never the `KeyInfo`
+//! of the signature itself.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use roxmltree::{Document, Node, NodeId, NodeType};
+use rsa::{
+    pkcs1v15,
+    pkcs8::{DecodePrivateKey, DecodePublicKey},
+    signature::{SignatureEncoding, Signer, Verifier},
+    RsaPrivateKey, RsaPublicKey,
+};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use x509_cert::der::{Decode, Encode};
+
+use crate::random::randomid;
+
+/// clock skew tolerated when checking the validity of assertions
+const LEEWAY_SECONDS: i64 = 60;
+
+const NS_PROTOCOL: &str = "urn:oasis:names:tc:SAML:2.0:protocol";
+const NS_ASSERTION: &str = "urn:oasis:names:tc:SAML:2.0:assertion";
+const NS_METADATA: &str = "urn:oasis:names:tc:SAML:2.0:metadata";
+const NS_DSIG: &str = "http://www.w3.org/2000/09/xmldsig#";
+
+const BINDING_REDIRECT: &str = "urn:oasis:names:tc:SAML:2.0:bindings:HTTP-Redirect";
+const BINDING_POST: &str = "urn:oasis:names:tc:SAML:2.0:bindings:HTTP-POST";
+const STATUS_SUCCESS: &str = "urn:oasis:names:tc:SAML:2.0:status:Success";
+const BEARER: &str = "urn:oasis:names:tc:SAML:2.0:cm:bearer";
+
+const EXC_C14N: &str = "http://www.w3.org/2001/10/xml-exc-c14n#";
+const ENVELOPED_SIGNATURE: &str = "http://www.w3.org/2000/09/xmldsig#enveloped-signature";
+const RSA_SHA256: &str = "http://www.w3.org/2001/04/xmldsig-more#rsa-sha256";
+const SHA256: &str = "http://www.w3.org/2001/04/xmlenc#sha256";
+
+/// NameID format of email addresses
+pub const NAMEID_EMAIL: &str = "urn:oasis:names:tc:SAML:1.1:nameid-format:emailAddress";
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum SamlError {
+    #[error("invalid xml: {0}")]
+    Xml(String),
+    #[error("invalid metadata: {0}")]
+    InvalidMetadata(&'static str),
+    #[error("missing signature")]
+    MissingSignature,
+    #[error("unsupported algorithm: {0}")]
+    UnsupportedAlgorithm(String),
+    #[error("invalid signature")]
+    InvalidSignature,
+    #[error("unsuccessful status: {0}")]
+    Status(String),
+    #[error("invalid response: {0}")]
+    InvalidResponse(&'static str),
+    #[error("expired assertion")]
+    ExpiredAssertion,
+}
+
+impl From<roxmltree::Error> for SamlError {
+    fn from(e: roxmltree::Error) -> Self {
+        Self::Xml(e.to_string())
+    }
+}
+
+/// What we need to know about an IdP, from its metadata
+#[derive(Debug, Clone)]
+pub struct IdpMetadata {
+    pub entity_id: String,
+    /// where AuthnRequests are sent to with the HTTP-Redirect binding
+    pub sso_url: String,
+    keys: Vec<RsaPublicKey>,
+}
+
+impl IdpMetadata {
+    /// Parse an `EntityDescriptor` (or the first one with an `IDPSSODescriptor` of an
+    /// `EntitiesDescriptor`). Only RSA signing certificates are used.
+    pub fn parse(xml: &str) -> Result<Self, SamlError> {
+        let doc = Document::parse(xml)?;
+        let entity = doc
+            .descendants()
+            .filter(|node| node.has_tag_name((NS_METADATA, "EntityDescriptor")))
+            .find(|node| child(*node, NS_METADATA, "IDPSSODescriptor").is_some())
+            .ok_or(SamlError::InvalidMetadata("no IDPSSODescriptor"))?;
+        let descriptor = child(entity, NS_METADATA, "IDPSSODescriptor")
+            .ok_or(SamlError::InvalidMetadata("no IDPSSODescriptor"))?;
+
+        let entity_id = entity
+            .attribute("entityID")
+            .ok_or(SamlError::InvalidMetadata("no entityID"))?;
+        let sso_url = children(descriptor, NS_METADATA, "SingleSignOnService")
+            .find(|service| service.attribute("Binding") == Some(BINDING_REDIRECT))
+            .and_then(|service| service.attribute("Location"))
+            .ok_or(SamlError::InvalidMetadata(
+                "no SingleSignOnService with the HTTP-Redirect binding",
+            ))?;
+
+        let keys = children(descriptor, NS_METADATA, "KeyDescriptor")
+            .filter(|key| key.attribute("use").is_none_or(|usage| usage == "signing"))
+            .flat_map(|key| key.descendants())
+            .filter(|node| node.has_tag_name((NS_DSIG, "X509Certificate")))
+            .map(|certificate| public_key(certificate.text().unwrap_or_default()))
+            .collect::<Result<Vec<_>, _>>()?;
+        if keys.is_empty() {
+            return Err(SamlError::InvalidMetadata("no signing certificate"));
+        }
+
+        Ok(Self {
+            entity_id: entity_id.to_string(),
+            sso_url: sso_url.to_string(),
+            keys,
+        })
+    }
+}
+
+// the RSA key of a base64 DER certificate
+fn public_key(certificate: &str) -> Result<RsaPublicKey, SamlError> {
+    let der =
+        decode_base64(certificate).ok_or(SamlError::InvalidMetadata("invalid certificate"))?;
+    let certificate = x509_cert::Certificate::from_der(&der)
+        .map_err(|_| SamlError::InvalidMetadata("invalid certificate"))?;
+    certificate
+        .tbs_certificate
+        .subject_public_key_info
+        .to_der()
+        .ok()
+        .and_then(|spki| RsaPublicKey::from_public_key_der(&spki).ok())
+        .ok_or(SamlError::InvalidMetadata("unsupported certificate key"))
+}
+
+/// The key AuthnRequests are signed with and its certificate, for the SP metadata
+#[derive(Clone)]
+pub struct SigningKey {
+    key: RsaPrivateKey,
+    /// DER
+    certificate: Vec<u8>,
+}
+
+impl SigningKey {
+    /// `private_key` is a PKCS#8 PEM RSA key, `certificate` the PEM certificate of its
+    /// public key
+    pub fn from_pem(private_key: &str, certificate: &str) -> Result<Self, SamlError> {
+        let key = RsaPrivateKey::from_pkcs8_pem(private_key)
+            .map_err(|_| SamlError::InvalidMetadata("invalid private key"))?;
+        let pem: String = certificate
+            .lines()
+            .skip_while(|line| !line.starts_with("-----BEGIN CERTIFICATE-----"))
+            .skip(1)
+            .take_while(|line| !line.starts_with("-----END CERTIFICATE-----"))
+            .collect();
+        let certificate =
+            decode_base64(&pem).ok_or(SamlError::InvalidMetadata("invalid certificate"))?;
+        if public_key(&pem)? != key.to_public_key() {
+            return Err(SamlError::InvalidMetadata(
+                "the certificate is not for the private key",
+            ));
+        }
+        Ok(Self { key, certificate })
+    }
+}
+
+impl std::fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SigningKey").finish_non_exhaustive()
+    }
+}
+
+/// Our side of a connection with an IdP
+#[derive(Debug, Clone)]
+pub struct ServiceProvider<'a> {
+    pub entity_id: &'a str,
+    /// where the IdP posts responses to
+    pub acs_url: &'a str,
+}
+
+impl ServiceProvider<'_> {
+    /// `EntityDescriptor` to import into the IdP
+    pub fn metadata(&self, signing_key: Option<&SigningKey>) -> String {
+        let key_descriptor = match signing_key {
+            Some(SigningKey { certificate, .. }) => format!(
+                r#"<md:KeyDescriptor use="signing"><ds:KeyInfo xmlns:ds="{}"><ds:X509Data><ds:X509Certificate>{}</ds:X509Certificate></ds:X509Data></ds:KeyInfo></md:KeyDescriptor>"#,
+                NS_DSIG,
+                STANDARD.encode(certificate)
+            ),
+            None => String::new(),
+        };
+
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?><md:EntityDescriptor xmlns:md="{}" entityID="{}"><md:SPSSODescriptor AuthnRequestsSigned="{}" protocolSupportEnumeration="{}">{}<md:AssertionConsumerService Binding="{}" Location="{}" index="0" isDefault="true"/></md:SPSSODescriptor></md:EntityDescriptor>"#,
+            NS_METADATA,
+            escape_attribute(self.entity_id),
+            signing_key.is_some(),
+            NS_PROTOCOL,
+            key_descriptor,
+            BINDING_POST,
+            escape_attribute(self.acs_url),
+        )
+    }
+
+    /// Start a login at the IdP. The response has to answer the returned request id.
+    pub fn authn_request(
+        &self,
+        idp: &IdpMetadata,
+        relay_state: &str,
+        signing_key: Option<&SigningKey>,
+        now: OffsetDateTime,
+    ) -> AuthnRequest {
+        let id = format!("_{}", randomid(32));
+        let issue_instant = now
+            .replace_nanosecond(0)
+            .ok()
+            .and_then(|now| now.format(&Rfc3339).ok())
+            .unwrap_or_default();
+        let xml = format!(
+            r#"<samlp:AuthnRequest xmlns:samlp="{}" xmlns:saml="{}" ID="{}" Version="2.0" IssueInstant="{}" Destination="{}" AssertionConsumerServiceURL="{}" ProtocolBinding="{}"><saml:Issuer>{}</saml:Issuer><samlp:NameIDPolicy AllowCreate="true"/></samlp:AuthnRequest>"#,
+            NS_PROTOCOL,
+            NS_ASSERTION,
+            id,
+            issue_instant,
+            escape_attribute(&idp.sso_url),
+            escape_attribute(self.acs_url),
+            BINDING_POST,
+            escape_text(self.entity_id),
+        );
+
+        // HTTP-Redirect binding: the deflated request, signed as a query string
+        let mut deflated = flate2::write::DeflateEncoder::new(Vec::new(), Default::default());
+        deflated
+            .write_all(xml.as_bytes())
+            .expect("writing to a vec");
+        let deflated = deflated.finish().expect("writing to a vec");
+
+        let mut query = format!(
+            "SAMLRequest={}&RelayState={}",
+            url_encode(&STANDARD.encode(deflated)),
+            url_encode(relay_state)
+        );
+        if let Some(SigningKey { key, .. }) = signing_key {
+            query.push_str(&format!("&SigAlg={}", url_encode(RSA_SHA256)));
+            let signature = pkcs1v15::SigningKey::<Sha256>::new(key.clone()).sign(query.as_bytes());
+            query.push_str(&format!(
+                "&Signature={}",
+                url_encode(&STANDARD.encode(signature.to_bytes()))
+            ));
+        }
+
+        let separator = match idp.sso_url.contains('?') {
+            true => '&',
+            false => '?',
+        };
+        AuthnRequest {
+            url: format!("{}{}{}", idp.sso_url, separator, query),
+            id,
+        }
+    }
+}
+
+/// An AuthnRequest with the HTTP-Redirect binding
+#[derive(Debug, Clone)]
+pub struct AuthnRequest {
+    pub id: String,
+    /// where to send the user to
+    pub url: String,
+}
+
+/// What a response has to match
+#[derive(Debug, Clone)]
+pub struct ResponseValidation<'a> {
+    pub sp: ServiceProvider<'a>,
+    /// id of the AuthnRequest the response answers
+    pub request_id: &'a str,
+    /// unix timestamp
+    pub now: i64,
+}
+
+/// The verified assertion of a response
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Assertion {
+    /// only accept an assertion once, at least until it expires
+    pub id: String,
+    pub name_id: String,
+    pub name_id_format: Option<String>,
+    /// values by attribute name
+    pub attributes: BTreeMap<String, Vec<String>>,
+    /// unix timestamp
+    pub expires_at: i64,
+}
+
+impl Assertion {
+    /// first value of an attribute
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .get(name)
+            .and_then(|values| values.first())
+            .map(String::as_str)
+    }
+}
+
+/// Decode the `SAMLResponse` form field of the HTTP-POST binding
+pub fn decode_response(saml_response: &str) -> Result<String, SamlError> {
+    decode_base64(saml_response)
+        .and_then(|xml| String::from_utf8(xml).ok())
+        .ok_or(SamlError::Xml("invalid encoding".into()))
+}
+
+/// Verify the signature and conditions of a response and return its assertion.
+pub fn verify_response(
+    xml: &str,
+    idp: &IdpMetadata,
+    validation: &ResponseValidation,
+) -> Result<Assertion, SamlError> {
+    let doc = Document::parse(xml)?;
+    let response = doc.root_element();
+    if !response.has_tag_name((NS_PROTOCOL, "Response")) {
+        return Err(SamlError::InvalidResponse("not a Response"));
+    }
+
+    // signatures reference elements by id, make sure there is only one to pick
+    let mut ids: Vec<_> = doc
+        .descendants()
+        .filter_map(|node| node.attribute("ID"))
+        .collect();
+    ids.sort_unstable();
+    if ids.windows(2).any(|pair| pair[0] == pair[1]) {
+        return Err(SamlError::InvalidResponse("duplicate ID"));
+    }
+
+    let status = child(response, NS_PROTOCOL, "Status")
+        .and_then(|status| child(status, NS_PROTOCOL, "StatusCode"))
+        .and_then(|code| code.attribute("Value"))
+        .ok_or(SamlError::InvalidResponse("no StatusCode"))?;
+    if status != STATUS_SUCCESS {
+        return Err(SamlError::Status(status.to_string()));
+    }
+
+    if response.attribute("InResponseTo") != Some(validation.request_id) {
+        return Err(SamlError::InvalidResponse("InResponseTo"));
+    }
+    if response
+        .attribute("Destination")
+        .is_some_and(|destination| destination != validation.sp.acs_url)
+    {
+        return Err(SamlError::InvalidResponse("Destination"));
+    }
+    if child(response, NS_ASSERTION, "Issuer")
+        .is_some_and(|issuer| issuer.text() != Some(&idp.entity_id))
+    {
+        return Err(SamlError::InvalidResponse("Issuer"));
+    }
+
+    let mut assertions = children(response, NS_ASSERTION, "Assertion");
+    let (Some(assertion), None) = (assertions.next(), assertions.next()) else {
+        return Err(SamlError::InvalidResponse("expected exactly one Assertion"));
+    };
+
+    // either signature is enough, as long as it covers the assertion
+    let response_signed = has_signature(response);
+    if response_signed {
+        verify_signature(response, &idp.keys)?;
+    }
+    if has_signature(assertion) {
+        verify_signature(assertion, &idp.keys)?;
+    } else if !response_signed {
+        return Err(SamlError::MissingSignature);
+    }
+
+    validate_assertion(assertion, idp, validation)
+}
+
+fn validate_assertion(
+    assertion: Node,
+    idp: &IdpMetadata,
+    validation: &ResponseValidation,
+) -> Result<Assertion, SamlError> {
+    let now = validation.now;
+    let id = assertion
+        .attribute("ID")
+        .ok_or(SamlError::InvalidResponse("no assertion ID"))?;
+    if child(assertion, NS_ASSERTION, "Issuer").map(text) != Some(idp.entity_id.clone()) {
+        return Err(SamlError::InvalidResponse("Issuer"));
+    }
+
+    let subject = child(assertion, NS_ASSERTION, "Subject")
+        .ok_or(SamlError::InvalidResponse("no Subject"))?;
+    let name_id =
+        child(subject, NS_ASSERTION, "NameID").ok_or(SamlError::InvalidResponse("no NameID"))?;
+
+    // the bearer confirmation has to be meant for this request
+    let expires_at = children(subject, NS_ASSERTION, "SubjectConfirmation")
+        .filter(|confirmation| confirmation.attribute("Method") == Some(BEARER))
+        .filter_map(|confirmation| child(confirmation, NS_ASSERTION, "SubjectConfirmationData"))
+        .find(|data| {
+            data.attribute("Recipient") == Some(validation.sp.acs_url)
+                && data
+                    .attribute("InResponseTo")
+                    .is_none_or(|request_id| request_id == validation.request_id)
+        })
+        .and_then(|data| data.attribute("NotOnOrAfter"))
+        .ok_or(SamlError::InvalidResponse("no valid SubjectConfirmation"))?;
+    let mut expires_at = instant(expires_at)?;
+    if now >= expires_at + LEEWAY_SECONDS {
+        return Err(SamlError::ExpiredAssertion);
+    }
+
+    let conditions = child(assertion, NS_ASSERTION, "Conditions")
+        .ok_or(SamlError::InvalidResponse("no Conditions"))?;
+    if let Some(not_before) = conditions.attribute("NotBefore").map(instant).transpose()? {
+        if now + LEEWAY_SECONDS < not_before {
+            return Err(SamlError::InvalidResponse("NotBefore"));
+        }
+    }
+    if let Some(not_on_or_after) = conditions
+        .attribute("NotOnOrAfter")
+        .map(instant)
+        .transpose()?
+    {
+        if now >= not_on_or_after + LEEWAY_SECONDS {
+            return Err(SamlError::ExpiredAssertion);
+        }
+        expires_at = expires_at.min(not_on_or_after);
+    }
+
+    // every restriction has to include us
+    let mut restrictions = children(conditions, NS_ASSERTION, "AudienceRestriction").peekable();
+    if restrictions.peek().is_none() {
+        return Err(SamlError::InvalidResponse("no AudienceRestriction"));
+    }
+    for restriction in restrictions {
+        if !restriction.children().any(|audience| {
+            audience.has_tag_name((NS_ASSERTION, "Audience"))
+                && text(audience).trim() == validation.sp.entity_id
+        }) {
+            return Err(SamlError::InvalidResponse("Audience"));
+        }
+    }
+
+    let mut attributes: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for attribute in children(assertion, NS_ASSERTION, "AttributeStatement")
+        .flat_map(|statement| children(statement, NS_ASSERTION, "Attribute"))
+    {
+        let Some(name) = attribute.attribute("Name") else {
+            continue;
+        };
+        attributes.entry(name.to_string()).or_default().extend(
+            children(attribute, NS_ASSERTION, "AttributeValue")
+                .map(|value| text(value).trim().to_string()),
+        );
+    }
+
+    Ok(Assertion {
+        id: id.to_string(),
+        name_id: text(name_id).trim().to_string(),
+        name_id_format: name_id.attribute("Format").map(str::to_string),
+        attributes,
+        expires_at,
+    })
+}
+
+// all text inside the element. `Node::text` stops at the first comment, which the signature
+// doesn't cover, so `victim@corp.com<!---->.evil.com` would be read as `victim@corp.com`
+// (CVE-2017-11427)
+fn text(node: Node) -> String {
+    node.descendants()
+        .filter(|node| node.is_text())
+        .filter_map(|node| node.text())
+        .collect()
+}
+
+fn instant(value: &str) -> Result<i64, SamlError> {
+    OffsetDateTime::parse(value, &Rfc3339)
+        .map(|instant| instant.unix_timestamp())
+        .map_err(|_| SamlError::InvalidResponse("invalid timestamp"))
+}
+
+fn has_signature(node: Node) -> bool {
+    child(node, NS_DSIG, "Signature").is_some()
+}
+
+// check the enveloped signature of `signed` against the keys of the IdP
+fn verify_signature(signed: Node, keys: &[RsaPublicKey]) -> Result<(), SamlError> {
+    let signature = child(signed, NS_DSIG, "Signature").ok_or(SamlError::MissingSignature)?;
+    let signed_info = child(signature, NS_DSIG, "SignedInfo").ok_or(SamlError::InvalidSignature)?;
+
+    let c14n =
+        child(signed_info, NS_DSIG, "CanonicalizationMethod").ok_or(SamlError::InvalidSignature)?;
+    check_algorithm(c14n, EXC_C14N)?;
+    let method =
+        child(signed_info, NS_DSIG, "SignatureMethod").ok_or(SamlError::InvalidSignature)?;
+    check_algorithm(method, RSA_SHA256)?;
+
+    let mut references = children(signed_info, NS_DSIG, "Reference");
+    let (Some(reference), None) = (references.next(), references.next()) else {
+        return Err(SamlError::InvalidSignature);
+    };
+    let id = signed.attribute("ID").ok_or(SamlError::InvalidSignature)?;
+    if reference.attribute("URI") != Some(&format!("#{}", id)) {
+        return Err(SamlError::InvalidSignature);
+    }
+
+    let mut enveloped = false;
+    let mut reference_c14n = None;
+    for transform in child(reference, NS_DSIG, "Transforms")
+        .into_iter()
+        .flat_map(|transforms| children(transforms, NS_DSIG, "Transform"))
+    {
+        match transform.attribute("Algorithm") {
+            Some(ENVELOPED_SIGNATURE) => enveloped = true,
+            Some(EXC_C14N) => reference_c14n = Some(transform),
+            algorithm => {
+                return Err(SamlError::UnsupportedAlgorithm(
+                    algorithm.unwrap_or_default().to_string(),
+                ))
+            }
+        }
+    }
+    let (true, Some(reference_c14n)) = (enveloped, reference_c14n) else {
+        return Err(SamlError::UnsupportedAlgorithm(
+            "transforms other than enveloped-signature and exc-c14n".into(),
+        ));
+    };
+    let digest_method =
+        child(reference, NS_DSIG, "DigestMethod").ok_or(SamlError::InvalidSignature)?;
+    check_algorithm(digest_method, SHA256)?;
+
+    let digest = child(reference, NS_DSIG, "DigestValue")
+        .and_then(|value| decode_base64(value.text().unwrap_or_default()))
+        .ok_or(SamlError::InvalidSignature)?;
+    let canonical = canonicalize(
+        signed,
+        Some(signature.id()),
+        &inclusive_prefixes(reference_c14n),
+    );
+    if Sha256::digest(canonical.as_bytes()).as_slice() != digest.as_slice() {
+        return Err(SamlError::InvalidSignature);
+    }
+
+    let signature_value = child(signature, NS_DSIG, "SignatureValue")
+        .and_then(|value| decode_base64(value.text().unwrap_or_default()))
+        .and_then(|value| pkcs1v15::Signature::try_from(value.as_slice()).ok())
+        .ok_or(SamlError::InvalidSignature)?;
+    let canonical = canonicalize(signed_info, None, &inclusive_prefixes(c14n));
+    keys.iter()
+        .any(|key| {
+            pkcs1v15::VerifyingKey::<Sha256>::new(key.clone())
+                .verify(canonical.as_bytes(), &signature_value)
+                .is_ok()
+        })
+        .then_some(())
+        .ok_or(SamlError::InvalidSignature)
+}
+
+fn check_algorithm(node: Node, expected: &str) -> Result<(), SamlError> {
+    match node.attribute("Algorithm") {
+        Some(algorithm) if algorithm == expected => Ok(()),
+        algorithm => Err(SamlError::UnsupportedAlgorithm(
+            algorithm.unwrap_or_default().to_string(),
+        )),
+    }
+}
+
+// the `PrefixList` of the `InclusiveNamespaces` of a canonicalization method
+fn inclusive_prefixes<'a>(method: Node<'a, '_>) -> Vec<&'a str> {
+    child(method, EXC_C14N, "InclusiveNamespaces")
+        .and_then(|namespaces| namespaces.attribute("PrefixList"))
+        .map(|prefixes| prefixes.split_whitespace().collect())
+        .unwrap_or_default()
+}
+
+/// Add an enveloped signature to the element with the `ID` `id`, like an IdP does.
+pub fn sign(xml: &str, id: &str, key: &RsaPrivateKey) -> Result<String, SamlError> {
+    let doc = Document::parse(xml)?;
+    let signed = doc
+        .descendants()
+        .find(|node| node.attribute("ID") == Some(id))
+        .ok_or(SamlError::InvalidResponse("no element with this ID"))?;
+    let digest = Sha256::digest(canonicalize(signed, None, &[]).as_bytes());
+
+    let signed_info = format!(
+        r##"<ds:SignedInfo><ds:CanonicalizationMethod Algorithm="{EXC_C14N}"/><ds:SignatureMethod Algorithm="{RSA_SHA256}"/><ds:Reference URI="#{id}"><ds:Transforms><ds:Transform Algorithm="{ENVELOPED_SIGNATURE}"/><ds:Transform Algorithm="{EXC_C14N}"/></ds:Transforms><ds:DigestMethod Algorithm="{SHA256}"/><ds:DigestValue>{}</ds:DigestValue></ds:Reference></ds:SignedInfo>"##,
+        STANDARD.encode(digest)
+    );
+    let unsigned = format!(r#"<ds:Signature xmlns:ds="{NS_DSIG}">{signed_info}</ds:Signature>"#);
+    let unsigned = Document::parse(&unsigned)?;
+    let canonical = canonicalize(unsigned.root_element().first_child().unwrap(), None, &[]);
+    let signature_value = pkcs1v15::SigningKey::<Sha256>::new(key.clone())
+        .sign(canonical.as_bytes())
+        .to_bytes();
+    let signature = format!(
+        r#"<ds:Signature xmlns:ds="{NS_DSIG}">{signed_info}<ds:SignatureValue>{}</ds:SignatureValue></ds:Signature>"#,
+        STANDARD.encode(signature_value)
+    );
+
+    // the signature follows the issuer
+    let position = match child(signed, NS_ASSERTION, "Issuer") {
+        Some(issuer) => issuer.range().end,
+        None => match signed.first_child() {
+            Some(first) => first.range().start,
+            None => return Err(SamlError::InvalidResponse("empty element")),
+        },
+    };
+    Ok(format!(
+        "{}{}{}",
+        &xml[..position],
+        signature,
+        &xml[position..]
+    ))
+}
+
+/// Exclusive XML canonicalization (without comments) of the subtree of `node`, leaving
+/// out the subtree of `exclude`. `inclusive_prefixes` are rendered like with inclusive
+/// canonicalization, `#default` stands for the default namespace.
+pub fn canonicalize<'a>(
+    node: Node<'a, '_>,
+    exclude: Option<NodeId>,
+    inclusive_prefixes: &'a [&'a str],
+) -> String {
+    let mut canonicalizer = Canonicalizer {
+        input: node.document().input_text(),
+        exclude,
+        inclusive_prefixes,
+        out: String::new(),
+    };
+    canonicalizer.element(node, &[]);
+    canonicalizer.out
+}
+
+struct Canonicalizer<'a> {
+    input: &'a str,
+    exclude: Option<NodeId>,
+    inclusive_prefixes: &'a [&'a str],
+    out: String,
+}
+
+impl<'a> Canonicalizer<'a> {
+    // `rendered` are the namespaces declared by output ancestors, `""` is the default one
+    fn element(&mut self, node: Node<'a, '_>, rendered: &[(&'a str, &'a str)]) {
+        // roxmltree resolves prefixes, the ones used in the document are in its source
+        let qname = qname_at(self.input, node.range().start + 1);
+        let attributes: Vec<_> = node
+            .attributes()
+            .map(|attribute| (qname_at(self.input, attribute.position()), attribute))
+            .collect();
+
+        // namespaces are rendered where they are visibly used
+        let mut prefixes = vec![prefix(qname).unwrap_or_default()];
+        prefixes.extend(
+            attributes
+                .iter()
+                .filter_map(|(qname, _)| prefix(qname))
+                .filter(|prefix| *prefix != "xml"),
+        );
+        prefixes.extend(self.inclusive_prefixes.iter().map(|prefix| match *prefix {
+            "#default" => "",
+            prefix => prefix,
+        }));
+        prefixes.sort_unstable();
+        prefixes.dedup();
+
+        let mut rendered = rendered.to_vec();
+        let mut declarations = Vec::new();
+        for prefix in prefixes {
+            let uri = node
+                .lookup_namespace_uri((!prefix.is_empty()).then_some(prefix))
+                .unwrap_or_default();
+            let current = rendered
+                .iter()
+                .find(|(rendered_prefix, _)| *rendered_prefix == prefix)
+                .map(|(_, uri)| *uri)
+                .unwrap_or_default();
+            if uri != current {
+                rendered.retain(|(rendered_prefix, _)| *rendered_prefix != prefix);
+                rendered.push((prefix, uri));
+                declarations.push((prefix, uri));
+            }
+        }
+
+        self.out.push('<');
+        self.out.push_str(qname);
+        for (prefix, uri) in declarations {
+            match prefix {
+                "" => self.out.push_str(" xmlns=\""),
+                prefix => {
+                    self.out.push_str(" xmlns:");
+                    self.out.push_str(prefix);
+                    self.out.push_str("=\"");
+                }
+            }
+            self.out.push_str(&escape_attribute(uri));
+            self.out.push('"');
+        }
+
+        let mut attributes = attributes;
+        attributes.sort_by_key(|(_, attribute)| {
+            (attribute.namespace().unwrap_or_default(), attribute.name())
+        });
+        for (qname, attribute) in attributes {
+            self.out.push(' ');
+            self.out.push_str(qname);
+            self.out.push_str("=\"");
+            self.out.push_str(&escape_attribute(attribute.value()));
+            self.out.push('"');
+        }
+        self.out.push('>');
+
+        for child in node.children() {
+            match child.node_type() {
+                NodeType::Element if Some(child.id()) != self.exclude => {
+                    self.element(child, &rendered)
+                }
+                NodeType::Text => self
+                    .out
+                    .push_str(&escape_text(child.text().unwrap_or_default())),
+                NodeType::PI => {
+                    if let Some(pi) = child.pi() {
+                        self.out.push_str("<?");
+                        self.out.push_str(pi.target);
+                        if let Some(value) = pi.value {
+                            self.out.push(' ');
+                            self.out.push_str(value);
+                        }
+                        self.out.push_str("?>");
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        self.out.push_str("</");
+        self.out.push_str(qname);
+        self.out.push('>');
+    }
+}
+
+// the qualified name starting at `position`
+fn qname_at(input: &str, position: usize) -> &str {
+    let rest = &input[position..];
+    let end = rest
+        .find(|c: char| c.is_whitespace() || matches!(c, '=' | '/' | '>'))
+        .unwrap_or(rest.len());
+    &rest[..end]
+}
+
+fn prefix(qname: &str) -> Option<&str> {
+    qname.split_once(':').map(|(prefix, _)| prefix)
+}
+
+fn child<'a, 'input>(
+    node: Node<'a, 'input>,
+    ns: &'a str,
+    name: &'a str,
+) -> Option<Node<'a, 'input>> {
+    children(node, ns, name).next()
+}
+
+fn children<'a, 'input: 'a>(
+    node: Node<'a, 'input>,
+    ns: &'a str,
+    name: &'a str,
+) -> impl Iterator<Item = Node<'a, 'input>> + 'a {
+    node.children()
+        .filter(move |child| child.has_tag_name((ns, name)))
+}
+
+// base64 in XML may be wrapped
+fn decode_base64(value: &str) -> Option<Vec<u8>> {
+    let value: String = value.chars().filter(|c| !c.is_whitespace()).collect();
+    STANDARD.decode(value).ok()
+}
+
+fn escape_text(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '\r' => escaped.push_str("&#xD;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn escape_attribute(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\t' => escaped.push_str("&#x9;"),
+            '\n' => escaped.push_str("&#xA;"),
+            '\r' => escaped.push_str("&#xD;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn url_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            byte => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    const TEST_KEY: &str = include_str!("testdata/oidc_rsa.pem");
+    const TEST_CERTIFICATE: &str = include_str!("testdata/saml_idp.crt");
+
+    const SP: ServiceProvider = ServiceProvider {
+        entity_id: "https://sp.example.com",
+        acs_url: "https://sp.example.com/acs",
+    };
+
+    fn certificate_base64() -> String {
+        TEST_CERTIFICATE
+            .lines()
+            .filter(|line| !line.starts_with("-----"))
+            .collect()
+    }
+
+    fn idp_metadata() -> String {
+        format!(
+            r#"<md:EntityDescriptor xmlns:md="{NS_METADATA}" entityID="https://idp.example.com">
+  <md:IDPSSODescriptor protocolSupportEnumeration="{NS_PROTOCOL}">
+    <md:KeyDescriptor use="signing">
+      <ds:KeyInfo xmlns:ds="{NS_DSIG}"><ds:X509Data><ds:X509Certificate>
+        {}
+      </ds:X509Certificate></ds:X509Data></ds:KeyInfo>
+    </md:KeyDescriptor>
+    <md:SingleSignOnService Binding="{BINDING_POST}" Location="https://idp.example.com/sso/post"/>
+    <md:SingleSignOnService Binding="{BINDING_REDIRECT}" Location="https://idp.example.com/sso"/>
+  </md:IDPSSODescriptor>
+</md:EntityDescriptor>"#,
+            certificate_base64()
+        )
+    }
+
+    fn timestamp(unix: i64) -> String {
+        OffsetDateTime::from_unix_timestamp(unix)
+            .unwrap()
+            .format(&Rfc3339)
+            .unwrap()
+    }
+
+    fn response(now: i64, audience: &str) -> String {
+        format!(
+            r#"<samlp:Response xmlns:samlp="{NS_PROTOCOL}" xmlns:saml="{NS_ASSERTION}" xmlns:xs="http://www.w3.org/2001/XMLSchema" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" ID="_response" Version="2.0" IssueInstant="{issued}" Destination="https://sp.example.com/acs" InResponseTo="_request">
+  <saml:Issuer>https://idp.example.com</saml:Issuer>
+  <samlp:Status><samlp:StatusCode Value="{STATUS_SUCCESS}"/></samlp:Status>
+  <saml:Assertion ID="_assertion" Version="2.0" IssueInstant="{issued}">
+    <saml:Issuer>https://idp.example.com</saml:Issuer>
+    <saml:Subject>
+      <saml:NameID Format="{NAMEID_EMAIL}">jane@example.com</saml:NameID>
+      <saml:SubjectConfirmation Method="{BEARER}">
+        <saml:SubjectConfirmationData InResponseTo="_request" Recipient="https://sp.example.com/acs" NotOnOrAfter="{expires}"/>
+      </saml:SubjectConfirmation>
+    </saml:Subject>
+    <saml:Conditions NotBefore="{issued}" NotOnOrAfter="{expires}">
+      <saml:AudienceRestriction><saml:Audience>{audience}</saml:Audience></saml:AudienceRestriction>
+    </saml:Conditions>
+    <saml:AttributeStatement>
+      <saml:Attribute Name="groups"><saml:AttributeValue xsi:type="xs:string">admins</saml:AttributeValue><saml:AttributeValue xsi:type="xs:string">users &amp; guests</saml:AttributeValue></saml:Attribute>
+    </saml:AttributeStatement>
+  </saml:Assertion>
+</samlp:Response>"#,
+            issued = timestamp(now),
+            expires = timestamp(now + 300),
+        )
+    }
+
+    fn validation(now: i64) -> ResponseValidation<'static> {
+        ResponseValidation {
+            sp: SP,
+            request_id: "_request",
+            now,
+        }
+    }
+
+    fn url_decode(value: &str) -> String {
+        let mut decoded = Vec::new();
+        let mut bytes = value.bytes();
+        while let Some(byte) = bytes.next() {
+            match byte {
+                b'%' => {
+                    let hex = [bytes.next().unwrap(), bytes.next().unwrap()];
+                    let hex = std::str::from_utf8(&hex).unwrap();
+                    decoded.push(u8::from_str_radix(hex, 16).unwrap());
+                }
+                byte => decoded.push(byte),
+            }
+        }
+        String::from_utf8(decoded).unwrap()
+    }
+
+    #[test]
+    fn test_canonicalize() {
+        let xml = r#"<root xmlns="urn:default" xmlns:a="urn:a" xmlns:unused="urn:unused"><a:child b="2" a:attr='1&amp;&lt;"' xml:lang="en">text &gt; &amp;<!-- comment --><?pi data?><empty/></a:child></root>"#;
+        let doc = Document::parse(xml).unwrap();
+        let child = doc.root_element().first_child().unwrap();
+        let empty = child.last_child().unwrap();
+
+        assert_eq!(
+            canonicalize(child, None, &[]),
+            r#"<a:child xmlns:a="urn:a" b="2" xml:lang="en" a:attr="1&amp;&lt;&quot;">text &gt; &amp;<?pi data?><empty xmlns="urn:default"></empty></a:child>"#
+        );
+        assert_eq!(
+            canonicalize(child, Some(empty.id()), &["unused"]),
+            r#"<a:child xmlns:a="urn:a" xmlns:unused="urn:unused" b="2" xml:lang="en" a:attr="1&amp;&lt;&quot;">text &gt; &amp;<?pi data?></a:child>"#
+        );
+
+        let doc = Document::parse(r#"<root xmlns="urn:d"><x xmlns=""><y/></x></root>"#).unwrap();
+        assert_eq!(
+            canonicalize(doc.root_element(), None, &[]),
+            r#"<root xmlns="urn:d"><x xmlns=""><y></y></x></root>"#
+        );
+    }
+
+    #[test]
+    fn test_idp_metadata() {
+        let idp = IdpMetadata::parse(&idp_metadata()).unwrap();
+        assert_eq!(idp.entity_id, "https://idp.example.com");
+        assert_eq!(idp.sso_url, "https://idp.example.com/sso");
+        assert_eq!(idp.keys.len(), 1);
+
+        let no_certificate = idp_metadata().replace(&certificate_base64(), "");
+        assert!(IdpMetadata::parse(&no_certificate).is_err());
+    }
+
+    #[test]
+    fn test_verify_response() {
+        let key = RsaPrivateKey::from_pkcs8_pem(TEST_KEY).unwrap();
+        let idp = IdpMetadata::parse(&idp_metadata()).unwrap();
+        let now = 1_700_000_000;
+
+        let signed = sign(&response(now, SP.entity_id), "_assertion", &key).unwrap();
+        let assertion = verify_response(&signed, &idp, &validation(now)).unwrap();
+        assert_eq!(assertion.id, "_assertion");
+        assert_eq!(assertion.name_id, "jane@example.com");
+        assert_eq!(assertion.name_id_format.as_deref(), Some(NAMEID_EMAIL));
+        assert_eq!(
+            assertion.attributes["groups"],
+            vec!["admins".to_string(), "users & guests".to_string()]
+        );
+        assert_eq!(assertion.expires_at, now + 300);
+
+        // a signed response covers the assertion as well
+        let signed_response = sign(&response(now, SP.entity_id), "_response", &key).unwrap();
+        assert!(verify_response(&signed_response, &idp, &validation(now)).is_ok());
+
+        assert_eq!(
+            verify_response(&response(now, SP.entity_id), &idp, &validation(now)),
+            Err(SamlError::MissingSignature)
+        );
+
+        let tampered = signed.replace("jane@example.com", "admin@example.com");
+        assert_eq!(
+            verify_response(&tampered, &idp, &validation(now)),
+            Err(SamlError::InvalidSignature)
+        );
+
+        let other_audience = sign(
+            &response(now, "https://other.example.com"),
+            "_assertion",
+            &key,
+        );
+        assert_eq!(
+            verify_response(&other_audience.unwrap(), &idp, &validation(now)),
+            Err(SamlError::InvalidResponse("Audience"))
+        );
+
+        assert_eq!(
+            verify_response(&signed, &idp, &validation(now + 3600)),
+            Err(SamlError::ExpiredAssertion)
+        );
+
+        let other_request = ResponseValidation {
+            request_id: "_other",
+            ..validation(now)
+        };
+        assert_eq!(
+            verify_response(&signed, &idp, &other_request),
+            Err(SamlError::InvalidResponse("InResponseTo"))
+        );
+    }
+
+    #[test]
+    fn test_signature_wrapping() {
+        let key = RsaPrivateKey::from_pkcs8_pem(TEST_KEY).unwrap();
+        let idp = IdpMetadata::parse(&idp_metadata()).unwrap();
+        let now = 1_700_000_000;
+        let signed = sign(&response(now, SP.entity_id), "_assertion", &key).unwrap();
+
+        let start = signed.find("<saml:Assertion").unwrap();
+        let end = signed.find("</saml:Assertion>").unwrap() + "</saml:Assertion>".len();
+        let original = &signed[start..end];
+        let evil = original
+            .replace("jane@example.com", "admin@example.com")
+            .replace(
+                &original[original.find("<ds:Signature").unwrap()
+                    ..original.find("</ds:Signature>").unwrap() + "</ds:Signature>".len()],
+                "",
+            );
+
+        // the signed assertion hidden next to an unsigned one
+        let wrapped = format!(
+            "{}{}<samlp:Extensions>{}</samlp:Extensions>{}",
+            &signed[..start],
+            evil,
+            original,
+            &signed[end..]
+        );
+        assert_eq!(
+            verify_response(&wrapped, &idp, &validation(now)),
+            Err(SamlError::InvalidResponse("duplicate ID"))
+        );
+
+        let evil = evil.replace("_assertion", "_evil");
+        let wrapped = format!(
+            "{}{}<samlp:Extensions>{}</samlp:Extensions>{}",
+            &signed[..start],
+            evil,
+            original,
+            &signed[end..]
+        );
+        assert_eq!(
+            verify_response(&wrapped, &idp, &validation(now)),
+            Err(SamlError::MissingSignature)
+        );
+
+        let both = format!("{}{}{}", &signed[..end], evil, &signed[end..]);
+        assert_eq!(
+            verify_response(&both, &idp, &validation(now)),
+            Err(SamlError::InvalidResponse("expected exactly one Assertion"))
+        );
+    }
+
+    #[test]
+    fn test_comment_truncation() {
+        let key = RsaPrivateKey::from_pkcs8_pem(TEST_KEY).unwrap();
+        let idp = IdpMetadata::parse(&idp_metadata()).unwrap();
+        let now = 1_700_000_000;
+        let xml = response(now, SP.entity_id)
+            .replace("jane@example.com", "jane@example.com.evil.com")
+            .replace(">admins<", ">admins.evil<");
+        let signed = sign(&xml, "_assertion", &key).unwrap();
+
+        // comments are dropped by the canonicalization, so the signature still holds
+        let commented = signed
+            .replace("jane@example.com.evil", "jane@example.com<!---->.evil")
+            .replace(">admins.evil<", ">admins<!---->.evil<");
+        let assertion = verify_response(&commented, &idp, &validation(now)).unwrap();
+        assert_eq!(assertion.name_id, "jane@example.com.evil.com");
+        assert_eq!(assertion.attributes["groups"][0], "admins.evil");
+    }
+
+    #[test]
+    fn test_authn_request() {
+        let signing_key = SigningKey::from_pem(TEST_KEY, TEST_CERTIFICATE).unwrap();
+        let key = &signing_key.key;
+        let idp = IdpMetadata::parse(&idp_metadata()).unwrap();
+        let now = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+
+        let request = SP.authn_request(&idp, "relay state", Some(&signing_key), now);
+        let (url, query) = request.url.split_once('?').unwrap();
+        assert_eq!(url, "https://idp.example.com/sso");
+
+        let params: BTreeMap<_, _> = query
+            .split('&')
+            .map(|param| param.split_once('=').unwrap())
+            .collect();
+        assert_eq!(url_decode(params["RelayState"]), "relay state");
+        assert_eq!(url_decode(params["SigAlg"]), RSA_SHA256);
+
+        let signed = &query[..query.find("&Signature=").unwrap()];
+        let signature = STANDARD.decode(url_decode(params["Signature"])).unwrap();
+        pkcs1v15::VerifyingKey::<Sha256>::new(key.to_public_key())
+            .verify(
+                signed.as_bytes(),
+                &pkcs1v15::Signature::try_from(signature.as_slice()).unwrap(),
+            )
+            .unwrap();
+
+        let deflated = STANDARD.decode(url_decode(params["SAMLRequest"])).unwrap();
+        let mut xml = String::new();
+        flate2::read::DeflateDecoder::new(deflated.as_slice())
+            .read_to_string(&mut xml)
+            .unwrap();
+        let doc = Document::parse(&xml).unwrap();
+        let authn_request = doc.root_element();
+        assert!(authn_request.has_tag_name((NS_PROTOCOL, "AuthnRequest")));
+        assert_eq!(authn_request.attribute("ID"), Some(request.id.as_str()));
+        assert_eq!(
+            authn_request.attribute("IssueInstant"),
+            Some("2023-11-14T22:13:20Z")
+        );
+        assert_eq!(
+            authn_request.attribute("AssertionConsumerServiceURL"),
+            Some(SP.acs_url)
+        );
+        assert_eq!(
+            child(authn_request, NS_ASSERTION, "Issuer").and_then(|issuer| issuer.text()),
+            Some(SP.entity_id)
+        );
+
+        let unsigned = SP.authn_request(&idp, "relay state", None, now);
+        assert!(!unsigned.url.contains("Signature="));
+    }
+
+    #[test]
+    fn test_sp_metadata() {
+        let signing_key = SigningKey::from_pem(TEST_KEY, TEST_CERTIFICATE).unwrap();
+        let metadata = SP.metadata(Some(&signing_key));
+        let doc = Document::parse(&metadata).unwrap();
+        let descriptor = child(doc.root_element(), NS_METADATA, "SPSSODescriptor").unwrap();
+        assert_eq!(descriptor.attribute("AuthnRequestsSigned"), Some("true"));
+        assert_eq!(
+            child(descriptor, NS_METADATA, "AssertionConsumerService")
+                .and_then(|service| service.attribute("Location")),
+            Some(SP.acs_url)
+        );
+        let certificate = doc
+            .descendants()
+            .find(|node| node.has_tag_name((NS_DSIG, "X509Certificate")))
+            .and_then(|certificate| certificate.text())
+            .unwrap();
+        assert_eq!(certificate, certificate_base64());
+
+        assert!(!SP.metadata(None).contains("KeyDescriptor"));
+        assert!(SigningKey::from_pem(TEST_KEY, TEST_KEY).is_err());
+    }
+}
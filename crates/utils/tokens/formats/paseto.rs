@@ -11,7 +11,7 @@ impl TokenFormat for Paseto {
         keypair: KeygateKeypair,
         token: GenerateAccessToken,
     ) -> Result<RawAccessToken, TokenError> {
-        let key = keypair.private_key();
+        let key = secret_key(&keypair);
         let key = PasetoAsymmetricPrivateKey::<V4, Public>::from(key.as_slice());
 
         let access_token = PasetoBuilder::<V4, Public>::default()
@@ -23,6 +23,9 @@ impl TokenFormat for Paseto {
             .set_claim(IssuerClaim::from(token.issuer.as_str()))
             .set_claim(CustomClaim::try_from(("sid", token.session_id))?)
             .set_claim(CustomClaim::try_from(("kind", "access"))?)
+            .set_claim(CustomClaim::try_from(("auth_time", token.auth_time))?)
+            .set_claim(CustomClaim::try_from(("amr", token.amr))?)
+            .set_claim(CustomClaim::try_from(("acr", token.acr))?)
             .set_footer(Footer::from(keypair.id.as_str()))
             .build(&key)
             .map_err(|_| TokenError::FailedToGenerateToken)?;
@@ -34,7 +37,7 @@ impl TokenFormat for Paseto {
         keypair: KeygateKeypair,
         token: GenerateRefreshToken,
     ) -> Result<RawRefreshToken, TokenError> {
-        let key = keypair.private_key();
+        let key = secret_key(&keypair);
         let key = PasetoAsymmetricPrivateKey::<V4, Public>::from(key.as_slice());
 
         let refresh_token = PasetoBuilder::<V4, Public>::default()
@@ -54,46 +57,73 @@ impl TokenFormat for Paseto {
     }
 
     fn verify_access_token(public_key: &[u8], token: &str) -> Result<AccessToken, TokenError> {
-        let key: Key<32> = public_key
+        // `Key::from(&[u8])` panics on other lengths
+        let key: &[u8; 32] = public_key
             .try_into()
             .map_err(|_| TokenError::InvalidToken)?;
+        let key = Key::<32>::from(key);
         let key = PasetoAsymmetricPublicKey::<V4, Public>::from(&key);
 
+        let key_id = get_key_id(token)?;
         let claims = PasetoParser::<V4, Public>::default()
             .check_claim(CustomClaim::try_from(("kind", "access"))?)
+            .set_footer(Footer::from(key_id.as_str()))
             .parse(token, &key)
             .map_err(|_| TokenError::InvalidToken)?;
 
         Ok(AccessToken {
-            audience: claims["aud"].to_string(),
-            subject: claims["sub"].to_string(),
-            issuer: claims["iss"].to_string(),
-            session_id: claims["sid"].to_string(),
-            key_id: claims["kid"].to_string(),
+            audience: string_claim(&claims, "aud"),
+            subject: string_claim(&claims, "sub"),
+            issuer: string_claim(&claims, "iss"),
+            session_id: string_claim(&claims, "sid"),
+            key_id,
+            auth_time: claims["auth_time"].as_i64().unwrap_or_default(),
+            amr: claims["amr"]
+                .as_array()
+                .map(|amr| {
+                    amr.iter()
+                        .filter_map(|method| method.as_str().map(String::from))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            acr: string_claim(&claims, "acr"),
         })
     }
 
     fn verify_refresh_token(public_key: &[u8], token: &str) -> Result<RefreshToken, TokenError> {
-        let key: Key<32> = public_key
+        // `Key::from(&[u8])` panics on other lengths
+        let key: &[u8; 32] = public_key
             .try_into()
             .map_err(|_| TokenError::InvalidToken)?;
+        let key = Key::<32>::from(key);
         let key = PasetoAsymmetricPublicKey::<V4, Public>::from(&key);
 
+        let key_id = get_key_id(token)?;
         let claims = PasetoParser::<V4, Public>::default()
             .check_claim(CustomClaim::try_from(("kind", "refresh"))?)
+            .set_footer(Footer::from(key_id.as_str()))
             .parse(token, &key)
             .map_err(|_| TokenError::InvalidToken)?;
 
         Ok(RefreshToken {
-            audience: claims["aud"].to_string(),
-            subject: claims["sub"].to_string(),
-            issuer: claims["iss"].to_string(),
-            session_id: claims["sid"].to_string(),
-            key_id: claims["kid"].to_string(),
+            audience: string_claim(&claims, "aud"),
+            subject: string_claim(&claims, "sub"),
+            issuer: string_claim(&claims, "iss"),
+            session_id: string_claim(&claims, "sid"),
+            key_id,
         })
     }
 }
 
+fn string_claim(claims: &serde_json::Value, name: &str) -> String {
+    claims[name].as_str().unwrap_or_default().to_string()
+}
+
+// paseto expects the ed25519 seed followed by the public key
+fn secret_key(keypair: &KeygateKeypair) -> Vec<u8> {
+    [keypair.private_key(), keypair.public_key()].concat()
+}
+
 pub fn get_key_id(token: &str) -> Result<String, TokenError> {
     // sadly we have to do this because the paseto library doesn't expose the footer
     // without parsing the token first and we need the footer to get the key id to parse the token
@@ -116,4 +146,36 @@ pub fn duration_to_rfc3339(duration: Duration) -> String {
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_access_token_auth_claims() {
+        let keypair = KeygateKeypair::generate(Algorithm::Ed25519);
+        let public_key = keypair.public_key();
+
+        let token = Paseto::generate_access_token(
+            keypair,
+            GenerateAccessToken {
+                duration: Duration::minutes(5),
+                audience: "app".to_string(),
+                subject: "identity".to_string(),
+                issuer: "keygate".to_string(),
+                session_id: "session".to_string(),
+                auth_time: 1700000000,
+                amr: vec!["pwd".to_string(), "otp".to_string(), "mfa".to_string()],
+                acr: "2".to_string(),
+            },
+        )
+        .unwrap();
+
+        // keys of the wrong length are rejected
+        assert!(Paseto::verify_access_token(&public_key[1..], &token.0).is_err());
+
+        let token = Paseto::verify_access_token(&public_key, &token.0).unwrap();
+        assert_eq!(token.subject, "identity");
+        assert_eq!(token.auth_time, 1700000000);
+        assert_eq!(token.amr, vec!["pwd", "otp", "mfa"]);
+        assert_eq!(token.acr, "2");
+    }
+}
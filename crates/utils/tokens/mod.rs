@@ -82,6 +82,12 @@ pub struct AccessToken {
     pub issuer: String,
     pub session_id: String,
     pub key_id: String,
+    /// unix timestamp of the last time the session authenticated
+    pub auth_time: i64,
+    /// authentication methods used, e.g. `pwd`, `otp` or `mfa`
+    pub amr: Vec<String>,
+    /// authentication context class, `1` for single-factor and `2` for multi-factor
+    pub acr: String,
 }
 
 pub struct GenerateAccessToken {
@@ -90,6 +96,9 @@ pub struct GenerateAccessToken {
     pub subject: String,
     pub issuer: String,
     pub session_id: String,
+    pub auth_time: i64,
+    pub amr: Vec<String>,
+    pub acr: String,
 }
 
 #[derive(Debug, Clone)]
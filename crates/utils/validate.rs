@@ -1,7 +1,7 @@
 use super::random::RANDOMID_ALPHABET;
 
 pub fn is_valid_password(password: &str) -> bool {
-    password.len() >= 8
+    password.chars().count() >= 8
 }
 
 pub fn is_valid_id(random_string: &str) -> bool {